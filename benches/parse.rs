@@ -0,0 +1,107 @@
+//! Times parsing large generated files. Run with `cargo bench --bench parse`,
+//! optionally followed by `--` and parts of the names of the cases to run.
+
+use matfile::parse::{parse_all, ByteOrder};
+use matfile::write::{CompressionLevel, MatFileBuilder, WriteOptions};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: usize = 5;
+const MIB: f64 = 1024.0 * 1024.0;
+
+/// Prints the fastest of a few runs of `f`, with the throughput for `bytes`
+/// bytes of input.
+fn bench<T>(name: &str, bytes: usize, mut f: impl FnMut() -> T) {
+    let mut best = Duration::MAX;
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        black_box(f());
+        best = best.min(start.elapsed());
+    }
+    println!(
+        "{:<32} {:>12.2?} {:>8.0} MiB/s",
+        name,
+        best,
+        bytes as f64 / MIB / best.as_secs_f64()
+    );
+}
+
+/// A file holding a single column vector of 16 Mi doubles, written in the
+/// given byte order.
+fn doubles(byte_order: ByteOrder) -> Vec<u8> {
+    let len = 16 * 1024 * 1024;
+    let values: Vec<f64> = (0..len).map(|x| x as f64).collect();
+    MatFileBuilder::new()
+        .options(WriteOptions::new().byte_order(byte_order))
+        .add_f64("x", &[len, 1], values)
+        .unwrap()
+        .build()
+        .unwrap()
+}
+
+/// A file holding a single column vector of 100 MiB of uint8 values.
+fn uint8() -> Vec<u8> {
+    let len = 100 * 1024 * 1024;
+    let values: Vec<u8> = (0..len).map(|x| x as u8).collect();
+    MatFileBuilder::new()
+        .add_u8("x", &[len, 1], values)
+        .unwrap()
+        .build()
+        .unwrap()
+}
+
+/// A compressed file holding 8 variables of 2 Mi doubles each, which are
+/// decompressed on all cores with the `parallel` feature.
+fn compressed() -> Vec<u8> {
+    let len = 2 * 1024 * 1024;
+    let mut builder = MatFileBuilder::new()
+        .options(WriteOptions::new().compression(Some(CompressionLevel::Fast)));
+    for n in 0..8 {
+        let values: Vec<f64> = (0..len)
+            .map(|x| (x as f64 * 0.001 + n as f64).sin())
+            .collect();
+        builder = builder
+            .add_f64(&format!("x{}", n), &[len, 1], values)
+            .unwrap();
+    }
+    builder.build().unwrap()
+}
+
+fn main() {
+    let filters: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with('-'))
+        .collect();
+    let selected = |name: &str| filters.is_empty() || filters.iter().any(|f| name.contains(f));
+
+    if selected("doubles") {
+        let data = doubles(ByteOrder::LittleEndian);
+        // Copying the input once is the least any parser has to do
+        bench("doubles: copy", data.len(), || data.to_vec());
+        bench("doubles: parse_all", data.len(), || {
+            parse_all(&data).unwrap()
+        });
+    }
+
+    if selected("big-endian") {
+        // Every value has to be byte-swapped on little-endian hosts
+        let data = doubles(ByteOrder::BigEndian);
+        bench("big-endian doubles: parse_all", data.len(), || {
+            parse_all(&data).unwrap()
+        });
+    }
+
+    if selected("uint8") {
+        let data = uint8();
+        bench("uint8: copy", data.len(), || data.to_vec());
+        bench("uint8: parse_all", data.len(), || parse_all(&data).unwrap());
+    }
+
+    if selected("compressed") {
+        // Compare with `cargo bench --features parallel --bench parse`
+        let data = compressed();
+        bench("compressed: parse_all", data.len(), || {
+            parse_all(&data).unwrap()
+        });
+    }
+}
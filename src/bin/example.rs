@@ -2,8 +2,6 @@ use matfile::MatFile;
 #[cfg(feature = "ndarray")]
 use ndarr as ndarray;
 #[cfg(feature = "ndarray")]
-use num_complex;
-#[cfg(feature = "ndarray")]
 use std::convert::TryInto;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -12,7 +10,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("{:#?}", mat_file);
     #[cfg(feature = "ndarray")]
     {
-        let mf_arr = mat_file.find_by_name("C").expect("Missing matrix");
+        let Some(matfile::Array::Numeric(mf_arr)) = mat_file.find_by_name("C") else {
+            panic!("Missing matrix");
+        };
         let nd_arr: ndarray::Array2<num_complex::Complex<f32>> = mf_arr.try_into()?;
         println!("{:#?}", nd_arr);
     }
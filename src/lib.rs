@@ -6,16 +6,23 @@
 //!
 //! ## Feature Status
 //!
-//! Matfile currently allows you to load numeric arrays from .mat files (all floating point and integer types, including complex numbers). All other types are currently ignored.
+//! Matfile currently allows you to load numeric arrays (all floating point and integer types, including complex numbers), sparse arrays, character arrays, cell arrays and structures from .mat files. All other types are currently ignored.
 //!
 //! * [ ] Loading .mat files
 //!   * [x] Numeric arrays
-//!   * [ ] Cell arrays
-//!   * [ ] Structure arrays
+//!   * [x] Cell arrays
+//!   * [x] Structure arrays
 //!   * [ ] Object arrays
-//!   * [ ] Character arrays
-//!   * [ ] Sparse arrays
+//!   * [x] Character arrays
+//!   * [x] Sparse arrays
+//!   * [x] Level 4 files (numeric and character matrices)
 //! * [ ] Writing .mat files
+//!   * [x] Numeric arrays
+//!   * [x] Sparse arrays
+//!   * [x] Character arrays
+//!   * [x] Cell arrays
+//!   * [x] Structure arrays
+//!   * [x] Level 4 files (numeric and character matrices)
 //!
 //! ## Examples
 //!
@@ -23,8 +30,7 @@
 //!
 //! ```rust
 //! # pub fn main() -> Result<(), Box<dyn std::error::Error>> {
-//! let file = std::fs::File::open("tests/double.mat")?;
-//! let mat_file = matfile::MatFile::parse(file)?;
+//! let mat_file = matfile::MatFile::from_path("tests/double.mat")?;
 //! let pos = mat_file.find_by_name("pos");
 //! println!("{:#?}", pos);
 //! # Ok(())
@@ -33,41 +39,91 @@
 //! Might output something like:
 //! ```text
 //! Some(
-//!     Array {
-//!         name: "pos",
-//!         size: [
-//!             2,
-//!             3
-//!         ],
-//!         data: Double {
-//!             real: [
-//!                 -5.0,
-//!                 8.0,
-//!                 6.0,
-//!                 9.0,
-//!                 7.0,
-//!                 10.0
-//!             ],
-//!             imag: None
-//!         }
-//!     }
+//!     Numeric(
+//!         Numeric {
+//!             name: "pos",
+//!             dimensions: Dimensions(
+//!                 [
+//!                     2,
+//!                     3,
+//!                 ],
+//!             ),
+//!             logical: false,
+//!             global: false,
+//!             data: Double {
+//!                 real: [
+//!                     -5.0,
+//!                     8.0,
+//!                     6.0,
+//!                     9.0,
+//!                     7.0,
+//!                     10.0,
+//!                 ],
+//!                 imag: None,
+//!             },
+//!         },
+//!     ),
 //! )
 //! ```
 //!
+//! Writing a .mat file with a few variables:
+//!
+//! ```rust
+//! # pub fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use matfile::write::MatFileBuilder;
+//!
+//! let bytes = MatFileBuilder::new()
+//!     .add_f64("pressure", &[2, 3], [1.0, 2.0, 3.0, 4.0, 5.0, 6.0])?
+//!     .add_string("units", "kPa")?
+//!     .add_struct("meta", |meta| meta.add_i32("run", &[1, 1], [7]))?
+//!     .build()?;
+//! let mat_file = matfile::MatFile::parse(bytes.as_slice())?;
+//! assert_eq!(mat_file.arrays().len(), 3);
+//! # Ok(())
+//! # }
+//! ```
+//!
 //! ## Crate Feature Flags
 //! The following crate feature flags can be enabled in your Cargo.toml:
 //! * `ndarray`
-//!   * Enable conversions between Matfile and `ndarray` array types
+//!   * Enable conversions between Matfile and `ndarray` array types, and
+//!     writing `ndarray` arrays with `MatFileBuilder::add_ndarray()`
+//! * `parallel`
+//!   * Decompress and parse the compressed variables of a file on all cores.
+//!     Concurrent calls share the cores instead of each starting a thread
+//!     per core
+//! * `random`
+//!   * Enable the `random` module, which generates random variables for
+//!     testing code that reads or writes MAT-files
+//! * `hdf5`
+//!   * Read v7.3 files, which are HDF5 containers, with the `parse_v73`
+//!     module. [`MatFile`] and [`parse::parse_auto`] read them as well
 
+use num_traits::Zero;
 use std::borrow::Cow;
 use std::convert::{TryFrom, TryInto};
 
 #[macro_use]
 extern crate enum_primitive_derive;
 
+#[macro_use]
+mod numeric;
 #[cfg(feature = "ndarray")]
 pub mod ndarray;
-mod parse;
+pub mod parse;
+pub mod parse_v4;
+#[cfg(feature = "hdf5")]
+pub mod parse_v73;
+#[cfg(any(test, feature = "random"))]
+pub mod random;
+pub mod write;
+pub mod write_v4;
+
+pub use numeric::NumericData;
+pub use parse::{
+    ByteOrder, Dimensions, DuplicateNames, Header, ParseOptions, UnsupportedArrays, VariableFilter,
+    Warning, WarningKind,
+};
 
 /// MatFile is a collection of named arrays.
 ///
@@ -81,17 +137,88 @@ mod parse;
 /// ```
 #[derive(Clone, Debug)]
 pub struct MatFile {
+    header: Header,
     arrays: Vec<Array>,
+    variables: Vec<parse::ArrayHeader>,
+    warnings: Vec<parse::Warning>,
+}
+
+/// How [`MatFile::merge`] handles variables that exist in both files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// Fail with [`Error::NameCollision`] without modifying either file.
+    Error,
+    /// Keep the existing variable and drop the new one.
+    KeepExisting,
+    /// Replace the existing variable with the new one.
+    Overwrite,
+    /// Add the new variable under its name with the first free suffix
+    /// `_1`, `_2`, ... appended.
+    RenameWithSuffix,
+}
+
+/// A short description of a variable stored in a .mat file, as listed by
+/// [`MatFile::variables`].
+#[derive(Clone, Copy, Debug)]
+pub struct VariableInfo<'a> {
+    pub name: &'a str,
+    pub class: parse::ArrayType,
+    pub dimensions: &'a Dimensions,
+    pub is_complex: bool,
+    pub is_logical: bool,
+    pub is_global: bool,
+}
+
+impl<'a> VariableInfo<'a> {
+    fn new(header: &'a parse::ArrayHeader) -> Self {
+        VariableInfo {
+            name: &header.name,
+            class: header.flags.class,
+            dimensions: &header.dimensions,
+            is_complex: header.flags.complex,
+            is_logical: header.flags.logical,
+            is_global: header.flags.global,
+        }
+    }
 }
 
+/// An array stored in a .mat file.
 #[derive(Clone, Debug)]
 pub enum Array {
     Numeric(Numeric),
+    Sparse(Sparse),
     Character(Character),
     Structure(Structure),
+    Cell(Cell),
+}
+
+/// The kind of an [`Array`], i.e. which variant it is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ArrayKind {
+    Numeric,
+    Sparse,
+    Character,
+    Structure,
+    Cell,
+    /// An array of a class that can't be loaded yet, e.g. an object array.
+    Unsupported,
+}
+
+impl std::fmt::Display for ArrayKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            ArrayKind::Numeric => "numeric array",
+            ArrayKind::Sparse => "sparse array",
+            ArrayKind::Character => "character array",
+            ArrayKind::Structure => "structure",
+            ArrayKind::Cell => "cell array",
+            ArrayKind::Unsupported => "unsupported array",
+        };
+        f.write_str(name)
+    }
 }
 
-/// A numeric array (the only type supported at the moment).
+/// A numeric array.
 ///
 /// You can access the arrays of a MatFile either by name or by iterating
 /// through all of them:
@@ -104,7 +231,9 @@ pub enum Array {
 /// }
 ///
 /// for array in mat_file.arrays() {
-///     println!("Found array named {} of size {:?}", array.name(), array.size());
+///     if let matfile::Array::Numeric(numeric) = array {
+///         println!("Found array named {} of size {:?}", numeric.name(), numeric.size());
+///     }
 /// }
 /// # Ok(())
 /// # }
@@ -112,419 +241,172 @@ pub enum Array {
 #[derive(Clone, Debug)]
 pub struct Numeric {
     name: String,
-    size: Vec<usize>,
+    dimensions: Dimensions,
+    logical: bool,
+    global: bool,
     data: NumericData,
+    imag_skipped: bool,
 }
 
-#[derive(Clone, Debug)]
-pub struct Character {
+/// A sparse matrix, stored in compressed sparse column (CSC) format.
+#[derive(Clone)]
+pub struct Sparse {
     name: String,
-    size: Vec<usize>,
-    data: CharacterData,
+    dimensions: Dimensions,
+    logical: bool,
+    global: bool,
+    row_indices: Vec<usize>,
+    column_pointers: Vec<usize>,
+    data: NumericData,
+    imag_skipped: bool,
 }
 
-impl Character {
+impl std::fmt::Debug for Sparse {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Sparse")
+            .field("name", &self.name)
+            .field("dimensions", &self.dimensions)
+            .field("logical", &self.logical)
+            .field("global", &self.global)
+            .field("row_indices", &DebugSlice(&self.row_indices))
+            .field("column_pointers", &DebugSlice(&self.column_pointers))
+            .field("data", &self.data)
+            .field("imag_skipped", &self.imag_skipped)
+            .finish()
+    }
+}
+
+impl Sparse {
+    /// The name of this array.
     pub fn name(&self) -> &str {
         &self.name
     }
 
-    /// The size of this array.
-    ///
-    /// The number of entries in this vector is equal to the number of
-    /// dimensions of this array. Each array has at least two dimensions.
-    /// For two-dimensional arrays the first dimension is the number of rows
-    /// while the second dimension is the number of columns.
+    /// The size of this array. Sparse arrays always have two dimensions.
     pub fn size(&self) -> &Vec<usize> {
-        &self.size
+        &self.dimensions.0
     }
 
-    /// The number of dimensions of this array. Is at least two.
+    /// The number of dimensions of this array. Is always two.
     pub fn ndims(&self) -> usize {
-        self.size.len()
+        self.dimensions.ndims()
     }
 
-    /// The character data stored in this array.
-    pub fn data(&self) -> &CharacterData {
-        &self.data
+    /// The dimensions of this array.
+    pub fn dimensions(&self) -> &Dimensions {
+        &self.dimensions
     }
-}
 
-#[derive(Clone, Debug)]
-pub struct Structure {
-    name: String,
-    values: Vec<Array>,
-}
-
-impl Structure {
-    pub fn name(&self) -> &str {
-        &self.name
+    /// Whether this array was saved as a global variable.
+    pub fn is_global(&self) -> bool {
+        self.global
     }
 
-    pub fn arrays(&self) -> &[Array] {
-        &self.values
+    /// Whether this is a logical array, i.e. its values represent booleans.
+    pub fn is_logical(&self) -> bool {
+        self.logical
     }
 
-    pub fn find_by_name(&self, name: &str) -> Option<&Array> {
-        self.values.iter().find(|v| v.name() == name)
+    /// Whether this array is complex but its imaginary part wasn't loaded,
+    /// see [`ParseOptions::load_imaginary`].
+    pub fn imag_skipped(&self) -> bool {
+        self.imag_skipped
     }
-}
 
-/// Stores the data of a numerical array and abstracts over the actual data
-/// type used. Real and imaginary parts are stored in separate vectors with the
-/// imaginary part being optional.
-///
-/// Numerical data is stored in column-major order. When talking about higher
-/// dimensional arrays this means that the index of the first dimension varies
-/// fastest.
-#[derive(Clone, Debug)]
-pub enum NumericData {
-    Int8 {
-        real: Vec<i8>,
-        imag: Option<Vec<i8>>,
-    },
-    UInt8 {
-        real: Vec<u8>,
-        imag: Option<Vec<u8>>,
-    },
-    Int16 {
-        real: Vec<i16>,
-        imag: Option<Vec<i16>>,
-    },
-    UInt16 {
-        real: Vec<u16>,
-        imag: Option<Vec<u16>>,
-    },
-    Int32 {
-        real: Vec<i32>,
-        imag: Option<Vec<i32>>,
-    },
-    UInt32 {
-        real: Vec<u32>,
-        imag: Option<Vec<u32>>,
-    },
-    Int64 {
-        real: Vec<i64>,
-        imag: Option<Vec<i64>>,
-    },
-    UInt64 {
-        real: Vec<u64>,
-        imag: Option<Vec<u64>>,
-    },
-    Single {
-        real: Vec<f32>,
-        imag: Option<Vec<f32>>,
-    },
-    Double {
-        real: Vec<f64>,
-        imag: Option<Vec<f64>>,
-    },
-}
+    /// Sets whether this array is saved as a global variable.
+    pub fn global(mut self, global: bool) -> Self {
+        self.global = global;
+        self
+    }
 
-#[derive(Clone, Debug)]
-pub enum CharacterData {
-    Unicode(String),
-    NonUnicode(Vec<u16>),
-}
+    /// Sets whether this is a logical array, whose non-zero values are
+    /// all `true`.
+    pub fn logical(mut self, logical: bool) -> Self {
+        self.logical = logical;
+        self
+    }
 
-impl CharacterData {
-    pub fn to_str(&self) -> Option<Cow<str>> {
-        match self {
-            CharacterData::Unicode(v) => Some(v.as_str().into()),
-            CharacterData::NonUnicode(vec) => String::from_utf16(&vec).ok().map(Cow::Owned),
+    /// The non-zero entries of a logical array as `(row, column, value)`
+    /// triplets, in column-major order. Returns `None` if this is not a
+    /// logical array.
+    pub fn to_logical_triplets(&self) -> Option<Vec<(usize, usize, bool)>> {
+        if !self.logical {
+            return None;
         }
+        let values = self.data.real_to_bool();
+        let triplets = self
+            .column_pointers
+            .windows(2)
+            .enumerate()
+            .flat_map(|(column, range)| (range[0]..range[1]).map(move |idx| (column, idx)))
+            .map(|(column, idx)| (self.row_indices[idx], column, values[idx]))
+            .collect();
+        Some(triplets)
     }
 
-    pub fn to_str_lossy(&self) -> Cow<str> {
-        match self {
-            CharacterData::Unicode(v) => v.as_str().into(),
-            CharacterData::NonUnicode(vec) => String::from_utf16_lossy(&vec).into(),
-        }
-    }
-}
-
-fn try_convert_number_format(
-    target_type: parse::ArrayType,
-    data: parse::NumericData,
-) -> Result<parse::NumericData, Error> {
-    match target_type {
-        parse::ArrayType::Double => match data {
-            parse::NumericData::UInt8(data) => Ok(parse::NumericData::Double(
-                data.into_iter().map(|x| x as f64).collect(),
-            )),
-            parse::NumericData::Int16(data) => Ok(parse::NumericData::Double(
-                data.into_iter().map(|x| x as f64).collect(),
-            )),
-            parse::NumericData::UInt16(data) => Ok(parse::NumericData::Double(
-                data.into_iter().map(|x| x as f64).collect(),
-            )),
-            parse::NumericData::Int32(data) => Ok(parse::NumericData::Double(
-                data.into_iter().map(|x| x as f64).collect(),
-            )),
-            parse::NumericData::Double(data) => Ok(parse::NumericData::Double(data)),
-            _ => Err(Error::ConversionError),
-        },
-        parse::ArrayType::Single => match data {
-            parse::NumericData::UInt8(data) => Ok(parse::NumericData::Single(
-                data.into_iter().map(|x| x as f32).collect(),
-            )),
-            parse::NumericData::Int16(data) => Ok(parse::NumericData::Single(
-                data.into_iter().map(|x| x as f32).collect(),
-            )),
-            parse::NumericData::UInt16(data) => Ok(parse::NumericData::Single(
-                data.into_iter().map(|x| x as f32).collect(),
-            )),
-            parse::NumericData::Int32(data) => Ok(parse::NumericData::Single(
-                data.into_iter().map(|x| x as f32).collect(),
-            )),
-            parse::NumericData::Single(data) => Ok(parse::NumericData::Single(data)),
-            _ => Err(Error::ConversionError),
-        },
-        parse::ArrayType::UInt64 => match data {
-            parse::NumericData::UInt8(data) => Ok(parse::NumericData::UInt64(
-                data.into_iter().map(|x| x as u64).collect(),
-            )),
-            parse::NumericData::Int16(data) => Ok(parse::NumericData::UInt64(
-                data.into_iter().map(|x| x as u64).collect(),
-            )),
-            parse::NumericData::UInt16(data) => Ok(parse::NumericData::UInt64(
-                data.into_iter().map(|x| x as u64).collect(),
-            )),
-            parse::NumericData::Int32(data) => Ok(parse::NumericData::UInt64(
-                data.into_iter().map(|x| x as u64).collect(),
-            )),
-            parse::NumericData::UInt64(data) => Ok(parse::NumericData::UInt64(data)),
-            _ => Err(Error::ConversionError),
-        },
-        parse::ArrayType::Int64 => match data {
-            parse::NumericData::UInt8(data) => Ok(parse::NumericData::Int64(
-                data.into_iter().map(|x| x as i64).collect(),
-            )),
-            parse::NumericData::Int16(data) => Ok(parse::NumericData::Int64(
-                data.into_iter().map(|x| x as i64).collect(),
-            )),
-            parse::NumericData::UInt16(data) => Ok(parse::NumericData::Int64(
-                data.into_iter().map(|x| x as i64).collect(),
-            )),
-            parse::NumericData::Int32(data) => Ok(parse::NumericData::Int64(
-                data.into_iter().map(|x| x as i64).collect(),
-            )),
-            parse::NumericData::Int64(data) => Ok(parse::NumericData::Int64(data)),
-            _ => Err(Error::ConversionError),
-        },
-        parse::ArrayType::UInt32 => match data {
-            parse::NumericData::UInt8(data) => Ok(parse::NumericData::UInt32(
-                data.into_iter().map(|x| x as u32).collect(),
-            )),
-            parse::NumericData::Int16(data) => Ok(parse::NumericData::UInt32(
-                data.into_iter().map(|x| x as u32).collect(),
-            )),
-            parse::NumericData::UInt16(data) => Ok(parse::NumericData::UInt32(
-                data.into_iter().map(|x| x as u32).collect(),
-            )),
-            parse::NumericData::UInt32(data) => Ok(parse::NumericData::UInt32(data)),
-            _ => Err(Error::ConversionError),
-        },
-        parse::ArrayType::Int32 => match data {
-            parse::NumericData::UInt8(data) => Ok(parse::NumericData::Int32(
-                data.into_iter().map(|x| x as i32).collect(),
-            )),
-            parse::NumericData::Int16(data) => Ok(parse::NumericData::Int32(
-                data.into_iter().map(|x| x as i32).collect(),
-            )),
-            parse::NumericData::UInt16(data) => Ok(parse::NumericData::Int32(
-                data.into_iter().map(|x| x as i32).collect(),
-            )),
-            parse::NumericData::Int32(data) => Ok(parse::NumericData::Int32(data)),
-            _ => Err(Error::ConversionError),
-        },
-        parse::ArrayType::UInt16 => match data {
-            parse::NumericData::UInt8(data) => Ok(parse::NumericData::UInt16(
-                data.into_iter().map(|x| x as u16).collect(),
-            )),
-            parse::NumericData::UInt16(data) => Ok(parse::NumericData::UInt16(data)),
-            _ => Err(Error::ConversionError),
-        },
-        parse::ArrayType::Int16 => match data {
-            parse::NumericData::UInt8(data) => Ok(parse::NumericData::Int16(
-                data.into_iter().map(|x| x as i16).collect(),
-            )),
-            parse::NumericData::Int16(data) => Ok(parse::NumericData::Int16(data)),
-            _ => Err(Error::ConversionError),
-        },
-        parse::ArrayType::UInt8 => match data {
-            parse::NumericData::UInt8(data) => Ok(parse::NumericData::UInt8(data)),
-            _ => Err(Error::ConversionError),
-        },
-        parse::ArrayType::Int8 => match data {
-            parse::NumericData::Int8(data) => Ok(parse::NumericData::Int8(data)),
-            _ => Err(Error::ConversionError),
-        },
-        _ => Err(Error::ConversionError),
+    /// The number of rows and columns of this array.
+    pub fn shape(&self) -> (usize, usize) {
+        (self.dimensions.rows(), self.dimensions.columns())
     }
-}
 
-impl Array {
-    pub fn name(&self) -> &str {
-        match self {
-            Array::Numeric(numeric) => &numeric.name,
-            Array::Structure(structure) => &structure.name,
-            Array::Character(character) => &character.name,
-        }
+    /// Whether this array has no elements, i.e. it has zero rows or zero
+    /// columns. Arrays whose entries are all zero aren't empty.
+    pub fn is_empty(&self) -> bool {
+        self.dimensions.is_empty()
     }
-}
 
-impl NumericData {
-    fn try_from(
-        target_type: parse::ArrayType,
-        real: parse::NumericData,
-        imag: Option<parse::NumericData>,
-    ) -> Result<Self, Error> {
-        let real = try_convert_number_format(target_type, real)?;
-        let imag = match imag {
-            Some(imag) => Some(try_convert_number_format(target_type, imag)?),
-            None => None,
-        };
-        // The next step should never fail unless there is a bug in the code
-        match (real, imag) {
-            (parse::NumericData::Double(real), None) => Ok(NumericData::Double {
-                real: real,
-                imag: None,
-            }),
-            (parse::NumericData::Double(real), Some(parse::NumericData::Double(imag))) => {
-                Ok(NumericData::Double {
-                    real: real,
-                    imag: Some(imag),
-                })
-            }
-            (parse::NumericData::Single(real), None) => Ok(NumericData::Single {
-                real: real,
-                imag: None,
-            }),
-            (parse::NumericData::Single(real), Some(parse::NumericData::Single(imag))) => {
-                Ok(NumericData::Single {
-                    real: real,
-                    imag: Some(imag),
-                })
-            }
-            (parse::NumericData::UInt64(real), None) => Ok(NumericData::UInt64 {
-                real: real,
-                imag: None,
-            }),
-            (parse::NumericData::UInt64(real), Some(parse::NumericData::UInt64(imag))) => {
-                Ok(NumericData::UInt64 {
-                    real: real,
-                    imag: Some(imag),
-                })
-            }
-            (parse::NumericData::Int64(real), None) => Ok(NumericData::Int64 {
-                real: real,
-                imag: None,
-            }),
-            (parse::NumericData::Int64(real), Some(parse::NumericData::Int64(imag))) => {
-                Ok(NumericData::Int64 {
-                    real: real,
-                    imag: Some(imag),
-                })
-            }
-            (parse::NumericData::UInt32(real), None) => Ok(NumericData::UInt32 {
-                real: real,
-                imag: None,
-            }),
-            (parse::NumericData::UInt32(real), Some(parse::NumericData::UInt32(imag))) => {
-                Ok(NumericData::UInt32 {
-                    real: real,
-                    imag: Some(imag),
-                })
-            }
-            (parse::NumericData::Int32(real), None) => Ok(NumericData::Int32 {
-                real: real,
-                imag: None,
-            }),
-            (parse::NumericData::Int32(real), Some(parse::NumericData::Int32(imag))) => {
-                Ok(NumericData::Int32 {
-                    real: real,
-                    imag: Some(imag),
-                })
-            }
-            (parse::NumericData::UInt16(real), None) => Ok(NumericData::UInt16 {
-                real: real,
-                imag: None,
-            }),
-            (parse::NumericData::UInt16(real), Some(parse::NumericData::UInt16(imag))) => {
-                Ok(NumericData::UInt16 {
-                    real: real,
-                    imag: Some(imag),
-                })
-            }
-            (parse::NumericData::Int16(real), None) => Ok(NumericData::Int16 {
-                real: real,
-                imag: None,
-            }),
-            (parse::NumericData::Int16(real), Some(parse::NumericData::Int16(imag))) => {
-                Ok(NumericData::Int16 {
-                    real: real,
-                    imag: Some(imag),
-                })
-            }
-            (parse::NumericData::UInt8(real), None) => Ok(NumericData::UInt8 {
-                real: real,
-                imag: None,
-            }),
-            (parse::NumericData::UInt8(real), Some(parse::NumericData::UInt8(imag))) => {
-                Ok(NumericData::UInt8 {
-                    real: real,
-                    imag: Some(imag),
-                })
-            }
-            (parse::NumericData::Int8(real), None) => Ok(NumericData::Int8 {
-                real: real,
-                imag: None,
-            }),
-            (parse::NumericData::Int8(real), Some(parse::NumericData::Int8(imag))) => {
-                Ok(NumericData::Int8 {
-                    real: real,
-                    imag: Some(imag),
-                })
-            }
-            _ => return Err(Error::InternalError),
+    /// The number of stored (non-zero) entries.
+    pub fn nnz(&self) -> usize {
+        self.column_pointers.last().copied().unwrap_or(0)
+    }
+
+    /// The real part of the entry at the given position, converted to `f64`.
+    ///
+    /// Returns `None` if the position is out of bounds and `Some(0.0)` for
+    /// entries that are not stored.
+    pub fn get(&self, row: usize, column: usize) -> Option<f64> {
+        let (rows, columns) = self.shape();
+        if row >= rows || column >= columns {
+            return None;
+        }
+        let start = self.column_pointers[column];
+        let end = self.column_pointers[column + 1];
+        // Row indices are sorted within each column
+        match self.row_indices[start..end].binary_search(&row) {
+            Ok(offset) => self.data.real_as_f64(start + offset),
+            Err(_) => Some(0.0),
         }
     }
-}
 
-#[derive(Debug)]
-pub enum Error {
-    IOError(std::io::Error),
-    ParseError(nom::Err<nom::error::Error<&'static [u8]>>),
-    ConversionError,
-    InternalError,
-    Unsupported,
-}
+    /// The row index of each non-zero entry.
+    pub fn row_indices(&self) -> &[usize] {
+        &self.row_indices
+    }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Error::IOError(_) => write!(f, "An I/O error occurred"),
-            Error::ParseError(_) => write!(f, "An error occurred while parsing the file"),
-            Error::ConversionError => {
-                write!(f, "An error occurred while converting number formats")
-            }
-            Error::InternalError => write!(f, "An internal error occurred, this is a bug"),
-            Error::Unsupported => write!(f, "Tried to load unsupported array type"),
-        }
+    /// For each column, the index of its first non-zero entry in
+    /// `row_indices` and `data`. Has one more entry than there are columns,
+    /// the last one being the number of non-zero entries.
+    pub fn column_pointers(&self) -> &[usize] {
+        &self.column_pointers
     }
-}
 
-impl std::error::Error for Error {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        match self {
-            Error::IOError(ref err) => Some(err),
-            _ => None,
-        }
+    /// The values of the non-zero entries of this array, in column-major
+    /// order.
+    pub fn data(&self) -> &NumericData {
+        &self.data
     }
 }
 
-impl Numeric {
-    /// The name of this array.
+/// A character array.
+#[derive(Clone, Debug)]
+pub struct Character {
+    name: String,
+    dimensions: Dimensions,
+    global: bool,
+    data: CharacterData,
+}
+
+impl Character {
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -536,151 +418,2364 @@ impl Numeric {
     /// For two-dimensional arrays the first dimension is the number of rows
     /// while the second dimension is the number of columns.
     pub fn size(&self) -> &Vec<usize> {
-        &self.size
+        &self.dimensions.0
     }
 
     /// The number of dimensions of this array. Is at least two.
     pub fn ndims(&self) -> usize {
-        self.size.len()
+        self.dimensions.ndims()
     }
 
-    /// The actual numerical data stored in this array.
-    ///
-    /// ```rust
-    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
-    /// # let file = std::fs::File::open("tests/double.mat")?;
-    /// # let mat_file = matfile::MatFile::parse(file)?;
-    /// # let array = &mat_file.arrays()[0];
-    /// if let matfile::NumericData::Double { real: real, imag: _ } = array.data() {
-    ///     println!("Real part of the data: {:?}", real);
-    /// }
-    /// # Ok(())
-    /// # }
-    /// ```
-    ///
-    /// For a more convenient access to the data, consider using the
-    /// `matfile-ndarray` crate.
-    pub fn data(&self) -> &NumericData {
-        &self.data
+    /// The dimensions of this array.
+    pub fn dimensions(&self) -> &Dimensions {
+        &self.dimensions
     }
-}
-
-impl TryFrom<parse::DataElement> for Array {
-    type Error = Error;
 
-    fn try_from(value: parse::DataElement) -> Result<Self, Self::Error> {
-        match value {
-            parse::DataElement::NumericMatrix(value) => {
-                let size = value
-                    .header
-                    .dimensions
-                    .into_iter()
-                    .map(|d| d as usize)
-                    .collect();
-                let numeric_data = match NumericData::try_from(
-                    value.header.flags.class,
-                    value.real_part,
-                    value.imag_part,
-                ) {
-                    Ok(numeric_data) => numeric_data,
-                    Err(err) => return Err(err),
-                };
-                Ok(Array::Numeric(Numeric {
-                    size,
-                    name: value.header.name,
-                    data: numeric_data,
-                }))
-            }
-            parse::DataElement::StructureMatrix(structure) => {
-                let mut values = Vec::with_capacity(structure.values.len());
-
-                for item in structure.values {
-                    let item = match item.try_into() {
-                        Ok(v) => v,
-                        Err(Error::Unsupported) => continue,
-                        Err(e) => return Err(e),
-                    };
-
-                    values.push(item);
-                }
-
-                Ok(Array::Structure(Structure {
-                    name: structure.header.name,
-                    values,
-                }))
-            }
-            parse::DataElement::CharacterMatrix(character) => {
-                let size = character
-                    .header
-                    .dimensions
-                    .iter()
-                    .map(|v| *v as usize)
-                    .collect();
+    /// Whether this array was saved as a global variable.
+    pub fn is_global(&self) -> bool {
+        self.global
+    }
 
-                assert!(character.imag_part.is_none());
+    /// Sets whether this array is saved as a global variable.
+    pub fn global(mut self, global: bool) -> Self {
+        self.global = global;
+        self
+    }
 
-                let data = match character.real_part {
-                    parse::CharacterData::Unicode(v) => CharacterData::Unicode(v),
-                    parse::CharacterData::NonUnicode(vec) => CharacterData::NonUnicode(vec),
-                };
+    /// The number of characters.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
 
-                Ok(Array::Character(Character {
-                    name: character.header.name,
-                    size,
-                    data,
-                }))
-            }
-            parse::DataElement::Unsupported => Err(Error::Unsupported),
-            x => unimplemented!("{:?}", x),
-        }
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
     }
-}
 
-impl MatFile {
-    /// Tries to parse a byte sequence as a ".mat" file.
-    pub fn parse<R: std::io::Read>(mut reader: R) -> Result<Self, Error> {
-        let mut buf = Vec::new();
-        reader
-            .read_to_end(&mut buf)
-            .map_err(|err| Error::IOError(err))?;
-        let (_remaining, parse_result) = parse::parse_all(&buf)
-            .map_err(|err| Error::ParseError(parse::replace_err_slice(err, &[])))?;
-        let arrays: Result<Vec<Array>, Error> = parse_result
-            .data_elements
-            .into_iter()
-            .filter_map(|data_element| match data_element.try_into() {
-                Err(Error::Unsupported) => None,
-                res => Some(res),
-            })
-            .collect();
-        let arrays = arrays?;
-        Ok(MatFile { arrays })
+    /// The character data stored in this array.
+    pub fn data(&self) -> &CharacterData {
+        &self.data
     }
 
-    /// List of all arrays in this .mat file.
+    /// The characters of this array in column-major order.
     ///
-    /// When parsing a .mat file all arrays of unsupported type (currently all
-    /// non-numerical and sparse arrays) will be ignored and will thus not be
-    /// part of this list.
-    pub fn arrays(&self) -> &[Array] {
-        &self.arrays
+    /// Returns `None` for arrays that aren't valid UTF-16, use
+    /// [`Character::to_string_lossy`] for those.
+    pub fn as_str(&self) -> Option<&str> {
+        match &self.data {
+            CharacterData::Unicode(str) => Some(str),
+            CharacterData::NonUnicode(_) => None,
+        }
     }
 
-    /// Returns an array with the given name if it exists. Case sensitive.
+    /// The characters of this array in column-major order, decoding raw 16
+    /// bit code units as UTF-16 and replacing invalid sequences with
+    /// `U+FFFD REPLACEMENT CHARACTER`.
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        self.data.to_str_lossy()
+    }
+
+    /// Iterates over the rows of this array.
     ///
-    /// When parsing a .mat file all arrays of unsupported type (currently all
-    /// non-numerical and sparse arrays) will be ignored and will thus not be
-    /// returned by this function.
-    pub fn find_by_name<'me>(&'me self, name: &'_ str) -> Option<&'me Array> {
-        for array in &self.arrays {
-            if array.name() == name {
-                return Some(array);
-            }
-        }
-        None
+    /// MATLAB stores a char matrix with one string per row, but the data
+    /// itself is laid out column by column. This reassembles each row into
+    /// a `String`. Arrays with more than two dimensions are treated as if
+    /// all trailing dimensions were folded into the columns.
+    pub fn rows(&self) -> impl Iterator<Item = String> + '_ {
+        let rows = self.dimensions.rows();
+        let numel = self.dimensions.numel().unwrap_or(0);
+        // The UTF-16 code units of each element. Elements are usually code
+        // units, but some writers count characters outside the BMP as one
+        // element.
+        let elements: Vec<Vec<u16>> = match &self.data {
+            CharacterData::Unicode(str) if str.chars().count() == numel => str
+                .chars()
+                .map(|ch| ch.encode_utf16(&mut [0; 2]).to_vec())
+                .collect(),
+            CharacterData::Unicode(str) => str.encode_utf16().map(|unit| vec![unit]).collect(),
+            CharacterData::NonUnicode(vec) => vec.iter().map(|&unit| vec![unit]).collect(),
+        };
+        let columns = elements.len().checked_div(rows).unwrap_or(0);
+        (0..rows).map(move |row| {
+            let units: Vec<u16> = (0..columns)
+                .flat_map(|column| elements[row + column * rows].iter().copied())
+                .collect();
+            String::from_utf16_lossy(&units)
+        })
     }
 }
 
+/// A structure with named fields.
+///
+/// Each field holds an [`Array`] whose name is the name of the field.
+/// Fields keep the order in which they were stored in the file or inserted.
+///
+/// Structures can also be arrays of structures which all have the same
+/// fields, see [`Structure::element`]. The methods accessing single fields
+/// by name, such as [`Structure::get`], work with the first element of
+/// such arrays.
+#[derive(Clone, Debug)]
+pub struct Structure {
+    name: String,
+    dimensions: Dimensions,
+    global: bool,
+    field_names: Vec<String>,
+    // The values of all fields of the first element, followed by those of
+    // the second element etc.
+    values: Vec<Array>,
+}
+
+impl Structure {
+    /// Creates a 1x1 structure without any fields.
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Structure {
+            name: name.into(),
+            dimensions: vec![1, 1].into(),
+            global: false,
+            field_names: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether this structure was saved as a global variable.
+    pub fn is_global(&self) -> bool {
+        self.global
+    }
+
+    /// Sets whether this structure is saved as a global variable.
+    pub fn global(mut self, global: bool) -> Self {
+        self.global = global;
+        self
+    }
+
+    /// The dimensions of this structure.
+    pub fn dimensions(&self) -> &Dimensions {
+        &self.dimensions
+    }
+
+    /// The number of elements of this structure array, 1 for plain
+    /// structures.
+    pub fn numel(&self) -> usize {
+        self.dimensions.numel().unwrap_or(0)
+    }
+
+    /// The fields of the element at the given linear (column-major) index.
+    pub fn element(&self, idx: usize) -> Option<StructView<'_>> {
+        let len = self.field_names.len();
+        if idx >= self.numel() {
+            return None;
+        }
+        Some(StructView {
+            field_names: &self.field_names,
+            values: &self.values[idx * len..][..len],
+        })
+    }
+
+    /// Iterates over the elements of this structure array in column-major
+    /// order.
+    pub fn elements(&self) -> impl Iterator<Item = StructView<'_>> {
+        (0..self.numel()).filter_map(move |idx| self.element(idx))
+    }
+
+    fn index(&self, name: &str) -> Option<usize> {
+        // unindexed search, let's assume that structures are small
+        self.field_names.iter().position(|v| v == name)
+    }
+
+    /// The number of fields.
+    pub fn len(&self) -> usize {
+        self.field_names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.field_names.is_empty()
+    }
+
+    pub fn field_names(&self) -> impl Iterator<Item = &str> {
+        self.field_names.iter().map(|v| &**v)
+    }
+
+    /// The values of all fields of the first element.
+    pub fn arrays(&self) -> &[Array] {
+        // Structure arrays without elements don't have any values
+        self.values.get(..self.field_names.len()).unwrap_or(&[])
+    }
+
+    /// The values of all fields of the first element, which can be modified
+    /// in place.
+    pub fn arrays_mut(&mut self) -> &mut [Array] {
+        let len = self.field_names.len();
+        self.values.get_mut(..len).unwrap_or(&mut [])
+    }
+
+    /// Iterates over the fields of the first element as
+    /// `(field name, value)` pairs.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Array)> {
+        self.field_names().zip(self.arrays())
+    }
+
+    /// Iterates over the fields of the first element as
+    /// `(field name, value)` pairs, allowing the values to be modified in
+    /// place.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&str, &mut Array)> {
+        self.field_names
+            .iter()
+            .map(|v| &**v)
+            .zip(self.values.iter_mut())
+    }
+
+    /// The value of the field `name` of the first element.
+    pub fn get(&self, name: &str) -> Option<&Array> {
+        let idx = self.index(name)?;
+        self.values.get(idx)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Array> {
+        let idx = self.index(name)?;
+        self.values.get_mut(idx)
+    }
+
+    /// The value at a dotted path of field names, descending into nested
+    /// structures, e.g. `"solver.tolerances.abs"`.
+    ///
+    /// Returns `None` if a field doesn't exist or if a value before the
+    /// last segment is not a structure. Field names can't contain dots in
+    /// Matlab, so no escaping is needed.
+    pub fn get_path(&self, path: &str) -> Option<&Array> {
+        let mut segments = path.split('.');
+        let mut value = self.get(segments.next()?)?;
+        for segment in segments {
+            value = value.as_struct()?.get(segment)?;
+        }
+        Some(value)
+    }
+
+    /// Mutable version of [`Structure::get_path`].
+    pub fn get_path_mut(&mut self, path: &str) -> Option<&mut Array> {
+        let mut segments = path.split('.');
+        let mut value = self.get_mut(segments.next()?)?;
+        for segment in segments {
+            value = match value {
+                Array::Structure(structure) => structure.get_mut(segment)?,
+                _ => return None,
+            };
+        }
+        Some(value)
+    }
+
+    /// Same as [`Structure::get`].
+    pub fn find_by_name(&self, name: &str) -> Option<&Array> {
+        self.get(name)
+    }
+
+    // Turns a structure array without elements into a 1x1 structure, like
+    // Matlab does when assigning to the field of the first element.
+    fn ensure_first_element(&mut self) {
+        if self.numel() == 0 {
+            self.dimensions = vec![1, 1].into();
+            self.values = self
+                .field_names
+                .iter()
+                .map(|name| Array::Numeric(Numeric::empty(name)))
+                .collect();
+        }
+    }
+
+    // Appends a field, setting it to an empty array in every element, and
+    // returns its index.
+    fn add_field(&mut self, name: &str) -> usize {
+        let len = self.field_names.len();
+        let mut old_values = std::mem::take(&mut self.values).into_iter();
+        for _ in 0..self.numel() {
+            self.values.extend(old_values.by_ref().take(len));
+            self.values.push(Array::Numeric(Numeric::empty(name)));
+        }
+        self.field_names.push(name.to_string());
+        len
+    }
+
+    /// Sets the field `name` of the first element to `value`, renaming
+    /// `value` to match the field.
+    ///
+    /// Returns the previous value if the field already existed, in which
+    /// case the field keeps its position. A new field is set to an empty
+    /// array in the other elements of a structure array, and a structure
+    /// array without elements becomes a 1x1 structure, as in Matlab.
+    pub fn insert(&mut self, name: &str, mut value: Array) -> Option<Array> {
+        value.set_name(name.to_string());
+        self.ensure_first_element();
+        match self.index(name) {
+            Some(idx) => Some(std::mem::replace(&mut self.values[idx], value)),
+            None => {
+                let idx = self.add_field(name);
+                self.values[idx] = value;
+                None
+            }
+        }
+    }
+
+    /// Removes the field `name` from all elements and returns its value in
+    /// the first element.
+    pub fn remove(&mut self, name: &str) -> Option<Array> {
+        let idx = self.index(name)?;
+        let len = self.field_names.len();
+        self.field_names.remove(idx);
+        let mut removed = None;
+        for (i, value) in std::mem::take(&mut self.values).into_iter().enumerate() {
+            if i % len != idx {
+                self.values.push(value);
+            } else if removed.is_none() {
+                removed = Some(value);
+            }
+        }
+        removed
+    }
+
+    /// Gets the field `name` of the first element for in-place
+    /// manipulation, like `HashMap::entry`. New fields are appended after
+    /// the existing ones.
+    pub fn entry(&mut self, name: &str) -> Entry<'_> {
+        Entry {
+            index: self.index(name),
+            name: name.to_string(),
+            structure: self,
+        }
+    }
+}
+
+/// The fields of one element of a structure array, see
+/// [`Structure::element`].
+#[derive(Clone, Copy, Debug)]
+pub struct StructView<'a> {
+    field_names: &'a [String],
+    values: &'a [Array],
+}
+
+impl<'a> StructView<'a> {
+    /// The number of fields.
+    pub fn len(&self) -> usize {
+        self.field_names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.field_names.is_empty()
+    }
+
+    pub fn field_names(&self) -> impl Iterator<Item = &'a str> {
+        self.field_names.iter().map(|v| &**v)
+    }
+
+    /// The values of all fields.
+    pub fn arrays(&self) -> &'a [Array] {
+        self.values
+    }
+
+    /// Iterates over the fields as `(field name, value)` pairs.
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, &'a Array)> {
+        self.field_names().zip(self.values)
+    }
+
+    /// The value of the field `name`.
+    pub fn get(&self, name: &str) -> Option<&'a Array> {
+        let idx = self.field_names.iter().position(|v| v == name)?;
+        self.values.get(idx)
+    }
+}
+
+impl std::ops::Index<&str> for Structure {
+    type Output = Array;
+
+    /// Panics if there is no field `name`.
+    fn index(&self, name: &str) -> &Array {
+        match self.get(name) {
+            Some(value) => value,
+            None => panic!("structure has no field `{}`", name),
+        }
+    }
+}
+
+impl std::ops::Index<&str> for StructView<'_> {
+    type Output = Array;
+
+    /// Panics if there is no field `name`.
+    fn index(&self, name: &str) -> &Array {
+        match self.get(name) {
+            Some(value) => value,
+            None => panic!("structure has no field `{}`", name),
+        }
+    }
+}
+
+impl IntoIterator for Structure {
+    type Item = (String, Array);
+    type IntoIter = std::iter::Zip<std::vec::IntoIter<String>, std::vec::IntoIter<Array>>;
+
+    /// Consumes the structure, yielding the fields of its first element as
+    /// `(field name, value)` pairs in field order.
+    fn into_iter(self) -> Self::IntoIter {
+        self.field_names.into_iter().zip(self.values)
+    }
+}
+
+impl std::iter::FromIterator<(String, Array)> for Structure {
+    /// Creates an unnamed 1x1 structure from `(field name, value)` pairs,
+    /// in iteration order. Later values replace earlier ones with the same
+    /// field name.
+    fn from_iter<I: IntoIterator<Item = (String, Array)>>(iter: I) -> Self {
+        let mut structure = Structure::new("");
+        for (name, value) in iter {
+            structure.insert(&name, value);
+        }
+        structure
+    }
+}
+
+/// A field of a [`Structure`] which may or may not exist yet, created by
+/// [`Structure::entry`].
+#[derive(Debug)]
+pub struct Entry<'a> {
+    structure: &'a mut Structure,
+    name: String,
+    index: Option<usize>,
+}
+
+impl<'a> Entry<'a> {
+    /// The name of the field.
+    pub fn key(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the value of the field, setting it to `default` first if it
+    /// doesn't exist.
+    pub fn or_insert(self, default: Array) -> &'a mut Array {
+        self.or_insert_with(|| default)
+    }
+
+    /// Returns the value of the field, setting it to the result of
+    /// `default` first if it doesn't exist.
+    pub fn or_insert_with<F: FnOnce() -> Array>(self, default: F) -> &'a mut Array {
+        let structure = self.structure;
+        let idx = match self.index {
+            Some(idx) => {
+                structure.ensure_first_element();
+                idx
+            }
+            None => {
+                structure.insert(&self.name, default());
+                structure.field_names.len() - 1
+            }
+        };
+        &mut structure.values[idx]
+    }
+
+    /// Calls `f` on the value of the field if it exists.
+    pub fn and_modify<F: FnOnce(&mut Array)>(self, f: F) -> Self {
+        if let Some(value) = self
+            .index
+            .and_then(|idx| self.structure.values.get_mut(idx))
+        {
+            f(value);
+        }
+        self
+    }
+}
+
+/// A cell array, holding an array in each of its cells.
+///
+/// The arrays in the cells don't have a name.
+#[derive(Clone, Debug)]
+pub struct Cell {
+    name: String,
+    dimensions: Dimensions,
+    global: bool,
+    values: Vec<Array>,
+}
+
+impl Cell {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether this cell array was saved as a global variable.
+    pub fn is_global(&self) -> bool {
+        self.global
+    }
+
+    /// Sets whether this cell array is saved as a global variable.
+    pub fn global(mut self, global: bool) -> Self {
+        self.global = global;
+        self
+    }
+
+    /// The dimensions of this cell array.
+    pub fn dimensions(&self) -> &Dimensions {
+        &self.dimensions
+    }
+
+    /// The number of cells.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// The arrays in all cells, in column-major order.
+    pub fn arrays(&self) -> &[Array] {
+        &self.values
+    }
+
+    /// The array in the cell at the given row and column. For arrays with
+    /// more than two dimensions, the trailing dimensions are counted as
+    /// columns.
+    pub fn get(&self, row: usize, column: usize) -> Option<&Array> {
+        let rows = self.dimensions.rows();
+        if row >= rows {
+            return None;
+        }
+        self.values.get(column.checked_mul(rows)?.checked_add(row)?)
+    }
+
+    /// Iterates over the arrays in all cells, in column-major order.
+    pub fn iter(&self) -> impl Iterator<Item = &Array> {
+        self.values.iter()
+    }
+}
+
+#[derive(Clone)]
+pub enum CharacterData {
+    Unicode(String),
+    /// UTF-16 code units that aren't valid UTF-16, i.e. contain unpaired
+    /// surrogates.
+    ///
+    /// Data stored as uint16, which is what Matlab writes by default, used to
+    /// be returned as this variant in any case. It is now decoded into
+    /// [`CharacterData::Unicode`] whenever possible.
+    NonUnicode(Vec<u16>),
+}
+
+impl std::fmt::Debug for CharacterData {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CharacterData::Unicode(str) => f.debug_tuple("Unicode").field(&DebugStr(str)).finish(),
+            CharacterData::NonUnicode(vec) => {
+                f.debug_tuple("NonUnicode").field(&DebugSlice(vec)).finish()
+            }
+        }
+    }
+}
+
+impl CharacterData {
+    pub fn to_str(&self) -> Option<Cow<'_, str>> {
+        match self {
+            CharacterData::Unicode(v) => Some(v.as_str().into()),
+            CharacterData::NonUnicode(vec) => String::from_utf16(vec).ok().map(Cow::Owned),
+        }
+    }
+
+    pub fn to_str_lossy(&self) -> Cow<'_, str> {
+        match self {
+            CharacterData::Unicode(v) => v.as_str().into(),
+            CharacterData::NonUnicode(vec) => String::from_utf16_lossy(vec).into(),
+        }
+    }
+
+    /// The number of characters, or 16 bit code units for non-unicode data.
+    pub fn len(&self) -> usize {
+        match self {
+            CharacterData::Unicode(v) => v.chars().count(),
+            CharacterData::NonUnicode(vec) => vec.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            CharacterData::Unicode(v) => v.is_empty(),
+            CharacterData::NonUnicode(vec) => vec.is_empty(),
+        }
+    }
+}
+
+impl Array {
+    pub fn name(&self) -> &str {
+        match self {
+            Array::Numeric(numeric) => &numeric.name,
+            Array::Sparse(sparse) => &sparse.name,
+            Array::Structure(structure) => &structure.name,
+            Array::Character(character) => &character.name,
+            Array::Cell(cell) => &cell.name,
+        }
+    }
+
+    pub fn kind(&self) -> ArrayKind {
+        match self {
+            Array::Numeric(_) => ArrayKind::Numeric,
+            Array::Sparse(_) => ArrayKind::Sparse,
+            Array::Structure(_) => ArrayKind::Structure,
+            Array::Character(_) => ArrayKind::Character,
+            Array::Cell(_) => ArrayKind::Cell,
+        }
+    }
+
+    /// The dimensions of this array.
+    pub fn dimensions(&self) -> &Dimensions {
+        match self {
+            Array::Numeric(numeric) => &numeric.dimensions,
+            Array::Sparse(sparse) => &sparse.dimensions,
+            Array::Structure(structure) => &structure.dimensions,
+            Array::Character(character) => &character.dimensions,
+            Array::Cell(cell) => &cell.dimensions,
+        }
+    }
+
+    /// Whether this array has no elements, like `[]` or `''`.
+    pub fn is_empty(&self) -> bool {
+        self.dimensions().is_empty()
+    }
+
+    /// Whether this array was saved as a global variable.
+    pub fn is_global(&self) -> bool {
+        match self {
+            Array::Numeric(numeric) => numeric.global,
+            Array::Sparse(sparse) => sparse.global,
+            Array::Structure(structure) => structure.global,
+            Array::Character(character) => character.global,
+            Array::Cell(cell) => cell.global,
+        }
+    }
+
+    /// Approximate number of bytes the data of this array occupies in
+    /// memory, computed the same way Matlab's `whos` command does.
+    pub fn byte_size(&self) -> usize {
+        match self {
+            Array::Numeric(numeric) => numeric.data.byte_size(),
+            Array::Sparse(sparse) => {
+                (sparse.row_indices.len() + sparse.column_pointers.len()) * 8
+                    + sparse.data.byte_size()
+            }
+            Array::Structure(structure) => structure.values.iter().map(Array::byte_size).sum(),
+            Array::Character(character) => character.data.len() * 2,
+            Array::Cell(cell) => cell.values.iter().map(Array::byte_size).sum(),
+        }
+    }
+
+    /// The array flags as they would be stored in a .mat file.
+    pub fn flags(&self) -> parse::ArrayFlags {
+        let (class, complex, logical, nzmax) = match self {
+            Array::Numeric(numeric) => (
+                numeric.data.class(),
+                numeric.data.is_complex(),
+                numeric.logical,
+                0,
+            ),
+            Array::Sparse(sparse) => (
+                parse::ArrayType::Sparse,
+                sparse.data.is_complex(),
+                sparse.logical,
+                sparse.nnz(),
+            ),
+            Array::Structure(_) => (parse::ArrayType::Struct, false, false, 0),
+            Array::Character(_) => (parse::ArrayType::Char, false, false, 0),
+            Array::Cell(_) => (parse::ArrayType::Cell, false, false, 0),
+        };
+        let global = self.is_global();
+        parse::ArrayFlags {
+            complex,
+            global,
+            logical,
+            class,
+            nzmax,
+        }
+    }
+
+    pub fn as_numeric(&self) -> Option<&Numeric> {
+        match self {
+            Array::Numeric(numeric) => Some(numeric),
+            _ => None,
+        }
+    }
+
+    pub fn as_sparse(&self) -> Option<&Sparse> {
+        match self {
+            Array::Sparse(sparse) => Some(sparse),
+            _ => None,
+        }
+    }
+
+    pub fn as_char(&self) -> Option<&Character> {
+        match self {
+            Array::Character(character) => Some(character),
+            _ => None,
+        }
+    }
+
+    pub fn as_struct(&self) -> Option<&Structure> {
+        match self {
+            Array::Structure(structure) => Some(structure),
+            _ => None,
+        }
+    }
+
+    pub fn as_cell(&self) -> Option<&Cell> {
+        match self {
+            Array::Cell(cell) => Some(cell),
+            _ => None,
+        }
+    }
+
+    fn wrong_type(&self, expected: ArrayKind) -> Error {
+        Error::WrongType {
+            name: self.name().to_string(),
+            expected,
+            found: self.kind(),
+        }
+    }
+
+    fn header(&self) -> parse::ArrayHeader {
+        parse::ArrayHeader {
+            flags: self.flags(),
+            dimensions: self.dimensions().clone(),
+            name: self.name().to_string(),
+        }
+    }
+
+    fn set_name(&mut self, name: String) {
+        match self {
+            Array::Numeric(numeric) => numeric.name = name,
+            Array::Sparse(sparse) => sparse.name = name,
+            Array::Structure(structure) => structure.name = name,
+            Array::Character(character) => character.name = name,
+            Array::Cell(cell) => cell.name = name,
+        }
+    }
+
+    /// Calls `f` on this array and, depth first, on every array nested in
+    /// it, together with its path.
+    ///
+    /// Arrays nested more than `max_depth` levels below this one are not
+    /// visited.
+    pub fn walk<F: FnMut(&ElementPath, &Array)>(&self, max_depth: usize, mut f: F) {
+        let mut walker = Walker::new(std::iter::once(self), max_depth);
+        while let Some((path, array)) = walker.next_element() {
+            f(&path, array);
+        }
+    }
+
+    /// Iterates depth first over this array and every array nested in it,
+    /// yielding the path of each array in Matlab syntax, e.g.
+    /// `"results.trials{2}.response"`.
+    ///
+    /// Arrays nested more than `max_depth` levels below this one are not
+    /// visited.
+    pub fn walk_iter(&self, max_depth: usize) -> WalkIter<'_> {
+        WalkIter {
+            walker: Walker::new(std::iter::once(self), max_depth),
+        }
+    }
+
+    // The arrays directly nested in this one, with the path segment leading
+    // to them.
+    fn children(&self) -> Vec<(PathSegment<'_>, &Array)> {
+        match self {
+            Array::Structure(structure) if structure.numel() == 1 => structure
+                .iter()
+                .map(|(name, value)| (PathSegment::Field(name), value))
+                .collect(),
+            Array::Structure(structure) => structure
+                .elements()
+                .enumerate()
+                .flat_map(|(idx, element)| {
+                    element
+                        .iter()
+                        .map(move |(name, value)| (PathSegment::ElementField(idx, name), value))
+                })
+                .collect(),
+            Array::Cell(cell) => cell
+                .iter()
+                .enumerate()
+                .map(|(idx, value)| (PathSegment::Index(idx), value))
+                .collect(),
+            Array::Numeric(_) | Array::Sparse(_) | Array::Character(_) => Vec::new(),
+        }
+    }
+}
+
+/// The location of a nested array, as visited by [`Array::walk`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ElementPath {
+    path: String,
+    depth: usize,
+}
+
+enum PathSegment<'a> {
+    Field(&'a str),
+    // Linear index of a cell
+    Index(usize),
+    // Linear index of an element of a structure array and a field name
+    ElementField(usize, &'a str),
+}
+
+impl ElementPath {
+    fn root(name: &str) -> Self {
+        ElementPath {
+            path: name.to_string(),
+            depth: 0,
+        }
+    }
+
+    fn join(&self, segment: PathSegment<'_>) -> Self {
+        let path = match segment {
+            PathSegment::Field(name) => format!("{}.{}", self.path, name),
+            // Matlab indices start at 1
+            PathSegment::Index(idx) => format!("{}{{{}}}", self.path, idx + 1),
+            PathSegment::ElementField(idx, name) => {
+                format!("{}({}).{}", self.path, idx + 1, name)
+            }
+        };
+        ElementPath {
+            path,
+            depth: self.depth + 1,
+        }
+    }
+
+    /// The path in Matlab syntax, starting with the name of the outermost
+    /// array.
+    pub fn as_str(&self) -> &str {
+        &self.path
+    }
+
+    /// The number of levels the array is nested below the outermost array.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+}
+
+impl std::fmt::Display for ElementPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.path)
+    }
+}
+
+// Depth first traversal shared by `Array::walk` and `WalkIter`
+#[derive(Debug)]
+struct Walker<'a> {
+    stack: Vec<(ElementPath, &'a Array)>,
+    max_depth: usize,
+}
+
+impl<'a> Walker<'a> {
+    fn new<I>(roots: I, max_depth: usize) -> Self
+    where
+        I: DoubleEndedIterator<Item = &'a Array>,
+    {
+        Walker {
+            stack: roots
+                .rev()
+                .map(|array| (ElementPath::root(array.name()), array))
+                .collect(),
+            max_depth,
+        }
+    }
+
+    fn next_element(&mut self) -> Option<(ElementPath, &'a Array)> {
+        let (path, array) = self.stack.pop()?;
+        if path.depth < self.max_depth {
+            let children = array.children();
+            self.stack.extend(
+                children
+                    .into_iter()
+                    .rev()
+                    .map(|(segment, child)| (path.join(segment), child)),
+            );
+        }
+        Some((path, array))
+    }
+}
+
+/// A depth first iterator over nested arrays, created by
+/// [`Array::walk_iter`] and [`MatFile::walk_iter`].
+#[derive(Debug)]
+pub struct WalkIter<'a> {
+    walker: Walker<'a>,
+}
+
+impl<'a> Iterator for WalkIter<'a> {
+    type Item = (String, &'a Array);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.walker
+            .next_element()
+            .map(|(path, array)| (path.path, array))
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IOError(std::io::Error),
+    /// The data of the file is malformed.
+    ParseError(parse::ParseError),
+    ConversionError,
+    InternalError,
+    Unsupported,
+    /// There is no variable with the given name.
+    NotFound(String),
+    /// A variable was expected to be a scalar (1x1) but isn't.
+    NotScalar(String),
+    /// A variable name is not valid, e.g. because it is empty.
+    InvalidName(String),
+    /// A variable with the given name already exists.
+    NameCollision(String),
+    /// A variable exists but is of a different kind than requested.
+    WrongType {
+        name: String,
+        expected: ArrayKind,
+        found: ArrayKind,
+    },
+    /// The contents of an array are inconsistent, e.g. its data doesn't
+    /// match its dimensions. `path` is the name of the array, prefixed with
+    /// the names of the structures containing it (e.g. `"outer.inner"`).
+    InvalidArray {
+        path: String,
+        reason: &'static str,
+    },
+    /// The file is a v7.3 MAT-file, which is an HDF5 container. Those are
+    /// only read by [`MatFile`] and [`parse::parse_auto`] with the `hdf5`
+    /// feature.
+    Hdf5,
+    /// The file isn't a Level 5 MAT-file of a supported version, and can't
+    /// be read as a Level 4 file either.
+    InvalidHeader(parse::HeaderError),
+    /// The file contains several variables with each of the given names.
+    DuplicateNames(Vec<String>),
+}
+
+impl Error {
+    /// The code identifying the kind of this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::IOError(_) => ErrorCode::Io,
+            Error::ParseError(err) => match err.kind() {
+                parse::ParseErrorKind::UnexpectedEof => ErrorCode::UnexpectedEof,
+                parse::ParseErrorKind::UnknownDataType(_) => ErrorCode::UnknownDataType,
+                parse::ParseErrorKind::InvalidTag => ErrorCode::InvalidTag,
+                parse::ParseErrorKind::UnknownClass(_) => ErrorCode::UnknownClass,
+                parse::ParseErrorKind::UnexpectedDataType { .. } => ErrorCode::UnexpectedDataType,
+                parse::ParseErrorKind::SizeMismatch { .. } => ErrorCode::SizeMismatch,
+                parse::ParseErrorKind::InvalidText {
+                    subelement: "array name" | "field names",
+                } => ErrorCode::Utf8Name,
+                parse::ParseErrorKind::InvalidText { .. } => ErrorCode::InvalidText,
+                parse::ParseErrorKind::NegativeDimension => ErrorCode::NegativeDimension,
+                parse::ParseErrorKind::TooManyElements => ErrorCode::TooManyElements,
+                parse::ParseErrorKind::LossyConversion { .. } => ErrorCode::LossyConversion,
+                parse::ParseErrorKind::MismatchedParts => ErrorCode::MismatchedParts,
+                parse::ParseErrorKind::InvalidFieldNameLength => ErrorCode::FieldNameLength,
+                parse::ParseErrorKind::UnexpectedName(_) => ErrorCode::UnexpectedName,
+                parse::ParseErrorKind::UnexpectedCompression => ErrorCode::UnexpectedCompression,
+                parse::ParseErrorKind::NestingTooDeep => ErrorCode::NestingTooDeep,
+                parse::ParseErrorKind::DecompressedSizeLimit => ErrorCode::DecompressedSizeLimit,
+                parse::ParseErrorKind::Zlib(_) => ErrorCode::Zlib,
+                parse::ParseErrorKind::TrailingData => ErrorCode::TrailingData,
+                parse::ParseErrorKind::InvalidTypeCode(_) => ErrorCode::V4TypeCode,
+                parse::ParseErrorKind::InvalidHdf5(_) => ErrorCode::Hdf5,
+                parse::ParseErrorKind::NotAllowed(_) => ErrorCode::NotAllowed,
+            },
+            Error::ConversionError => ErrorCode::Conversion,
+            Error::InternalError => ErrorCode::Internal,
+            Error::Unsupported => ErrorCode::Unsupported,
+            Error::NotFound(_) => ErrorCode::NotFound,
+            Error::NotScalar(_) => ErrorCode::NotScalar,
+            Error::InvalidName(_) => ErrorCode::InvalidName,
+            Error::NameCollision(_) => ErrorCode::NameCollision,
+            Error::WrongType { .. } => ErrorCode::WrongType,
+            Error::InvalidArray { .. } => ErrorCode::InvalidArray,
+            Error::Hdf5 => ErrorCode::Hdf5,
+            Error::InvalidHeader(parse::HeaderError::NullText) => ErrorCode::HeaderMagic,
+            Error::InvalidHeader(parse::HeaderError::UnsupportedVersion(_)) => {
+                ErrorCode::UnsupportedVersion
+            }
+            Error::InvalidHeader(parse::HeaderError::Invalid(_)) => ErrorCode::InvalidHeader,
+            Error::DuplicateNames(_) => ErrorCode::DuplicateNames,
+        }
+    }
+
+    fn invalid_array(header: &parse::ArrayHeader, reason: &'static str) -> Self {
+        Error::InvalidArray {
+            path: header.name.clone(),
+            reason,
+        }
+    }
+
+    /// Adds the name of an enclosing structure to the path of an invalid
+    /// array.
+    fn within(self, name: &str) -> Self {
+        match self {
+            Error::InvalidArray { path, reason } => Error::InvalidArray {
+                path: format!("{}.{}", name, path),
+                reason,
+            },
+            err => err,
+        }
+    }
+
+    /// Adds the name of an enclosing cell array and the index of the cell to
+    /// the path of an invalid array.
+    fn within_cell(self, name: &str, index: usize) -> Self {
+        match self {
+            // The arrays in cells don't have a name, so `path` is either
+            // empty or starts with the path into the array
+            Error::InvalidArray { path, reason } => Error::InvalidArray {
+                path: format!("{}{{{}}}{}", name, index + 1, path),
+                reason,
+            },
+            err => err,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "[{}] ", self.code())?;
+        match self {
+            Error::IOError(_) => write!(f, "An I/O error occurred"),
+            Error::ParseError(err) => err.fmt(f),
+            Error::ConversionError => {
+                write!(f, "An error occurred while converting number formats")
+            }
+            Error::InternalError => write!(f, "An internal error occurred, this is a bug"),
+            Error::Unsupported => write!(f, "Tried to load unsupported array type"),
+            Error::NotFound(name) => write!(f, "No variable named \"{}\"", name),
+            Error::NotScalar(name) => write!(f, "Variable \"{}\" is not a scalar", name),
+            Error::InvalidName(name) => write!(f, "Invalid variable name \"{}\"", name),
+            Error::NameCollision(name) => {
+                write!(f, "A variable named \"{}\" already exists", name)
+            }
+            Error::WrongType {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Expected \"{}\" to be a {}, but found a {}",
+                name, expected, found
+            ),
+            Error::InvalidArray { path, reason } => {
+                write!(f, "Invalid array \"{}\": {}", path, reason)
+            }
+            #[cfg(not(feature = "hdf5"))]
+            Error::Hdf5 => write!(
+                f,
+                "This is a v7.3/HDF5 MAT-file, enable the `hdf5` feature of matfile to read it"
+            ),
+            #[cfg(feature = "hdf5")]
+            Error::Hdf5 => write!(
+                f,
+                "This is a v7.3/HDF5 MAT-file, which can only be read as a whole with \
+                 MatFile::parse() or parse::parse_auto()"
+            ),
+            Error::InvalidHeader(err) => err.fmt(f),
+            Error::DuplicateNames(names) => {
+                write!(f, "Duplicate variable names: {}", names.join(", "))
+            }
+        }
+    }
+}
+
+/// A stable identifier for the kind of an [`Error`], for classifying
+/// failures without depending on the wording of the messages.
+///
+/// The string of a code, as returned by [`ErrorCode::as_str`], never changes
+/// and is never reused for a different failure. New codes may be added in
+/// any release.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    Io,
+    /// The file doesn't start with a MAT-file header.
+    HeaderMagic,
+    UnsupportedVersion,
+    InvalidHeader,
+    Hdf5,
+    UnexpectedEof,
+    UnknownDataType,
+    InvalidTag,
+    UnknownClass,
+    UnexpectedDataType,
+    SizeMismatch,
+    /// Character data isn't valid text.
+    InvalidText,
+    /// A variable or field name isn't valid UTF-8.
+    Utf8Name,
+    NegativeDimension,
+    TooManyElements,
+    LossyConversion,
+    MismatchedParts,
+    FieldNameLength,
+    UnexpectedName,
+    UnexpectedCompression,
+    NestingTooDeep,
+    DecompressedSizeLimit,
+    Zlib,
+    TrailingData,
+    V4TypeCode,
+    /// A problem the parse options reject, see [`ParseOptions::strict`].
+    NotAllowed,
+    Conversion,
+    Internal,
+    Unsupported,
+    NotFound,
+    NotScalar,
+    InvalidName,
+    NameCollision,
+    WrongType,
+    InvalidArray,
+    DuplicateNames,
+}
+
+impl ErrorCode {
+    /// The code as a string, e.g. `"E_SIZE_MISMATCH"`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::Io => "E_IO",
+            ErrorCode::HeaderMagic => "E_HEADER_MAGIC",
+            ErrorCode::UnsupportedVersion => "E_UNSUPPORTED_VERSION",
+            ErrorCode::InvalidHeader => "E_INVALID_HEADER",
+            ErrorCode::Hdf5 => "E_HDF5",
+            ErrorCode::UnexpectedEof => "E_UNEXPECTED_EOF",
+            ErrorCode::UnknownDataType => "E_UNKNOWN_DATA_TYPE",
+            ErrorCode::InvalidTag => "E_INVALID_TAG",
+            ErrorCode::UnknownClass => "E_UNKNOWN_CLASS",
+            ErrorCode::UnexpectedDataType => "E_UNEXPECTED_DATA_TYPE",
+            ErrorCode::SizeMismatch => "E_SIZE_MISMATCH",
+            ErrorCode::InvalidText => "E_INVALID_TEXT",
+            ErrorCode::Utf8Name => "E_UTF8_NAME",
+            ErrorCode::NegativeDimension => "E_NEGATIVE_DIMENSION",
+            ErrorCode::TooManyElements => "E_TOO_MANY_ELEMENTS",
+            ErrorCode::LossyConversion => "E_LOSSY_CONVERSION",
+            ErrorCode::MismatchedParts => "E_MISMATCHED_PARTS",
+            ErrorCode::FieldNameLength => "E_FIELD_NAME_LENGTH",
+            ErrorCode::UnexpectedName => "E_UNEXPECTED_NAME",
+            ErrorCode::UnexpectedCompression => "E_UNEXPECTED_COMPRESSION",
+            ErrorCode::NestingTooDeep => "E_NESTING_TOO_DEEP",
+            ErrorCode::DecompressedSizeLimit => "E_DECOMPRESSED_SIZE_LIMIT",
+            ErrorCode::Zlib => "E_ZLIB",
+            ErrorCode::TrailingData => "E_TRAILING_DATA",
+            ErrorCode::V4TypeCode => "E_V4_TYPE_CODE",
+            ErrorCode::NotAllowed => "E_NOT_ALLOWED",
+            ErrorCode::Conversion => "E_CONVERSION",
+            ErrorCode::Internal => "E_INTERNAL",
+            ErrorCode::Unsupported => "E_UNSUPPORTED",
+            ErrorCode::NotFound => "E_NOT_FOUND",
+            ErrorCode::NotScalar => "E_NOT_SCALAR",
+            ErrorCode::InvalidName => "E_INVALID_NAME",
+            ErrorCode::NameCollision => "E_NAME_COLLISION",
+            ErrorCode::WrongType => "E_WRONG_TYPE",
+            ErrorCode::InvalidArray => "E_INVALID_ARRAY",
+            ErrorCode::DuplicateNames => "E_DUPLICATE_NAMES",
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IOError(ref err) => Some(err),
+            Error::InvalidHeader(ref err) => Some(err),
+            Error::ParseError(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Numeric {
+    /// Creates a new numeric array.
+    ///
+    /// Fails if the length of the real part or of the (optional) imaginary
+    /// part doesn't match the number of elements given by the dimensions.
+    ///
+    /// ```rust
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let data = matfile::NumericData::Double {
+    ///     real: vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+    ///     imag: None,
+    /// };
+    /// let array = matfile::Numeric::new("A", vec![2, 3].into(), data)?;
+    /// assert_eq!(array.len(), 6);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn new<S: Into<String>>(
+        name: S,
+        dimensions: Dimensions,
+        data: NumericData,
+    ) -> Result<Self, Error> {
+        let name = name.into();
+        let invalid = |reason| Error::InvalidArray {
+            path: name.clone(),
+            reason,
+        };
+        if dimensions.ndims() < 2 {
+            return Err(invalid("arrays need at least two dimensions"));
+        }
+        let numel = dimensions
+            .numel()
+            .ok_or_else(|| invalid("number of elements overflows"))?;
+        if data.len() != numel || data.imag_len().is_some_and(|len| len != numel) {
+            return Err(invalid("data length does not match the dimensions"));
+        }
+        Ok(Numeric {
+            name,
+            dimensions,
+            logical: false,
+            global: false,
+            data,
+            imag_skipped: false,
+        })
+    }
+
+    // An empty 0x0 double array, which Matlab uses as the default value of
+    // fields
+    fn empty(name: &str) -> Self {
+        Numeric {
+            name: name.to_string(),
+            dimensions: vec![0, 0].into(),
+            logical: false,
+            global: false,
+            data: NumericData::Double {
+                real: Vec::new(),
+                imag: None,
+            },
+            imag_skipped: false,
+        }
+    }
+
+    /// The name of this array.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The size of this array.
+    ///
+    /// The number of entries in this vector is equal to the number of
+    /// dimensions of this array. Each array has at least two dimensions.
+    /// For two-dimensional arrays the first dimension is the number of rows
+    /// while the second dimension is the number of columns.
+    pub fn size(&self) -> &Vec<usize> {
+        &self.dimensions.0
+    }
+
+    /// The number of dimensions of this array. Is at least two.
+    pub fn ndims(&self) -> usize {
+        self.dimensions.ndims()
+    }
+
+    /// The dimensions of this array.
+    pub fn dimensions(&self) -> &Dimensions {
+        &self.dimensions
+    }
+
+    /// The number of elements of this array.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Whether this array has an imaginary part.
+    pub fn is_complex(&self) -> bool {
+        self.data.is_complex()
+    }
+
+    /// Whether this array is complex but its imaginary part wasn't loaded,
+    /// see [`ParseOptions::load_imaginary`].
+    pub fn imag_skipped(&self) -> bool {
+        self.imag_skipped
+    }
+
+    /// Whether this array was saved as a global variable.
+    pub fn is_global(&self) -> bool {
+        self.global
+    }
+
+    /// Whether this is a logical array, i.e. its values represent booleans.
+    pub fn is_logical(&self) -> bool {
+        self.logical
+    }
+
+    /// Sets whether this array is saved as a global variable.
+    pub fn global(mut self, global: bool) -> Self {
+        self.global = global;
+        self
+    }
+
+    /// Sets whether this is a logical array. Matlab only creates logical
+    /// arrays of class uint8.
+    pub fn logical(mut self, logical: bool) -> Self {
+        self.logical = logical;
+        self
+    }
+
+    /// The values of a logical array as booleans, in column-major order.
+    ///
+    /// Any non-zero value is `true`, regardless of the integer type Matlab
+    /// used to store it. Returns `None` if this is not a logical array.
+    pub fn to_logical(&self) -> Option<Vec<bool>> {
+        if !self.logical {
+            return None;
+        }
+        Some(self.data.real_to_bool())
+    }
+
+    /// The values of a complex array as pairs of real and imaginary part,
+    /// converted to `f64`, in column-major order. Returns `None` if the
+    /// array is not complex.
+    ///
+    /// The two parts may be stored in different types in the file, e.g. a
+    /// double real part with an imaginary part of small integers stored as
+    /// uint8. Both are converted to the class of the array while parsing, so
+    /// this works the same for any combination.
+    pub fn to_complex_f64(&self) -> Option<Vec<(f64, f64)>> {
+        self.data.to_complex_f64()
+    }
+
+    /// Like [`Numeric::to_complex_f64`], but converts the pairs one at a
+    /// time instead of collecting them into a vector.
+    pub fn iter_complex_f64(&self) -> Option<impl ExactSizeIterator<Item = (f64, f64)> + '_> {
+        let imag = self.data.iter_imag_f64()?;
+        Some(self.data.iter_f64().zip(imag))
+    }
+
+    /// The actual numerical data stored in this array.
+    ///
+    /// ```rust
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = std::fs::File::open("tests/double.mat")?;
+    /// # let mat_file = matfile::MatFile::parse(file)?;
+    /// # let matfile::Array::Numeric(array) = &mat_file.arrays()[0] else { unreachable!() };
+    /// if let matfile::NumericData::Double { real, imag: _ } = array.data() {
+    ///     println!("Real part of the data: {:?}", real);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// For a more convenient access to the data, consider using the
+    /// `matfile-ndarray` crate.
+    pub fn data(&self) -> &NumericData {
+        &self.data
+    }
+}
+
+/// Formats a slice for `Debug` output like a `Vec`, but elides the middle of
+/// long slices so that printing large arrays doesn't flood the terminal.
+pub(crate) struct DebugSlice<'a, T>(pub(crate) &'a [T]);
+
+// Number of elements shown at either end of a truncated slice
+const DEBUG_EDGE_ELEMENTS: usize = 5;
+
+impl<T: std::fmt::Debug> std::fmt::Debug for DebugSlice<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let slice = self.0;
+        if slice.len() <= 2 * DEBUG_EDGE_ELEMENTS + 1 {
+            return f.debug_list().entries(slice).finish();
+        }
+        f.debug_list()
+            .entries(&slice[..DEBUG_EDGE_ELEMENTS])
+            .entry(&format_args!("... {} elements in total ...", slice.len()))
+            .entries(&slice[slice.len() - DEBUG_EDGE_ELEMENTS..])
+            .finish()
+    }
+}
+
+/// Like [`DebugSlice`] but for strings.
+pub(crate) struct DebugStr<'a>(pub(crate) &'a str);
+
+// Number of characters shown of a truncated string
+const DEBUG_STR_CHARS: usize = 64;
+
+impl std::fmt::Debug for DebugStr<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.0.char_indices().nth(DEBUG_STR_CHARS) {
+            None => std::fmt::Debug::fmt(self.0, f),
+            Some((end, _)) => write!(
+                f,
+                "{:?}... ({} characters in total)",
+                &self.0[..end],
+                self.0.chars().count()
+            ),
+        }
+    }
+}
+
+/// Checks the dimensions of an array header.
+fn array_dimensions(header: &parse::ArrayHeader) -> Result<Dimensions, Error> {
+    if header.dimensions.ndims() < 2 {
+        return Err(Error::invalid_array(
+            header,
+            "arrays need at least two dimensions",
+        ));
+    }
+    Ok(header.dimensions.clone())
+}
+
+/// The number of elements of an array with the given dimensions.
+fn number_of_elements(
+    header: &parse::ArrayHeader,
+    dimensions: &Dimensions,
+) -> Result<usize, Error> {
+    dimensions
+        .numel()
+        .ok_or_else(|| Error::invalid_array(header, "number of elements overflows"))
+}
+
+/// Types that numeric data can be converted into, see
+/// [`MatFile::get_scalar`].
+///
+/// Implemented for all primitive integer and floating point types.
+/// Conversions into integer types fail if they would change the value, e.g.
+/// for `2.5` or `-1` into `u8`. Conversions into floating point types round
+/// to the nearest representable value.
+pub trait FromNumeric: Sized {
+    /// Converts the element of the real part at the given index. Returns
+    /// `None` if the index is out of bounds or the value can't be
+    /// converted.
+    fn from_numeric(data: &NumericData, index: usize) -> Option<Self>;
+}
+
+// Converts `value` only if converting it back yields the same value
+fn exact_cast<S, T>(value: S) -> Option<T>
+where
+    S: num_traits::NumCast + PartialEq + Copy,
+    T: num_traits::NumCast + Copy,
+{
+    let result = T::from(value)?;
+    if S::from(result)? == value {
+        Some(result)
+    } else {
+        None
+    }
+}
+
+macro_rules! impl_from_numeric {
+    ( $cast:path => $( $t:ty ),* ) => {
+        $(
+            impl FromNumeric for $t {
+                fn from_numeric(data: &NumericData, index: usize) -> Option<Self> {
+                    numeric_data_parts!(data, real, _imag => $cast(*real.get(index)?))
+                }
+            }
+        )*
+    };
+}
+
+impl_from_numeric!(exact_cast => i8, u8, i16, u16, i32, u32, i64, u64);
+impl_from_numeric!(num_traits::NumCast::from => f32, f64);
+
+impl TryFrom<parse::Numeric> for Numeric {
+    type Error = Error;
+
+    fn try_from(value: parse::Numeric) -> Result<Self, Self::Error> {
+        let header = &value.header;
+        let dimensions = array_dimensions(header)?;
+        let numel = number_of_elements(header, &dimensions)?;
+        let has_imag = value.data.is_complex() || value.imag_skipped;
+        if header.flags.complex != has_imag || value.data.is_complex() && value.imag_skipped {
+            return Err(Error::invalid_array(
+                header,
+                "complex flag does not match the presence of an imaginary part",
+            ));
+        }
+        if Some(value.data.data_type()) != header.flags.class.numeric_data_type() {
+            return Err(Error::invalid_array(
+                header,
+                "data type does not match the array class",
+            ));
+        }
+        if value.data.len() != numel || value.data.imag_len().is_some_and(|len| len != numel) {
+            return Err(Error::invalid_array(
+                header,
+                "data length does not match the dimensions",
+            ));
+        }
+        Ok(Numeric {
+            data: value.data,
+            dimensions,
+            logical: value.header.flags.logical,
+            global: value.header.flags.global,
+            name: value.header.name,
+            imag_skipped: value.imag_skipped,
+        })
+    }
+}
+
+impl TryFrom<parse::Sparse> for Sparse {
+    type Error = Error;
+
+    fn try_from(value: parse::Sparse) -> Result<Self, Self::Error> {
+        let header = &value.header;
+        let dimensions = array_dimensions(header)?;
+        let (rows, columns) = match dimensions.as_slice() {
+            &[rows, columns] => (rows, columns),
+            _ => {
+                return Err(Error::invalid_array(
+                    header,
+                    "sparse arrays need exactly two dimensions",
+                ))
+            }
+        };
+        let has_imag = value.data.is_complex() || value.imag_skipped;
+        if header.flags.complex != has_imag || value.data.is_complex() && value.imag_skipped {
+            return Err(Error::invalid_array(
+                header,
+                "complex flag does not match the presence of an imaginary part",
+            ));
+        }
+        let nnz = value.row_index.len();
+        if value.data.len() != nnz || value.data.imag_len().is_some_and(|len| len != nnz) {
+            return Err(Error::invalid_array(
+                header,
+                "number of values does not match the number of row indices",
+            ));
+        }
+        if value.column_index.len() != columns + 1
+            || value.column_index.windows(2).any(|w| w[0] > w[1])
+            || value.column_index.last() > Some(&nnz)
+        {
+            return Err(Error::invalid_array(header, "invalid column pointers"));
+        }
+        if value.row_index.iter().any(|&row| row >= rows) {
+            return Err(Error::invalid_array(header, "row index out of bounds"));
+        }
+        Ok(Sparse {
+            data: value.data,
+            dimensions,
+            logical: value.header.flags.logical,
+            global: value.header.flags.global,
+            row_indices: value.row_index,
+            column_pointers: value.column_index,
+            name: value.header.name,
+            imag_skipped: value.imag_skipped,
+        })
+    }
+}
+
+impl TryFrom<parse::Character> for Character {
+    type Error = Error;
+
+    fn try_from(value: parse::Character) -> Result<Self, Self::Error> {
+        let header = &value.header;
+        let dimensions = array_dimensions(header)?;
+        let numel = number_of_elements(header, &dimensions)?;
+        if value.imag_part.is_some() {
+            return Err(Error::invalid_array(
+                header,
+                "character arrays can't be complex",
+            ));
+        }
+        // Characters outside the BMP count as two elements in Matlab, but
+        // some writers count them as one
+        if !(value.real_part.utf16_len() == numel || value.real_part.char_count() == numel) {
+            return Err(Error::invalid_array(
+                header,
+                "number of characters does not match the dimensions",
+            ));
+        }
+        let data = match value.real_part {
+            parse::CharacterData::Unicode(v) => CharacterData::Unicode(v),
+            parse::CharacterData::NonUnicode(vec) => CharacterData::NonUnicode(vec),
+        };
+        Ok(Character {
+            global: value.header.flags.global,
+            name: value.header.name,
+            dimensions,
+            data,
+        })
+    }
+}
+
+impl TryFrom<parse::Structure> for Structure {
+    type Error = Error;
+
+    fn try_from(value: parse::Structure) -> Result<Self, Self::Error> {
+        let dimensions = array_dimensions(&value.header)?;
+        let numel = number_of_elements(&value.header, &dimensions)?;
+        let len = value.field_names.len();
+        if value.values.len() != numel.saturating_mul(len) {
+            return Err(Error::invalid_array(
+                &value.header,
+                "number of values does not match the number of fields and elements",
+            ));
+        }
+        let mut structure = Structure::new(value.header.name);
+        structure.dimensions = dimensions;
+        structure.global = value.header.flags.global;
+
+        let mut items = Vec::with_capacity(value.values.len());
+        for item in value.values {
+            items.push(match item.try_into() {
+                Ok(v) => Some(v),
+                Err(Error::Unsupported) => None,
+                Err(e) => return Err(e.within(&structure.name)),
+            });
+        }
+        // Fields of unsupported type are skipped, in all elements
+        let supported: Vec<bool> = (0..len)
+            .map(|idx| items.iter().skip(idx).step_by(len).all(Option::is_some))
+            .collect();
+        structure.field_names = value
+            .field_names
+            .into_iter()
+            .zip(&supported)
+            .filter_map(|(name, &supported)| supported.then_some(name))
+            .collect();
+        structure.values = items
+            .into_iter()
+            .enumerate()
+            .filter_map(|(idx, item)| if supported[idx % len] { item } else { None })
+            .collect();
+
+        Ok(structure)
+    }
+}
+
+impl TryFrom<parse::Cell> for Cell {
+    type Error = Error;
+
+    /// Fails with [`Error::Unsupported`] if any of the cells holds an array
+    /// of unsupported type.
+    fn try_from(value: parse::Cell) -> Result<Self, Self::Error> {
+        let header = &value.header;
+        let dimensions = array_dimensions(header)?;
+        let numel = number_of_elements(header, &dimensions)?;
+        if value.values.len() != numel {
+            return Err(Error::invalid_array(
+                header,
+                "number of cells does not match the dimensions",
+            ));
+        }
+        let values = value
+            .values
+            .into_iter()
+            .enumerate()
+            .map(|(idx, item)| {
+                item.try_into()
+                    .map_err(|e: Error| e.within_cell(&header.name, idx))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Cell {
+            name: value.header.name,
+            dimensions,
+            global: value.header.flags.global,
+            values,
+        })
+    }
+}
+
+impl TryFrom<parse::DataElement> for Array {
+    type Error = Error;
+
+    fn try_from(value: parse::DataElement) -> Result<Self, Self::Error> {
+        match value {
+            parse::DataElement::NumericMatrix(numeric) => numeric.try_into().map(Array::Numeric),
+            parse::DataElement::SparseMatrix(sparse) => sparse.try_into().map(Array::Sparse),
+            parse::DataElement::CharacterMatrix(character) => {
+                character.try_into().map(Array::Character)
+            }
+            parse::DataElement::StructureMatrix(structure) => {
+                structure.try_into().map(Array::Structure)
+            }
+            parse::DataElement::CellMatrix(cell) => cell.try_into().map(Array::Cell),
+            parse::DataElement::Opaque(_)
+            | parse::DataElement::StringArray(_)
+            | parse::DataElement::SubsystemData(_)
+            | parse::DataElement::Unsupported(_)
+            | parse::DataElement::Corrupt(_)
+            | parse::DataElement::Skipped(_) => Err(Error::Unsupported),
+        }
+    }
+}
+
+/// The header the parser would have read for an array with these
+/// properties.
+fn array_header(
+    name: String,
+    dimensions: Dimensions,
+    class: parse::ArrayType,
+    global: bool,
+) -> parse::ArrayHeader {
+    parse::ArrayHeader {
+        flags: parse::ArrayFlags {
+            complex: false,
+            global,
+            logical: false,
+            class,
+            nzmax: 0,
+        },
+        dimensions,
+        name,
+    }
+}
+
+impl From<Numeric> for parse::Numeric {
+    fn from(value: Numeric) -> Self {
+        let class = value.data.class();
+        let mut header = array_header(value.name, value.dimensions, class, value.global);
+        header.flags.complex = value.data.is_complex() || value.imag_skipped;
+        header.flags.logical = value.logical;
+        parse::Numeric {
+            header,
+            data: value.data,
+            imag_skipped: value.imag_skipped,
+        }
+    }
+}
+
+impl From<Sparse> for parse::Sparse {
+    fn from(value: Sparse) -> Self {
+        let class = parse::ArrayType::Sparse;
+        let mut header = array_header(value.name, value.dimensions, class, value.global);
+        header.flags.complex = value.data.is_complex() || value.imag_skipped;
+        header.flags.logical = value.logical;
+        header.flags.nzmax = value.row_indices.len();
+        parse::Sparse {
+            header,
+            row_index: value.row_indices,
+            column_index: value.column_pointers,
+            data: value.data,
+            imag_skipped: value.imag_skipped,
+        }
+    }
+}
+
+impl From<Character> for parse::Character {
+    fn from(value: Character) -> Self {
+        let class = parse::ArrayType::Char;
+        let real_part = match value.data {
+            CharacterData::Unicode(str) => parse::CharacterData::Unicode(str),
+            CharacterData::NonUnicode(vec) => parse::CharacterData::NonUnicode(vec),
+        };
+        parse::Character {
+            header: array_header(value.name, value.dimensions, class, value.global),
+            real_part,
+            imag_part: None,
+        }
+    }
+}
+
+impl From<Structure> for parse::Structure {
+    fn from(value: Structure) -> Self {
+        let class = parse::ArrayType::Struct;
+        let header = array_header(value.name, value.dimensions, class, value.global);
+        let field_names = value.field_names;
+        // The parser names the values after their fields
+        let values = value
+            .values
+            .into_iter()
+            .zip(field_names.iter().cycle())
+            .map(|(mut array, name)| {
+                array.set_name(name.clone());
+                array.into()
+            })
+            .collect();
+        parse::Structure {
+            header,
+            field_names,
+            values,
+        }
+    }
+}
+
+impl From<Cell> for parse::Cell {
+    fn from(value: Cell) -> Self {
+        let class = parse::ArrayType::Cell;
+        parse::Cell {
+            header: array_header(value.name, value.dimensions, class, value.global),
+            values: value
+                .values
+                .into_iter()
+                .map(|mut array| {
+                    array.set_name(String::new());
+                    array.into()
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Turns an array back into the data element it would have been parsed
+/// from, e.g. for writing it with [`write`].
+impl From<Array> for parse::DataElement {
+    fn from(value: Array) -> Self {
+        match value {
+            Array::Numeric(numeric) => parse::DataElement::NumericMatrix(numeric.into()),
+            Array::Sparse(sparse) => parse::DataElement::SparseMatrix(sparse.into()),
+            Array::Character(character) => parse::DataElement::CharacterMatrix(character.into()),
+            Array::Structure(structure) => parse::DataElement::StructureMatrix(structure.into()),
+            Array::Cell(cell) => parse::DataElement::CellMatrix(cell.into()),
+        }
+    }
+}
+
+/// Lists the variables of the file like Matlab's `whos` command, one line
+/// per variable.
+impl std::fmt::Display for MatFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let rows: Vec<[String; 5]> = self
+            .variables()
+            .map(|variable| {
+                let size = variable
+                    .dimensions
+                    .as_slice()
+                    .iter()
+                    .map(usize::to_string)
+                    .collect::<Vec<_>>()
+                    .join("x");
+                // Unsupported arrays are not loaded, so their size is unknown
+                let bytes = self
+                    .get(variable.name)
+                    .map_or_else(|| "-".to_string(), |array| array.byte_size().to_string());
+                let class = if variable.is_logical {
+                    "logical"
+                } else if variable.class == parse::ArrayType::Sparse {
+                    // Sparse arrays are listed as double with a sparse attribute
+                    "double"
+                } else {
+                    variable.class.matlab_name()
+                };
+                let attributes: Vec<&str> = [
+                    (variable.class == parse::ArrayType::Sparse, "sparse"),
+                    (variable.is_complex, "complex"),
+                    (variable.is_global, "global"),
+                ]
+                .iter()
+                .filter(|(set, _)| *set)
+                .map(|(_, name)| *name)
+                .collect();
+                [
+                    variable.name.to_string(),
+                    size,
+                    bytes,
+                    class.to_string(),
+                    attributes.join(", "),
+                ]
+            })
+            .collect();
+        let header = ["Name", "Size", "Bytes", "Class", "Attributes"].map(String::from);
+        let mut widths = [0; 5];
+        for row in std::iter::once(&header).chain(&rows) {
+            for (width, column) in widths.iter_mut().zip(row) {
+                *width = (*width).max(column.chars().count());
+            }
+        }
+        for row in std::iter::once(&header).chain(&rows) {
+            let line = format!(
+                "  {:<w0$}  {:<w1$}  {:>w2$}  {:<w3$}  {}",
+                row[0],
+                row[1],
+                row[2],
+                row[3],
+                row[4],
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2],
+                w3 = widths[3],
+            );
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+impl MatFile {
+    /// Tries to parse a byte sequence as a ".mat" file.
+    ///
+    /// This is the same as [`MatFile::from_reader`].
+    pub fn parse<R: std::io::Read>(reader: R) -> Result<Self, Error> {
+        Self::from_reader(reader)
+    }
+
+    /// Reads a ".mat" file from `reader`. Both Level 5 and Level 4 files are
+    /// supported.
+    ///
+    /// The whole input is buffered in memory before it is parsed. Failing to
+    /// read the input is reported as [`Error::IOError`], malformed input as
+    /// [`Error::ParseError`] and v7.3 files as [`Error::Hdf5`], unless the
+    /// `hdf5` feature is enabled to read them.
+    pub fn from_reader<R: std::io::Read>(reader: R) -> Result<Self, Error> {
+        Self::parse_with_options(reader, &ParseOptions::default())
+    }
+
+    /// Reads a ".mat" file from `reader` like [`MatFile::from_reader`], but
+    /// with the given options instead of the default ones.
+    pub fn parse_with_options<R: std::io::Read>(
+        mut reader: R,
+        options: &ParseOptions,
+    ) -> Result<Self, Error> {
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).map_err(Error::IOError)?;
+        let parse_result = parse::parse_auto_with_options(&buf, options)?;
+        // The subsystem data and other elements without a name aren't
+        // variables. Indices are kept for the warnings.
+        let data_elements: Vec<_> = parse_result
+            .data_elements
+            .into_iter()
+            .enumerate()
+            .filter(|(_, data_element)| {
+                !matches!(data_element, parse::DataElement::SubsystemData(_))
+                    && data_element.name() != Some("")
+            })
+            .collect();
+        let variables: Vec<parse::ArrayHeader> = data_elements
+            .iter()
+            .filter_map(|(_, data_element)| data_element.header())
+            .cloned()
+            .collect();
+        if options.on_duplicate == DuplicateNames::Error {
+            let mut duplicates = Vec::new();
+            for (idx, variable) in variables.iter().enumerate() {
+                let name = &variable.name;
+                if variables[..idx].iter().any(|v| &v.name == name) && !duplicates.contains(name) {
+                    duplicates.push(name.clone());
+                }
+            }
+            if !duplicates.is_empty() {
+                return Err(Error::DuplicateNames(duplicates));
+            }
+        }
+        let mut warnings = parse_result.warnings;
+        let mut arrays = Vec::new();
+        for (idx, data_element) in data_elements {
+            // Unsupported and corrupt elements were already reported while
+            // parsing, and skipped ones were asked for
+            let reported = matches!(
+                data_element,
+                parse::DataElement::Unsupported(_)
+                    | parse::DataElement::Corrupt(_)
+                    | parse::DataElement::Skipped(_)
+            );
+            let name = data_element.name().map(String::from);
+            match data_element.try_into() {
+                Ok(array) => arrays.push(array),
+                Err(Error::Unsupported) if reported => {}
+                Err(Error::Unsupported) => {
+                    let warning = parse::Warning {
+                        variable_index: Some(idx),
+                        variable_name: name,
+                        kind: parse::WarningKind::SkippedVariable,
+                    };
+                    if options.rejects(&warning.kind) {
+                        return Err(Error::ParseError(parse::ParseError::from_warning(warning)));
+                    }
+                    warnings.push(warning);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(MatFile {
+            header: parse_result.header,
+            arrays,
+            variables,
+            warnings,
+        })
+    }
+
+    /// Opens and reads the ".mat" file at `path`.
+    pub fn from_path<P: AsRef<std::path::Path>>(path: P) -> Result<Self, Error> {
+        let file = std::fs::File::open(path).map_err(Error::IOError)?;
+        Self::from_reader(std::io::BufReader::new(file))
+    }
+
+    /// The header of this .mat file.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Problems found while reading this file that didn't keep it from being
+    /// read, such as variables that were left out of [`MatFile::arrays`].
+    pub fn warnings(&self) -> &[parse::Warning] {
+        &self.warnings
+    }
+
+    /// List of all arrays in this .mat file.
+    ///
+    /// When parsing a .mat file all arrays of unsupported type (currently
+    /// object arrays) will be ignored and will thus not be part of this list.
+    pub fn arrays(&self) -> &[Array] {
+        &self.arrays
+    }
+
+    /// Returns an array with the given name if it exists. Case sensitive.
+    ///
+    /// When parsing a .mat file all arrays of unsupported type (currently
+    /// object arrays) will be ignored and will thus not be returned by this
+    /// function.
+    pub fn find_by_name<'me>(&'me self, name: &'_ str) -> Option<&'me Array> {
+        self.arrays.iter().find(|array| array.name() == name)
+    }
+
+    fn check_name(name: &str) -> Result<(), Error> {
+        if name.is_empty() {
+            return Err(Error::InvalidName(name.to_string()));
+        }
+        Ok(())
+    }
+
+    fn variable_index(&self, name: &str) -> Option<usize> {
+        self.variables.iter().position(|header| header.name == name)
+    }
+
+    /// Sets the variable `name` to `array`, renaming `array` to match.
+    ///
+    /// Returns the previous array if a variable with this name existed, in
+    /// which case the variable keeps its position. A variable of
+    /// unsupported type with this name is replaced as well, but can't be
+    /// returned. Fails with [`Error::InvalidName`] if the name is empty.
+    pub fn insert(&mut self, name: &str, mut array: Array) -> Result<Option<Array>, Error> {
+        Self::check_name(name)?;
+        array.set_name(name.to_string());
+        let header = array.header();
+        match self.variable_index(name) {
+            Some(idx) => self.variables[idx] = header,
+            None => self.variables.push(header),
+        }
+        match self.arrays.iter().position(|a| a.name() == name) {
+            Some(idx) => Ok(Some(std::mem::replace(&mut self.arrays[idx], array))),
+            None => {
+                self.arrays.push(array);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Removes the variable `name` and returns its array.
+    ///
+    /// Variables of unsupported type are removed as well, but `None` is
+    /// returned for them.
+    pub fn remove(&mut self, name: &str) -> Option<Array> {
+        let idx = self.variable_index(name)?;
+        self.variables.remove(idx);
+        let idx = self.arrays.iter().position(|a| a.name() == name)?;
+        Some(self.arrays.remove(idx))
+    }
+
+    /// Renames the variable `old` to `new`.
+    ///
+    /// Fails with [`Error::NotFound`] if there is no variable `old`,
+    /// [`Error::NameCollision`] if a variable `new` already exists and
+    /// [`Error::InvalidName`] if `new` is empty.
+    pub fn rename(&mut self, old: &str, new: &str) -> Result<(), Error> {
+        Self::check_name(new)?;
+        let idx = self
+            .variable_index(old)
+            .ok_or_else(|| Error::NotFound(old.to_string()))?;
+        if old == new {
+            return Ok(());
+        }
+        if self.variable_index(new).is_some() {
+            return Err(Error::NameCollision(new.to_string()));
+        }
+        self.variables[idx].name = new.to_string();
+        if let Some(array) = self.arrays.iter_mut().find(|a| a.name() == old) {
+            array.set_name(new.to_string());
+        }
+        Ok(())
+    }
+
+    /// Adds all variables of `other` to this file.
+    ///
+    /// Variables whose name already exists in this file are handled
+    /// according to `policy`. Returns the names of these variables, in the
+    /// order they appear in `other`. Flags and data of the merged variables
+    /// are preserved, including variables of unsupported type.
+    pub fn merge(&mut self, other: MatFile, policy: MergePolicy) -> Result<Vec<String>, Error> {
+        let collisions: Vec<String> = other
+            .variables
+            .iter()
+            .filter(|header| self.variable_index(&header.name).is_some())
+            .map(|header| header.name.clone())
+            .collect();
+        if policy == MergePolicy::Error {
+            if let Some(name) = collisions.first() {
+                return Err(Error::NameCollision(name.clone()));
+            }
+        }
+
+        let mut arrays = other.arrays;
+        for mut header in other.variables {
+            let mut array = arrays
+                .iter()
+                .position(|a| a.name() == header.name)
+                .map(|idx| arrays.remove(idx));
+            if self.variable_index(&header.name).is_some() {
+                match policy {
+                    MergePolicy::Error | MergePolicy::Overwrite => {}
+                    MergePolicy::KeepExisting => continue,
+                    MergePolicy::RenameWithSuffix => {
+                        let name = (1..)
+                            .map(|n| format!("{}_{}", header.name, n))
+                            .find(|name| {
+                                self.variable_index(name).is_none()
+                                    && !collisions.contains(name)
+                                    && arrays.iter().all(|a| a.name() != name)
+                            })
+                            .expect("ran out of suffixes");
+                        if let Some(array) = &mut array {
+                            array.set_name(name.clone());
+                        }
+                        header.name = name;
+                    }
+                }
+            }
+            self.put(header, array);
+        }
+        Ok(collisions)
+    }
+
+    /// Stores a variable, replacing any existing variable of the same name.
+    fn put(&mut self, header: parse::ArrayHeader, array: Option<Array>) {
+        let position = self.arrays.iter().position(|a| a.name() == header.name);
+        match (position, array) {
+            (Some(idx), Some(array)) => self.arrays[idx] = array,
+            (Some(idx), None) => {
+                self.arrays.remove(idx);
+            }
+            (None, Some(array)) => self.arrays.push(array),
+            (None, None) => {}
+        }
+        match self.variable_index(&header.name) {
+            Some(idx) => self.variables[idx] = header,
+            None => self.variables.push(header),
+        }
+    }
+
+    /// Iterates over all arrays that were saved as global variables.
+    pub fn globals(&self) -> impl Iterator<Item = &Array> {
+        self.arrays.iter().filter(|array| array.is_global())
+    }
+
+    /// Returns the array with the given name if it exists. Case sensitive.
+    ///
+    /// Same as [`MatFile::find_by_name`].
+    pub fn get(&self, name: &str) -> Option<&Array> {
+        self.find_by_name(name)
+    }
+
+    /// Returns all arrays with the given name, in the order they are stored
+    /// in the file. There is more than one only if the file was read with
+    /// [`DuplicateNames::KeepAll`].
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Array> {
+        self.arrays.iter().filter(move |array| array.name() == name)
+    }
+
+    /// Returns the array at a dotted path whose first segment is the name of
+    /// a variable and whose other segments are field names of nested
+    /// structures, e.g. `"params.solver.tolerance"`.
+    ///
+    /// See [`Structure::get_path`].
+    pub fn get_path(&self, path: &str) -> Option<&Array> {
+        match path.split_once('.') {
+            Some((name, fields)) => self.get(name)?.as_struct()?.get_path(fields),
+            None => self.get(path),
+        }
+    }
+
+    /// Iterates depth first over all variables and the arrays nested in
+    /// them, yielding the dotted path of each array.
+    ///
+    /// See [`Array::walk_iter`].
+    pub fn walk_iter(&self, max_depth: usize) -> WalkIter<'_> {
+        WalkIter {
+            walker: Walker::new(self.arrays.iter(), max_depth),
+        }
+    }
+
+    /// Mutable version of [`MatFile::get_path`].
+    pub fn get_path_mut(&mut self, path: &str) -> Option<&mut Array> {
+        let (name, fields) = match path.split_once('.') {
+            Some((name, fields)) => (name, Some(fields)),
+            None => (path, None),
+        };
+        let array = self.arrays.iter_mut().find(|a| a.name() == name)?;
+        match (array, fields) {
+            (array, None) => Some(array),
+            (Array::Structure(structure), Some(fields)) => structure.get_path_mut(fields),
+            _ => None,
+        }
+    }
+
+    /// Returns the numeric array with the given name. Returns `None` if
+    /// there is no such array or if it is not numeric.
+    pub fn get_numeric(&self, name: &str) -> Option<&Numeric> {
+        self.get(name)?.as_numeric()
+    }
+
+    /// Returns the sparse array with the given name. Returns `None` if there
+    /// is no such array or if it is not sparse.
+    pub fn get_sparse(&self, name: &str) -> Option<&Sparse> {
+        self.get(name)?.as_sparse()
+    }
+
+    /// Returns the character array with the given name. Returns `None` if
+    /// there is no such array or if it is not a character array.
+    pub fn get_char(&self, name: &str) -> Option<&Character> {
+        self.get(name)?.as_char()
+    }
+
+    /// Returns the structure with the given name. Returns `None` if there is
+    /// no such array or if it is not a structure.
+    pub fn get_struct(&self, name: &str) -> Option<&Structure> {
+        self.get(name)?.as_struct()
+    }
+
+    /// Returns the cell array with the given name. Returns `None` if there
+    /// is no such array or if it is not a cell array.
+    pub fn get_cell(&self, name: &str) -> Option<&Cell> {
+        self.get(name)?.as_cell()
+    }
+
+    /// Returns the value of a 1x1 numeric array converted into `T`.
+    ///
+    /// The value is converted regardless of the class of the array, e.g. a
+    /// `double` scalar can be read as `u8` as long as it is a whole number
+    /// in the range of `u8`. Fails with [`Error::NotScalar`] if the array
+    /// has more than one element and with [`Error::ConversionError`] if the
+    /// value can't be converted or has a non-zero imaginary part.
+    ///
+    /// ```rust
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mat_file = matfile::MatFile::from_path("tests/scalars.mat")?;
+    /// let a: u8 = mat_file.get_scalar("a")?;
+    /// assert_eq!(a, 3);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_scalar<T: FromNumeric>(&self, name: &str) -> Result<T, Error> {
+        let numeric = self.try_get_numeric(name)?;
+        if !numeric.dimensions.is_scalar() {
+            return Err(Error::NotScalar(name.to_string()));
+        }
+        let has_imag = numeric_data_parts!(&numeric.data, _real, imag => imag
+            .iter()
+            .flatten()
+            .any(|v| !v.is_zero()));
+        if has_imag {
+            return Err(Error::ConversionError);
+        }
+        T::from_numeric(&numeric.data, 0).ok_or(Error::ConversionError)
+    }
+
+    /// Like [`MatFile::get`], but returns [`Error::NotFound`] if there is no
+    /// array with this name.
+    pub fn try_get(&self, name: &str) -> Result<&Array, Error> {
+        self.get(name)
+            .ok_or_else(|| Error::NotFound(name.to_string()))
+    }
+
+    /// Like [`MatFile::get_numeric`], but reports why the array couldn't be
+    /// returned: [`Error::NotFound`] if there is no array with this name,
+    /// [`Error::WrongType`] if it is not numeric.
+    pub fn try_get_numeric(&self, name: &str) -> Result<&Numeric, Error> {
+        let array = self.try_get(name)?;
+        array
+            .as_numeric()
+            .ok_or_else(|| array.wrong_type(ArrayKind::Numeric))
+    }
+
+    /// Like [`MatFile::get_sparse`], but reports why the array couldn't be
+    /// returned.
+    pub fn try_get_sparse(&self, name: &str) -> Result<&Sparse, Error> {
+        let array = self.try_get(name)?;
+        array
+            .as_sparse()
+            .ok_or_else(|| array.wrong_type(ArrayKind::Sparse))
+    }
+
+    /// Like [`MatFile::get_char`], but reports why the array couldn't be
+    /// returned.
+    pub fn try_get_char(&self, name: &str) -> Result<&Character, Error> {
+        let array = self.try_get(name)?;
+        array
+            .as_char()
+            .ok_or_else(|| array.wrong_type(ArrayKind::Character))
+    }
+
+    /// Like [`MatFile::get_struct`], but reports why the array couldn't be
+    /// returned.
+    pub fn try_get_struct(&self, name: &str) -> Result<&Structure, Error> {
+        let array = self.try_get(name)?;
+        array
+            .as_struct()
+            .ok_or_else(|| array.wrong_type(ArrayKind::Structure))
+    }
+
+    /// Like [`MatFile::get_cell`], but reports why the array couldn't be
+    /// returned.
+    pub fn try_get_cell(&self, name: &str) -> Result<&Cell, Error> {
+        let array = self.try_get(name)?;
+        array
+            .as_cell()
+            .ok_or_else(|| array.wrong_type(ArrayKind::Cell))
+    }
+
+    /// Lists the name, class, dimensions and flags of every variable in the
+    /// file, similar to Matlab's `whos` command.
+    ///
+    /// Unlike [`MatFile::arrays`] this includes variables of unsupported
+    /// type, so their class can be inspected even though their data was not
+    /// loaded.
+    pub fn variables(&self) -> impl Iterator<Item = VariableInfo<'_>> {
+        self.variables.iter().map(VariableInfo::new)
+    }
+}
+
+impl IntoIterator for MatFile {
+    type Item = (String, Array);
+    type IntoIter = IntoIter;
+
+    /// Consumes the file, yielding the name and array of every variable
+    /// without copying their data.
+    fn into_iter(self) -> IntoIter {
+        IntoIter {
+            arrays: self.arrays.into_iter(),
+        }
+    }
+}
+
+/// An iterator over the owned variables of a [`MatFile`], created by its
+/// `into_iter()` method.
+#[derive(Debug)]
+pub struct IntoIter {
+    arrays: std::vec::IntoIter<Array>,
+}
+
+impl Iterator for IntoIter {
+    type Item = (String, Array);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.arrays
+            .next()
+            .map(|array| (array.name().to_string(), array))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.arrays.size_hint()
+    }
+}
+
+impl ExactSizeIterator for IntoIter {}
+
 // TODO: improve tests.
 // The tests are not very comprehensive yet and they only test whether
 // the files can be loaded without error, but not whether the result
@@ -702,9 +2797,131 @@ mod tests {
     }
 
     #[test]
-    fn double_as_uint8_array() {
-        let data = include_bytes!("../tests/double_as_uint8.mat");
-        let _mat_file = MatFile::parse(data.as_ref()).unwrap();
+    fn double_as_uint8_array() {
+        let data = include_bytes!("../tests/double_as_uint8.mat");
+        let _mat_file = MatFile::parse(data.as_ref()).unwrap();
+    }
+
+    #[test]
+    fn double_storage_types() {
+        let data = include_bytes!("../tests/double_storage.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let expected: [(&str, Vec<f64>); 5] = [
+            ("as_uint8", vec![1.0, 2.0, 255.0]),
+            ("as_int16", vec![-300.0, 0.0, 300.0]),
+            ("as_uint16", vec![0.0, 1000.0, 65535.0]),
+            ("as_int32", vec![-70000.0, 0.0, 70000.0]),
+            ("as_uint32", vec![0.0, 1.0, 4000000000.0]),
+        ];
+        for (name, values) in expected {
+            match mat_file.get_numeric(name).map(Numeric::data) {
+                Some(NumericData::Double { real, imag: None }) => assert_eq!(*real, values),
+                other => panic!("unexpected data {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn mismatched_storage_types() {
+        let data = include_bytes!("../tests/mismatched_types.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        assert_eq!(
+            mat_file.get_numeric("a").map(Numeric::data),
+            Some(&NumericData::Int16 {
+                real: vec![1, -2, 300],
+                imag: None
+            })
+        );
+        assert_eq!(
+            mat_file.get_numeric("b").map(Numeric::data),
+            Some(&NumericData::UInt8 {
+                real: vec![1, 255],
+                imag: None
+            })
+        );
+        assert_eq!(
+            mat_file.get_numeric("c").map(Numeric::data),
+            Some(&NumericData::Single {
+                real: vec![0.5, -1.25],
+                imag: None
+            })
+        );
+
+        let strict = ParseOptions {
+            strict_numeric_types: true,
+            ..ParseOptions::default()
+        };
+        assert!(MatFile::parse_with_options(data.as_ref(), &strict).is_err());
+
+        // Values that don't fit into the array class are rejected either way
+        let data = include_bytes!("../tests/mismatched_types_lossy.mat");
+        assert!(MatFile::parse(data.as_ref()).is_err());
+    }
+
+    #[test]
+    fn complex_mixed_storage_types() {
+        let data = include_bytes!("../tests/complex_mixed.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let z = mat_file.get_numeric("z").unwrap();
+        assert_eq!(
+            z.to_complex_f64(),
+            Some(vec![(0.5, 1.0), (-1.0, 2.0), (2.25, 3.0)])
+        );
+        let k = mat_file.get_numeric("k").unwrap();
+        assert_eq!(
+            k.data(),
+            &NumericData::Int16 {
+                real: vec![300, -2],
+                imag: Some(vec![-1, 7])
+            }
+        );
+        assert_eq!(k.to_complex_f64(), Some(vec![(300.0, -1.0), (-2.0, 7.0)]));
+        assert_eq!(
+            k.iter_complex_f64().unwrap().collect::<Vec<_>>(),
+            k.to_complex_f64().unwrap()
+        );
+        assert_eq!(mat_file.get_numeric("r").unwrap().to_complex_f64(), None);
+        assert!(mat_file
+            .get_numeric("r")
+            .unwrap()
+            .iter_complex_f64()
+            .is_none());
+    }
+
+    #[test]
+    fn numeric_iterators() {
+        let real: Vec<i16> = (0..1_000_000u64).map(|x| (x * 7919) as i16).collect();
+        let converted: Vec<f64> = real.iter().map(|&x| f64::from(x)).collect();
+        let data = NumericData::Int16 { real, imag: None };
+        let mut values = data.iter_f64();
+        assert_eq!(values.len(), 1_000_000);
+        assert_eq!(values.nth(2), Some(converted[2]));
+        assert_eq!(data.iter_f64().sum::<f64>(), converted.iter().sum::<f64>());
+        assert_eq!(
+            data.iter_i64().map(Option::unwrap).sum::<i64>(),
+            converted.iter().map(|&x| x as i64).sum::<i64>()
+        );
+
+        let data = NumericData::UInt64 {
+            real: vec![0, 1 << 53, u64::MAX],
+            imag: None,
+        };
+        assert_eq!(
+            data.iter_f64().collect::<Vec<_>>(),
+            [0.0, 9007199254740992.0, 18446744073709551615.0]
+        );
+        assert_eq!(
+            data.iter_i64().collect::<Vec<_>>(),
+            [Some(0), Some(1 << 53), None]
+        );
+        let data = NumericData::Single {
+            real: vec![-3.0, 0.5, f32::NAN, f32::INFINITY],
+            imag: None,
+        };
+        assert_eq!(
+            data.iter_i64().collect::<Vec<_>>(),
+            [Some(-3), None, None, None]
+        );
     }
 
     #[test]
@@ -730,4 +2947,1299 @@ mod tests {
         let data = include_bytes!("../tests/long_name.mat");
         let _mat_file = MatFile::parse(data.as_ref()).unwrap();
     }
+
+    #[test]
+    fn int32_arrays() {
+        let data = include_bytes!("../tests/int32.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let expected: [(&str, Vec<i32>); 2] = [
+            ("a", vec![-1, 2, -70000, i32::MAX]),
+            // Stored as int16
+            ("b", vec![-300, 0, 300]),
+        ];
+        for (name, values) in expected {
+            let numeric = mat_file.get_numeric(name).unwrap();
+            assert_eq!(numeric.data().data_type(), parse::DataType::Int32);
+            match numeric.data() {
+                NumericData::Int32 { real, imag: None } => assert_eq!(*real, values),
+                other => panic!("unexpected data {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn int64_arrays() {
+        let data = include_bytes!("../tests/int64.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let expected: [(&str, Vec<i64>); 4] = [
+            ("small", vec![1, 2, 3]),
+            ("negative", vec![-300, 0, 300]),
+            ("medium", vec![70000, 4000000000]),
+            ("large", vec![-9007199254740993, i64::MAX]),
+        ];
+        for (name, values) in expected {
+            match mat_file.find_by_name(name) {
+                Some(Array::Numeric(Numeric {
+                    data: NumericData::Int64 { real, imag: None },
+                    ..
+                })) => assert_eq!(*real, values),
+                other => panic!("unexpected array {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn uint64_arrays() {
+        let data = include_bytes!("../tests/uint64.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let expected: [(&str, Vec<u64>); 3] = [
+            ("small", vec![1, 2, 255]),
+            ("medium", vec![70000, 4000000000]),
+            ("large", vec![1 << 63, u64::MAX]),
+        ];
+        for (name, values) in expected {
+            match mat_file.find_by_name(name) {
+                Some(Array::Numeric(Numeric {
+                    data: NumericData::UInt64 { real, imag: None },
+                    ..
+                })) => assert_eq!(*real, values),
+                other => panic!("unexpected array {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn negative_values_in_unsigned_array() {
+        let data = include_bytes!("../tests/uint64_negative.mat");
+        assert!(MatFile::parse(data.as_ref()).is_err());
+    }
+
+    #[test]
+    fn sparse_array() {
+        let data = include_bytes!("../tests/sparse2.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let Array::Sparse(sparse) = &mat_file.arrays()[0] else {
+            panic!("expected a sparse array");
+        };
+        assert_eq!(
+            mat_file.find_by_name(sparse.name()).unwrap().name(),
+            sparse.name()
+        );
+        assert_eq!(sparse.size(), &vec![8, 8]);
+        assert_eq!(sparse.row_indices(), &[5, 7, 2, 0, 1, 5, 3, 6]);
+        assert_eq!(sparse.column_pointers(), &[0, 1, 2, 2, 3, 4, 6, 7, 8]);
+        assert!(matches!(
+            sparse.data(),
+            NumericData::Double { imag: Some(_), .. }
+        ));
+        assert_eq!(sparse.nnz(), 8);
+        assert_eq!(sparse.shape(), (8, 8));
+        assert_eq!(sparse.get(1, 5), Some(5.0));
+        assert_eq!(sparse.get(6, 7), Some(6.0));
+        assert_eq!(sparse.get(4, 4), Some(0.0));
+        assert_eq!(sparse.get(8, 0), None);
+    }
+
+    #[test]
+    fn character_string() {
+        let data = include_bytes!("../tests/character.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let Some(Array::Character(character)) = mat_file.find_by_name("chr") else {
+            panic!("expected a character array");
+        };
+        assert_eq!(character.as_str(), Some("Hello, world!"));
+        assert_eq!(character.to_string_lossy(), "Hello, world!");
+        assert_eq!(character.rows().collect::<Vec<_>>(), vec!["Hello, world!"]);
+    }
+
+    #[test]
+    fn invalid_dimensions() {
+        // 2^33 elements, of which only one is stored
+        let data = include_bytes!("../tests/huge_dims.mat");
+        assert!(MatFile::parse(data.as_ref()).is_err());
+        // 2^65 elements
+        let data = include_bytes!("../tests/overflow_dims.mat");
+        assert!(MatFile::parse(data.as_ref()).is_err());
+        let data = include_bytes!("../tests/negative_dims.mat");
+        assert!(MatFile::parse(data.as_ref()).is_err());
+    }
+
+    #[test]
+    fn unknown_class() {
+        let data = include_bytes!("../tests/unknown_class.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        assert_eq!(mat_file.arrays().len(), 2);
+        assert!(mat_file.find_by_name("a").is_some());
+        assert!(mat_file.find_by_name("b").is_some());
+        assert!(mat_file.find_by_name("handle").is_none());
+        assert_eq!(mat_file.warnings().len(), 1);
+        assert_eq!(
+            mat_file.warnings()[0].variable_name.as_deref(),
+            Some("handle")
+        );
+
+        // String arrays can't be represented as an `Array`
+        let data = include_bytes!("../tests/string.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        assert!(mat_file.arrays().is_empty());
+        assert_eq!(mat_file.warnings()[0].kind, WarningKind::SkippedVariable);
+        let strict = ParseOptions::new().strict(true);
+        match MatFile::parse_with_options(data.as_ref(), &strict) {
+            Err(Error::ParseError(err)) => assert_eq!(err.variable_name(), Some("s")),
+            other => panic!("expected an error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn skip_corrupt() {
+        let data = include_bytes!("../tests/corrupt_middle.mat");
+        let options = ParseOptions::new().skip_corrupt(true);
+        let mat_file = MatFile::parse_with_options(data.as_ref(), &options).unwrap();
+        let names: Vec<_> = mat_file.arrays().iter().map(Array::name).collect();
+        assert_eq!(names, ["a", "c"]);
+        assert_eq!(mat_file.warnings().len(), 1);
+        assert_eq!(
+            mat_file.warnings()[0].variable_name.as_deref(),
+            Some("sensor_data")
+        );
+    }
+
+    #[test]
+    fn error_codes() {
+        use parse::{HeaderError, ParseError, ParseErrorKind};
+
+        // An error for every code. The match has no wildcard so that new
+        // codes can't be added without an error producing them.
+        fn example(code: ErrorCode) -> Error {
+            let parse_error = |kind| Error::ParseError(ParseError::new(&[], kind));
+            match code {
+                ErrorCode::Io => Error::IOError(std::io::ErrorKind::Other.into()),
+                ErrorCode::HeaderMagic => Error::InvalidHeader(HeaderError::NullText),
+                ErrorCode::UnsupportedVersion => {
+                    Error::InvalidHeader(HeaderError::UnsupportedVersion(0x0300))
+                }
+                ErrorCode::InvalidHeader => {
+                    Error::InvalidHeader(HeaderError::Invalid(nom::error::ErrorKind::Tag))
+                }
+                ErrorCode::Hdf5 => Error::Hdf5,
+                ErrorCode::UnexpectedEof => parse_error(ParseErrorKind::UnexpectedEof),
+                ErrorCode::UnknownDataType => parse_error(ParseErrorKind::UnknownDataType(99)),
+                ErrorCode::InvalidTag => parse_error(ParseErrorKind::InvalidTag),
+                ErrorCode::UnknownClass => parse_error(ParseErrorKind::UnknownClass(99)),
+                ErrorCode::UnexpectedDataType => parse_error(ParseErrorKind::UnexpectedDataType {
+                    subelement: "dimensions",
+                    data_type: parse::DataType::Double,
+                }),
+                ErrorCode::SizeMismatch => parse_error(ParseErrorKind::SizeMismatch {
+                    subelement: "real part",
+                }),
+                ErrorCode::InvalidText => parse_error(ParseErrorKind::InvalidText {
+                    subelement: "character data",
+                }),
+                ErrorCode::Utf8Name => parse_error(ParseErrorKind::InvalidText {
+                    subelement: "array name",
+                }),
+                ErrorCode::NegativeDimension => parse_error(ParseErrorKind::NegativeDimension),
+                ErrorCode::TooManyElements => parse_error(ParseErrorKind::TooManyElements),
+                ErrorCode::LossyConversion => parse_error(ParseErrorKind::LossyConversion {
+                    subelement: "real part",
+                }),
+                ErrorCode::MismatchedParts => parse_error(ParseErrorKind::MismatchedParts),
+                ErrorCode::FieldNameLength => parse_error(ParseErrorKind::InvalidFieldNameLength),
+                ErrorCode::UnexpectedName => {
+                    parse_error(ParseErrorKind::UnexpectedName("x".to_string()))
+                }
+                ErrorCode::UnexpectedCompression => {
+                    parse_error(ParseErrorKind::UnexpectedCompression)
+                }
+                ErrorCode::NestingTooDeep => parse_error(ParseErrorKind::NestingTooDeep),
+                ErrorCode::DecompressedSizeLimit => {
+                    parse_error(ParseErrorKind::DecompressedSizeLimit)
+                }
+                ErrorCode::Zlib => parse_error(ParseErrorKind::Zlib(String::new())),
+                ErrorCode::TrailingData => parse_error(ParseErrorKind::TrailingData),
+                ErrorCode::V4TypeCode => parse_error(ParseErrorKind::InvalidTypeCode(-1)),
+                ErrorCode::NotAllowed => {
+                    parse_error(ParseErrorKind::NotAllowed(WarningKind::SkippedVariable))
+                }
+                ErrorCode::Conversion => Error::ConversionError,
+                ErrorCode::Internal => Error::InternalError,
+                ErrorCode::Unsupported => Error::Unsupported,
+                ErrorCode::NotFound => Error::NotFound("x".to_string()),
+                ErrorCode::NotScalar => Error::NotScalar("x".to_string()),
+                ErrorCode::InvalidName => Error::InvalidName(String::new()),
+                ErrorCode::NameCollision => Error::NameCollision("x".to_string()),
+                ErrorCode::WrongType => Error::WrongType {
+                    name: "x".to_string(),
+                    expected: ArrayKind::Numeric,
+                    found: ArrayKind::Cell,
+                },
+                ErrorCode::InvalidArray => Error::InvalidArray {
+                    path: "x".to_string(),
+                    reason: "reason",
+                },
+                ErrorCode::DuplicateNames => Error::DuplicateNames(vec!["x".to_string()]),
+            }
+        }
+
+        let codes = [
+            ErrorCode::Io,
+            ErrorCode::HeaderMagic,
+            ErrorCode::UnsupportedVersion,
+            ErrorCode::InvalidHeader,
+            ErrorCode::Hdf5,
+            ErrorCode::UnexpectedEof,
+            ErrorCode::UnknownDataType,
+            ErrorCode::InvalidTag,
+            ErrorCode::UnknownClass,
+            ErrorCode::UnexpectedDataType,
+            ErrorCode::SizeMismatch,
+            ErrorCode::InvalidText,
+            ErrorCode::Utf8Name,
+            ErrorCode::NegativeDimension,
+            ErrorCode::TooManyElements,
+            ErrorCode::LossyConversion,
+            ErrorCode::MismatchedParts,
+            ErrorCode::FieldNameLength,
+            ErrorCode::UnexpectedName,
+            ErrorCode::UnexpectedCompression,
+            ErrorCode::NestingTooDeep,
+            ErrorCode::DecompressedSizeLimit,
+            ErrorCode::Zlib,
+            ErrorCode::TrailingData,
+            ErrorCode::V4TypeCode,
+            ErrorCode::NotAllowed,
+            ErrorCode::Conversion,
+            ErrorCode::Internal,
+            ErrorCode::Unsupported,
+            ErrorCode::NotFound,
+            ErrorCode::NotScalar,
+            ErrorCode::InvalidName,
+            ErrorCode::NameCollision,
+            ErrorCode::WrongType,
+            ErrorCode::InvalidArray,
+            ErrorCode::DuplicateNames,
+        ];
+        let mut strings = std::collections::HashSet::new();
+        for code in codes {
+            let err = example(code);
+            assert_eq!(err.code(), code);
+            assert!(err.to_string().starts_with(&format!("[{}] ", code)));
+            assert!(code.as_str().starts_with("E_"));
+            assert!(strings.insert(code.as_str()), "{} is used twice", code);
+        }
+
+        assert_eq!(
+            Error::NotFound("x".to_string()).to_string(),
+            "[E_NOT_FOUND] No variable named \"x\""
+        );
+        let data = include_bytes!("../tests/invalid_name.mat");
+        let err = MatFile::parse(data.as_ref()).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Utf8Name);
+    }
+
+    #[test]
+    fn duplicate_names() {
+        let data = include_bytes!("../tests/duplicates.mat");
+        match MatFile::parse(data.as_ref()) {
+            Err(Error::DuplicateNames(names)) => assert_eq!(names, ["x"]),
+            other => panic!("expected duplicate names, got {:?}", other.map(|_| ())),
+        }
+
+        let options = ParseOptions {
+            on_duplicate: DuplicateNames::KeepAll,
+            ..ParseOptions::default()
+        };
+        let mat_file = MatFile::parse_with_options(data.as_ref(), &options).unwrap();
+        assert_eq!(mat_file.arrays().len(), 3);
+        let values: Vec<_> = mat_file
+            .get_all("x")
+            .map(|array| match array {
+                Array::Numeric(numeric) => numeric.data().real_as_f64(0),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(values, [Some(1.0), Some(3.0)]);
+        assert_eq!(mat_file.get_all("y").count(), 1);
+        assert_eq!(mat_file.get_all("z").count(), 0);
+    }
+
+    #[test]
+    #[cfg(not(feature = "hdf5"))]
+    fn hdf5() {
+        let data = include_bytes!("../tests/v73.mat");
+        let err = MatFile::parse(data.as_ref()).unwrap_err();
+        assert!(matches!(err, Error::Hdf5));
+        assert!(err.to_string().contains("enable the `hdf5` feature"));
+    }
+
+    #[test]
+    #[cfg(feature = "hdf5")]
+    fn hdf5() {
+        let data = include_bytes!("../tests/v73.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        assert_eq!(
+            mat_file.get_path("st.x").and_then(|x| match x {
+                Array::Numeric(x) => x.data().real_as_f64(0),
+                _ => None,
+            }),
+            Some(42.0)
+        );
+        assert_eq!(mat_file.get_numeric("c").unwrap().size(), &[3, 4]);
+        assert_eq!(mat_file.get_sparse("sp").unwrap().size(), &[3, 2]);
+        assert_eq!(mat_file.get_cell("cel").unwrap().arrays().len(), 2);
+        assert_eq!(
+            mat_file.get_struct("sa").unwrap().dimensions().as_slice(),
+            &[1, 2]
+        );
+        assert!(mat_file.get("str").is_none());
+        assert_eq!(mat_file.warnings().len(), 1);
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut data = include_bytes!("../tests/double.mat").to_vec();
+        data[124..126].copy_from_slice(&[0x00, 0x03]);
+        let err = MatFile::parse(data.as_slice()).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::InvalidHeader(parse::HeaderError::UnsupportedVersion(0x0300))
+        ));
+        assert_eq!(
+            err.to_string(),
+            "[E_UNSUPPORTED_VERSION] Unsupported MAT-file version 0x0300, only v5 to v7 files are supported"
+        );
+    }
+
+    #[test]
+    fn struct_without_fields() {
+        let data = include_bytes!("../tests/struct_no_fields_v7.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let s = mat_file.get_struct("s").unwrap();
+        assert_eq!(s.numel(), 1);
+        assert_eq!(s.field_names().count(), 0);
+        assert_eq!(mat_file.get_struct("t").unwrap().numel(), 3);
+    }
+
+    #[test]
+    fn empty_arrays() {
+        let files: [&[u8]; 2] = [
+            include_bytes!("../tests/empty.mat"),
+            include_bytes!("../tests/empty_v7.mat"),
+        ];
+        for data in files {
+            let mat_file = MatFile::parse(data).unwrap();
+            assert_eq!(mat_file.arrays().len(), 6);
+            // Only the structure holding the empty fields has an element
+            assert!(mat_file
+                .arrays()
+                .iter()
+                .all(|array| array.is_empty() == (array.name() != "st")));
+
+            let x = mat_file.get_numeric("x").unwrap();
+            assert!(x.is_empty());
+            assert_eq!(x.dimensions().as_slice(), &[0, 0]);
+            assert_eq!(x.data().data_type(), parse::DataType::Double);
+            // Stored as a zero-length uint8 element
+            let y = mat_file.get_numeric("y").unwrap();
+            assert_eq!(y.dimensions().as_slice(), &[0, 3]);
+            assert_eq!(y.data().data_type(), parse::DataType::Int8);
+            for name in ["s", "u"] {
+                let Some(Array::Character(character)) = mat_file.get(name) else {
+                    panic!("expected a character array");
+                };
+                assert!(character.is_empty());
+                assert_eq!(character.to_string_lossy(), "");
+            }
+            let st = mat_file.get_struct("st").unwrap();
+            assert!(st.get("f").is_some_and(Array::is_empty));
+            assert!(st.get("g").is_some_and(Array::is_empty));
+            let sp = mat_file.get_sparse("sp").unwrap();
+            assert!(sp.is_empty());
+            assert_eq!(sp.nnz(), 0);
+        }
+    }
+
+    #[test]
+    fn level_4() {
+        let data = include_bytes!("../tests/v4.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        assert_eq!(mat_file.header().text(), "");
+        let Some(Array::Numeric(x)) = mat_file.find_by_name("x") else {
+            panic!("expected a numeric array");
+        };
+        assert_eq!(x.dimensions().as_slice(), &[2, 3]);
+        let Some(Array::Character(t)) = mat_file.find_by_name("t") else {
+            panic!("expected a character array");
+        };
+        assert_eq!(t.to_string_lossy(), "hello");
+    }
+
+    #[test]
+    fn unpaired_surrogates() {
+        let data = include_bytes!("../tests/char_surrogate.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let character = mat_file.get_char("c").unwrap();
+        assert!(matches!(
+            character.data(),
+            CharacterData::NonUnicode(vec) if vec == &[b'a' as u16, 0xd800, b'b' as u16]
+        ));
+        assert_eq!(character.as_str(), None);
+        assert_eq!(character.to_string_lossy(), "a\u{fffd}b");
+    }
+
+    #[test]
+    fn emoji_characters() {
+        let data = include_bytes!("../tests/char_emoji.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        // Stored as uint16 and utf8 with the emoji counted as two elements,
+        // and as utf8 with the emoji counted as one
+        for name in ["u", "e", "c"] {
+            let character = mat_file.get_char(name).unwrap();
+            assert_eq!(character.as_str(), Some("a\u{1f600}b"));
+            assert_eq!(character.rows().collect::<Vec<_>>(), ["a\u{1f600}b"]);
+        }
+        let m = mat_file.get_char("m").unwrap();
+        assert_eq!(m.rows().collect::<Vec<_>>(), ["a\u{1f600}", "bcd"]);
+    }
+
+    #[test]
+    fn character_matrix_rows() {
+        let data = include_bytes!("../tests/char_matrix.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let Some(Array::Character(character)) = mat_file.find_by_name("names") else {
+            panic!("expected a character array");
+        };
+        assert_eq!(character.dimensions().as_slice(), &[3, 5]);
+        // Stored as uint16, which is decoded as UTF-16
+        assert_eq!(character.as_str(), Some("abgleaptmhama a"));
+        assert_eq!(character.to_string_lossy(), "abgleaptmhama a");
+        assert_eq!(
+            character.rows().collect::<Vec<_>>(),
+            vec!["alpha", "beta ", "gamma"]
+        );
+    }
+
+    #[test]
+    fn structure_fields() {
+        let data = include_bytes!("../tests/struct.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let Some(Array::Structure(structure)) = mat_file.find_by_name("s") else {
+            panic!("expected a structure");
+        };
+        assert_eq!(
+            structure.field_names().collect::<Vec<_>>(),
+            vec!["a", "label", "inner"]
+        );
+        assert!(matches!(structure.get("a"), Some(Array::Numeric(_))));
+        let Some(Array::Character(label)) = structure.get("label") else {
+            panic!("expected a character array");
+        };
+        assert_eq!(label.to_string_lossy(), "xyz");
+        let Some(Array::Structure(inner)) = structure.get("inner") else {
+            panic!("expected a nested structure");
+        };
+        assert_eq!(inner.name(), "inner");
+        assert_eq!(inner.get("b").map(Array::name), Some("b"));
+        assert!(structure.get("missing").is_none());
+
+        let mut structure = structure.clone();
+        let label = structure.remove("label").unwrap();
+        assert_eq!(structure.len(), 2);
+        assert!(structure.insert("text", label).is_none());
+        assert_eq!(structure.get("text").map(Array::name), Some("text"));
+        let previous = structure.insert("a", Array::Structure(Structure::new("x")));
+        assert!(matches!(previous, Some(Array::Numeric(_))));
+        assert_eq!(
+            structure.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            vec!["a", "inner", "text"]
+        );
+    }
+
+    #[test]
+    fn get_path() {
+        let data = include_bytes!("../tests/struct.mat");
+        let mut mat_file = MatFile::parse(data.as_ref()).unwrap();
+        assert_eq!(mat_file.get_path("s").map(Array::name), Some("s"));
+        assert_eq!(mat_file.get_path("s.inner.b").map(Array::name), Some("b"));
+        assert!(mat_file.get_path("s.inner.missing").is_none());
+        // `label` is not a structure
+        assert!(mat_file.get_path("s.label.x").is_none());
+        // field names never contain dots, so there is nothing to escape
+        assert!(mat_file.get_path("s.inner.b.").is_none());
+
+        let structure = mat_file.get_struct("s").unwrap();
+        assert_eq!(structure.get_path("inner.b").map(Array::name), Some("b"));
+        assert!(structure.get_path("a.b").is_none());
+
+        let Some(Array::Structure(inner)) = mat_file.get_path_mut("s.inner") else {
+            panic!("expected a nested structure");
+        };
+        inner.insert("c", Array::Structure(Structure::new("")));
+        assert!(mat_file.get_path("s.inner.c").is_some());
+        assert!(mat_file.get_path_mut("s.a.b").is_none());
+    }
+
+    #[test]
+    fn walk() {
+        let data = include_bytes!("../tests/struct.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let paths: Vec<_> = mat_file
+            .walk_iter(usize::MAX)
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(paths, vec!["s", "s.a", "s.label", "s.inner", "s.inner.b"]);
+        let paths: Vec<_> = mat_file.walk_iter(1).map(|(path, _)| path).collect();
+        assert_eq!(paths, vec!["s", "s.a", "s.label", "s.inner"]);
+
+        let mut visited = Vec::new();
+        let inner = mat_file.get_path("s.inner").unwrap();
+        inner.walk(usize::MAX, |path, array| {
+            visited.push((path.to_string(), path.depth(), array.name().to_string()))
+        });
+        assert_eq!(
+            visited,
+            vec![
+                ("inner".to_string(), 0, "inner".to_string()),
+                ("inner.b".to_string(), 1, "b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn cell_array() {
+        let data = include_bytes!("../tests/cell.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let cell = mat_file.get_cell("c").unwrap();
+        assert_eq!(cell.dimensions().as_slice(), &[1, 3]);
+        assert_eq!(cell.len(), 3);
+        assert_eq!(
+            cell.get(0, 1)
+                .and_then(Array::as_char)
+                .unwrap()
+                .to_string_lossy(),
+            "abc"
+        );
+        assert!(cell.get(1, 1).is_none());
+        assert!(cell.iter().all(|array| array.name().is_empty()));
+
+        let paths: Vec<_> = mat_file
+            .walk_iter(usize::MAX)
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(paths, vec!["c", "c{1}", "c{2}", "c{3}", "c{3}.x", "e"]);
+
+        let empty = mat_file.get_cell("e").unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(empty.dimensions().as_slice(), &[0, 0]);
+
+        let data = include_bytes!("../tests/struct_cell_v7.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let names = mat_file
+            .get_path("s.names")
+            .and_then(Array::as_cell)
+            .unwrap();
+        let names: Vec<_> = names
+            .iter()
+            .map(|name| name.as_char().unwrap().to_string_lossy())
+            .collect();
+        assert_eq!(names, vec!["alpha", "beta"]);
+    }
+
+    #[test]
+    fn structure_array() {
+        let data = include_bytes!("../tests/struct_array.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let s = mat_file.get_struct("s").unwrap();
+        assert_eq!(s.numel(), 3);
+        assert_eq!(s.len(), 2);
+        let names: Vec<_> = s
+            .elements()
+            .map(|element| {
+                element
+                    .get("name")
+                    .unwrap()
+                    .as_char()
+                    .unwrap()
+                    .to_string_lossy()
+            })
+            .collect();
+        assert_eq!(names, vec!["a", "bb", "ccc"]);
+        let x = s
+            .element(2)
+            .unwrap()
+            .get("x")
+            .unwrap()
+            .as_numeric()
+            .unwrap();
+        assert_eq!(x.data().real_as_f64(0), Some(3.0));
+        assert!(s.element(3).is_none());
+        // field accessors use the first element
+        assert_eq!(
+            s.get("x")
+                .unwrap()
+                .as_numeric()
+                .unwrap()
+                .data()
+                .real_as_f64(0),
+            Some(1.0)
+        );
+
+        let paths: Vec<_> = mat_file.walk_iter(1).map(|(path, _)| path).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "s",
+                "s(1).x",
+                "s(1).name",
+                "s(2).x",
+                "s(2).name",
+                "s(3).x",
+                "s(3).name",
+                "e"
+            ]
+        );
+
+        let mut s = s.clone();
+        s.insert("y", Array::Structure(Structure::new("")));
+        assert_eq!(s.element(1).unwrap()["y"].dimensions().as_slice(), &[0, 0]);
+        assert!(s.remove("x").is_some());
+        assert!(s
+            .elements()
+            .all(|element| element.field_names().eq(["name", "y"])));
+
+        let mut e = mat_file.get_struct("e").unwrap().clone();
+        assert_eq!(e.dimensions().as_slice(), &[0, 0]);
+        assert_eq!(e.numel(), 0);
+        assert_eq!(e.len(), 2);
+        assert!(e.get("a").is_none());
+        assert!(e.arrays().is_empty());
+        e.entry("b").or_insert(Array::Structure(Structure::new("")));
+        assert_eq!(e.numel(), 1);
+        assert_eq!(e.arrays().len(), 2);
+    }
+
+    #[test]
+    fn structure_entry() {
+        let mut structure = Structure::new("s");
+        let numeric = |name: &str, value: f64| {
+            Array::Numeric(
+                Numeric::new(
+                    name,
+                    vec![1, 1].into(),
+                    NumericData::Double {
+                        real: vec![value],
+                        imag: None,
+                    },
+                )
+                .unwrap(),
+            )
+        };
+        structure.insert("b", numeric("b", 1.0));
+        structure
+            .entry("a")
+            .and_modify(|_| panic!("field doesn't exist yet"))
+            .or_insert_with(|| numeric("", 2.0));
+        let mut modified = false;
+        let value = structure
+            .entry("b")
+            .and_modify(|_| modified = true)
+            .or_insert(numeric("", 3.0));
+        assert_eq!(value.name(), "b");
+        assert!(modified);
+        assert_eq!(structure.field_names().collect::<Vec<_>>(), vec!["b", "a"]);
+        assert_eq!(structure["a"].name(), "a");
+
+        let copy: Structure = structure
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect();
+        assert_eq!(copy.field_names().collect::<Vec<_>>(), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn structure_iter_mut() {
+        let data = include_bytes!("../tests/struct.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let mut structure = mat_file.get_struct("s").unwrap().clone();
+        for (name, value) in structure.iter_mut() {
+            if name == "inner" {
+                let Array::Structure(inner) = value else {
+                    panic!("expected a nested structure");
+                };
+                inner.remove("b");
+            }
+        }
+        for value in structure.arrays_mut() {
+            if let Array::Structure(inner) = value {
+                assert!(inner.is_empty());
+            }
+        }
+        let fields: Vec<_> = structure.into_iter().collect();
+        assert_eq!(fields.len(), 3);
+        assert_eq!(fields[0].0, "a");
+        assert_eq!(fields[2].1.name(), "inner");
+    }
+
+    #[test]
+    #[should_panic(expected = "structure has no field `missing`")]
+    fn structure_index_missing() {
+        let structure = Structure::new("s");
+        let _ = &structure["missing"];
+    }
+
+    #[test]
+    fn variables() {
+        let data = include_bytes!("../tests/variables.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let variables: Vec<_> = mat_file.variables().collect();
+        assert_eq!(variables.len(), 4);
+        assert_eq!(mat_file.arrays().len(), 4);
+
+        let names: Vec<_> = variables.iter().map(|v| v.name).collect();
+        assert_eq!(names, vec!["x", "c", "flag", "g"]);
+        assert_eq!(variables[0].class, parse::ArrayType::Double);
+        assert_eq!(variables[0].dimensions.as_slice(), &[2, 3]);
+        assert!(variables[0].is_complex);
+        assert_eq!(variables[1].class, parse::ArrayType::Cell);
+        assert_eq!(variables[1].dimensions.as_slice(), &[1, 2]);
+        assert!(!variables[1].is_complex);
+        assert_eq!(variables[2].class, parse::ArrayType::UInt8);
+        assert!(variables[2].is_logical);
+        assert!(!variables[2].is_global);
+        assert!(variables[3].is_global);
+    }
+
+    #[test]
+    fn from_path() {
+        let mat_file = MatFile::from_path("tests/double.mat").unwrap();
+        assert!(mat_file.find_by_name("A").is_some());
+        assert!(matches!(
+            MatFile::from_path("tests/does_not_exist.mat"),
+            Err(Error::IOError(_))
+        ));
+        assert!(matches!(
+            MatFile::from_reader(&b"not a mat file"[..]),
+            Err(Error::InvalidHeader(parse::HeaderError::Invalid(_)))
+        ));
+    }
+
+    #[test]
+    fn into_iter() {
+        let data = include_bytes!("../tests/variables.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let iter = mat_file.into_iter();
+        assert_eq!(iter.len(), 4);
+        let (names, arrays): (Vec<_>, Vec<_>) = iter.unzip();
+        assert_eq!(names, vec!["x", "c", "flag", "g"]);
+        assert_eq!(arrays[0].kind(), ArrayKind::Numeric);
+        assert_eq!(arrays[1].kind(), ArrayKind::Cell);
+        assert_eq!(arrays[3].name(), "g");
+    }
+
+    #[test]
+    fn typed_getters() {
+        let data = include_bytes!("../tests/variables.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        assert!(mat_file.get("x").is_some());
+        assert!(mat_file.get_numeric("x").is_some());
+        assert!(mat_file.get_numeric("X").is_none());
+        assert!(mat_file.get_sparse("x").is_none());
+        assert!(mat_file.get_char("x").is_none());
+        assert!(mat_file.get_struct("x").is_none());
+        assert!(mat_file.get_cell("x").is_none());
+        assert!(mat_file.get_cell("c").is_some());
+        assert!(mat_file.try_get_cell("c").is_ok());
+
+        assert!(mat_file.try_get_numeric("flag").unwrap().is_logical());
+        assert!(matches!(
+            mat_file.try_get_numeric("X"),
+            Err(Error::NotFound(name)) if name == "X"
+        ));
+        assert!(matches!(
+            mat_file.try_get_struct("x"),
+            Err(Error::WrongType {
+                expected: ArrayKind::Structure,
+                found: ArrayKind::Numeric,
+                ..
+            })
+        ));
+        assert_eq!(
+            mat_file.try_get_char("g").unwrap_err().to_string(),
+            "[E_WRONG_TYPE] Expected \"g\" to be a character array, but found a numeric array"
+        );
+    }
+
+    #[test]
+    fn array_kind_and_flags() {
+        let data = include_bytes!("../tests/variables.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let x = mat_file.get("x").unwrap();
+        assert_eq!(x.kind(), ArrayKind::Numeric);
+        assert_eq!(x.name(), "x");
+        assert_eq!(x.dimensions().as_slice(), &[2, 3]);
+        let flags = x.flags();
+        assert_eq!(flags.class, parse::ArrayType::Double);
+        assert!(flags.complex);
+        assert!(!flags.logical);
+        assert!(!flags.global);
+        assert!(mat_file.get("flag").unwrap().flags().logical);
+        assert!(mat_file.get("g").unwrap().flags().global);
+
+        let data = include_bytes!("../tests/struct.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let s = mat_file.get("s").unwrap();
+        assert_eq!(s.kind(), ArrayKind::Structure);
+        assert_eq!(s.dimensions().as_slice(), &[1, 1]);
+        assert_eq!(s.flags().class, parse::ArrayType::Struct);
+    }
+
+    #[test]
+    fn insert_remove_rename() {
+        let data = include_bytes!("../tests/variables.mat");
+        let mut mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let names = |mat_file: &MatFile| {
+            mat_file
+                .variables()
+                .map(|v| v.name.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let data = NumericData::Int16 {
+            real: vec![1, 2, 3],
+            imag: None,
+        };
+        let array = Array::Numeric(Numeric::new("ignored", vec![1, 3].into(), data).unwrap());
+        assert!(mat_file.insert("y", array.clone()).unwrap().is_none());
+        assert_eq!(mat_file.get("y").map(Array::name), Some("y"));
+        assert_eq!(names(&mat_file), vec!["x", "c", "flag", "g", "y"]);
+        let y = mat_file.variables().last().unwrap();
+        assert_eq!(y.class, parse::ArrayType::Int16);
+        assert_eq!(y.dimensions.as_slice(), &[1, 3]);
+
+        let previous = mat_file.insert("x", array.clone()).unwrap();
+        assert!(previous.unwrap().as_numeric().unwrap().is_complex());
+        assert_eq!(names(&mat_file), vec!["x", "c", "flag", "g", "y"]);
+        assert!(!mat_file.variables().next().unwrap().is_complex);
+        assert!(matches!(
+            mat_file.insert("", array),
+            Err(Error::InvalidName(_))
+        ));
+
+        assert!(mat_file.remove("flag").is_some());
+        assert!(mat_file.remove("flag").is_none());
+        assert!(mat_file.remove("c").is_some());
+        assert_eq!(names(&mat_file), vec!["x", "g", "y"]);
+
+        mat_file.rename("g", "h").unwrap();
+        assert!(mat_file.get("g").is_none());
+        assert_eq!(mat_file.get("h").map(Array::name), Some("h"));
+        assert!(matches!(
+            mat_file.rename("h", "x"),
+            Err(Error::NameCollision(name)) if name == "x"
+        ));
+        assert!(matches!(mat_file.rename("g", "z"), Err(Error::NotFound(_))));
+        assert!(matches!(
+            mat_file.rename("h", ""),
+            Err(Error::InvalidName(_))
+        ));
+        assert_eq!(names(&mat_file), vec!["x", "h", "y"]);
+
+        // unsupported variables are removed without returning anything
+        let data = include_bytes!("../tests/object.mat");
+        let mut mat_file = MatFile::parse(data.as_ref()).unwrap();
+        assert!(mat_file.remove("obj").is_none());
+        assert_eq!(mat_file.variables().count(), 0);
+    }
+
+    #[test]
+    fn merge() {
+        let load = |data: &[u8]| MatFile::parse(data).unwrap();
+        let first = load(include_bytes!("../tests/variables.mat"));
+        let second = load(include_bytes!("../tests/merge.mat"));
+        let names = |mat_file: &MatFile| {
+            mat_file
+                .variables()
+                .map(|v| v.name.to_string())
+                .collect::<Vec<_>>()
+        };
+        let x_len = |mat_file: &MatFile| mat_file.get_numeric("x").unwrap().len();
+
+        let mut merged = first.clone();
+        assert!(matches!(
+            merged.merge(second.clone(), MergePolicy::Error),
+            Err(Error::NameCollision(name)) if name == "x"
+        ));
+        assert_eq!(names(&merged), names(&first));
+
+        let mut merged = first.clone();
+        let collisions = merged
+            .merge(second.clone(), MergePolicy::KeepExisting)
+            .unwrap();
+        assert_eq!(collisions, vec!["x"]);
+        assert_eq!(names(&merged), vec!["x", "c", "flag", "g", "z"]);
+        assert_eq!(x_len(&merged), 6);
+
+        let mut merged = first.clone();
+        merged
+            .merge(second.clone(), MergePolicy::Overwrite)
+            .unwrap();
+        assert_eq!(names(&merged), vec!["x", "c", "flag", "g", "z"]);
+        assert_eq!(x_len(&merged), 1);
+        assert!(!merged.variables().next().unwrap().is_complex);
+
+        let mut merged = first.clone();
+        merged
+            .merge(second.clone(), MergePolicy::RenameWithSuffix)
+            .unwrap();
+        assert_eq!(names(&merged), vec!["x", "c", "flag", "g", "x_1", "z"]);
+        assert_eq!(x_len(&merged), 6);
+        assert_eq!(merged.get_numeric("x_1").unwrap().len(), 1);
+
+        // unsupported variables and flags are carried over
+        let mut merged = second;
+        let collisions = merged.merge(first, MergePolicy::RenameWithSuffix).unwrap();
+        assert_eq!(collisions, vec!["x"]);
+        assert_eq!(names(&merged), vec!["x", "z", "x_1", "c", "flag", "g"]);
+        let c = merged.variables().find(|v| v.name == "c").unwrap();
+        assert_eq!(c.class, parse::ArrayType::Cell);
+        assert!(merged.variables().last().unwrap().is_global);
+        assert!(merged.get_numeric("x_1").unwrap().is_complex());
+    }
+
+    #[test]
+    fn display_whos() {
+        let data = include_bytes!("../tests/variables.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        assert_eq!(
+            mat_file.to_string(),
+            "  Name  Size  Bytes  Class    Attributes\n\
+             \x20 x     2x3      96  double   complex\n\
+             \x20 c     1x2      12  cell\n\
+             \x20 flag  1x1       1  logical\n\
+             \x20 g     1x1       8  double   global\n"
+        );
+
+        // the size of unsupported arrays is unknown
+        let data = include_bytes!("../tests/object.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        assert_eq!(
+            mat_file.to_string(),
+            "  Name  Size  Bytes  Class   Attributes\n\
+             \x20 obj   1x1       -  object\n"
+        );
+
+        // opaque objects are listed, the subsystem data isn't
+        let data = include_bytes!("../tests/string.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        assert_eq!(
+            mat_file.to_string(),
+            "  Name  Size  Bytes  Class   Attributes\n\
+             \x20 s     1x1       -  opaque\n"
+        );
+    }
+
+    #[test]
+    fn debug_truncates_long_data() {
+        let data = NumericData::Double {
+            real: (0..100).map(f64::from).collect(),
+            imag: None,
+        };
+        assert_eq!(
+            format!("{:?}", data),
+            "Double { real: [0.0, 1.0, 2.0, 3.0, 4.0, ... 100 elements in total ..., \
+             95.0, 96.0, 97.0, 98.0, 99.0], imag: None }"
+        );
+        let short = NumericData::UInt8 {
+            real: vec![1, 2, 3],
+            imag: Some(vec![4, 5, 6]),
+        };
+        assert_eq!(
+            format!("{:?}", short),
+            "UInt8 { real: [1, 2, 3], imag: Some([4, 5, 6]) }"
+        );
+        let text = CharacterData::Unicode("a".repeat(100));
+        assert_eq!(
+            format!("{:?}", text),
+            format!("Unicode({:?}... (100 characters in total))", "a".repeat(64))
+        );
+    }
+
+    #[test]
+    fn get_scalar() {
+        let data = include_bytes!("../tests/scalars.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        assert_eq!(mat_file.get_scalar::<f64>("a").unwrap(), 3.0);
+        assert_eq!(mat_file.get_scalar::<u8>("a").unwrap(), 3);
+        assert_eq!(mat_file.get_scalar::<f32>("b").unwrap(), 2.5);
+        assert!(matches!(
+            mat_file.get_scalar::<i32>("b"),
+            Err(Error::ConversionError)
+        ));
+        assert_eq!(mat_file.get_scalar::<i8>("c").unwrap(), -7);
+        assert_eq!(mat_file.get_scalar::<f64>("c").unwrap(), -7.0);
+        assert!(matches!(
+            mat_file.get_scalar::<u64>("c"),
+            Err(Error::ConversionError)
+        ));
+        assert_eq!(mat_file.get_scalar::<u16>("big").unwrap(), 300);
+        assert!(matches!(
+            mat_file.get_scalar::<u8>("big"),
+            Err(Error::ConversionError)
+        ));
+        assert!(matches!(
+            mat_file.get_scalar::<f64>("m"),
+            Err(Error::NotScalar(name)) if name == "m"
+        ));
+        assert_eq!(mat_file.get_scalar::<f64>("z").unwrap(), 1.0);
+        assert!(matches!(
+            mat_file.get_scalar::<f64>("w"),
+            Err(Error::ConversionError)
+        ));
+        assert!(matches!(
+            mat_file.get_scalar::<f64>("missing"),
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn logical_arrays() {
+        let files: [&[u8]; 2] = [
+            include_bytes!("../tests/logical_v7.mat"),
+            include_bytes!("../tests/logical_v6.mat"),
+        ];
+        for data in files {
+            let mat_file = MatFile::parse(data).unwrap();
+            let mask = mat_file.get_numeric("mask").unwrap();
+            assert!(mask.is_logical());
+            assert_eq!(
+                mask.to_logical(),
+                Some(vec![true, false, false, true, true, false])
+            );
+            assert_eq!(mat_file.get_numeric("notmask").unwrap().to_logical(), None);
+            let smask = mat_file.get_sparse("smask").unwrap();
+            assert!(smask.is_logical());
+            assert_eq!(
+                smask.to_logical_triplets(),
+                Some(vec![(0, 0, true), (2, 2, true), (1, 2, true)])
+            );
+        }
+    }
+
+    #[test]
+    fn arrays_into_data_elements() {
+        let files: [&[u8]; 6] = [
+            include_bytes!("../tests/sparse1.mat"),
+            include_bytes!("../tests/sparse_logical.mat"),
+            include_bytes!("../tests/cell.mat"),
+            include_bytes!("../tests/struct_array.mat"),
+            include_bytes!("../tests/char_matrix.mat"),
+            include_bytes!("../tests/globals.mat"),
+        ];
+        for data in files {
+            let mat_file = MatFile::parse(data).unwrap();
+            let mut result = parse::parse_all(data).unwrap();
+            result.data_elements = mat_file
+                .arrays()
+                .iter()
+                .cloned()
+                .map(parse::DataElement::from)
+                .collect();
+            let written = write::write_all(&result).unwrap();
+            let read = MatFile::parse(written.as_slice()).unwrap();
+            assert_eq!(
+                format!("{:?}", read.arrays()),
+                format!("{:?}", mat_file.arrays())
+            );
+        }
+    }
+
+    #[test]
+    fn logical_sparse() {
+        // sparse(logical(eye(4))), once with and once without stored values
+        let data = include_bytes!("../tests/sparse_logical.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        for name in ["a", "b"] {
+            let sparse = mat_file.get_sparse(name).unwrap();
+            assert!(sparse.is_logical());
+            assert_eq!(
+                sparse.to_logical_triplets(),
+                Some(vec![(0, 0, true), (1, 1, true), (2, 2, true), (3, 3, true)])
+            );
+        }
+    }
+
+    #[test]
+    fn globals() {
+        let data = include_bytes!("../tests/globals.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let globals: Vec<_> = mat_file.globals().map(Array::name).collect();
+        assert_eq!(globals, vec!["counter"]);
+        assert!(mat_file.get_numeric("counter").unwrap().is_global());
+        assert!(!mat_file.get_numeric("local").unwrap().is_global());
+        assert!(!mat_file.get("local").unwrap().is_global());
+    }
+
+    #[test]
+    fn sparse_get() {
+        let data = include_bytes!("../tests/sparse1.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let Array::Sparse(sparse) = &mat_file.arrays()[0] else {
+            panic!("expected a sparse array");
+        };
+        assert_eq!(sparse.nnz(), 7);
+        let expected = [
+            (5, 0, 2.0),
+            (7, 1, 7.0),
+            (2, 3, 4.0),
+            (0, 4, 9.0),
+            (1, 5, 5.0),
+            (3, 6, 8.0),
+            (6, 7, 6.0),
+        ];
+        for row in 0..8 {
+            for column in 0..8 {
+                let value = expected
+                    .iter()
+                    .find(|&&(r, c, _)| (r, c) == (row, column))
+                    .map_or(0.0, |&(_, _, v)| v);
+                assert_eq!(sparse.get(row, column), Some(value));
+            }
+        }
+        assert_eq!(sparse.get(0, 8), None);
+    }
+
+    #[test]
+    fn sparse_without_values() {
+        let data = include_bytes!("../tests/sparse_empty.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let Some(Array::Sparse(sparse)) = mat_file.find_by_name("s") else {
+            panic!("expected a sparse array");
+        };
+        assert_eq!(sparse.shape(), (5, 5));
+        assert_eq!(sparse.nnz(), 0);
+        assert_eq!(sparse.get(4, 4), Some(0.0));
+    }
+
+    fn array_header(
+        name: &str,
+        class: parse::ArrayType,
+        dimensions: Vec<usize>,
+    ) -> parse::ArrayHeader {
+        parse::ArrayHeader {
+            flags: parse::ArrayFlags {
+                complex: false,
+                global: false,
+                logical: false,
+                class,
+                nzmax: 0,
+            },
+            dimensions: dimensions.into(),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn convert_logical_numeric() {
+        let mut header = array_header("mask", parse::ArrayType::UInt8, vec![1, 2]);
+        header.flags.logical = true;
+        let element = parse::DataElement::NumericMatrix(parse::Numeric {
+            header,
+            data: NumericData::UInt8 {
+                real: vec![0, 1],
+                imag: None,
+            },
+            imag_skipped: false,
+        });
+        let Ok(Array::Numeric(numeric)) = Array::try_from(element) else {
+            panic!("expected a numeric array");
+        };
+        assert!(numeric.is_logical());
+        assert_eq!(numeric.size(), &vec![1, 2]);
+        assert_eq!(numeric.data().data_type(), parse::DataType::UInt8);
+    }
+
+    #[test]
+    fn convert_invalid_numeric() {
+        let mut header = array_header("x", parse::ArrayType::Double, vec![2, 2]);
+        let element = parse::DataElement::NumericMatrix(parse::Numeric {
+            header: header.clone(),
+            data: NumericData::Double {
+                real: vec![1.0, 2.0, 3.0],
+                imag: None,
+            },
+            imag_skipped: false,
+        });
+        assert!(matches!(
+            Array::try_from(element),
+            Err(Error::InvalidArray { path, .. }) if path == "x"
+        ));
+
+        header.flags.complex = true;
+        let element = parse::DataElement::NumericMatrix(parse::Numeric {
+            header,
+            data: NumericData::Double {
+                real: vec![1.0, 2.0, 3.0, 4.0],
+                imag: None,
+            },
+            imag_skipped: false,
+        });
+        assert!(matches!(
+            Array::try_from(element),
+            Err(Error::InvalidArray { path, .. }) if path == "x"
+        ));
+    }
+
+    #[test]
+    fn convert_invalid_structure_field() {
+        let inner = parse::DataElement::CharacterMatrix(parse::Character {
+            header: array_header("label", parse::ArrayType::Char, vec![1, 5]),
+            real_part: parse::CharacterData::Unicode("abc".to_string()),
+            imag_part: None,
+        });
+        let mut middle =
+            parse::Structure::new(array_header("middle", parse::ArrayType::Struct, vec![1, 1]));
+        middle.insert("label", inner);
+        let mut outer =
+            parse::Structure::new(array_header("outer", parse::ArrayType::Struct, vec![1, 1]));
+        outer.insert("middle", parse::DataElement::StructureMatrix(middle));
+        let err = Array::try_from(parse::DataElement::StructureMatrix(outer)).unwrap_err();
+        assert!(matches!(
+            &err,
+            Error::InvalidArray { path, .. } if path == "outer.middle.label"
+        ));
+        assert_eq!(
+            err.to_string(),
+            "[E_INVALID_ARRAY] Invalid array \"outer.middle.label\": number of characters does not match the dimensions"
+        );
+    }
+
+    #[test]
+    fn new_numeric() {
+        let data = NumericData::Int16 {
+            real: vec![1, 2, 3],
+            imag: Some(vec![-1, -2, -3]),
+        };
+        let numeric = Numeric::new("z", vec![3, 1].into(), data).unwrap();
+        assert_eq!(numeric.name(), "z");
+        assert_eq!(numeric.dimensions().as_slice(), &[3, 1]);
+        assert_eq!(numeric.len(), 3);
+        assert!(numeric.is_complex());
+        assert!(!numeric.is_logical());
+        assert!(matches!(
+            numeric.data(),
+            NumericData::Int16 { real, imag: Some(imag) } if real == &[1, 2, 3] && imag == &[-1, -2, -3]
+        ));
+
+        let data = NumericData::Double {
+            real: vec![1.0, 2.0, 3.0],
+            imag: Some(vec![1.0]),
+        };
+        assert!(Numeric::new("z", vec![3, 1].into(), data).is_err());
+        let data = NumericData::Double {
+            real: vec![1.0, 2.0, 3.0],
+            imag: None,
+        };
+        assert!(Numeric::new("z", vec![2, 2].into(), data).is_err());
+    }
 }
@@ -1,4 +1,4 @@
-//! Helpers for converting between `matfile::Array` and `ndarray::Array`.
+//! Helpers for converting between `matfile::Numeric` and `ndarray::Array`.
 //!
 //! While `matfile` arrays abstract over the underlying data type, `ndarray`
 //! arrays are parameterized by a concrete data type. Thus the conversions
@@ -20,7 +20,7 @@
 //! # fn main() -> Result<(), Box<dyn std::error::Error>> {
 //! #     let data = include_bytes!("../tests/multidimensional.mat");
 //! #     let mat_file = matfile::MatFile::parse(data.as_ref()).unwrap();
-//! #     let mf_arr = &mat_file.arrays()[0];
+//! #     let matfile::Array::Numeric(mf_arr) = &mat_file.arrays()[0] else { unreachable!() };
 //! #     use ndarr as ndarray;
 //! #     use std::convert::TryInto;
 //! let nd_arr: ndarray::ArrayD<f64> = mf_arr.try_into()?;
@@ -36,7 +36,7 @@
 //! # fn main() -> Result<(), Box<dyn std::error::Error>> {
 //! #     let data = include_bytes!("../tests/single_complex.mat");
 //! #     let mat_file = matfile::MatFile::parse(data.as_ref()).unwrap();
-//! #     let mf_arr = &mat_file.arrays()[0];
+//! #     let matfile::Array::Numeric(mf_arr) = &mat_file.arrays()[0] else { unreachable!() };
 //! #     use ndarr as ndarray;
 //! #     use num_complex;
 //! #     use std::convert::TryInto;
@@ -77,7 +77,7 @@ impl std::error::Error for Error {
 
 macro_rules! dynamic_conversions {
     ( $num:ty, $variant:ident ) => {
-        impl<'me> TryInto<nd::ArrayViewD<'me, $num>> for &'me crate::Array {
+        impl<'me> TryInto<nd::ArrayViewD<'me, $num>> for &'me crate::Numeric {
             type Error = Error;
             fn try_into(self) -> Result<nd::ArrayViewD<'me, $num>, Self::Error> {
                 match self.data() {
@@ -94,7 +94,7 @@ macro_rules! dynamic_conversions {
             }
         }
 
-        impl TryInto<nd::ArrayD<$num>> for &crate::Array {
+        impl TryInto<nd::ArrayD<$num>> for &crate::Numeric {
             type Error = Error;
             fn try_into(self) -> Result<nd::ArrayD<$num>, Self::Error> {
                 match self.data() {
@@ -111,7 +111,7 @@ macro_rules! dynamic_conversions {
             }
         }
 
-        impl TryInto<nd::ArrayD<Complex<$num>>> for &crate::Array {
+        impl TryInto<nd::ArrayD<Complex<$num>>> for &crate::Numeric {
             type Error = Error;
             fn try_into(self) -> Result<nd::ArrayD<Complex<$num>>, Self::Error> {
                 match self.data() {
@@ -138,7 +138,7 @@ macro_rules! dynamic_conversions {
 macro_rules! static_conversions_n {
     ( $num:ty, $variant:ident, $ndims:literal ) => {
         impl<'me> TryInto<nd::ArrayView<'me, $num, nd::Dim<[nd::Ix; $ndims]>>>
-            for &'me crate::Array
+            for &'me crate::Numeric
         {
             type Error = Error;
             fn try_into(
@@ -164,7 +164,7 @@ macro_rules! static_conversions_n {
             }
         }
 
-        impl TryInto<nd::Array<$num, nd::Dim<[nd::Ix; $ndims]>>> for &crate::Array {
+        impl TryInto<nd::Array<$num, nd::Dim<[nd::Ix; $ndims]>>> for &crate::Numeric {
             type Error = Error;
             fn try_into(self) -> Result<nd::Array<$num, nd::Dim<[nd::Ix; $ndims]>>, Self::Error> {
                 let size = self.size();
@@ -187,7 +187,7 @@ macro_rules! static_conversions_n {
             }
         }
 
-        impl TryInto<nd::Array<Complex<$num>, nd::Dim<[nd::Ix; $ndims]>>> for &crate::Array {
+        impl TryInto<nd::Array<Complex<$num>, nd::Dim<[nd::Ix; $ndims]>>> for &crate::Numeric {
             type Error = Error;
             fn try_into(
                 self,
@@ -246,3 +246,62 @@ all_conversions!(i16, Int16);
 all_conversions!(u16, UInt16);
 all_conversions!(i8, Int8);
 all_conversions!(u8, UInt8);
+
+/// Element types of `ndarray` arrays that can be written as numeric arrays
+/// with [`crate::write::MatFileBuilder::add_ndarray`].
+pub trait NumericElement: Clone {
+    /// The data of an array holding `values` in column-major order.
+    fn numeric_data(values: Vec<Self>) -> crate::NumericData;
+}
+
+macro_rules! numeric_element {
+    ( $num:ty, $variant:ident ) => {
+        impl NumericElement for $num {
+            fn numeric_data(values: Vec<Self>) -> crate::NumericData {
+                crate::NumericData::$variant {
+                    real: values,
+                    imag: None,
+                }
+            }
+        }
+
+        impl NumericElement for Complex<$num> {
+            fn numeric_data(values: Vec<Self>) -> crate::NumericData {
+                let (real, imag) = values.into_iter().map(|value| (value.re, value.im)).unzip();
+                crate::NumericData::$variant {
+                    real,
+                    imag: Some(imag),
+                }
+            }
+        }
+    };
+}
+
+numeric_element!(f64, Double);
+numeric_element!(f32, Single);
+numeric_element!(i64, Int64);
+numeric_element!(u64, UInt64);
+numeric_element!(i32, Int32);
+numeric_element!(u32, UInt32);
+numeric_element!(i16, Int16);
+numeric_element!(u16, UInt16);
+numeric_element!(i8, Int8);
+numeric_element!(u8, UInt8);
+
+/// The dimensions and the column-major data of `array`. Arrays with less
+/// than two dimensions become row vectors, like numpy arrays saved by scipy.
+pub(crate) fn column_major<A, S, D>(array: &nd::ArrayBase<S, D>) -> (Vec<usize>, crate::NumericData)
+where
+    A: NumericElement,
+    S: nd::Data<Elem = A>,
+    D: nd::Dimension,
+{
+    let dimensions = match array.shape() {
+        [] => vec![1, 1],
+        [len] => vec![1, *len],
+        shape => shape.to_vec(),
+    };
+    // Reversing the axes makes the first one vary fastest when iterating
+    let values = array.t().iter().cloned().collect();
+    (dimensions, A::numeric_data(values))
+}
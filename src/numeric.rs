@@ -0,0 +1,388 @@
+//! Numeric data shared by the parser and the array types.
+
+use crate::parse::{ArrayType, DataType};
+use num_traits::{AsPrimitive, NumCast, Zero};
+use std::slice::Iter;
+
+/// Stores the data of a numerical array and abstracts over the actual data
+/// type used. Real and imaginary parts are stored in separate vectors with the
+/// imaginary part being optional.
+///
+/// Numerical data is stored in column-major order. When talking about higher
+/// dimensional arrays this means that the index of the first dimension varies
+/// fastest.
+#[derive(Clone, PartialEq)]
+pub enum NumericData {
+    Int8 {
+        real: Vec<i8>,
+        imag: Option<Vec<i8>>,
+    },
+    UInt8 {
+        real: Vec<u8>,
+        imag: Option<Vec<u8>>,
+    },
+    Int16 {
+        real: Vec<i16>,
+        imag: Option<Vec<i16>>,
+    },
+    UInt16 {
+        real: Vec<u16>,
+        imag: Option<Vec<u16>>,
+    },
+    Int32 {
+        real: Vec<i32>,
+        imag: Option<Vec<i32>>,
+    },
+    UInt32 {
+        real: Vec<u32>,
+        imag: Option<Vec<u32>>,
+    },
+    Int64 {
+        real: Vec<i64>,
+        imag: Option<Vec<i64>>,
+    },
+    UInt64 {
+        real: Vec<u64>,
+        imag: Option<Vec<u64>>,
+    },
+    Single {
+        real: Vec<f32>,
+        imag: Option<Vec<f32>>,
+    },
+    Double {
+        real: Vec<f64>,
+        imag: Option<Vec<f64>>,
+    },
+}
+
+macro_rules! numeric_data_parts {
+    ( $data:expr, $real:ident, $imag:ident => $body:expr ) => {
+        match $data {
+            NumericData::Int8 {
+                real: $real,
+                imag: $imag,
+            } => $body,
+            NumericData::UInt8 {
+                real: $real,
+                imag: $imag,
+            } => $body,
+            NumericData::Int16 {
+                real: $real,
+                imag: $imag,
+            } => $body,
+            NumericData::UInt16 {
+                real: $real,
+                imag: $imag,
+            } => $body,
+            NumericData::Int32 {
+                real: $real,
+                imag: $imag,
+            } => $body,
+            NumericData::UInt32 {
+                real: $real,
+                imag: $imag,
+            } => $body,
+            NumericData::Int64 {
+                real: $real,
+                imag: $imag,
+            } => $body,
+            NumericData::UInt64 {
+                real: $real,
+                imag: $imag,
+            } => $body,
+            NumericData::Single {
+                real: $real,
+                imag: $imag,
+            } => $body,
+            NumericData::Double {
+                real: $real,
+                imag: $imag,
+            } => $body,
+        }
+    };
+}
+
+impl std::fmt::Debug for NumericData {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            NumericData::Int8 { .. } => "Int8",
+            NumericData::UInt8 { .. } => "UInt8",
+            NumericData::Int16 { .. } => "Int16",
+            NumericData::UInt16 { .. } => "UInt16",
+            NumericData::Int32 { .. } => "Int32",
+            NumericData::UInt32 { .. } => "UInt32",
+            NumericData::Int64 { .. } => "Int64",
+            NumericData::UInt64 { .. } => "UInt64",
+            NumericData::Single { .. } => "Single",
+            NumericData::Double { .. } => "Double",
+        };
+        numeric_data_parts!(self, real, imag => f
+            .debug_struct(name)
+            .field("real", &crate::DebugSlice(real))
+            .field("imag", &imag.as_deref().map(crate::DebugSlice))
+            .finish())
+    }
+}
+
+impl NumericData {
+    /// The number of elements, i.e. the length of the real part.
+    pub fn len(&self) -> usize {
+        numeric_data_parts!(self, real, _imag => real.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The type of the elements.
+    pub fn data_type(&self) -> DataType {
+        match self {
+            NumericData::Int8 { .. } => DataType::Int8,
+            NumericData::UInt8 { .. } => DataType::UInt8,
+            NumericData::Int16 { .. } => DataType::Int16,
+            NumericData::UInt16 { .. } => DataType::UInt16,
+            NumericData::Int32 { .. } => DataType::Int32,
+            NumericData::UInt32 { .. } => DataType::UInt32,
+            NumericData::Int64 { .. } => DataType::Int64,
+            NumericData::UInt64 { .. } => DataType::UInt64,
+            NumericData::Single { .. } => DataType::Single,
+            NumericData::Double { .. } => DataType::Double,
+        }
+    }
+
+    /// Whether the data has an imaginary part.
+    pub fn is_complex(&self) -> bool {
+        numeric_data_parts!(self, _real, imag => imag.is_some())
+    }
+
+    /// The elements of the real part converted to `f64`, without converting
+    /// the whole part at once. Conversions round to the nearest `f64`, which
+    /// only changes 64-bit integers larger than 2^53.
+    ///
+    /// ```rust
+    /// let data = matfile::NumericData::Int16 {
+    ///     real: vec![-1, 2, 300],
+    ///     imag: None,
+    /// };
+    /// assert_eq!(data.iter_f64().sum::<f64>(), 301.0);
+    /// ```
+    pub fn iter_f64(&self) -> impl ExactSizeIterator<Item = f64> + '_ {
+        Values::<ToF64>::new(self.real())
+    }
+
+    /// The elements of the real part converted to `i64`, or `None` for those
+    /// that would change, i.e. fractional, infinite or NaN values and
+    /// `uint64` values larger than `i64::MAX`.
+    ///
+    /// ```rust
+    /// let data = matfile::NumericData::Double {
+    ///     real: vec![1.0, 2.5],
+    ///     imag: None,
+    /// };
+    /// assert_eq!(data.iter_i64().collect::<Vec<_>>(), [Some(1), None]);
+    /// ```
+    pub fn iter_i64(&self) -> impl ExactSizeIterator<Item = Option<i64>> + '_ {
+        Values::<ToI64>::new(self.real())
+    }
+
+    /// Like [`NumericData::iter_f64`] for the imaginary part, if there is
+    /// one.
+    pub(crate) fn iter_imag_f64(&self) -> Option<impl ExactSizeIterator<Item = f64> + '_> {
+        Some(Values::<ToF64>::new(self.imag()?))
+    }
+
+    fn real(&self) -> Part<'_> {
+        numeric_data_parts!(self, real, _imag => Part::from(real.as_slice()))
+    }
+
+    fn imag(&self) -> Option<Part<'_>> {
+        numeric_data_parts!(self, _real, imag => imag.as_deref().map(Part::from))
+    }
+
+    /// The real part converted to booleans, with any non-zero value being
+    /// `true`.
+    pub(crate) fn real_to_bool(&self) -> Vec<bool> {
+        numeric_data_parts!(self, real, _imag => real.iter().map(|v| !v.is_zero()).collect())
+    }
+
+    /// The number of bytes occupied by the real and imaginary parts.
+    pub(crate) fn byte_size(&self) -> usize {
+        numeric_data_parts!(self, real, imag => std::mem::size_of_val(real.as_slice())
+            + imag.as_ref().map_or(0, |imag| std::mem::size_of_val(imag.as_slice())))
+    }
+
+    /// The array class corresponding to the type of this data.
+    pub(crate) fn class(&self) -> ArrayType {
+        match self {
+            NumericData::Int8 { .. } => ArrayType::Int8,
+            NumericData::UInt8 { .. } => ArrayType::UInt8,
+            NumericData::Int16 { .. } => ArrayType::Int16,
+            NumericData::UInt16 { .. } => ArrayType::UInt16,
+            NumericData::Int32 { .. } => ArrayType::Int32,
+            NumericData::UInt32 { .. } => ArrayType::UInt32,
+            NumericData::Int64 { .. } => ArrayType::Int64,
+            NumericData::UInt64 { .. } => ArrayType::UInt64,
+            NumericData::Single { .. } => ArrayType::Single,
+            NumericData::Double { .. } => ArrayType::Double,
+        }
+    }
+
+    /// The element of the real part at the given index, converted to `f64`.
+    #[allow(clippy::unnecessary_cast)]
+    pub(crate) fn real_as_f64(&self, index: usize) -> Option<f64> {
+        numeric_data_parts!(self, real, _imag => real.get(index).map(|&x| x as f64))
+    }
+
+    /// The real and imaginary parts converted to `f64` and paired up, or
+    /// `None` if there is no imaginary part.
+    #[allow(clippy::unnecessary_cast)]
+    pub(crate) fn to_complex_f64(&self) -> Option<Vec<(f64, f64)>> {
+        numeric_data_parts!(self, real, imag => {
+            let imag = imag.as_ref()?;
+            Some(
+                real.iter()
+                    .zip(imag)
+                    .map(|(&re, &im)| (re as f64, im as f64))
+                    .collect(),
+            )
+        })
+    }
+
+    /// The length of the imaginary part, if there is one.
+    pub(crate) fn imag_len(&self) -> Option<usize> {
+        numeric_data_parts!(self, _real, imag => imag.as_ref().map(Vec::len))
+    }
+}
+
+/// An iterator over one of the parts of [`NumericData`].
+enum Part<'a> {
+    Int8(Iter<'a, i8>),
+    UInt8(Iter<'a, u8>),
+    Int16(Iter<'a, i16>),
+    UInt16(Iter<'a, u16>),
+    Int32(Iter<'a, i32>),
+    UInt32(Iter<'a, u32>),
+    Int64(Iter<'a, i64>),
+    UInt64(Iter<'a, u64>),
+    Single(Iter<'a, f32>),
+    Double(Iter<'a, f64>),
+}
+
+macro_rules! part_from {
+    ( $( $variant:ident: $t:ty ),* ) => {
+        $(
+            impl<'a> From<&'a [$t]> for Part<'a> {
+                fn from(values: &'a [$t]) -> Self {
+                    Part::$variant(values.iter())
+                }
+            }
+        )*
+    };
+}
+
+part_from!(
+    Int8: i8,
+    UInt8: u8,
+    Int16: i16,
+    UInt16: u16,
+    Int32: i32,
+    UInt32: u32,
+    Int64: i64,
+    UInt64: u64,
+    Single: f32,
+    Double: f64
+);
+
+macro_rules! part_iter {
+    ( $part:expr, $iter:ident => $body:expr ) => {
+        match $part {
+            Part::Int8($iter) => $body,
+            Part::UInt8($iter) => $body,
+            Part::Int16($iter) => $body,
+            Part::UInt16($iter) => $body,
+            Part::Int32($iter) => $body,
+            Part::UInt32($iter) => $body,
+            Part::Int64($iter) => $body,
+            Part::UInt64($iter) => $body,
+            Part::Single($iter) => $body,
+            Part::Double($iter) => $body,
+        }
+    };
+}
+
+/// A conversion applied to each element by [`Values`].
+trait Conversion {
+    type Output;
+
+    fn convert<T>(value: T) -> Self::Output
+    where
+        T: AsPrimitive<f64> + NumCast + PartialEq;
+}
+
+struct ToF64;
+
+impl Conversion for ToF64 {
+    type Output = f64;
+
+    fn convert<T>(value: T) -> f64
+    where
+        T: AsPrimitive<f64> + NumCast + PartialEq,
+    {
+        value.as_()
+    }
+}
+
+struct ToI64;
+
+impl Conversion for ToI64 {
+    type Output = Option<i64>;
+
+    fn convert<T>(value: T) -> Option<i64>
+    where
+        T: AsPrimitive<f64> + NumCast + PartialEq,
+    {
+        crate::exact_cast(value)
+    }
+}
+
+/// Converts the elements of a part one at a time. Folding, which `sum()`
+/// and `for_each()` are built on, matches the type once and then runs over
+/// the slice directly.
+struct Values<'a, C> {
+    part: Part<'a>,
+    conversion: std::marker::PhantomData<C>,
+}
+
+impl<'a, C> Values<'a, C> {
+    fn new(part: Part<'a>) -> Self {
+        Values {
+            part,
+            conversion: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<C: Conversion> Iterator for Values<'_, C> {
+    type Item = C::Output;
+
+    fn next(&mut self) -> Option<C::Output> {
+        part_iter!(&mut self.part, iter => iter.next().map(|&value| C::convert(value)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        part_iter!(&self.part, iter => iter.size_hint())
+    }
+
+    fn nth(&mut self, n: usize) -> Option<C::Output> {
+        part_iter!(&mut self.part, iter => iter.nth(n).map(|&value| C::convert(value)))
+    }
+
+    fn fold<B, F>(self, init: B, mut f: F) -> B
+    where
+        F: FnMut(B, C::Output) -> B,
+    {
+        part_iter!(self.part, iter => iter.fold(init, |acc, &value| f(acc, C::convert(value))))
+    }
+}
+
+impl<C: Conversion> ExactSizeIterator for Values<'_, C> {}
@@ -1,1267 +1,5668 @@
+//! Low-level parser for the MAT-file Level 5 format.
+//!
+//! The types in this module mirror the on-disk structure of a ".mat" file
+//! quite closely. Most users will want to use [`crate::MatFile`] instead.
+
 use libflate::zlib::Decoder;
 use nom::branch::alt;
 use nom::bytes::complete::tag;
 use nom::bytes::complete::take;
-use nom::character::complete::char;
-use nom::combinator::{complete, cond, eof, map, map_res, not, opt, peek, value};
-use nom::multi::{count, length_value, many0};
-use nom::number::complete::f32;
-use nom::number::complete::f64;
-use nom::number::complete::i16;
+use nom::combinator::{complete, cond, map, opt, value};
+use nom::multi::{count, length_value};
 use nom::number::complete::i32;
-use nom::number::complete::i64;
-use nom::number::complete::i8;
 use nom::number::complete::u16;
 use nom::number::complete::u32;
 use nom::number::complete::u64;
-use nom::number::complete::u8;
-use nom::sequence::pair;
-use nom::{error_position, IResult};
 use num_traits::FromPrimitive;
-use std::ffi::CStr;
+use std::cell::RefCell;
+use std::convert::{TryFrom, TryInto};
 use std::io::Read;
+use std::sync::Arc;
+
+mod borrowed;
+mod subsystem;
+
+/// The result of the internal parsers, which fail with a [`ParseError`]
+/// unless they say otherwise.
+pub(crate) type IResult<I, O, E = ParseError> = nom::IResult<I, O, E>;
 
 // https://www.mathworks.com/help/pdf_doc/matlab/matfile_format.pdf
 // https://www.mathworks.com/help/matlab/import_export/mat-file-versions.html
 
 #[derive(Clone, Debug)]
 pub struct Header {
-    text: String,
-    is_little_endian: bool,
+    pub text: String,
+    pub is_little_endian: bool,
+    pub(crate) raw_text: [u8; 116],
+    pub(crate) subsystem_offset: Option<u64>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum NumericData {
-    Int8(Vec<i8>),
-    UInt8(Vec<u8>),
-    Int16(Vec<i16>),
-    UInt16(Vec<u16>),
-    Int32(Vec<i32>),
-    UInt32(Vec<u32>),
-    Int64(Vec<i64>),
-    UInt64(Vec<u64>),
-    Single(Vec<f32>),
-    Double(Vec<f64>),
+/// Why the header of a Level 5 file couldn't be read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeaderError {
+    /// One of the first four bytes is null. Level 5 files start with text,
+    /// so this is most likely a Level 4 file.
+    NullText,
+    /// The version field holds a value other than 0x0100. Version 0x0200 is
+    /// used by v7.3 files, which are HDF5 containers.
+    UnsupportedVersion(u16),
+    /// The header is truncated or its endian indicator is neither "IM" nor
+    /// "MI".
+    Invalid(nom::error::ErrorKind),
 }
 
-impl NumericData {
-    fn len(&self) -> usize {
-        match self {
-            NumericData::Single(vec) => vec.len(),
-            NumericData::Double(vec) => vec.len(),
-            NumericData::Int8(vec) => vec.len(),
-            NumericData::UInt8(vec) => vec.len(),
-            NumericData::Int16(vec) => vec.len(),
-            NumericData::UInt16(vec) => vec.len(),
-            NumericData::Int32(vec) => vec.len(),
-            NumericData::UInt32(vec) => vec.len(),
-            NumericData::Int64(vec) => vec.len(),
-            NumericData::UInt64(vec) => vec.len(),
-        }
+impl<I> nom::error::ParseError<I> for HeaderError {
+    fn from_error_kind(_input: I, kind: nom::error::ErrorKind) -> Self {
+        HeaderError::Invalid(kind)
     }
 
-    fn data_type(&self) -> DataType {
-        match self {
-            NumericData::Single(_) => DataType::Single,
-            NumericData::Double(_) => DataType::Double,
-            NumericData::Int8(_) => DataType::Int8,
-            NumericData::UInt8(_) => DataType::UInt8,
-            NumericData::Int16(_) => DataType::Int16,
-            NumericData::UInt16(_) => DataType::UInt16,
-            NumericData::Int32(_) => DataType::Int32,
-            NumericData::UInt32(_) => DataType::UInt32,
-            NumericData::Int64(_) => DataType::Int64,
-            NumericData::UInt64(_) => DataType::UInt64,
-        }
+    fn append(_input: I, _kind: nom::error::ErrorKind, other: Self) -> Self {
+        other
     }
 }
 
-#[derive(Clone, Debug)]
-pub enum DataElement {
-    NumericMatrix(Numeric),
-    SparseMatrix(Sparse),
-    CharacterMatrix(Character),
-    // Cell Matrix,
-    StructureMatrix(Structure),
-    // Object Matrix,
-    Unsupported,
-}
-
-#[derive(Clone, Debug)]
-pub struct ArrayHeader {
-    pub flags: ArrayFlags,
-    pub dimensions: Dimensions,
-    pub name: String,
-}
-
-#[derive(Clone, Debug)]
-pub struct Numeric {
-    pub header: ArrayHeader,
-    pub real_part: NumericData,
-    pub imag_part: Option<NumericData>,
+impl std::fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HeaderError::NullText => write!(
+                f,
+                "The file doesn't start with a MAT-file header, it may be a Level 4 MAT-file"
+            ),
+            HeaderError::UnsupportedVersion(0x0200) => write!(
+                f,
+                "Unsupported MAT-file version 0x0200 (v7.3/HDF5), only v5 to v7 files are supported"
+            ),
+            HeaderError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported MAT-file version 0x{:04x}, only v5 to v7 files are supported",
+                version
+            ),
+            HeaderError::Invalid(_) => write!(f, "Invalid MAT-file header"),
+        }
+    }
 }
 
-#[derive(Clone, Debug)]
-pub struct Sparse {
-    pub header: ArrayHeader,
-    pub row_index: RowIndex,
-    pub column_index: ColumnShift,
-    pub real_part: NumericData,
-    pub imag_part: Option<NumericData>,
-}
+impl std::error::Error for HeaderError {}
 
-#[derive(Clone, Debug)]
-pub struct Character {
-    pub header: ArrayHeader,
-    pub real_part: CharacterData,
-    pub imag_part: Option<CharacterData>,
+/// What is wrong with the data elements of a file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseErrorKind {
+    /// The data ends in the middle of an element, or an element claims more
+    /// bytes than are left.
+    UnexpectedEof,
+    /// A data element tag holds a data type that doesn't exist.
+    UnknownDataType(u32),
+    /// A data element tag in the small format claims more than 4 bytes.
+    InvalidTag,
+    /// The array flags hold a class that doesn't exist.
+    UnknownClass(u8),
+    /// A subelement has a data type that isn't allowed for it.
+    UnexpectedDataType {
+        subelement: &'static str,
+        data_type: DataType,
+    },
+    /// The size of a subelement doesn't fit its data type or the dimensions
+    /// of its array.
+    SizeMismatch { subelement: &'static str },
+    /// Text isn't valid UTF-8 or contains invalid code points.
+    InvalidText { subelement: &'static str },
+    /// The dimensions of an array are negative.
+    NegativeDimension,
+    /// An array has more elements than fit into its data, or their number
+    /// overflows.
+    TooManyElements,
+    /// Numeric values can't be converted into the class of their array
+    /// without changing them.
+    LossyConversion { subelement: &'static str },
+    /// The real and imaginary part of an array have different types.
+    MismatchedParts,
+    /// The maximum length of the field names of a structure isn't a single
+    /// positive integer.
+    InvalidFieldNameLength,
+    /// A cell or structure field has a name of its own.
+    UnexpectedName(String),
+    /// A cell or structure field is compressed, which only variables can be.
+    UnexpectedCompression,
+    /// Data elements are nested more deeply than
+    /// [`ParseOptions::max_depth`] allows.
+    NestingTooDeep,
+    /// A compressed element decompresses to more than
+    /// [`ParseOptions::max_decompressed_size`] bytes.
+    DecompressedSizeLimit,
+    /// The data of a compressed element isn't a valid zlib stream.
+    Zlib(String),
+    /// A compressed element holds more than a single element.
+    TrailingData,
+    /// The type code of a Level 4 variable is invalid or uses a format that
+    /// isn't supported.
+    InvalidTypeCode(i32),
+    /// The HDF5 structures of a v7.3 file are invalid or use a part of the
+    /// format that isn't supported.
+    InvalidHdf5(&'static str),
+    /// A problem that is reported as a [`Warning`] by default, but which the
+    /// options reject, see [`ParseOptions::strict`].
+    NotAllowed(WarningKind),
 }
 
-#[derive(Clone, Debug)]
-pub enum CharacterData {
-    Unicode(String),
-    NonUnicode(Vec<u16>),
+/// Why and where the data elements of a file couldn't be read.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    // Boxed to keep the results of the parsers small
+    inner: Box<ParseErrorInner>,
 }
 
-#[derive(Clone, Debug)]
-pub struct Structure {
-    pub header: ArrayHeader,
-    pub field_names: Vec<String>,
-    pub values: Vec<DataElement>,
+#[derive(Clone, Debug, PartialEq)]
+struct ParseErrorInner {
+    kind: ParseErrorKind,
+    /// The address of the data the error was detected at, which is turned
+    /// into offsets by the outermost parser
+    address: Option<usize>,
+    offset: Option<u64>,
+    decompressed_offset: Option<u64>,
+    variable_index: Option<usize>,
+    variable_name: Option<String>,
 }
 
-impl Structure {
-    pub fn new(header: ArrayHeader) -> Self {
-        Structure {
-            header,
-            field_names: Vec::new(),
-            values: Vec::new(),
+impl ParseError {
+    pub(crate) fn new(input: &[u8], kind: ParseErrorKind) -> Self {
+        ParseError {
+            inner: Box::new(ParseErrorInner {
+                kind,
+                address: Some(input.as_ptr() as usize),
+                offset: None,
+                decompressed_offset: None,
+                variable_index: None,
+                variable_name: None,
+            }),
         }
     }
 
-    pub fn header(&self) -> &ArrayHeader {
-        &self.header
+    /// What is wrong with the data.
+    pub fn kind(&self) -> &ParseErrorKind {
+        &self.inner.kind
     }
 
-    fn index(&self, name: &str) -> Option<usize> {
-        // unindexed search, let's assume that structures are small
-        self.field_names.iter().position(|v| v == name)
+    /// The offset in the file at which the error was detected. Errors inside
+    /// compressed elements report the offset of the compressed data, and the
+    /// offset into the decompressed data in
+    /// [`ParseError::decompressed_offset`].
+    pub fn offset(&self) -> Option<u64> {
+        self.inner.offset
     }
 
-    pub fn len(&self) -> usize {
-        self.field_names.len()
+    /// The offset into the decompressed data at which the error was
+    /// detected, for errors inside compressed elements.
+    pub fn decompressed_offset(&self) -> Option<u64> {
+        self.inner.decompressed_offset
     }
 
-    pub fn field_names(&self) -> impl Iterator<Item = &str> {
-        self.field_names.iter().map(|v| &**v)
+    /// An error detected at `offset` in the file, in the data element with
+    /// the given index.
+    pub(crate) fn at(kind: ParseErrorKind, offset: u64, variable_index: usize) -> Self {
+        ParseError {
+            inner: Box::new(ParseErrorInner {
+                kind,
+                address: None,
+                offset: Some(offset),
+                decompressed_offset: None,
+                variable_index: Some(variable_index),
+                variable_name: None,
+            }),
+        }
     }
 
-    pub fn values(&self) -> impl Iterator<Item = &DataElement> {
-        self.values.iter()
+    /// The zero-based index of the data element the error occurred in.
+    pub fn variable_index(&self) -> Option<usize> {
+        self.inner.variable_index
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&str, &DataElement)> {
-        self.field_names().zip(self.values())
+    /// The name of the variable the error occurred in, if it could be read
+    /// before the error.
+    pub fn variable_name(&self) -> Option<&str> {
+        self.inner.variable_name.as_deref()
     }
 
-    pub fn get(&self, name: &str) -> Option<&DataElement> {
-        let idx = self.index(name)?;
-        Some(&self.values[idx])
+    /// Offset of the address this error was detected at, relative to `data`.
+    fn offset_in(&self, data: &[u8]) -> Option<u64> {
+        let offset = self.inner.address?.checked_sub(data.as_ptr() as usize)?;
+        (offset <= data.len()).then_some(offset as u64)
     }
 
-    pub fn get_mut(&mut self, name: &str) -> Option<&mut DataElement> {
-        let idx = self.index(name)?;
-        Some(&mut self.values[idx])
+    /// Turns the address into an offset into decompressed `data`, and
+    /// continues as if the error was detected at `compressed`.
+    fn decompressed(mut self, data: &[u8], compressed: &[u8]) -> Self {
+        if self.inner.decompressed_offset.is_none() {
+            self.inner.decompressed_offset = self.offset_in(data);
+        }
+        self.inner.address = Some(compressed.as_ptr() as usize);
+        self
     }
 
-    pub fn insert(&mut self, name: &str, v: DataElement) -> Option<DataElement> {
-        match self.index(name) {
-            Some(idx) => Some(std::mem::replace(&mut self.values[idx], v)),
-            None => {
-                self.field_names.push(name.to_string());
-                self.values.push(v);
-                None
-            }
-        }
+    /// Records the variable an error occurred in, unless an enclosing
+    /// variable has been recorded already.
+    pub(crate) fn in_variable(mut self, name: &str) -> Self {
+        self.inner
+            .variable_name
+            .get_or_insert_with(|| name.to_owned());
+        self
     }
 
-    pub fn remove(&mut self, name: &str) -> Option<DataElement> {
-        let idx = self.index(name)?;
-        self.field_names.remove(idx);
-        Some(self.values.remove(idx))
+    /// Turns the address into an offset into the whole `input`, which the
+    /// data element with the given index is part of.
+    pub(crate) fn locate(mut self, input: &[u8], variable_index: usize) -> Self {
+        self.inner.offset = self.offset_in(input);
+        self.inner.address = None;
+        self.inner.variable_index = Some(variable_index);
+        self
     }
-}
 
-// #[cfg(feature = "ndarray")]
-// {
-//     #[derive(Debug)]
-//     enum NumericArrayData {
-//         Double(ndarray::ArrayD<f64>),
-//     }
+    /// The error for a warning the options reject, when there is no input
+    /// left to point to.
+    pub(crate) fn from_warning(warning: Warning) -> Self {
+        ParseError {
+            inner: Box::new(ParseErrorInner {
+                kind: ParseErrorKind::NotAllowed(warning.kind),
+                address: None,
+                offset: None,
+                decompressed_offset: None,
+                variable_index: warning.variable_index,
+                variable_name: warning.variable_name,
+            }),
+        }
+    }
+}
 
-//     impl From<NumericData> for NumericArrayData {
-//         fn from(nd: NumericData) -> Self;
-//     }
-// }
+/// The error for data that doesn't make sense at the given position.
+pub(crate) fn failure(input: &[u8], kind: ParseErrorKind) -> nom::Err<ParseError> {
+    nom::Err::Failure(ParseError::new(input, kind))
+}
 
-pub fn parse_header(i: &[u8]) -> IResult<&[u8], Header> {
-    // Make sure that the first four bytes are not null
-    let (i, _) = peek(count(pair(not(char('\0')), take(1usize)), 4))(i)?;
-    // Header text field
-    let (i, text) = take(116usize)(i)?;
-    // Header subsystem data offset field
-    let (i, _ssdo) = take(8usize)(i)?;
-    // Header flag fields
-    // Assume little endian for now
-    let (i, mut version) = u16(nom::number::Endianness::Little)(i)?;
-    // Check the endianness
-    let (i, is_little_endian) = alt((value(true, tag("IM")), value(false, tag("MI"))))(i)?;
-    // Fix endianness of the version field if we assumed the wrong one
-    if !is_little_endian {
-        version = version.swap_bytes();
+// The nom parsers used here only fail when they run out of data
+impl<'a> nom::error::ParseError<&'a [u8]> for ParseError {
+    fn from_error_kind(input: &'a [u8], _kind: nom::error::ErrorKind) -> Self {
+        ParseError::new(input, ParseErrorKind::UnexpectedEof)
     }
-    if version != 0x0100 {
-        return Err(nom::Err::Failure(error_position!(
-            i,
-            // TODO
-            nom::error::ErrorKind::Tag
-        )));
+
+    fn append(_input: &'a [u8], _kind: nom::error::ErrorKind, other: Self) -> Self {
+        other
     }
-    Ok((
-        i,
-        Header {
-            text: std::str::from_utf8(text).unwrap_or(&"").to_owned(),
-            is_little_endian: is_little_endian,
-        },
-    ))
 }
 
-fn constant<T: Clone>(v: T) -> impl Fn(&[u8]) -> IResult<&[u8], T> {
-    move |i: &[u8]| Ok((i, v.clone()))
+impl From<nom::Err<ParseError>> for ParseError {
+    fn from(err: nom::Err<ParseError>) -> Self {
+        match err {
+            nom::Err::Incomplete(_) => ParseError::new(&[], ParseErrorKind::UnexpectedEof),
+            nom::Err::Error(err) | nom::Err::Failure(err) => err,
+        }
+    }
 }
 
-fn parse_next_data_element(
-    endianness: nom::number::Endianness,
-    name: Option<&str>,
-) -> impl Fn(&[u8]) -> IResult<&[u8], DataElement> + '_ {
-    move |i: &[u8]| {
-        let (i, data_element_tag) = parse_data_element_tag(endianness)(i)?;
-        let next_parser: Box<dyn Fn(_) -> _> = match data_element_tag.data_type {
-            DataType::Matrix => Box::new(parse_matrix_data_element(endianness, name)),
-            DataType::Compressed => {
-                if name.is_some() {
-                    // only supplied for struct fields, and they are always Matrix
-                    unreachable!();
-                }
-
-                Box::new(parse_compressed_data_element(endianness))
+impl std::fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseErrorKind::UnexpectedEof => write!(f, "unexpected end of data"),
+            ParseErrorKind::UnknownDataType(data_type) => {
+                write!(f, "unknown data type {}", data_type)
             }
-            _ => {
-                println!(
-                    "Unsupported variable type: {:?} (must be Matrix or Compressed)",
-                    data_element_tag.data_type
-                );
-                Box::new(parse_unsupported_data_element(endianness))
+            ParseErrorKind::InvalidTag => write!(f, "invalid data element tag"),
+            ParseErrorKind::UnknownClass(class) => write!(f, "unknown array class {}", class),
+            ParseErrorKind::UnexpectedDataType {
+                subelement,
+                data_type,
+            } => write!(
+                f,
+                "{} subelement has unexpected type {:?}",
+                subelement, data_type
+            ),
+            ParseErrorKind::SizeMismatch { subelement } => {
+                write!(f, "{} subelement has the wrong size", subelement)
             }
-        };
-        let (i, data_element) =
-            length_value(constant(data_element_tag.data_byte_size), next_parser)(i)?;
-        // Take care of padding. It seems like either all variables in a mat file are compressed or none are.
-        // If the variables are compressed there is no alignment to take care of (only uncompressed data
-        // needs to be aligned according to the spec). Otherwise make sure that we end up on an 8 byte
-        // boundary (ignore if there is not enough data left)
-        let num_padding_bytes = if data_element_tag.data_type == DataType::Compressed {
-            0
-        } else {
-            data_element_tag.padding_byte_size
-        };
-        let (i, _) = opt(complete(take(num_padding_bytes)))(i)?;
-        Ok((i, data_element))
+            ParseErrorKind::InvalidText { subelement } => {
+                write!(f, "{} subelement is not valid text", subelement)
+            }
+            ParseErrorKind::NegativeDimension => write!(f, "negative dimension"),
+            ParseErrorKind::TooManyElements => write!(f, "too many elements"),
+            ParseErrorKind::LossyConversion { subelement } => write!(
+                f,
+                "{} subelement holds values that don't fit the array class",
+                subelement
+            ),
+            ParseErrorKind::MismatchedParts => {
+                write!(f, "real and imaginary part have different types")
+            }
+            ParseErrorKind::InvalidFieldNameLength => write!(f, "invalid field name length"),
+            ParseErrorKind::UnexpectedName(name) => {
+                write!(f, "unexpected name \"{}\" in a cell or field", name)
+            }
+            ParseErrorKind::UnexpectedCompression => {
+                write!(f, "compressed element in a cell or field")
+            }
+            ParseErrorKind::NestingTooDeep => write!(f, "maximum nesting depth exceeded"),
+            ParseErrorKind::DecompressedSizeLimit => {
+                write!(f, "compressed element exceeds the decompressed size limit")
+            }
+            ParseErrorKind::Zlib(message) => write!(f, "invalid compressed data: {}", message),
+            ParseErrorKind::TrailingData => {
+                write!(f, "unexpected data following a compressed element")
+            }
+            ParseErrorKind::InvalidTypeCode(type_code) => {
+                write!(f, "invalid Level 4 type code {}", type_code)
+            }
+            ParseErrorKind::InvalidHdf5(reason) => write!(f, "invalid HDF5 data: {}", reason),
+            ParseErrorKind::NotAllowed(warning) => warning.fmt(f),
+        }
     }
 }
 
-fn ceil_to_multiple(x: u32, multiple: u32) -> u32 {
-    if x > 0 {
-        (((x - 1) / multiple) + 1) * multiple
-    } else {
-        0
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (&self.inner.variable_name, self.inner.variable_index) {
+            (Some(name), Some(index)) => {
+                write!(f, "error parsing variable '{}' (element {})", name, index)?
+            }
+            (Some(name), None) => write!(f, "error parsing variable '{}'", name)?,
+            (None, Some(index)) => write!(f, "error parsing element {}", index)?,
+            (None, None) => return self.inner.kind.fmt(f),
+        }
+        if let Some(offset) = self.inner.offset {
+            write!(f, " at offset {:#x}", offset)?;
+        }
+        if let Some(offset) = self.inner.decompressed_offset {
+            write!(f, " (decompressed offset {:#x})", offset)?;
+        }
+        write!(f, ": {}", self.inner.kind)
     }
 }
 
-#[derive(Clone, Copy, Debug)]
-pub struct ArrayFlags {
-    pub complex: bool,
-    pub global: bool,
-    pub logical: bool,
-    pub class: ArrayType,
-    pub nzmax: usize,
-}
+impl std::error::Error for ParseError {}
 
-#[derive(Debug, PartialEq, Clone, Copy, Primitive)]
-pub enum DataType {
-    Int8 = 1,
-    UInt8 = 2,
-    Int16 = 3,
-    UInt16 = 4,
-    Int32 = 5,
-    UInt32 = 6,
-    Single = 7,
-    Double = 9,
-    Int64 = 12,
-    UInt64 = 13,
-    Matrix = 14,
-    Compressed = 15,
-    Utf8 = 16,
-    Utf16 = 17,
-    Utf32 = 18,
+/// Something about a file that doesn't keep it from being parsed, but means
+/// that its contents aren't read exactly as stored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+    /// The index of the data element the warning is about, if any
+    pub variable_index: Option<usize>,
+    /// The name of the variable the warning is about, if known
+    pub variable_name: Option<String>,
+    pub kind: WarningKind,
 }
 
-// impl DataType {
-//     fn byte_size(&self) -> Option<usize> {
-//         match self {
-//             DataType::Int8 | DataType::UInt8 | DataType::Utf8 => Some(1),
-//             DataType::Int16 | DataType::UInt16 | DataType::Utf16 => Some(2),
-//             DataType::Int32 | DataType::UInt32 | DataType::Single | DataType::Utf32 => Some(4),
-//             DataType::Int64 | DataType::UInt64 | DataType::Double => Some(8),
-//             _ => None,
-//         }
-//     }
-// }
-
-#[derive(Debug, PartialEq, Clone, Copy, Primitive)]
-pub enum ArrayType {
-    Cell = 1,
-    Struct = 2,
-    Object = 3,
-    Char = 4,
-    Sparse = 5,
-    Double = 6,
-    Single = 7,
-    Int8 = 8,
-    UInt8 = 9,
-    Int16 = 10,
-    UInt16 = 11,
-    Int32 = 12,
-    UInt32 = 13,
-    Int64 = 14,
-    UInt64 = 15,
+/// What a [`Warning`] is about.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WarningKind {
+    /// An array of a class that isn't supported was kept as
+    /// [`DataElement::Unsupported`].
+    UnsupportedClass(u8),
+    /// A data element that isn't an array was kept as
+    /// [`DataElement::Unsupported`].
+    UnsupportedDataType(DataType),
+    /// A variable was left out of a [`crate::MatFile`] because it can't be
+    /// represented as an [`crate::Array`].
+    SkippedVariable,
+    /// Numeric data stored in a type that Matlab wouldn't choose for its
+    /// array class was converted, which is only allowed without
+    /// [`ParseOptions::strict_numeric_types`].
+    NumericTypeCoerced {
+        class: ArrayType,
+        data_type: DataType,
+    },
+    /// Data following the last element was ignored because of
+    /// [`ParseOptions::allow_trailing_bytes`].
+    TrailingBytes(usize),
+    /// A name isn't valid UTF-8 and had invalid bytes replaced, which is
+    /// only done without [`ParseOptions::validate_utf8_names`].
+    InvalidName { subelement: &'static str },
+    /// A variable couldn't be parsed and was kept as
+    /// [`DataElement::Corrupt`] because of [`ParseOptions::skip_corrupt`].
+    CorruptElement,
 }
 
-impl ArrayType {
-    // fn is_numeric(&self) -> bool {
-    //     match self {
-    //         ArrayType::Cell
-    //         | ArrayType::Struct
-    //         | ArrayType::Object
-    //         | ArrayType::Char
-    //         | ArrayType::Sparse => false,
-    //         _ => true,
-    //     }
-    // }
+impl WarningKind {
+    /// Whether this is about data the crate can't represent, as opposed to
+    /// data that is malformed.
+    fn is_unsupported(&self) -> bool {
+        matches!(
+            self,
+            WarningKind::UnsupportedClass(_)
+                | WarningKind::UnsupportedDataType(_)
+                | WarningKind::SkippedVariable
+        )
+    }
+}
 
-    fn numeric_data_type(&self) -> Option<DataType> {
+impl std::fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            ArrayType::Double => Some(DataType::Double),
-            ArrayType::Single => Some(DataType::Single),
-            ArrayType::Int8 => Some(DataType::Int8),
-            ArrayType::UInt8 => Some(DataType::UInt8),
-            ArrayType::Int16 => Some(DataType::Int16),
-            ArrayType::UInt16 => Some(DataType::UInt16),
-            ArrayType::Int32 => Some(DataType::UInt32),
-            ArrayType::UInt32 => Some(DataType::UInt32),
-            ArrayType::Int64 => Some(DataType::Int64),
-            ArrayType::UInt64 => Some(DataType::UInt64),
-            _ => None,
+            WarningKind::UnsupportedClass(class) => match ArrayType::from_u8(*class) {
+                Some(class) => write!(f, "unsupported array class {}", class.matlab_name()),
+                None => write!(f, "unknown array class {}", class),
+            },
+            WarningKind::UnsupportedDataType(data_type) => {
+                write!(f, "unsupported data element of type {:?}", data_type)
+            }
+            WarningKind::SkippedVariable => write!(f, "variable skipped"),
+            WarningKind::NumericTypeCoerced { class, data_type } => write!(
+                f,
+                "{:?} data converted to {}",
+                data_type,
+                class.matlab_name()
+            ),
+            WarningKind::TrailingBytes(len) => write!(f, "{} trailing bytes ignored", len),
+            WarningKind::InvalidName { subelement } => {
+                write!(f, "invalid UTF-8 in {} replaced", subelement)
+            }
+            WarningKind::CorruptElement => write!(f, "corrupt data element skipped"),
         }
     }
 }
 
-pub type Dimensions = Vec<i32>;
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (&self.variable_name, self.variable_index) {
+            (Some(name), Some(index)) => write!(f, "variable '{}' (element {}): ", name, index)?,
+            (Some(name), None) => write!(f, "variable '{}': ", name)?,
+            (None, Some(index)) => write!(f, "element {}: ", index)?,
+            (None, None) => {}
+        }
+        self.kind.fmt(f)
+    }
+}
 
-#[derive(Clone, Copy, Debug)]
-pub struct DataElementTag {
-    data_type: DataType,
-    data_byte_size: u32,
+/// The byte order a file was written in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ByteOrder {
+    #[default]
+    LittleEndian,
+    BigEndian,
+}
+
+impl Header {
+    /// The descriptive text at the start of the file, without the trailing
+    /// padding. Bytes that aren't valid UTF-8 are replaced with U+FFFD.
+    pub fn text(&self) -> &str {
+        self.text.trim_end_matches([' ', '\0'])
+    }
+
+    /// The descriptive text field as stored in the file, including the
+    /// padding, for decoding text that isn't UTF-8.
+    pub fn raw_text(&self) -> &[u8; 116] {
+        &self.raw_text
+    }
+
+    /// The platform the file was written on, e.g. "GLNXA64".
+    ///
+    /// This is read from the "Platform: " field Matlab writes into the
+    /// header text. Returns `None` if the text doesn't contain the field.
+    pub fn platform(&self) -> Option<&str> {
+        self.text_field("Platform:")
+    }
+
+    /// The time the file was written, as formatted in the "Created on: "
+    /// field of the header text. Returns `None` if the text doesn't contain
+    /// the field.
+    pub fn created(&self) -> Option<&str> {
+        self.text_field("Created on:")
+    }
+
+    /// The offset of the subsystem data from the start of the file, or
+    /// `None` if the file doesn't have any. Matlab stores the contents of
+    /// objects such as strings there.
+    pub fn subsystem_offset(&self) -> Option<u64> {
+        self.subsystem_offset
+    }
+
+    pub fn byte_order(&self) -> ByteOrder {
+        if self.is_little_endian {
+            ByteOrder::LittleEndian
+        } else {
+            ByteOrder::BigEndian
+        }
+    }
+
+    // Fields in the header text look like "Key: value" and are separated by
+    // commas. Other writers don't necessarily follow this layout, so this is
+    // best-effort only.
+    fn text_field(&self, key: &str) -> Option<&str> {
+        let text = self.text();
+        let start = text.find(key)? + key.len();
+        let value = text[start..].split(',').next()?.trim();
+        if value.is_empty() {
+            None
+        } else {
+            Some(value)
+        }
+    }
+}
+
+pub use crate::numeric::NumericData;
+pub use borrowed::{
+    parse_all_borrowed, parse_all_borrowed_with_options, DataElementRef, NumericRef, ParseResultRef,
+};
+
+/// Options that control how strictly files are parsed.
+///
+/// The fields can be set directly or through the builder methods of the same
+/// name:
+///
+/// ```
+/// # use matfile::ParseOptions;
+/// let options = ParseOptions::new().strict(true).max_depth(16);
+/// assert!(options.strict);
+/// ```
+#[derive(Clone, Debug)]
+pub struct ParseOptions {
+    /// Fail on every problem that is otherwise reported as a [`Warning`]
+    /// and worked around, with [`ParseErrorKind::NotAllowed`]. This rejects
+    /// unsupported and unknown array classes, numeric data stored in a type
+    /// Matlab wouldn't choose for its class, names that had invalid UTF-8
+    /// replaced, and variables [`crate::MatFile`] can't represent.
+    /// [`ParseOptions::allow_trailing_bytes`] and
+    /// [`ParseOptions::skip_corrupt`] have no effect in strict mode.
+    ///
+    /// The structural checks, e.g. of sizes and dimensions, and the limits
+    /// apply either way.
+    pub strict: bool,
+
+    /// Only accept numeric data stored in one of the types the automatic
+    /// compression rules of the MAT-file format allow for the array class.
+    ///
+    /// By default, any stored type is accepted as long as all values can be
+    /// converted into the array class without changing them, e.g. an int16
+    /// array stored as int32 as some third-party writers do.
+    pub strict_numeric_types: bool,
+
+    /// Stop at the first data element that can't be parsed and report the
+    /// remaining bytes in [`ParseResult::trailing_bytes`], instead of failing
+    /// with the error of that element.
+    pub allow_trailing_bytes: bool,
+
+    /// What [`crate::MatFile`] does with variables that have the same name
+    /// as an earlier one. Matlab doesn't write such files, but some other
+    /// tools do.
+    pub on_duplicate: DuplicateNames,
+
+    /// How deeply data elements may be nested in cells, structures and
+    /// compressed elements before parsing fails. Each variable counts as one
+    /// level. This keeps crafted files from overflowing the stack.
+    pub max_depth: usize,
+
+    /// The maximum number of bytes a compressed data element may decompress
+    /// to. Parsing fails with [`ParseErrorKind::DecompressedSizeLimit`] for
+    /// larger elements, so a small file can't exhaust the memory.
+    pub max_decompressed_size: u64,
+
+    /// What happens to arrays of unsupported or unknown classes and other
+    /// data elements that can't be represented.
+    pub on_unsupported: UnsupportedArrays,
+
+    /// Fail with [`ParseErrorKind::InvalidText`] if a variable or field name
+    /// isn't valid UTF-8. Otherwise invalid bytes are replaced with U+FFFD
+    /// and a [`WarningKind::InvalidName`] is reported.
+    pub validate_utf8_names: bool,
+
+    /// Keep variables that can't be parsed as [`DataElement::Corrupt`] and
+    /// continue with the next one, instead of failing. The size in the tag
+    /// of an element tells where the next one starts, so only a truncated
+    /// element or a corrupt tag ends the file early. This has no effect in
+    /// strict mode.
+    pub skip_corrupt: bool,
+
+    /// Only parse the variables whose names this filter matches. The others
+    /// are returned as [`DataElement::Skipped`], with just their header read
+    /// and, if they are compressed, only the start of them decompressed.
+    /// Struct fields and cell elements are never filtered.
+    pub filter: Option<VariableFilter>,
+
+    /// Read the imaginary parts of complex numeric and sparse arrays. If
+    /// this is `false`, they are skipped over without being converted and
+    /// the arrays have [`Numeric::imag_skipped`] or
+    /// [`Sparse::imag_skipped`] set instead.
+    pub load_imaginary: bool,
+}
+
+impl ParseOptions {
+    /// The default options, same as [`ParseOptions::default`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets [`ParseOptions::strict`].
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Sets [`ParseOptions::strict_numeric_types`].
+    pub fn strict_numeric_types(mut self, strict_numeric_types: bool) -> Self {
+        self.strict_numeric_types = strict_numeric_types;
+        self
+    }
+
+    /// Sets [`ParseOptions::allow_trailing_bytes`].
+    pub fn allow_trailing_bytes(mut self, allow_trailing_bytes: bool) -> Self {
+        self.allow_trailing_bytes = allow_trailing_bytes;
+        self
+    }
+
+    /// Sets [`ParseOptions::on_duplicate`].
+    pub fn on_duplicate(mut self, on_duplicate: DuplicateNames) -> Self {
+        self.on_duplicate = on_duplicate;
+        self
+    }
+
+    /// Sets [`ParseOptions::max_depth`].
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Sets [`ParseOptions::max_decompressed_size`].
+    pub fn max_decompressed_size(mut self, max_decompressed_size: u64) -> Self {
+        self.max_decompressed_size = max_decompressed_size;
+        self
+    }
+
+    /// Sets [`ParseOptions::on_unsupported`].
+    pub fn on_unsupported(mut self, on_unsupported: UnsupportedArrays) -> Self {
+        self.on_unsupported = on_unsupported;
+        self
+    }
+
+    /// Sets [`ParseOptions::validate_utf8_names`].
+    pub fn validate_utf8_names(mut self, validate_utf8_names: bool) -> Self {
+        self.validate_utf8_names = validate_utf8_names;
+        self
+    }
+
+    /// Sets [`ParseOptions::skip_corrupt`].
+    pub fn skip_corrupt(mut self, skip_corrupt: bool) -> Self {
+        self.skip_corrupt = skip_corrupt;
+        self
+    }
+
+    /// Sets [`ParseOptions::load_imaginary`].
+    pub fn load_imaginary(mut self, load_imaginary: bool) -> Self {
+        self.load_imaginary = load_imaginary;
+        self
+    }
+
+    /// Sets [`ParseOptions::filter`].
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use matfile::parse::{parse_all_with_options, DataElement};
+    /// use matfile::ParseOptions;
+    ///
+    /// let data = include_bytes!("../tests/two_arrays.mat");
+    /// let options = ParseOptions::new().filter(|name| name == "A");
+    /// let result = parse_all_with_options(data, &options)?;
+    /// assert!(matches!(result.data_elements[0], DataElement::NumericMatrix(_)));
+    /// assert_eq!(result.data_elements[0].name(), Some("A"));
+    /// assert!(matches!(result.data_elements[1], DataElement::Skipped(_)));
+    /// assert_eq!(result.data_elements[1].name(), Some("B"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn filter<F: Fn(&str) -> bool + Send + Sync + 'static>(mut self, filter: F) -> Self {
+        self.filter = Some(VariableFilter::new(filter));
+        self
+    }
+
+    /// Whether a problem is an error rather than a warning.
+    pub(crate) fn rejects(&self, warning: &WarningKind) -> bool {
+        self.strict || (self.on_unsupported == UnsupportedArrays::Error && warning.is_unsupported())
+    }
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            strict: false,
+            strict_numeric_types: false,
+            allow_trailing_bytes: false,
+            on_duplicate: DuplicateNames::default(),
+            max_depth: 64,
+            max_decompressed_size: 4 << 30,
+            on_unsupported: UnsupportedArrays::default(),
+            validate_utf8_names: true,
+            skip_corrupt: false,
+            filter: None,
+            load_imaginary: true,
+        }
+    }
+}
+
+/// Buffers that are reused from one variable to the next, and from one file
+/// to the next if the same context is passed to [`parse_all_with_context`].
+///
+/// Compressed variables are decompressed into a scratch buffer, so a file
+/// with many of them only allocates it once.
+#[derive(Debug)]
+pub struct ParseContext {
+    scratch: RefCell<Vec<u8>>,
+    max_retained_size: usize,
+}
+
+impl ParseContext {
+    /// A context without any buffers, which keeps up to 16 MiB between
+    /// variables.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how many bytes the scratch buffer may keep between variables.
+    /// It is freed after a variable that needed more, so that a single large
+    /// variable doesn't hold on to its memory.
+    pub fn max_retained_size(mut self, max_retained_size: usize) -> Self {
+        self.max_retained_size = max_retained_size;
+        self
+    }
+
+    /// Takes the scratch buffer, which has to be returned with
+    /// [`ParseContext::return_scratch`].
+    fn take_scratch(&self) -> Vec<u8> {
+        let mut scratch = self.scratch.take();
+        scratch.clear();
+        scratch
+    }
+
+    fn return_scratch(&self, scratch: Vec<u8>) {
+        if scratch.capacity() <= self.max_retained_size {
+            self.scratch.replace(scratch);
+        }
+    }
+}
+
+impl Default for ParseContext {
+    fn default() -> Self {
+        ParseContext {
+            scratch: RefCell::default(),
+            max_retained_size: 16 << 20,
+        }
+    }
+}
+
+/// How [`crate::MatFile`] handles several variables with the same name.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DuplicateNames {
+    /// Fail with [`crate::Error::DuplicateNames`].
+    #[default]
+    Error,
+    /// Keep all of them. [`crate::MatFile::get`] returns the first one and
+    /// [`crate::MatFile::get_all`] all of them.
+    KeepAll,
+}
+
+/// How arrays and other data elements that can't be represented are
+/// handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnsupportedArrays {
+    /// Keep them as [`DataElement::Unsupported`] in the parse result and
+    /// leave them out of [`crate::MatFile::arrays`], with a [`Warning`].
+    #[default]
+    Skip,
+    /// Fail with [`ParseErrorKind::NotAllowed`].
+    Error,
+}
+
+/// Decides by name which variables are parsed, see
+/// [`ParseOptions::filter`].
+#[derive(Clone)]
+pub struct VariableFilter(Arc<dyn Fn(&str) -> bool + Send + Sync>);
+
+impl VariableFilter {
+    /// Creates a filter from a function that returns `true` for the names of
+    /// the variables to parse.
+    pub fn new<F: Fn(&str) -> bool + Send + Sync + 'static>(filter: F) -> Self {
+        VariableFilter(Arc::new(filter))
+    }
+
+    /// Whether the variable with this name is parsed.
+    pub fn matches(&self, name: &str) -> bool {
+        (self.0)(name)
+    }
+}
+
+impl std::fmt::Debug for VariableFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("VariableFilter")
+    }
+}
+
+/// The contents of a single numeric subelement, i.e. the real or the
+/// imaginary part of an array, in the type it was stored in.
+#[derive(Clone, Debug, PartialEq)]
+enum NumericPart {
+    Int8(Vec<i8>),
+    UInt8(Vec<u8>),
+    Int16(Vec<i16>),
+    UInt16(Vec<u16>),
+    Int32(Vec<i32>),
+    UInt32(Vec<u32>),
+    Int64(Vec<i64>),
+    UInt64(Vec<u64>),
+    Single(Vec<f32>),
+    Double(Vec<f64>),
+}
+
+impl NumericPart {
+    /// Combines a real and an optional imaginary part into `NumericData`.
+    /// Returns `None` if the parts have different types.
+    fn into_data(self, imag: Option<NumericPart>) -> Option<NumericData> {
+        macro_rules! combine {
+            ( $( $variant:ident ),* ) => {
+                match (self, imag) {
+                    $(
+                        (NumericPart::$variant(real), None) => {
+                            Some(NumericData::$variant { real, imag: None })
+                        }
+                        (NumericPart::$variant(real), Some(NumericPart::$variant(imag))) => {
+                            Some(NumericData::$variant { real, imag: Some(imag) })
+                        }
+                    )*
+                    _ => None,
+                }
+            };
+        }
+        combine!(Int8, UInt8, Int16, UInt16, Int32, UInt32, Int64, UInt64, Single, Double)
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            NumericPart::Single(vec) => vec.len(),
+            NumericPart::Double(vec) => vec.len(),
+            NumericPart::Int8(vec) => vec.len(),
+            NumericPart::UInt8(vec) => vec.len(),
+            NumericPart::Int16(vec) => vec.len(),
+            NumericPart::UInt16(vec) => vec.len(),
+            NumericPart::Int32(vec) => vec.len(),
+            NumericPart::UInt32(vec) => vec.len(),
+            NumericPart::Int64(vec) => vec.len(),
+            NumericPart::UInt64(vec) => vec.len(),
+        }
+    }
+
+    fn empty(data_type: DataType) -> Option<NumericPart> {
+        match data_type {
+            DataType::Int8 => Some(NumericPart::Int8(Vec::new())),
+            DataType::UInt8 => Some(NumericPart::UInt8(Vec::new())),
+            DataType::Int16 => Some(NumericPart::Int16(Vec::new())),
+            DataType::UInt16 => Some(NumericPart::UInt16(Vec::new())),
+            DataType::Int32 => Some(NumericPart::Int32(Vec::new())),
+            DataType::UInt32 => Some(NumericPart::UInt32(Vec::new())),
+            DataType::Int64 => Some(NumericPart::Int64(Vec::new())),
+            DataType::UInt64 => Some(NumericPart::UInt64(Vec::new())),
+            DataType::Single => Some(NumericPart::Single(Vec::new())),
+            DataType::Double => Some(NumericPart::Double(Vec::new())),
+            _ => None,
+        }
+    }
+
+    fn truncate(&mut self, len: usize) {
+        match self {
+            NumericPart::Single(vec) => vec.truncate(len),
+            NumericPart::Double(vec) => vec.truncate(len),
+            NumericPart::Int8(vec) => vec.truncate(len),
+            NumericPart::UInt8(vec) => vec.truncate(len),
+            NumericPart::Int16(vec) => vec.truncate(len),
+            NumericPart::UInt16(vec) => vec.truncate(len),
+            NumericPart::Int32(vec) => vec.truncate(len),
+            NumericPart::UInt32(vec) => vec.truncate(len),
+            NumericPart::Int64(vec) => vec.truncate(len),
+            NumericPart::UInt64(vec) => vec.truncate(len),
+        }
+    }
+
+    fn data_type(&self) -> DataType {
+        match self {
+            NumericPart::Single(_) => DataType::Single,
+            NumericPart::Double(_) => DataType::Double,
+            NumericPart::Int8(_) => DataType::Int8,
+            NumericPart::UInt8(_) => DataType::UInt8,
+            NumericPart::Int16(_) => DataType::Int16,
+            NumericPart::UInt16(_) => DataType::UInt16,
+            NumericPart::Int32(_) => DataType::Int32,
+            NumericPart::UInt32(_) => DataType::UInt32,
+            NumericPart::Int64(_) => DataType::Int64,
+            NumericPart::UInt64(_) => DataType::UInt64,
+        }
+    }
+
+    /// Converts the data into the given (usually wider) type. Returns `None`
+    /// if the conversion would change any of the values, e.g. when a
+    /// negative number is converted into an unsigned type or a fractional
+    /// number into an integer type.
+    fn widen(self, data_type: DataType) -> Option<NumericPart> {
+        if self.data_type() == data_type {
+            return Some(self);
+        }
+        // Empty data can be converted into any type. Some writers store empty
+        // arrays as uint8 regardless of their class.
+        if self.len() == 0 {
+            return NumericPart::empty(data_type);
+        }
+        macro_rules! widen_integers {
+            ( $vec:expr ) => {
+                match data_type {
+                    DataType::Int8 => widen_integers!($vec, Int8, i8),
+                    DataType::UInt8 => widen_integers!($vec, UInt8, u8),
+                    DataType::Int16 => widen_integers!($vec, Int16, i16),
+                    DataType::UInt16 => widen_integers!($vec, UInt16, u16),
+                    DataType::Int32 => widen_integers!($vec, Int32, i32),
+                    DataType::UInt32 => widen_integers!($vec, UInt32, u32),
+                    DataType::Int64 => widen_integers!($vec, Int64, i64),
+                    DataType::UInt64 => widen_integers!($vec, UInt64, u64),
+                    DataType::Single => widen_integers!($vec, Single as f32),
+                    DataType::Double => widen_integers!($vec, Double as f64),
+                    _ => None,
+                }
+            };
+            ( $vec:expr, $variant:ident, $num:ty ) => {
+                $vec.into_iter()
+                    .map(|x| <$num>::try_from(x).ok())
+                    .collect::<Option<Vec<_>>>()
+                    .map(NumericPart::$variant)
+            };
+            ( $vec:expr, $variant:ident as $num:ty ) => {
+                $vec.into_iter()
+                    .map(|x| {
+                        let y = x as $num;
+                        (y as i128 == x as i128).then_some(y)
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .map(NumericPart::$variant)
+            };
+        }
+        macro_rules! widen_floats {
+            ( $vec:expr ) => {
+                match data_type {
+                    DataType::Int8 => widen_floats!($vec, Int8, i8),
+                    DataType::UInt8 => widen_floats!($vec, UInt8, u8),
+                    DataType::Int16 => widen_floats!($vec, Int16, i16),
+                    DataType::UInt16 => widen_floats!($vec, UInt16, u16),
+                    DataType::Int32 => widen_floats!($vec, Int32, i32),
+                    DataType::UInt32 => widen_floats!($vec, UInt32, u32),
+                    DataType::Int64 => widen_floats!($vec, Int64, i64),
+                    DataType::UInt64 => widen_floats!($vec, UInt64, u64),
+                    DataType::Single => $vec
+                        .into_iter()
+                        .map(|x| {
+                            let x = f64::from(x);
+                            let y = x as f32;
+                            (f64::from(y) == x || x.is_nan()).then_some(y)
+                        })
+                        .collect::<Option<Vec<_>>>()
+                        .map(NumericPart::Single),
+                    DataType::Double => Some(NumericPart::Double(
+                        $vec.into_iter().map(f64::from).collect(),
+                    )),
+                    _ => None,
+                }
+            };
+            ( $vec:expr, $variant:ident, $num:ty ) => {
+                $vec.into_iter()
+                    .map(|x| {
+                        let x = f64::from(x);
+                        let y = x as $num;
+                        // NaN and infinity have no integral part either
+                        (x.fract() == 0.0 && y as i128 == x as i128).then_some(y)
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .map(NumericPart::$variant)
+            };
+        }
+        match self {
+            NumericPart::Int8(vec) => widen_integers!(vec),
+            NumericPart::UInt8(vec) => widen_integers!(vec),
+            NumericPart::Int16(vec) => widen_integers!(vec),
+            NumericPart::UInt16(vec) => widen_integers!(vec),
+            NumericPart::Int32(vec) => widen_integers!(vec),
+            NumericPart::UInt32(vec) => widen_integers!(vec),
+            NumericPart::Int64(vec) => widen_integers!(vec),
+            NumericPart::UInt64(vec) => widen_integers!(vec),
+            NumericPart::Single(vec) => widen_floats!(vec),
+            NumericPart::Double(vec) => widen_floats!(vec),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DataElement {
+    NumericMatrix(Numeric),
+    SparseMatrix(Sparse),
+    CharacterMatrix(Character),
+    CellMatrix(Cell),
+    StructureMatrix(Structure),
+    /// An object whose data is stored in the subsystem data of the file.
+    Opaque(Opaque),
+    /// A Matlab `string` array, decoded from the subsystem data.
+    StringArray(StringArray),
+    /// The subsystem data, i.e. the uint8 array stored at the offset given
+    /// in the file header. It holds the contents of opaque objects and isn't
+    /// a variable.
+    SubsystemData(Vec<u8>),
+    // Object Matrix,
+    /// A data element that can't be read yet, such as an object array.
+    Unsupported(UnsupportedArray),
+    /// A variable that couldn't be parsed and was skipped because of
+    /// [`ParseOptions::skip_corrupt`].
+    Corrupt(CorruptElement),
+    /// A variable that wasn't parsed because [`ParseOptions::filter`]
+    /// rejected its name. Only its header was read.
+    Skipped(ArrayHeader),
+}
+
+/// A data element that the parser doesn't understand, kept as raw bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnsupportedArray {
+    /// The array header, if the element is an array of an unsupported class.
+    pub header: Option<ArrayHeader>,
+    /// The data type of the element, as given by its tag.
+    pub data_type: DataType,
+    /// The bytes of the element following its tag, decompressed if the
+    /// element was compressed.
+    pub data: Vec<u8>,
+    /// The class id of an array whose class the parser doesn't know. Such
+    /// arrays have no header.
+    pub class_id: Option<u8>,
+    /// The name of an array whose class the parser doesn't know, if it could
+    /// be read.
+    pub name: Option<String>,
+    /// The byte order of the file the element was read from, which any
+    /// numbers in `data` are stored in.
+    pub byte_order: ByteOrder,
+}
+
+/// A variable that couldn't be parsed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CorruptElement {
+    /// The name of the variable, if its header could be read.
+    pub name: Option<String>,
+    /// The offset of the tag of the element from the start of the file.
+    pub offset: u64,
+    /// Why the element couldn't be parsed.
+    pub error: ParseError,
+}
+
+impl DataElement {
+    /// The array header of this element. Unsupported elements only have one
+    /// if they are arrays.
+    pub fn header(&self) -> Option<&ArrayHeader> {
+        match self {
+            DataElement::NumericMatrix(numeric) => Some(&numeric.header),
+            DataElement::SparseMatrix(sparse) => Some(&sparse.header),
+            DataElement::CharacterMatrix(character) => Some(&character.header),
+            DataElement::CellMatrix(cell) => Some(&cell.header),
+            DataElement::StructureMatrix(structure) => Some(&structure.header),
+            DataElement::Opaque(opaque) => Some(&opaque.header),
+            DataElement::StringArray(strings) => Some(&strings.header),
+            DataElement::Unsupported(unsupported) => unsupported.header.as_ref(),
+            DataElement::Skipped(header) => Some(header),
+            DataElement::SubsystemData(_) | DataElement::Corrupt(_) => None,
+        }
+    }
+
+    /// The name of this element.
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            DataElement::Unsupported(UnsupportedArray {
+                header: None,
+                name: Some(name),
+                ..
+            }) => Some(name),
+            DataElement::Corrupt(corrupt) => corrupt.name.as_deref(),
+            _ => self.header().map(|header| header.name.as_str()),
+        }
+    }
+
+    /// The dimensions of this element.
+    pub fn dimensions(&self) -> Option<&Dimensions> {
+        self.header().map(|header| &header.dimensions)
+    }
+
+    /// What kind of array this element is.
+    pub fn kind(&self) -> crate::ArrayKind {
+        match self {
+            DataElement::NumericMatrix(_) => crate::ArrayKind::Numeric,
+            DataElement::SparseMatrix(_) => crate::ArrayKind::Sparse,
+            DataElement::CharacterMatrix(_) => crate::ArrayKind::Character,
+            DataElement::CellMatrix(_) => crate::ArrayKind::Cell,
+            DataElement::StructureMatrix(_) => crate::ArrayKind::Structure,
+            DataElement::Unsupported(UnsupportedArray {
+                header: Some(header),
+                ..
+            }) if header.flags.class == ArrayType::Cell => crate::ArrayKind::Cell,
+            // What it would have been if it had been parsed
+            DataElement::Skipped(header) => match header.flags.class {
+                ArrayType::Cell => crate::ArrayKind::Cell,
+                ArrayType::Struct => crate::ArrayKind::Structure,
+                ArrayType::Char => crate::ArrayKind::Character,
+                ArrayType::Sparse => crate::ArrayKind::Sparse,
+                class if class.numeric_data_type().is_some() => crate::ArrayKind::Numeric,
+                _ => crate::ArrayKind::Unsupported,
+            },
+            DataElement::Opaque(_)
+            | DataElement::StringArray(_)
+            | DataElement::SubsystemData(_)
+            | DataElement::Unsupported(_)
+            | DataElement::Corrupt(_) => crate::ArrayKind::Unsupported,
+        }
+    }
+
+    /// The array flags of this element.
+    pub fn flags(&self) -> Option<&ArrayFlags> {
+        self.header().map(|header| &header.flags)
+    }
+
+    /// The array class of this element.
+    pub fn class(&self) -> Option<ArrayType> {
+        self.header().map(|header| header.flags.class)
+    }
+
+    /// Approximate number of bytes the data of this element occupies in
+    /// memory, computed the same way Matlab's `whos` command does.
+    pub fn byte_size(&self) -> usize {
+        match self {
+            // The data has already been converted into the type of the array
+            // class, so its size is the same as in Matlab
+            DataElement::NumericMatrix(numeric) => numeric.data.byte_size(),
+            DataElement::SparseMatrix(sparse) => {
+                (sparse.row_index.len() + sparse.column_index.len()) * 8 + sparse.data.byte_size()
+            }
+            // Matlab stores characters as UTF-16 code units
+            DataElement::CharacterMatrix(character) => {
+                let units = character.real_part.utf16_len()
+                    + character
+                        .imag_part
+                        .as_ref()
+                        .map_or(0, CharacterData::utf16_len);
+                units * 2
+            }
+            DataElement::CellMatrix(cell) => cell.iter().map(DataElement::byte_size).sum(),
+            DataElement::StructureMatrix(structure) => {
+                structure.values().map(DataElement::byte_size).sum()
+            }
+            // Matlab reports the size of the object, not of its characters
+            DataElement::Opaque(_)
+            | DataElement::StringArray(_)
+            | DataElement::SubsystemData(_)
+            | DataElement::Unsupported(_)
+            | DataElement::Corrupt(_)
+            | DataElement::Skipped(_) => 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArrayHeader {
+    pub flags: ArrayFlags,
+    pub dimensions: Dimensions,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Numeric {
+    pub header: ArrayHeader,
+    pub data: NumericData,
+    /// Whether the array has an imaginary part that wasn't loaded because
+    /// of [`ParseOptions::load_imaginary`].
+    pub imag_skipped: bool,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Sparse {
+    pub header: ArrayHeader,
+    pub row_index: RowIndex,
+    pub column_index: ColumnShift,
+    pub data: NumericData,
+    /// Whether the array has an imaginary part that wasn't loaded because
+    /// of [`ParseOptions::load_imaginary`].
+    pub imag_skipped: bool,
+}
+
+impl Sparse {
+    /// The number of non-zero values, given by the last column index. May
+    /// be less than the number of values stored.
+    pub fn nnz(&self) -> usize {
+        self.column_index.last().copied().unwrap_or(0)
+    }
+}
+
+impl std::fmt::Debug for Sparse {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Sparse")
+            .field("header", &self.header)
+            .field("row_index", &crate::DebugSlice(&self.row_index))
+            .field("column_index", &crate::DebugSlice(&self.column_index))
+            .field("data", &self.data)
+            .field("imag_skipped", &self.imag_skipped)
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Character {
+    pub header: ArrayHeader,
+    pub real_part: CharacterData,
+    pub imag_part: Option<CharacterData>,
+}
+
+#[derive(Clone, PartialEq)]
+pub enum CharacterData {
+    Unicode(String),
+    /// UTF-16 code units that aren't valid UTF-16, i.e. contain unpaired
+    /// surrogates.
+    ///
+    /// Data stored as uint16, which is what Matlab writes by default, used to
+    /// be returned as this variant in any case. It is now decoded into
+    /// [`CharacterData::Unicode`] whenever possible.
+    NonUnicode(Vec<u16>),
+}
+
+impl CharacterData {
+    /// Decodes UTF-16 code units, keeping them as they are if they aren't
+    /// valid UTF-16.
+    pub fn from_utf16(units: Vec<u16>) -> Self {
+        match String::from_utf16(&units) {
+            Ok(str) => CharacterData::Unicode(str),
+            Err(_) => CharacterData::NonUnicode(units),
+        }
+    }
+
+    /// The number of UTF-16 code units, which is what Matlab counts in the
+    /// dimensions of a character array.
+    pub fn utf16_len(&self) -> usize {
+        match self {
+            CharacterData::Unicode(str) => str.encode_utf16().count(),
+            CharacterData::NonUnicode(vec) => vec.len(),
+        }
+    }
+
+    /// The number of characters. Unpaired surrogates count as one character
+    /// each.
+    pub fn char_count(&self) -> usize {
+        match self {
+            CharacterData::Unicode(str) => str.chars().count(),
+            CharacterData::NonUnicode(vec) => char::decode_utf16(vec.iter().copied()).count(),
+        }
+    }
+
+    /// The characters, replacing invalid UTF-16 with `U+FFFD REPLACEMENT
+    /// CHARACTER`.
+    pub fn to_string_lossy(&self) -> std::borrow::Cow<'_, str> {
+        match self {
+            CharacterData::Unicode(str) => str.as_str().into(),
+            CharacterData::NonUnicode(vec) => String::from_utf16_lossy(vec).into(),
+        }
+    }
+}
+
+impl std::fmt::Debug for CharacterData {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CharacterData::Unicode(str) => f
+                .debug_tuple("Unicode")
+                .field(&crate::DebugStr(str))
+                .finish(),
+            CharacterData::NonUnicode(vec) => f
+                .debug_tuple("NonUnicode")
+                .field(&crate::DebugSlice(vec))
+                .finish(),
+        }
+    }
+}
+
+/// A cell array. Each cell holds an unnamed data element.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cell {
+    pub header: ArrayHeader,
+    /// The cells in column-major order
+    pub values: Vec<DataElement>,
+}
+
+impl Cell {
+    pub fn header(&self) -> &ArrayHeader {
+        &self.header
+    }
+
+    /// The number of cells.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// The cell at the given row and column. For arrays with more than two
+    /// dimensions, the trailing dimensions are counted as columns.
+    pub fn get(&self, row: usize, column: usize) -> Option<&DataElement> {
+        let rows = self.header.dimensions.rows();
+        if row >= rows {
+            return None;
+        }
+        self.values.get(column.checked_mul(rows)?.checked_add(row)?)
+    }
+
+    /// Iterates over the cells in column-major order.
+    pub fn iter(&self) -> impl Iterator<Item = &DataElement> {
+        self.values.iter()
+    }
+}
+
+/// An object of a class like `string`, `datetime` or `table`, whose data is
+/// stored in the subsystem data of the file. Only the class name and the
+/// raw payload are read.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Opaque {
+    /// The array header. The dimensions are read from the object metadata
+    /// if possible and are 1x1 otherwise.
+    pub header: ArrayHeader,
+    /// The type system of the class, usually "MCOS"
+    pub type_system: String,
+    /// The name of the class, e.g. "string"
+    pub class_name: String,
+    /// The element following the class name, including its tag. For MCOS
+    /// objects this is a uint32 array referencing the subsystem data.
+    pub data: Vec<u8>,
+    /// The byte order of the file the object was read from, which `data` is
+    /// stored in.
+    pub byte_order: ByteOrder,
+}
+
+impl Opaque {
+    pub fn header(&self) -> &ArrayHeader {
+        &self.header
+    }
+}
+
+/// A Matlab `string` array with a single row or column.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StringArray {
+    /// The array header, with the dimensions of the string array
+    pub header: ArrayHeader,
+    pub values: Vec<String>,
+}
+
+impl StringArray {
+    pub fn header(&self) -> &ArrayHeader {
+        &self.header
+    }
+}
+
+/// A structure or structure array. Methods accessing a single field work
+/// with the first element of structure arrays.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Structure {
+    pub header: ArrayHeader,
+    pub field_names: Vec<String>,
+    /// The values of all fields of the first element, followed by those of
+    /// the second element etc.
+    pub values: Vec<DataElement>,
+}
+
+impl Structure {
+    pub fn new(header: ArrayHeader) -> Self {
+        Structure {
+            header,
+            field_names: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    pub fn header(&self) -> &ArrayHeader {
+        &self.header
+    }
+
+    fn index(&self, name: &str) -> Option<usize> {
+        // unindexed search, let's assume that structures are small
+        self.field_names.iter().position(|v| v == name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.field_names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.field_names.is_empty()
+    }
+
+    pub fn field_names(&self) -> impl Iterator<Item = &str> {
+        self.field_names.iter().map(|v| &**v)
+    }
+
+    /// The number of elements of this structure array.
+    pub fn numel(&self) -> usize {
+        self.header.dimensions.numel().unwrap_or(0)
+    }
+
+    /// The values of the fields of the element at the given linear index.
+    pub fn element(&self, idx: usize) -> Option<&[DataElement]> {
+        let len = self.len();
+        let start = idx.checked_mul(len)?;
+        self.values.get(start..start.checked_add(len)?)
+    }
+
+    /// The values of all fields of all elements.
+    pub fn values(&self) -> impl Iterator<Item = &DataElement> {
+        self.values.iter()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &DataElement)> {
+        self.field_names().zip(self.values())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&DataElement> {
+        let idx = self.index(name)?;
+        self.values.get(idx)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut DataElement> {
+        let idx = self.index(name)?;
+        self.values.get_mut(idx)
+    }
+
+    /// Sets the field `name` of the first element to `v`. A new field is
+    /// set to an empty array in the other elements of a structure array,
+    /// and a structure array without elements becomes a 1x1 structure.
+    pub fn insert(&mut self, name: &str, v: DataElement) -> Option<DataElement> {
+        if self.numel() == 0 {
+            self.header.dimensions = vec![1, 1].into();
+            self.values = self
+                .field_names
+                .iter()
+                .map(|name| empty_field(name))
+                .collect();
+        }
+        if let Some(idx) = self.index(name) {
+            return Some(std::mem::replace(&mut self.values[idx], v));
+        }
+        let len = self.len();
+        let mut old_values = std::mem::take(&mut self.values).into_iter();
+        let mut v = Some(v);
+        for _ in 0..self.numel() {
+            self.values.extend(old_values.by_ref().take(len));
+            self.values
+                .push(v.take().unwrap_or_else(|| empty_field(name)));
+        }
+        self.field_names.push(name.to_string());
+        None
+    }
+
+    /// Removes the field `name` from all elements and returns its value in
+    /// the first element.
+    pub fn remove(&mut self, name: &str) -> Option<DataElement> {
+        let idx = self.index(name)?;
+        let len = self.len();
+        self.field_names.remove(idx);
+        let mut removed = None;
+        for (i, value) in std::mem::take(&mut self.values).into_iter().enumerate() {
+            if i % len != idx {
+                self.values.push(value);
+            } else if removed.is_none() {
+                removed = Some(value);
+            }
+        }
+        removed
+    }
+}
+
+// An empty 0x0 double array, which Matlab uses as the default value of fields
+fn empty_field(name: &str) -> DataElement {
+    DataElement::NumericMatrix(Numeric {
+        header: ArrayHeader {
+            flags: ArrayFlags {
+                complex: false,
+                global: false,
+                logical: false,
+                class: ArrayType::Double,
+                nzmax: 0,
+            },
+            dimensions: vec![0, 0].into(),
+            name: name.to_string(),
+        },
+        data: NumericData::Double {
+            real: Vec::new(),
+            imag: None,
+        },
+        imag_skipped: false,
+    })
+}
+
+// #[cfg(feature = "ndarray")]
+// {
+//     #[derive(Debug)]
+//     enum NumericArrayData {
+//         Double(ndarray::ArrayD<f64>),
+//     }
+
+//     impl From<NumericData> for NumericArrayData {
+//         fn from(nd: NumericData) -> Self;
+//     }
+// }
+
+pub fn parse_header(i: &[u8]) -> IResult<&[u8], Header, HeaderError> {
+    // Make sure that the first four bytes are not null
+    if i.iter().take(4).any(|&b| b == 0) {
+        return Err(nom::Err::Error(HeaderError::NullText));
+    }
+    // Header text field
+    let (i, raw_text) = take(116usize)(i)?;
+    // Header subsystem data offset field
+    let (i, ssdo) = take(8usize)(i)?;
+    // Header flag fields
+    // Assume little endian for now
+    let (i, mut version) = u16(nom::number::Endianness::Little)(i)?;
+    // Check the endianness
+    let (i, is_little_endian) = alt((value(true, tag("IM")), value(false, tag("MI"))))(i)?;
+    // Fix endianness of the version field if we assumed the wrong one
+    if !is_little_endian {
+        version = version.swap_bytes();
+    }
+    if version != 0x0100 {
+        return Err(nom::Err::Failure(HeaderError::UnsupportedVersion(version)));
+    }
+    let endianness = if is_little_endian {
+        nom::number::Endianness::Little
+    } else {
+        nom::number::Endianness::Big
+    };
+    Ok((
+        i,
+        Header {
+            text: String::from_utf8_lossy(raw_text).into_owned(),
+            is_little_endian,
+            // take() returned exactly 116 bytes
+            raw_text: <[u8; 116]>::try_from(raw_text).unwrap(),
+            subsystem_offset: parse_subsystem_offset(endianness, ssdo),
+        },
+    ))
+}
+
+fn constant<T: Clone>(v: T) -> impl Fn(&[u8]) -> IResult<&[u8], T> {
+    move |i: &[u8]| Ok((i, v.clone()))
+}
+
+/// What the recursive parsers need to know besides their input.
+#[derive(Clone, Copy, Debug)]
+struct Context<'a> {
+    endianness: nom::number::Endianness,
+    options: &'a ParseOptions,
+    /// How many data elements enclose the one being parsed
+    depth: usize,
+    /// Warnings about the variable being parsed
+    warnings: &'a RefCell<Vec<WarningKind>>,
+    buffers: &'a ParseContext,
+}
+
+impl Context<'_> {
+    fn warn(&self, kind: WarningKind) {
+        self.warnings.borrow_mut().push(kind);
+    }
+}
+
+/// Where a data element is stored, which decides where its name comes from.
+#[derive(Clone, Copy, Debug)]
+enum ElementName<'a> {
+    /// A variable, which stores its own name
+    Variable,
+    /// The value of a structure field, which is named after the field and
+    /// stores an empty name
+    Field(&'a str),
+    /// A cell of a cell array, which has no name
+    Cell,
+}
+
+fn parse_next_data_element<'a>(
+    ctx: Context<'a>,
+    name: ElementName<'a>,
+) -> impl Fn(&[u8]) -> IResult<&[u8], DataElement> + 'a {
+    move |i: &[u8]| {
+        if ctx.depth >= ctx.options.max_depth {
+            return Err(failure(i, ParseErrorKind::NestingTooDeep));
+        }
+        let ctx = Context {
+            depth: ctx.depth + 1,
+            ..ctx
+        };
+        let (i, data_element_tag) = parse_data_element_tag(ctx.endianness)(i)?;
+        let next_parser: Box<dyn Fn(_) -> _> = match data_element_tag.data_type {
+            DataType::Matrix => Box::new(parse_matrix_data_element(ctx, name)),
+            DataType::Compressed => {
+                if !matches!(name, ElementName::Variable) {
+                    // Only variables are compressed, struct fields and cells
+                    // are always Matrix
+                    return Err(failure(i, ParseErrorKind::UnexpectedCompression));
+                }
+
+                Box::new(parse_compressed_data_element(ctx))
+            }
+            data_type => {
+                ctx.warn(WarningKind::UnsupportedDataType(data_type));
+                Box::new(parse_unsupported_data_element(
+                    ctx.endianness,
+                    data_type,
+                    None,
+                ))
+            }
+        };
+        let (i, data_element) =
+            length_value(constant(data_element_tag.data_byte_size), next_parser)(i)?;
+        // Take care of padding. It seems like either all variables in a mat file are compressed or none are.
+        // If the variables are compressed there is no alignment to take care of (only uncompressed data
+        // needs to be aligned according to the spec). Otherwise make sure that we end up on an 8 byte
+        // boundary (ignore if there is not enough data left)
+        let num_padding_bytes = if data_element_tag.data_type == DataType::Compressed {
+            0
+        } else {
+            data_element_tag.padding_byte_size
+        };
+        let (i, _) = opt(complete(take(num_padding_bytes)))(i)?;
+        Ok((i, data_element))
+    }
+}
+
+pub(crate) fn byte_order(endianness: nom::number::Endianness) -> ByteOrder {
+    match endianness {
+        nom::number::Endianness::Big => ByteOrder::BigEndian,
+        _ => ByteOrder::LittleEndian,
+    }
+}
+
+/// The number of bytes needed to pad `x` to a multiple of `multiple`. This
+/// doesn't round `x` up first, which would overflow for sizes close to
+/// `u32::MAX`.
+fn padding_to_multiple(x: u32, multiple: u32) -> u32 {
+    (multiple - x % multiple) % multiple
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ArrayFlags {
+    pub complex: bool,
+    pub global: bool,
+    pub logical: bool,
+    pub class: ArrayType,
+    pub nzmax: usize,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Primitive)]
+pub enum DataType {
+    Int8 = 1,
+    UInt8 = 2,
+    Int16 = 3,
+    UInt16 = 4,
+    Int32 = 5,
+    UInt32 = 6,
+    Single = 7,
+    Double = 9,
+    Int64 = 12,
+    UInt64 = 13,
+    Matrix = 14,
+    Compressed = 15,
+    Utf8 = 16,
+    Utf16 = 17,
+    Utf32 = 18,
+}
+
+impl DataType {
+    /// The size of a single value of this type in bytes, or `None` for the
+    /// types of nested elements.
+    pub fn byte_size(&self) -> Option<usize> {
+        match self {
+            DataType::Int8 | DataType::UInt8 | DataType::Utf8 => Some(1),
+            DataType::Int16 | DataType::UInt16 | DataType::Utf16 => Some(2),
+            DataType::Int32 | DataType::UInt32 | DataType::Single | DataType::Utf32 => Some(4),
+            DataType::Int64 | DataType::UInt64 | DataType::Double => Some(8),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Primitive)]
+pub enum ArrayType {
+    Cell = 1,
+    Struct = 2,
+    Object = 3,
+    Char = 4,
+    Sparse = 5,
+    Double = 6,
+    Single = 7,
+    Int8 = 8,
+    UInt8 = 9,
+    Int16 = 10,
+    UInt16 = 11,
+    Int32 = 12,
+    UInt32 = 13,
+    Int64 = 14,
+    UInt64 = 15,
+    Opaque = 17,
+}
+
+impl ArrayType {
+    /// The name Matlab uses for this class, e.g. "double" or "struct".
+    pub fn matlab_name(&self) -> &'static str {
+        match self {
+            ArrayType::Cell => "cell",
+            ArrayType::Struct => "struct",
+            ArrayType::Object => "object",
+            ArrayType::Char => "char",
+            ArrayType::Sparse => "sparse",
+            ArrayType::Double => "double",
+            ArrayType::Single => "single",
+            ArrayType::Int8 => "int8",
+            ArrayType::UInt8 => "uint8",
+            ArrayType::Int16 => "int16",
+            ArrayType::UInt16 => "uint16",
+            ArrayType::Int32 => "int32",
+            ArrayType::UInt32 => "uint32",
+            ArrayType::Int64 => "int64",
+            ArrayType::UInt64 => "uint64",
+            ArrayType::Opaque => "opaque",
+        }
+    }
+
+    // fn is_numeric(&self) -> bool {
+    //     match self {
+    //         ArrayType::Cell
+    //         | ArrayType::Struct
+    //         | ArrayType::Object
+    //         | ArrayType::Char
+    //         | ArrayType::Sparse => false,
+    //         _ => true,
+    //     }
+    // }
+
+    pub(crate) fn numeric_data_type(&self) -> Option<DataType> {
+        match self {
+            ArrayType::Double => Some(DataType::Double),
+            ArrayType::Single => Some(DataType::Single),
+            ArrayType::Int8 => Some(DataType::Int8),
+            ArrayType::UInt8 => Some(DataType::UInt8),
+            ArrayType::Int16 => Some(DataType::Int16),
+            ArrayType::UInt16 => Some(DataType::UInt16),
+            ArrayType::Int32 => Some(DataType::Int32),
+            ArrayType::UInt32 => Some(DataType::UInt32),
+            ArrayType::Int64 => Some(DataType::Int64),
+            ArrayType::UInt64 => Some(DataType::UInt64),
+            _ => None,
+        }
+    }
+}
+
+/// The size of an array along each of its dimensions.
+///
+/// Arrays stored in .mat files have at least two dimensions. For
+/// two-dimensional arrays the first dimension is the number of rows while
+/// the second dimension is the number of columns.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Dimensions(pub(crate) Vec<usize>);
+
+impl Dimensions {
+    /// The size along the first dimension.
+    pub fn rows(&self) -> usize {
+        self.0.first().copied().unwrap_or(1)
+    }
+
+    /// The size along the second dimension.
+    pub fn columns(&self) -> usize {
+        self.0.get(1).copied().unwrap_or(1)
+    }
+
+    /// The number of dimensions.
+    pub fn ndims(&self) -> usize {
+        self.0.len()
+    }
+
+    /// The total number of elements, or `None` if it overflows `usize`.
+    pub fn numel(&self) -> Option<usize> {
+        // Computed in u64 so that the result doesn't depend on the platform
+        // unless it is too large for it
+        let numel = self
+            .0
+            .iter()
+            .try_fold(1u64, |acc, &d| acc.checked_mul(u64::try_from(d).ok()?))?;
+        usize::try_from(numel).ok()
+    }
+
+    /// Whether this is the size of an array without elements, i.e. one of
+    /// the dimensions is zero.
+    pub fn is_empty(&self) -> bool {
+        self.numel() == Some(0)
+    }
+
+    /// Whether this is the size of a two-dimensional array with a single row
+    /// or a single column.
+    pub fn is_vector(&self) -> bool {
+        self.ndims() == 2 && (self.rows() == 1 || self.columns() == 1)
+    }
+
+    /// Whether this is the size of an array with exactly one element.
+    pub fn is_scalar(&self) -> bool {
+        self.numel() == Some(1)
+    }
+
+    pub fn as_slice(&self) -> &[usize] {
+        &self.0
+    }
+}
+
+impl From<Vec<usize>> for Dimensions {
+    fn from(dimensions: Vec<usize>) -> Self {
+        Dimensions(dimensions)
+    }
+}
+
+impl TryFrom<&[i32]> for Dimensions {
+    type Error = std::num::TryFromIntError;
+
+    /// Converts dimensions as stored in a .mat file, rejecting negative
+    /// values.
+    fn try_from(dimensions: &[i32]) -> Result<Self, Self::Error> {
+        dimensions
+            .iter()
+            .map(|&d| usize::try_from(d))
+            .collect::<Result<_, _>>()
+            .map(Dimensions)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DataElementTag {
+    data_type: DataType,
+    data_byte_size: u32,
     padding_byte_size: u32,
 }
 
-fn parse_data_element_tag(
+impl DataElementTag {
+    /// The number of values in the element, or `None` if its size isn't a
+    /// multiple of the size of its data type. Elements of nested types count
+    /// their bytes.
+    fn value_count(&self) -> Option<usize> {
+        let width = self.data_type.byte_size().unwrap_or(1);
+        let byte_size = self.data_byte_size as usize;
+        byte_size.is_multiple_of(width).then_some(byte_size / width)
+    }
+}
+
+/// The error for data whose size isn't a multiple of the size of its type.
+fn partial_value_error(i: &[u8], subelement: &'static str) -> nom::Err<ParseError> {
+    failure(i, ParseErrorKind::SizeMismatch { subelement })
+}
+
+fn parse_data_element_tag(
+    endianness: nom::number::Endianness,
+) -> impl Fn(&[u8]) -> IResult<&[u8], DataElementTag> {
+    move |i: &[u8]| {
+        let (i, starting_bytes) = u32(endianness)(i)?;
+        let (i, data_type, byte_size, padding_byte_size) = if starting_bytes & 0xFFFF0000 == 0 {
+            // Long Data Element Format
+            let data_type = starting_bytes;
+            let (i, byte_size) = u32(endianness)(i)?;
+            let padding_byte_size = padding_to_multiple(byte_size, 8);
+            (i, data_type, byte_size, padding_byte_size)
+        } else {
+            // Small Data Element Format
+            let data_type = starting_bytes & 0x0000FFFF;
+            let byte_size = (starting_bytes & 0xFFFF0000) >> 16;
+            // Assert that byte_size is <= 4
+            if byte_size > 4 {
+                return Err(failure(i, ParseErrorKind::InvalidTag));
+            }
+            let padding_byte_size = 4 - byte_size;
+            (i, data_type, byte_size, padding_byte_size)
+        };
+        // Check the size before anything gets allocated for the data. This
+        // isn't fatal so that a truncated file can still be read up to its
+        // last complete element.
+        if byte_size as usize > i.len() {
+            return Err(nom::Err::Error(ParseError::new(
+                i,
+                ParseErrorKind::UnexpectedEof,
+            )));
+        }
+        Ok((
+            i,
+            DataElementTag {
+                data_type: DataType::from_u32(data_type)
+                    .ok_or(failure(i, ParseErrorKind::UnknownDataType(data_type)))?,
+                data_byte_size: byte_size,
+                padding_byte_size,
+            },
+        ))
+    }
+}
+
+fn parse_array_name_subelement(
+    ctx: Context<'_>,
+) -> impl Fn(&[u8]) -> IResult<&[u8], Option<String>> + '_ {
+    move |i: &[u8]| {
+        let (i, data_element_tag) = parse_data_element_tag(ctx.endianness)(i)?;
+
+        if data_element_tag.data_type != DataType::Int8 {
+            return Err(failure(
+                i,
+                ParseErrorKind::UnexpectedDataType {
+                    subelement: "array name",
+                    data_type: data_element_tag.data_type,
+                },
+            ));
+        }
+
+        if data_element_tag.data_byte_size == 0 {
+            return Ok((i, None));
+        }
+
+        let (i, name) = take(data_element_tag.data_byte_size)(i)?;
+        let name = decode_name(ctx, i, name, "array name")?;
+        // Padding bytes
+        let (i, _) = take(data_element_tag.padding_byte_size)(i)?;
+        Ok((i, Some(name)))
+    }
+}
+
+fn maybe_parse_array_name_subelement<'a>(
+    ctx: Context<'a>,
+    supplied_name: ElementName<'a>,
+) -> impl Fn(&[u8]) -> IResult<&[u8], String> + 'a {
+    move |i| {
+        let (i, element_name) = parse_array_name_subelement(ctx)(i)?;
+
+        match (supplied_name, element_name) {
+            (ElementName::Variable, Some(v)) => Ok((i, v)),
+            // Matlab stores the subsystem data as a variable without a name
+            (ElementName::Variable, None) => Ok((i, String::new())),
+            (ElementName::Field(v), None) => Ok((i, v.to_string())),
+            (ElementName::Cell, None) => Ok((i, String::new())),
+            (_, Some(name)) => Err(failure(i, ParseErrorKind::UnexpectedName(name))),
+        }
+    }
+}
+
+fn parse_dimensions_array_subelement(
+    endianness: nom::number::Endianness,
+) -> impl Fn(&[u8]) -> IResult<&[u8], Dimensions> {
+    move |i: &[u8]| {
+        let (i, data_element_tag) = parse_data_element_tag(endianness)(i)?;
+        if data_element_tag.data_type != DataType::Int32 {
+            return Err(failure(
+                i,
+                ParseErrorKind::UnexpectedDataType {
+                    subelement: "dimensions",
+                    data_type: data_element_tag.data_type,
+                },
+            ));
+        }
+        if !(data_element_tag.data_byte_size >= 8 && data_element_tag.data_byte_size % 4 == 0) {
+            return Err(failure(
+                i,
+                ParseErrorKind::SizeMismatch {
+                    subelement: "dimensions",
+                },
+            ));
+        }
+        let (i, dimensions) = count(
+            i32(endianness),
+            (data_element_tag.data_byte_size / 4) as usize,
+        )(i)?;
+        // Negative dimensions are invalid
+        let Ok(dimensions) = Dimensions::try_from(dimensions.as_slice()) else {
+            return Err(failure(i, ParseErrorKind::NegativeDimension));
+        };
+        // So are dimensions whose number of elements overflows
+        if dimensions.numel().is_none() {
+            return Err(failure(i, ParseErrorKind::TooManyElements));
+        }
+        let (i, _) = take(data_element_tag.padding_byte_size)(i)?;
+        Ok((i, dimensions))
+    }
+}
+
+/// Parses the array flags subelement into the raw flags and class word and
+/// the maximum number of non-zero elements.
+fn parse_raw_array_flags_subelement(
+    endianness: nom::number::Endianness,
+) -> impl Fn(&[u8]) -> IResult<&[u8], (u32, u32)> {
+    move |i: &[u8]| {
+        let (i, tag_data_type) = u32(endianness)(i)?;
+        let (i, tag_data_len) = u32(endianness)(i)?;
+        if tag_data_type != DataType::UInt32 as u32 {
+            return Err(failure(
+                i,
+                match DataType::from_u32(tag_data_type) {
+                    Some(data_type) => ParseErrorKind::UnexpectedDataType {
+                        subelement: "array flags",
+                        data_type,
+                    },
+                    None => ParseErrorKind::UnknownDataType(tag_data_type),
+                },
+            ));
+        }
+        if tag_data_len != 8 {
+            return Err(failure(
+                i,
+                ParseErrorKind::SizeMismatch {
+                    subelement: "array flags",
+                },
+            ));
+        }
+        let (i, flags_and_class) = u32(endianness)(i)?;
+        let (i, nzmax) = u32(endianness)(i)?;
+        Ok((i, (flags_and_class, nzmax)))
+    }
+}
+
+fn parse_array_flags_subelement(
+    endianness: nom::number::Endianness,
+) -> impl Fn(&[u8]) -> IResult<&[u8], ArrayFlags> {
+    move |i: &[u8]| {
+        let (i, (flags_and_class, nzmax)) = parse_raw_array_flags_subelement(endianness)(i)?;
+        let class_id = (flags_and_class & 0xFF) as u8;
+
+        Ok((
+            i,
+            ArrayFlags {
+                complex: (flags_and_class & 0x0800) != 0,
+                global: (flags_and_class & 0x0400) != 0,
+                logical: (flags_and_class & 0x0200) != 0,
+                class: ArrayType::from_u8(class_id)
+                    .ok_or(failure(i, ParseErrorKind::UnknownClass(class_id)))?,
+                nzmax: nzmax as usize,
+            },
+        ))
+    }
+}
+
+fn parse_matrix_data_element<'a>(
+    ctx: Context<'a>,
+    supplied_name: ElementName<'a>,
+) -> impl Fn(&[u8]) -> IResult<&[u8], DataElement> + 'a {
+    move |input: &[u8]| {
+        let (_, (flags_and_class, _)) = parse_raw_array_flags_subelement(ctx.endianness)(input)?;
+        let class_id = (flags_and_class & 0xFF) as u8;
+        if ArrayType::from_u8(class_id).is_none() {
+            ctx.warn(WarningKind::UnsupportedClass(class_id));
+            return parse_unknown_class_array(ctx, supplied_name, class_id)(input);
+        }
+        let (i, header) = parse_array_header(ctx, supplied_name)(input)?;
+        // Errors past the header can tell which variable they occurred in
+        let variable_name = match supplied_name {
+            ElementName::Variable if !header.name.is_empty() => Some(header.name.clone()),
+            _ => None,
+        };
+        let result = match header.flags.class {
+            ArrayType::Char => parse_character_array(ctx.endianness, header)(i),
+            ArrayType::Cell => {
+                parse_cell(ctx, header)(i).map(|(i, v)| (i, DataElement::CellMatrix(v)))
+            }
+            ArrayType::Struct => {
+                parse_struct(ctx, header)(i).map(|(i, v)| (i, DataElement::StructureMatrix(v)))
+            }
+            ArrayType::Opaque => {
+                parse_opaque(ctx, header)(i).map(|(i, v)| (i, DataElement::Opaque(v)))
+            }
+            ArrayType::Sparse => parse_sparse_matrix_subelements(ctx, header)(i),
+            x if x.numeric_data_type().is_some() => {
+                parse_numeric_matrix_subelements(ctx, header)(i)
+            }
+            class => {
+                ctx.warn(WarningKind::UnsupportedClass(class as u8));
+                parse_unsupported_data_element(ctx.endianness, DataType::Matrix, Some(header))(
+                    input,
+                )
+            }
+        };
+        match variable_name {
+            Some(name) => result.map_err(|err| err.map(|err| err.in_variable(&name))),
+            None => result,
+        }
+    }
+}
+
+// Matlab stores numeric data using the smallest data type that can hold all
+// values of an array, so the type of a data subelement does not need to match
+// the class of the array it belongs to. The table below lists the storage
+// types accepted for each class, following the automatic compression rules of
+// the MAT-file format documentation:
+//
+// | Class           | Storage types                                       |
+// |-----------------|-----------------------------------------------------|
+// | int8, uint8     | int8, uint8                                         |
+// | int16, uint16   | int8, uint8, int16, uint16                          |
+// | int32, uint32   | int8, uint8, int16, uint16, int32, uint32           |
+// | int64, uint64   | any integer type                                    |
+// | single          | int8, uint8, int16, uint16, int32, uint32, single   |
+// | double          | int8, uint8, int16, uint16, int32, uint32, double   |
+//
+// Integer types of the other signedness are accepted as well: the values are
+// checked when they are widened into the array class, which fails for e.g.
+// negative values of an unsigned class.
+pub(crate) fn numeric_data_types_are_compatible(
+    array_type: DataType,
+    subelement_type: DataType,
+) -> bool {
+    use DataType::*;
+    let storage_types: &[DataType] = match array_type {
+        Int8 | UInt8 => &[Int8, UInt8],
+        Int16 | UInt16 => &[Int8, UInt8, Int16, UInt16],
+        Int32 | UInt32 => &[Int8, UInt8, Int16, UInt16, Int32, UInt32],
+        Int64 | UInt64 => &[Int8, UInt8, Int16, UInt16, Int32, UInt32, Int64, UInt64],
+        Single => &[Int8, UInt8, Int16, UInt16, Int32, UInt32, Single],
+        Double => &[Int8, UInt8, Int16, UInt16, Int32, UInt32, Double],
+        _ => &[],
+    };
+    storage_types.contains(&subelement_type)
+}
+
+/// Primitive values that numeric data is read into.
+trait FromBytes: Sized {
+    const SIZE: usize;
+
+    fn from_le_bytes(bytes: &[u8]) -> Self;
+
+    fn from_be_bytes(bytes: &[u8]) -> Self;
+}
+
+macro_rules! from_bytes {
+    ( $( $t:ty ),* ) => {
+        $(
+            impl FromBytes for $t {
+                const SIZE: usize = std::mem::size_of::<$t>();
+
+                fn from_le_bytes(bytes: &[u8]) -> Self {
+                    <$t>::from_le_bytes(bytes.try_into().unwrap())
+                }
+
+                fn from_be_bytes(bytes: &[u8]) -> Self {
+                    <$t>::from_be_bytes(bytes.try_into().unwrap())
+                }
+            }
+        )*
+    };
+}
+
+from_bytes!(i8, u8, i16, u16, i32, u32, i64, u64, f32, f64);
+
+fn is_big_endian(endianness: nom::number::Endianness) -> bool {
+    match endianness {
+        nom::number::Endianness::Big => true,
+        nom::number::Endianness::Little => false,
+        nom::number::Endianness::Native => cfg!(target_endian = "big"),
+    }
+}
+
+/// Reads `len` values at once. Converting the whole slice in one loop lets
+/// the compiler turn it into a copy if the file has the byte order of the
+/// machine, and into a vectorized byte swap otherwise, which is much faster
+/// than reading the values one at a time with `count`. The byte order is
+/// picked outside of the loop so that each loop only does one of the two.
+fn values<T: FromBytes>(
+    endianness: nom::number::Endianness,
+    len: usize,
+) -> impl Fn(&[u8]) -> IResult<&[u8], Vec<T>> {
+    move |i: &[u8]| {
+        let (i, bytes) = take(len * T::SIZE)(i)?;
+        let chunks = bytes.chunks_exact(T::SIZE);
+        let values = if is_big_endian(endianness) {
+            chunks.map(T::from_be_bytes).collect()
+        } else {
+            chunks.map(T::from_le_bytes).collect()
+        };
+        Ok((i, values))
+    }
+}
+
+fn parse_numeric_subelement(
+    endianness: nom::number::Endianness,
+) -> impl Fn(&[u8]) -> IResult<&[u8], NumericPart> {
+    move |i: &[u8]| {
+        let (i, data_element_tag) = parse_data_element_tag(endianness)(i)?;
+        // A partial value at the end would shift all of the following data
+        let Some(len) = data_element_tag.value_count() else {
+            return Err(partial_value_error(i, "numeric data"));
+        };
+        let (i, numeric_data) = match data_element_tag.data_type {
+            // Bytes don't need to be converted
+            DataType::Int8 => map(take(len), |bytes: &[u8]| {
+                NumericPart::Int8(bytes.iter().map(|&b| b as i8).collect())
+            })(i)?,
+            DataType::UInt8 => {
+                map(take(len), |bytes: &[u8]| NumericPart::UInt8(bytes.to_vec()))(i)?
+            }
+            DataType::Int16 => map(values(endianness, len), NumericPart::Int16)(i)?,
+            DataType::UInt16 => map(values(endianness, len), NumericPart::UInt16)(i)?,
+            DataType::Int32 => map(values(endianness, len), NumericPart::Int32)(i)?,
+            DataType::UInt32 => map(values(endianness, len), NumericPart::UInt32)(i)?,
+            DataType::Int64 => map(values(endianness, len), NumericPart::Int64)(i)?,
+            DataType::UInt64 => map(values(endianness, len), NumericPart::UInt64)(i)?,
+            DataType::Single => map(values(endianness, len), NumericPart::Single)(i)?,
+            DataType::Double => map(values(endianness, len), NumericPart::Double)(i)?,
+            DataType::Compressed
+            | DataType::Matrix
+            | DataType::Utf8
+            | DataType::Utf16
+            | DataType::Utf32 => {
+                return Err(failure(
+                    i,
+                    ParseErrorKind::UnexpectedDataType {
+                        subelement: "numeric data",
+                        data_type: data_element_tag.data_type,
+                    },
+                ));
+            }
+        };
+        // Padding bytes
+        let (i, _) = take(data_element_tag.padding_byte_size)(i)?;
+        Ok((i, numeric_data))
+    }
+}
+
+/// Skips over a numeric subelement without reading its values.
+fn skip_numeric_subelement(
+    endianness: nom::number::Endianness,
+) -> impl Fn(&[u8]) -> IResult<&[u8], ()> {
+    move |i: &[u8]| {
+        let (i, data_element_tag) = parse_data_element_tag(endianness)(i)?;
+        let (i, _) = take(data_element_tag.data_byte_size)(i)?;
+        let (i, _) = take(data_element_tag.padding_byte_size)(i)?;
+        Ok((i, ()))
+    }
+}
+
+fn parse_compressed_data_element(
+    ctx: Context<'_>,
+) -> impl Fn(&[u8]) -> IResult<&[u8], DataElement> + '_ {
+    move |i: &[u8]| {
+        let mut buf = ctx.buffers.take_scratch();
+        let result = decompress_and_parse(ctx, &mut buf, i);
+        ctx.buffers.return_scratch(buf);
+        result
+    }
+}
+
+/// Decompresses the element in `i` into `buf` and parses it.
+fn decompress_and_parse<'i>(
+    ctx: Context<'_>,
+    buf: &mut Vec<u8>,
+    i: &'i [u8],
+) -> IResult<&'i [u8], DataElement> {
+    // Read one byte more than allowed to tell whether the limit was hit
+    let limit = ctx.options.max_decompressed_size;
+    let zlib_error = |err: std::io::Error| failure(i, ParseErrorKind::Zlib(err.to_string()));
+    let mut decoder = Decoder::new(i)
+        .map_err(zlib_error)?
+        .take(limit.saturating_add(1));
+    (&mut decoder)
+        .take(STREAMING_THRESHOLD)
+        .read_to_end(buf)
+        .map_err(zlib_error)?;
+    if buf.len() as u64 == STREAMING_THRESHOLD {
+        let warnings = ctx.warnings.borrow().len();
+        if let Some(result) = parse_streamed_numeric(ctx, buf, &mut decoder, i) {
+            return result.map(|data_element| (&i[i.len()..], data_element));
+        }
+        ctx.warnings.borrow_mut().truncate(warnings);
+        decoder.read_to_end(buf).map_err(zlib_error)?;
+    }
+    if buf.len() as u64 > limit {
+        return Err(failure(i, ParseErrorKind::DecompressedSizeLimit));
+    }
+    parse_decompressed(ctx, buf, i)
+}
+
+/// Parses the element that `buf` was decompressed from `i`.
+fn parse_decompressed<'i>(
+    ctx: Context<'_>,
+    buf: &[u8],
+    i: &'i [u8],
+) -> IResult<&'i [u8], DataElement> {
+    // The compressed element exists, so any error inside it is fatal.
+    // Offsets into the decompressed data mean nothing outside of it.
+    let (remaining, data_element) = parse_next_data_element(ctx, ElementName::Variable)(buf)
+        .map_err(|err| {
+            let err = match err {
+                nom::Err::Incomplete(_) => {
+                    ParseError::new(&buf[buf.len()..], ParseErrorKind::UnexpectedEof)
+                }
+                nom::Err::Error(err) | nom::Err::Failure(err) => err,
+            };
+            nom::Err::Failure(err.decompressed(buf, i))
+        })?;
+    // Some writers align the decompressed data to 8 bytes. Anything else
+    // following the element means the data is corrupted.
+    if !(remaining.len() < 8 && remaining.iter().all(|&b| b == 0)) {
+        return Err(failure(i, ParseErrorKind::TrailingData));
+    }
+    Ok((&[], data_element))
+}
+
+/// Compressed elements larger than this are checked for a numeric array,
+/// whose data is then converted while it is decompressed instead of after
+/// the whole element is.
+const STREAMING_THRESHOLD: u64 = 64 * 1024;
+
+/// Decompressed data is converted in chunks of this size.
+const STREAMING_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Parses a compressed numeric array without keeping its decompressed data
+/// in memory. `prefix` is the start of the decompressed element and
+/// `decoder` yields the rest of it. Returns `None` without reading from
+/// `decoder` if the element isn't a numeric array whose header is in
+/// `prefix`, in which case it has to be parsed as usual.
+///
+/// Errors are reported at the start of `compressed` with the offset into
+/// the decompressed data, like the errors of the usual path.
+fn parse_streamed_numeric<R: Read>(
+    ctx: Context<'_>,
+    prefix: &[u8],
+    decoder: &mut std::io::Take<R>,
+    compressed: &[u8],
+) -> Option<Result<DataElement, nom::Err<ParseError>>> {
+    let ctx = Context {
+        depth: ctx.depth + 1,
+        ..ctx
+    };
+    if ctx.depth >= ctx.options.max_depth {
+        return None;
+    }
+    let (body, (data_type, size)) = long_tag(ctx.endianness, prefix)?;
+    if data_type != DataType::Matrix as u32 {
+        return None;
+    }
+    let (_, (flags_and_class, _)) = parse_raw_array_flags_subelement(ctx.endianness)(body).ok()?;
+    let class = ArrayType::from_u8((flags_and_class & 0xFF) as u8)?;
+    class.numeric_data_type()?;
+    let (i, header) = parse_array_header(ctx, ElementName::Variable)(body).ok()?;
+    // The data of the real part has to follow a tag in the long format
+    let (_, (data_type, _)) = long_tag(ctx.endianness, i)?;
+    DataType::from_u32(data_type)?.byte_size()?;
+
+    let name = header.name.clone();
+    let mut stream = Stream {
+        reader: i.chain(decoder),
+        offset: (prefix.len() - i.len()) as u64,
+        endianness: ctx.endianness,
+        compressed,
+    };
+    let parse = || {
+        let real_part = stream.numeric_subelement()?;
+        let real_part = check_numeric_part(ctx, &header, real_part, "real part", compressed)
+            .map_err(|err| stream.locate(err))?;
+        // A skipped imaginary part is skipped with anything else following
+        // the data below
+        let imag_skipped = header.flags.complex && !ctx.options.load_imaginary;
+        let imag_part = if header.flags.complex && !imag_skipped {
+            let imag_part = stream.numeric_subelement()?;
+            let imag_part =
+                check_numeric_part(ctx, &header, imag_part, "imaginary part", compressed)
+                    .map_err(|err| stream.locate(err))?;
+            Some(imag_part)
+        } else {
+            None
+        };
+        let Some(data) = real_part.into_data(imag_part) else {
+            return Err(stream.error(ParseErrorKind::MismatchedParts));
+        };
+        // Anything following the data inside the element is ignored, like
+        // in the usual path
+        let end = 8 + u64::from(size);
+        if stream.offset > end {
+            return Err(stream.error(ParseErrorKind::UnexpectedEof));
+        }
+        stream.skip(end - stream.offset)?;
+        stream.finish(u64::from(padding_to_multiple(size, 8)))?;
+        Ok(DataElement::NumericMatrix(Numeric {
+            header,
+            data,
+            imag_skipped,
+        }))
+    };
+    let result = parse();
+    Some(match name.is_empty() {
+        true => result,
+        false => result.map_err(|err| err.map(|err| err.in_variable(&name))),
+    })
+}
+
+/// Reads the type and size of a tag in the long format.
+fn long_tag(endianness: nom::number::Endianness, i: &[u8]) -> Option<(&[u8], (u32, u32))> {
+    let (i, (data_type, size)) =
+        nom::sequence::pair(u32::<_, ()>(endianness), u32(endianness))(i).ok()?;
+    (data_type & 0xFFFF0000 == 0).then_some((i, (data_type, size)))
+}
+
+/// The decompressed data of a compressed element that is parsed while it is
+/// decompressed.
+struct Stream<'a, R> {
+    reader: std::io::Chain<&'a [u8], &'a mut std::io::Take<R>>,
+    /// How much of the decompressed data has been read
+    offset: u64,
+    endianness: nom::number::Endianness,
+    compressed: &'a [u8],
+}
+
+impl<R: Read> Stream<'_, R> {
+    /// An error at the current offset.
+    fn error(&self, kind: ParseErrorKind) -> nom::Err<ParseError> {
+        self.locate(failure(self.compressed, kind))
+    }
+
+    /// Records the current offset in an error of a parser that was given
+    /// the compressed data, as it didn't see the decompressed data.
+    fn locate(&self, err: nom::Err<ParseError>) -> nom::Err<ParseError> {
+        err.map(|mut err| {
+            err.inner.decompressed_offset.get_or_insert(self.offset);
+            err
+        })
+    }
+
+    /// Whether more than [`ParseOptions::max_decompressed_size`] bytes have
+    /// been decompressed.
+    fn limit_exceeded(&self) -> bool {
+        self.reader.get_ref().1.limit() == 0
+    }
+
+    fn io_error(&self, err: std::io::Error) -> nom::Err<ParseError> {
+        match err.kind() {
+            _ if self.limit_exceeded() => self.error(ParseErrorKind::DecompressedSizeLimit),
+            std::io::ErrorKind::UnexpectedEof => self.error(ParseErrorKind::UnexpectedEof),
+            _ => failure(self.compressed, ParseErrorKind::Zlib(err.to_string())),
+        }
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), nom::Err<ParseError>> {
+        self.reader
+            .read_exact(buf)
+            .map_err(|err| self.io_error(err))?;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+
+    fn skip(&mut self, len: u64) -> Result<(), nom::Err<ParseError>> {
+        let skipped = std::io::copy(&mut (&mut self.reader).take(len), &mut std::io::sink())
+            .map_err(|err| self.io_error(err))?;
+        self.offset += skipped;
+        if skipped < len {
+            return Err(self.io_error(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        Ok(())
+    }
+
+    /// Reads `len` values, converting them a chunk at a time.
+    fn values<T: FromBytes>(&mut self, len: usize) -> Result<Vec<T>, nom::Err<ParseError>> {
+        // The size in the tag isn't trusted, so memory only grows with the
+        // data that was actually decompressed
+        let mut values = Vec::new();
+        let mut chunk = vec![0; STREAMING_CHUNK_SIZE / T::SIZE * T::SIZE];
+        while values.len() < len {
+            let chunk_len = ((len - values.len()) * T::SIZE).min(chunk.len());
+            self.read_exact(&mut chunk[..chunk_len])?;
+            values.reserve(chunk_len / T::SIZE);
+            let chunks = chunk[..chunk_len].chunks_exact(T::SIZE);
+            if is_big_endian(self.endianness) {
+                values.extend(chunks.map(T::from_be_bytes));
+            } else {
+                values.extend(chunks.map(T::from_le_bytes));
+            }
+        }
+        Ok(values)
+    }
+
+    /// Reads a numeric subelement like [`parse_numeric_subelement`].
+    fn numeric_subelement(&mut self) -> Result<NumericPart, nom::Err<ParseError>> {
+        let mut tag = [0; 8];
+        self.read_exact(&mut tag)?;
+        let Some((_, (data_type, size))) = long_tag(self.endianness, &tag) else {
+            // The data of small elements is part of the tag
+            return match parse_numeric_subelement(self.endianness)(&tag) {
+                Ok((_, part)) => Ok(part),
+                Err(err) => Err(self.locate(err.map(|mut err| {
+                    err.inner.address = Some(self.compressed.as_ptr() as usize);
+                    err
+                }))),
+            };
+        };
+        let tag = DataElementTag {
+            data_type: DataType::from_u32(data_type)
+                .ok_or_else(|| self.error(ParseErrorKind::UnknownDataType(data_type)))?,
+            data_byte_size: size,
+            padding_byte_size: padding_to_multiple(size, 8),
+        };
+        let Some(len) = tag.value_count() else {
+            return Err(self.error(ParseErrorKind::SizeMismatch {
+                subelement: "numeric data",
+            }));
+        };
+        let part = match tag.data_type {
+            DataType::Int8 => NumericPart::Int8(self.values(len)?),
+            DataType::UInt8 => NumericPart::UInt8(self.values(len)?),
+            DataType::Int16 => NumericPart::Int16(self.values(len)?),
+            DataType::UInt16 => NumericPart::UInt16(self.values(len)?),
+            DataType::Int32 => NumericPart::Int32(self.values(len)?),
+            DataType::UInt32 => NumericPart::UInt32(self.values(len)?),
+            DataType::Int64 => NumericPart::Int64(self.values(len)?),
+            DataType::UInt64 => NumericPart::UInt64(self.values(len)?),
+            DataType::Single => NumericPart::Single(self.values(len)?),
+            DataType::Double => NumericPart::Double(self.values(len)?),
+            data_type => {
+                return Err(self.error(ParseErrorKind::UnexpectedDataType {
+                    subelement: "numeric data",
+                    data_type,
+                }))
+            }
+        };
+        self.skip(u64::from(tag.padding_byte_size))?;
+        Ok(part)
+    }
+
+    /// Checks what follows the element like [`parse_compressed_data_element`]:
+    /// its padding may be missing, anything else has to be shorter than 8
+    /// bytes and zero.
+    fn finish(&mut self, padding: u64) -> Result<(), nom::Err<ParseError>> {
+        let mut rest = Vec::new();
+        (&mut self.reader)
+            .take(padding + 8)
+            .read_to_end(&mut rest)
+            .map_err(|err| self.io_error(err))?;
+        if self.limit_exceeded() {
+            return Err(self.error(ParseErrorKind::DecompressedSizeLimit));
+        }
+        let rest = rest.get(padding as usize..).unwrap_or(&rest);
+        if !(rest.len() < 8 && rest.iter().all(|&b| b == 0)) {
+            return Err(failure(self.compressed, ParseErrorKind::TrailingData));
+        }
+        Ok(())
+    }
+}
+
+pub type RowIndex = Vec<usize>;
+pub type ColumnShift = Vec<usize>;
+
+fn parse_numeric_matrix_subelements(
+    ctx: Context<'_>,
+    header: ArrayHeader,
+) -> impl FnOnce(&[u8]) -> IResult<&[u8], DataElement> + '_ {
+    move |i: &[u8]| {
+        let (i, real_part) = parse_numeric_subelement(ctx.endianness)(i)?;
+        let real_part = check_numeric_part(ctx, &header, real_part, "real part", i)?;
+        let imag_skipped = header.flags.complex && !ctx.options.load_imaginary;
+        let (i, _) = cond(imag_skipped, skip_numeric_subelement(ctx.endianness))(i)?;
+        let (i, imag_part) = cond(
+            header.flags.complex && !imag_skipped,
+            parse_numeric_subelement(ctx.endianness),
+        )(i)?;
+        let imag_part = imag_part
+            .map(|imag_part| check_numeric_part(ctx, &header, imag_part, "imaginary part", i))
+            .transpose()?;
+        // Both parts have the type of the array class at this point
+        let Some(data) = real_part.into_data(imag_part) else {
+            return Err(failure(i, ParseErrorKind::MismatchedParts));
+        };
+        Ok((
+            i,
+            DataElement::NumericMatrix(Numeric {
+                header,
+                data,
+                imag_skipped,
+            }),
+        ))
+    }
+}
+
+/// Checks that size and type of a part of a numeric array are correct, and
+/// converts it into the type of the array class. Errors are reported at `i`.
+fn check_numeric_part(
+    ctx: Context<'_>,
+    header: &ArrayHeader,
+    part: NumericPart,
+    subelement: &'static str,
+    i: &[u8],
+) -> Result<NumericPart, nom::Err<ParseError>> {
+    let Some(num_required_elements) = header.dimensions.numel() else {
+        return Err(failure(i, ParseErrorKind::TooManyElements));
+    };
+    let array_data_type = header.flags.class.numeric_data_type().unwrap();
+    if part.len() != num_required_elements {
+        return Err(failure(i, ParseErrorKind::SizeMismatch { subelement }));
+    }
+    // Unless the options ask for the automatic compression rules to be
+    // followed, any type is accepted as long as the conversion into the
+    // array class below doesn't change the values
+    let is_compatible = numeric_data_types_are_compatible(array_data_type, part.data_type());
+    if part.len() > 0 && ctx.options.strict_numeric_types && !is_compatible {
+        return Err(failure(
+            i,
+            ParseErrorKind::UnexpectedDataType {
+                subelement,
+                data_type: part.data_type(),
+            },
+        ));
+    }
+    // The imaginary part is stored like the real part, one warning is enough
+    if part.len() > 0 && !is_compatible && subelement == "real part" {
+        ctx.warn(WarningKind::NumericTypeCoerced {
+            class: header.flags.class,
+            data_type: part.data_type(),
+        });
+    }
+    // Convert the data into the type of the array class
+    part.widen(array_data_type)
+        .ok_or_else(|| failure(i, ParseErrorKind::LossyConversion { subelement }))
+}
+
+fn parse_character_array(
+    endianness: nom::number::Endianness,
+    header: ArrayHeader,
+) -> impl FnOnce(&[u8]) -> IResult<&[u8], DataElement> {
+    move |i: &[u8]| {
+        let (i, real_part) = parse_character_array_data(endianness, &header.dimensions)(i)?;
+        let (i, imag_part) = cond(
+            header.flags.complex,
+            parse_character_array_data(endianness, &header.dimensions),
+        )(i)?;
+
+        Ok((
+            i,
+            DataElement::CharacterMatrix(Character {
+                header,
+                real_part,
+                imag_part,
+            }),
+        ))
+    }
+}
+
+fn parse_character_array_data(
+    endianness: nom::number::Endianness,
+    dimensions: &Dimensions,
+) -> impl Fn(&[u8]) -> IResult<&[u8], CharacterData> + '_ {
+    move |i| {
+        let (i, data_element_tag) = parse_data_element_tag(endianness)(i)?;
+
+        let Some(cells) = dimensions.numel() else {
+            return Err(failure(i, ParseErrorKind::TooManyElements));
+        };
+
+        let (i, buf) = take(data_element_tag.data_byte_size)(i)?;
+
+        // Empty arrays may be stored with any data type
+        if cells == 0 && buf.is_empty() {
+            return Ok((i, CharacterData::Unicode(String::new())));
+        }
+
+        // The data has to consist of whole code units. The error points at
+        // the data, so its length is the offending size.
+        let check_unit_size = |size: usize| {
+            if buf.len() % size == 0 {
+                Ok(())
+            } else {
+                Err(failure(
+                    i,
+                    ParseErrorKind::SizeMismatch {
+                        subelement: "character data",
+                    },
+                ))
+            }
+        };
+
+        // The whole data is decoded, whatever the dimensions say
+        let data = match data_element_tag.data_type {
+            // The code units of uint16 data are UTF-16 like in the Utf16 case
+            DataType::UInt16 | DataType::Utf16 => {
+                check_unit_size(2)?;
+                let units = buf
+                    .chunks_exact(2)
+                    .map(|b| match endianness {
+                        nom::number::Endianness::Big => u16::from_be_bytes([b[0], b[1]]),
+                        _ => u16::from_le_bytes([b[0], b[1]]),
+                    })
+                    .collect();
+                CharacterData::from_utf16(units)
+            }
+            DataType::Utf8 => {
+                let Ok(str) = String::from_utf8(buf.to_vec()) else {
+                    return Err(failure(
+                        i,
+                        ParseErrorKind::InvalidText {
+                            subelement: "character data",
+                        },
+                    ));
+                };
+                CharacterData::Unicode(str)
+            }
+            DataType::Utf32 => {
+                check_unit_size(4)?;
+                let str: Option<String> = buf
+                    .chunks_exact(4)
+                    .map(|b| {
+                        char::from_u32(match endianness {
+                            nom::number::Endianness::Big => {
+                                u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+                            }
+                            _ => u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+                        })
+                    })
+                    .collect();
+                let Some(str) = str else {
+                    return Err(failure(
+                        i,
+                        ParseErrorKind::InvalidText {
+                            subelement: "character data",
+                        },
+                    ));
+                };
+                CharacterData::Unicode(str)
+            }
+            _ => {
+                return Err(failure(
+                    i,
+                    ParseErrorKind::UnexpectedDataType {
+                        subelement: "character data",
+                        data_type: data_element_tag.data_type,
+                    },
+                ));
+            }
+        };
+
+        // Matlab counts UTF-16 code units in the dimensions, so characters
+        // outside the BMP take up two elements. Some writers count them as
+        // one element instead.
+        if !(data.utf16_len() == cells || data.char_count() == cells) {
+            return Err(failure(
+                i,
+                ParseErrorKind::SizeMismatch {
+                    subelement: "character data",
+                },
+            ));
+        }
+
+        Ok((i, data))
+    }
+}
+
+fn parse_sparse_matrix_subelements(
+    ctx: Context<'_>,
+    header: ArrayHeader,
+) -> impl FnOnce(&[u8]) -> IResult<&[u8], DataElement> + '_ {
+    move |i: &[u8]| {
+        let endianness = ctx.endianness;
+        // Figure out the type of array
+        let (i, mut row_index) = parse_row_index_array_subelement(endianness)(i)?;
+        let (i, column_index) = parse_column_index_array_subelement(endianness)(i)?;
+        // Matlab preallocates storage for nzmax values, of which only the
+        // first nnz are used. Only those are kept.
+        let nnz = column_index.last().copied().unwrap_or(0);
+        let stored_len_is_valid = |len: usize| nnz <= len && len <= header.flags.nzmax;
+        if !stored_len_is_valid(row_index.len()) {
+            return Err(failure(
+                i,
+                ParseErrorKind::SizeMismatch {
+                    subelement: "row index",
+                },
+            ));
+        }
+        row_index.truncate(nnz);
+        // Some writers omit the values of logical sparse matrices, which are
+        // all true then
+        let (i, mut real_part) = if header.flags.logical && i.is_empty() {
+            (i, NumericPart::UInt8(vec![1; nnz]))
+        } else {
+            parse_numeric_subelement(endianness)(i)?
+        };
+        // Check that size of the real part is correct (can't check for type in sparse matrices)
+        if !stored_len_is_valid(real_part.len()) {
+            return Err(failure(
+                i,
+                ParseErrorKind::SizeMismatch {
+                    subelement: "real part",
+                },
+            ));
+        }
+        real_part.truncate(nnz);
+        // Non-logical sparse matrices always hold doubles, but their data may
+        // still be stored using a smaller type
+        let widen = |data: NumericPart| {
+            if header.flags.logical {
+                Some(data)
+            } else {
+                data.widen(DataType::Double)
+            }
+        };
+        let Some(real_part) = widen(real_part) else {
+            return Err(failure(
+                i,
+                ParseErrorKind::LossyConversion {
+                    subelement: "real part",
+                },
+            ));
+        };
+        let imag_skipped = header.flags.complex && !ctx.options.load_imaginary;
+        let (i, _) = cond(imag_skipped, skip_numeric_subelement(endianness))(i)?;
+        let (i, imag_part) = cond(
+            header.flags.complex && !imag_skipped,
+            parse_numeric_subelement(endianness),
+        )(i)?;
+        // Check that size of the imaginary part is correct if present (can't check for type in sparse matrices)
+        let imag_part = match imag_part {
+            Some(mut imag_part) => {
+                if !stored_len_is_valid(imag_part.len()) {
+                    return Err(failure(
+                        i,
+                        ParseErrorKind::SizeMismatch {
+                            subelement: "imaginary part",
+                        },
+                    ));
+                }
+                imag_part.truncate(nnz);
+                let Some(imag_part) = widen(imag_part) else {
+                    return Err(failure(
+                        i,
+                        ParseErrorKind::LossyConversion {
+                            subelement: "imaginary part",
+                        },
+                    ));
+                };
+                Some(imag_part)
+            }
+            None => None,
+        };
+        let Some(data) = real_part.into_data(imag_part) else {
+            return Err(failure(i, ParseErrorKind::MismatchedParts));
+        };
+        Ok((
+            i,
+            DataElement::SparseMatrix(Sparse {
+                header,
+                row_index,
+                column_index,
+                data,
+                imag_skipped,
+            }),
+        ))
+    }
+}
+
+fn parse_row_index_array_subelement(
+    endianness: nom::number::Endianness,
+) -> impl Fn(&[u8]) -> IResult<&[u8], RowIndex> {
+    move |i: &[u8]| {
+        let (i, data_element_tag) = parse_data_element_tag(endianness)(i)?;
+        // Sparse matrices without non-zero values may have no row indices
+        if data_element_tag.data_type != DataType::Int32 {
+            return Err(failure(
+                i,
+                ParseErrorKind::UnexpectedDataType {
+                    subelement: "row index",
+                    data_type: data_element_tag.data_type,
+                },
+            ));
+        }
+        let Some(len) = data_element_tag.value_count() else {
+            return Err(partial_value_error(i, "row index"));
+        };
+        let (i, row_index) = count(i32(endianness), len)(i)?;
+        let (i, _) = take(data_element_tag.padding_byte_size)(i)?;
+        Ok((i, row_index.iter().map(|&i| i as usize).collect()))
+    }
+}
+
+fn parse_column_index_array_subelement(
+    endianness: nom::number::Endianness,
+) -> impl Fn(&[u8]) -> IResult<&[u8], ColumnShift> {
+    move |i: &[u8]| {
+        let (i, data_element_tag) = parse_data_element_tag(endianness)(i)?;
+        if data_element_tag.data_type != DataType::Int32 {
+            return Err(failure(
+                i,
+                ParseErrorKind::UnexpectedDataType {
+                    subelement: "column index",
+                    data_type: data_element_tag.data_type,
+                },
+            ));
+        }
+        if data_element_tag.data_byte_size == 0 {
+            return Err(failure(
+                i,
+                ParseErrorKind::SizeMismatch {
+                    subelement: "column index",
+                },
+            ));
+        }
+        let Some(len) = data_element_tag.value_count() else {
+            return Err(partial_value_error(i, "column index"));
+        };
+        let (i, column_index) = count(i32(endianness), len)(i)?;
+        let (i, _) = take(data_element_tag.padding_byte_size)(i)?;
+        Ok((i, column_index.iter().map(|&i| i as usize).collect()))
+    }
+}
+
+fn parse_array_header<'a>(
+    ctx: Context<'a>,
+    supplied_name: ElementName<'a>,
+) -> impl Fn(&[u8]) -> IResult<&[u8], ArrayHeader> + 'a {
+    move |i| {
+        let endianness = ctx.endianness;
+        let (i, flags) = parse_array_flags_subelement(endianness)(i)?;
+        // Opaque objects have no dimensions subelement, their dimensions are
+        // part of the object metadata following the name
+        let (i, dimensions) = if flags.class == ArrayType::Opaque {
+            (i, Dimensions::from(vec![1, 1]))
+        } else {
+            parse_dimensions_array_subelement(endianness)(i)?
+        };
+        let (i, name) = maybe_parse_array_name_subelement(ctx, supplied_name)(i)?;
+
+        Ok((
+            i,
+            ArrayHeader {
+                flags,
+                dimensions,
+                name,
+            },
+        ))
+    }
+}
+
+fn parse_struct(
+    ctx: Context<'_>,
+    header: ArrayHeader,
+) -> impl FnOnce(&[u8]) -> IResult<&[u8], Structure> + '_ {
+    move |i| {
+        let (i, max_length) = parse_struct_field_name_length(ctx.endianness)(i)?;
+        let (i, field_names) = parse_struct_names(ctx, max_length)(i)?;
+        let Some(numel) = header.dimensions.numel() else {
+            return Err(failure(i, ParseErrorKind::TooManyElements));
+        };
+        let (i, values) = parse_struct_fields(ctx, &field_names, numel)(i)?;
+
+        Ok((
+            i,
+            Structure {
+                header,
+                field_names,
+                values,
+            },
+        ))
+    }
+}
+
+fn parse_cell(
+    ctx: Context<'_>,
+    header: ArrayHeader,
+) -> impl FnOnce(&[u8]) -> IResult<&[u8], Cell> + '_ {
+    move |i| {
+        let Some(numel) = header.dimensions.numel() else {
+            return Err(failure(i, ParseErrorKind::TooManyElements));
+        };
+        // Each cell is a complete matrix element with a tag of its own, so
+        // there can't be more cells than tags fitting into the data
+        if numel > i.len() / 8 {
+            return Err(failure(i, ParseErrorKind::TooManyElements));
+        }
+        // Each cell is a complete matrix element, stored in column-major order
+        let (i, values) = count(parse_next_data_element(ctx, ElementName::Cell), numel)(i)?;
+
+        Ok((i, Cell { header, values }))
+    }
+}
+
+fn parse_opaque(
+    ctx: Context<'_>,
+    mut header: ArrayHeader,
+) -> impl FnOnce(&[u8]) -> IResult<&[u8], Opaque> + '_ {
+    move |i| {
+        let (i, type_system) = parse_array_name_subelement(ctx)(i)?;
+        let (i, class_name) = parse_array_name_subelement(ctx)(i)?;
+        if let Ok((
+            _,
+            DataElement::NumericMatrix(Numeric {
+                data: NumericData::UInt32 { real, .. },
+                ..
+            }),
+        )) = parse_next_data_element(ctx, ElementName::Cell)(i)
+        {
+            if let Some(dimensions) = object_dimensions(&real) {
+                header.dimensions = dimensions;
+            }
+        }
+
+        Ok((
+            &[],
+            Opaque {
+                header,
+                type_system: type_system.unwrap_or_default(),
+                class_name: class_name.unwrap_or_default(),
+                data: i.to_vec(),
+                byte_order: byte_order(ctx.endianness),
+            },
+        ))
+    }
+}
+
+/// Reads the dimensions from the metadata of MCOS objects, which starts with
+/// a magic number followed by the number of dimensions and the dimensions.
+fn object_dimensions(metadata: &[u32]) -> Option<Dimensions> {
+    const MAGIC: u32 = 0xDD00_0000;
+    match metadata {
+        [MAGIC, ndims, rest @ ..] if *ndims >= 2 => Some(Dimensions::from(
+            rest.get(..*ndims as usize)?
+                .iter()
+                .map(|&d| d as usize)
+                .collect::<Vec<_>>(),
+        )),
+        _ => None,
+    }
+}
+
+fn parse_struct_field_name_length(
+    endianness: nom::number::Endianness,
+) -> impl Fn(&[u8]) -> IResult<&[u8], usize> {
+    move |i| {
+        let (i, numeric) = parse_numeric_subelement(endianness)(i)?;
+        // The length is a single integer. Negative lengths fail to widen.
+        let length = match numeric {
+            NumericPart::Single(_) | NumericPart::Double(_) => None,
+            numeric => match numeric.widen(DataType::UInt64) {
+                Some(NumericPart::UInt64(vec)) => match vec.as_slice() {
+                    [length] => usize::try_from(*length).ok(),
+                    _ => None,
+                },
+                _ => None,
+            },
+        };
+        let Some(length) = length else {
+            return Err(failure(i, ParseErrorKind::InvalidFieldNameLength));
+        };
+        Ok((i, length))
+    }
+}
+
+fn parse_struct_names(
+    ctx: Context<'_>,
+    max_length: usize,
+) -> impl Fn(&[u8]) -> IResult<&[u8], Vec<String>> + '_ {
+    move |i| {
+        let (i, data_element_tag) = parse_data_element_tag(ctx.endianness)(i)?;
+
+        if data_element_tag.data_type != DataType::Int8 {
+            return Err(failure(
+                i,
+                ParseErrorKind::UnexpectedDataType {
+                    subelement: "field names",
+                    data_type: data_element_tag.data_type,
+                },
+            ));
+        }
+
+        let (i, data) = take(data_element_tag.data_byte_size)(i)?;
+        let (i, _) = take(data_element_tag.padding_byte_size)(i)?;
+
+        // Structures without fields store no names
+        if data.is_empty() {
+            return Ok((i, Vec::new()));
+        }
+        if max_length == 0 {
+            return Err(failure(i, ParseErrorKind::InvalidFieldNameLength));
+        }
+
+        let value_count = data.len() / max_length;
+        let mut result = Vec::with_capacity(value_count);
+
+        for idx in 0..value_count {
+            let buf = &data[max_length * idx..][..max_length];
+
+            // Names are padded with null bytes unless they fill the slot
+            let name = buf
+                .iter()
+                .position(|&b| b == 0)
+                .map_or(buf, |end| &buf[..end]);
+
+            result.push(decode_name(ctx, i, name, "field names")?);
+        }
+
+        Ok((i, result))
+    }
+}
+
+/// Decodes a variable or field name, which Matlab always writes as UTF-8.
+/// Invalid bytes are replaced unless the options ask for names to be
+/// validated.
+fn decode_name(
+    ctx: Context<'_>,
+    i: &[u8],
+    name: &[u8],
+    subelement: &'static str,
+) -> Result<String, nom::Err<ParseError>> {
+    match std::str::from_utf8(name) {
+        Ok(name) => Ok(name.to_string()),
+        Err(_) if !ctx.options.validate_utf8_names => {
+            ctx.warn(WarningKind::InvalidName { subelement });
+            Ok(String::from_utf8_lossy(name).into_owned())
+        }
+        Err(_) => Err(failure(i, ParseErrorKind::InvalidText { subelement })),
+    }
+}
+
+fn parse_struct_field<'a>(
+    ctx: Context<'a>,
+    name: &'a str,
+) -> impl Fn(&[u8]) -> IResult<&[u8], DataElement> + 'a {
+    parse_next_data_element(ctx, ElementName::Field(name))
+}
+
+// Reads the values of all fields of the first element, followed by those of
+// the second element etc.
+fn parse_struct_fields<'a>(
+    ctx: Context<'a>,
+    names: &'a [String],
+    numel: usize,
+) -> impl Fn(&[u8]) -> IResult<&[u8], Vec<DataElement>> + 'a {
+    move |i| {
+        // Without fields there are no values, whatever the dimensions
+        if names.is_empty() {
+            return Ok((i, Vec::new()));
+        }
+        // Each value is a complete matrix element with a tag of its own
+        if numel
+            .checked_mul(names.len())
+            .is_none_or(|values| values > i.len() / 8)
+        {
+            return Err(failure(i, ParseErrorKind::TooManyElements));
+        }
+
+        let mut result = Vec::with_capacity(names.len());
+
+        let mut i = i;
+
+        for _ in 0..numel {
+            for name in names {
+                let (j, val) = parse_struct_field(ctx, name)(i)?;
+                result.push(val);
+                i = j;
+            }
+        }
+
+        Ok((i, result))
+    }
+}
+
+fn parse_unsupported_data_element(
+    endianness: nom::number::Endianness,
+    data_type: DataType,
+    header: Option<ArrayHeader>,
+) -> impl Fn(&[u8]) -> IResult<&[u8], DataElement> {
+    move |i: &[u8]| {
+        Ok((
+            &[],
+            DataElement::Unsupported(UnsupportedArray {
+                header: header.clone(),
+                data_type,
+                data: i.to_vec(),
+                class_id: None,
+                name: None,
+                byte_order: byte_order(endianness),
+            }),
+        ))
+    }
+}
+
+/// Skips an array of a class the parser doesn't know, keeping its bytes. Its
+/// layout is unknown, so the name is only picked up if the array starts with
+/// the usual dimensions and name subelements.
+fn parse_unknown_class_array<'a>(
+    ctx: Context<'a>,
+    supplied_name: ElementName<'a>,
+    class_id: u8,
+) -> impl Fn(&[u8]) -> IResult<&[u8], DataElement> + 'a {
+    move |input: &[u8]| {
+        let (i, _) = parse_raw_array_flags_subelement(ctx.endianness)(input)?;
+        let name = parse_dimensions_array_subelement(ctx.endianness)(i)
+            .and_then(|(i, _)| maybe_parse_array_name_subelement(ctx, supplied_name)(i))
+            .ok()
+            .map(|(_, name)| name);
+        Ok((
+            &[],
+            DataElement::Unsupported(UnsupportedArray {
+                header: None,
+                data_type: DataType::Matrix,
+                data: input.to_vec(),
+                class_id: Some(class_id),
+                name,
+                byte_order: byte_order(ctx.endianness),
+            }),
+        ))
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseResult {
+    pub header: Header,
+    pub data_elements: Vec<DataElement>,
+    /// The number of bytes following the last data element that couldn't be
+    /// parsed. This is only ever non-zero if
+    /// [`ParseOptions::allow_trailing_bytes`] is set.
+    pub trailing_bytes: usize,
+    /// Problems that didn't keep the file from being parsed, in the order
+    /// they were found.
+    pub warnings: Vec<Warning>,
+}
+
+/// A short description of a variable, as listed by [`ParseResult::summary`].
+#[derive(Clone, Copy, Debug)]
+pub struct VariableSummary<'a> {
+    pub name: &'a str,
+    pub class: ArrayType,
+    pub dimensions: &'a Dimensions,
+    /// Approximate size of the variable's data in bytes
+    pub byte_size: usize,
+}
+
+impl ParseResult {
+    /// Iterates over all named data elements together with their names.
+    ///
+    /// Unsupported elements that aren't arrays are skipped since they don't
+    /// carry a name, as is the unnamed subsystem data.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &DataElement)> {
+        self.data_elements.iter().filter_map(|data_element| {
+            Some((
+                data_element.name().filter(|name| !name.is_empty())?,
+                data_element,
+            ))
+        })
+    }
+
+    /// Removes the first data element named `name` and returns it, without
+    /// copying its data.
+    pub fn take(&mut self, name: &str) -> Option<DataElement> {
+        let idx = self
+            .data_elements
+            .iter()
+            .position(|data_element| data_element.name() == Some(name))?;
+        Some(self.data_elements.remove(idx))
+    }
+
+    /// Lists the name, class, dimensions and approximate size of every
+    /// variable, similar to Matlab's `whos` command.
+    pub fn summary(&self) -> Vec<VariableSummary<'_>> {
+        self.data_elements
+            .iter()
+            .filter_map(|data_element| {
+                let header = data_element
+                    .header()
+                    .filter(|header| !header.name.is_empty())?;
+                Some(VariableSummary {
+                    name: &header.name,
+                    class: header.flags.class,
+                    dimensions: &header.dimensions,
+                    byte_size: data_element.byte_size(),
+                })
+            })
+            .collect()
+    }
+}
+
+impl IntoIterator for ParseResult {
+    type Item = (String, DataElement);
+    type IntoIter = IntoIter;
+
+    /// Consumes the result, yielding all named data elements together with
+    /// their names. Like [`ParseResult::iter`], unnamed elements are
+    /// skipped.
+    fn into_iter(self) -> IntoIter {
+        IntoIter {
+            data_elements: self.data_elements.into_iter(),
+        }
+    }
+}
+
+/// An iterator over the owned data elements of a [`ParseResult`].
+#[derive(Debug)]
+pub struct IntoIter {
+    data_elements: std::vec::IntoIter<DataElement>,
+}
+
+impl Iterator for IntoIter {
+    type Item = (String, DataElement);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.data_elements.by_ref().find_map(|data_element| {
+            let name = data_element
+                .name()
+                .filter(|name| !name.is_empty())?
+                .to_string();
+            Some((name, data_element))
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.data_elements.size_hint().1)
+    }
+}
+
+pub fn parse_all(input: &[u8]) -> Result<ParseResult, crate::Error> {
+    parse_all_with_options(input, &ParseOptions::default())
+}
+
+/// Like [`parse_all`], but with the given options instead of the default
+/// ones.
+pub fn parse_all_with_options(
+    input: &[u8],
+    options: &ParseOptions,
+) -> Result<ParseResult, crate::Error> {
+    parse_all_with_context(input, options, &mut ParseContext::new())
+}
+
+/// A compressed variable that was parsed ahead of the others.
+struct DecodedElement<'a> {
+    /// The offset of the element in the input
+    offset: usize,
+    result: IResult<&'a [u8], DataElement>,
+    warnings: Vec<WarningKind>,
+}
+
+/// Parses the compressed variables of a file on the calling thread and the
+/// cores left in the budget of [`SpareThreads`]. The input is
+/// split at the tags of the variables first, up to the first tag that
+/// doesn't look right, which is left to the caller to report.
+#[cfg(feature = "parallel")]
+fn decode_compressed_elements<'a>(
     endianness: nom::number::Endianness,
-) -> impl Fn(&[u8]) -> IResult<&[u8], DataElementTag> {
-    move |i: &[u8]| {
-        let (i, starting_bytes) = u32(endianness)(i)?;
-        let (i, data_type, byte_size, padding_byte_size) = if starting_bytes & 0xFFFF0000 == 0 {
-            // Long Data Element Format
-            let data_type = starting_bytes;
-            let (i, byte_size) = u32(endianness)(i)?;
-            let padding_byte_size = ceil_to_multiple(byte_size, 8) - byte_size;
-            (i, data_type, byte_size, padding_byte_size)
-        } else {
-            // Small Data Element Format
-            let data_type = starting_bytes & 0x0000FFFF;
-            let byte_size = (starting_bytes & 0xFFFF0000) >> 16;
-            // Assert that byte_size is <= 4
-            if byte_size > 4 {
-                return Err(nom::Err::Failure(error_position!(
-                    i,
-                    // TODO
-                    nom::error::ErrorKind::Tag
-                )));
+    options: &ParseOptions,
+    context: &mut ParseContext,
+    input: &'a [u8],
+    subsystem_offset: Option<usize>,
+) -> Vec<DecodedElement<'a>> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let warnings = RefCell::new(Vec::new());
+    let ctx = Context {
+        endianness,
+        options,
+        depth: 0,
+        warnings: &warnings,
+        buffers: context,
+    };
+    let mut offsets = Vec::new();
+    let mut skipped = Vec::new();
+    let mut offset = 0;
+    while let Some((_, (data_type, size))) =
+        input.get(offset..).and_then(|i| long_tag(endianness, i))
+    {
+        if let Some(rest) = skip_filler(endianness, &input[offset..]) {
+            offset = input.len() - rest.len();
+            continue;
+        }
+        let size = size as usize;
+        let end = match DataType::from_u32(data_type) {
+            Some(DataType::Compressed) => {
+                // The filter is only asked once for each variable
+                let filtered = match subsystem_offset == Some(offset) {
+                    true => None,
+                    false => skip_filtered_element(ctx, &input[offset..]),
+                };
+                match filtered {
+                    Some(element) => skipped.push(DecodedElement {
+                        offset,
+                        result: Ok(element),
+                        warnings: Vec::new(),
+                    }),
+                    None => offsets.push(offset),
+                }
+                offset + 8 + size
             }
-            let padding_byte_size = 4 - byte_size;
-            (i, data_type, byte_size, padding_byte_size)
+            Some(DataType::Matrix) => offset + 8 + size.next_multiple_of(8),
+            _ => break,
         };
-        Ok((
+        if end > input.len() {
+            break;
+        }
+        offset = end;
+    }
+
+    if offsets.is_empty() {
+        return skipped;
+    }
+    let next = AtomicUsize::new(0);
+    let work = |buffers: &ParseContext| {
+        let warnings = RefCell::new(Vec::new());
+        let ctx = Context {
+            endianness,
+            options,
+            depth: 0,
+            warnings: &warnings,
+            buffers,
+        };
+        let mut decoded = Vec::new();
+        while let Some(&offset) = offsets.get(next.fetch_add(1, Ordering::Relaxed)) {
+            let parser = parse_next_data_element(ctx, ElementName::Variable);
+            decoded.push(DecodedElement {
+                offset,
+                result: complete(parser)(&input[offset..]),
+                warnings: warnings.take(),
+            });
+        }
+        decoded
+    };
+    // The calling thread decodes variables too, with the buffers of
+    // `context`, helped by as many threads as the budget has left
+    let helpers = SpareThreads::take(offsets.len() - 1);
+    let max_retained_size = context.max_retained_size;
+    let mut decoded: Vec<DecodedElement> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..helpers.0)
+            .map(|_| {
+                let work = &work;
+                scope.spawn(move || {
+                    work(&ParseContext {
+                        scratch: RefCell::default(),
+                        max_retained_size,
+                    })
+                })
+            })
+            .collect();
+        let mut decoded = skipped;
+        decoded.extend(work(context));
+        for worker in workers {
+            decoded.extend(worker.join().unwrap());
+        }
+        decoded
+    });
+    drop(helpers);
+    decoded.sort_by_key(|element| element.offset);
+    decoded
+}
+
+/// Threads taken from the budget shared by every call of the parser, which
+/// is one less than the number of cores. Calls on several threads at once,
+/// or from inside the threads of another pool, only start the threads left
+/// over, so together they don't start more than there are cores. The
+/// threads are returned to the budget when this is dropped.
+#[cfg(feature = "parallel")]
+struct SpareThreads(usize);
+
+#[cfg(feature = "parallel")]
+impl SpareThreads {
+    fn budget() -> &'static std::sync::atomic::AtomicUsize {
+        static BUDGET: std::sync::OnceLock<std::sync::atomic::AtomicUsize> =
+            std::sync::OnceLock::new();
+        BUDGET.get_or_init(|| {
+            let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+            std::sync::atomic::AtomicUsize::new(cores - 1)
+        })
+    }
+
+    /// Takes up to `wanted` threads, or none if the budget is used up.
+    fn take(wanted: usize) -> Self {
+        use std::sync::atomic::Ordering;
+        let left = Self::budget()
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |left| {
+                Some(left - left.min(wanted))
+            })
+            .unwrap();
+        SpareThreads(left.min(wanted))
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl Drop for SpareThreads {
+    fn drop(&mut self) {
+        Self::budget().fetch_add(self.0, std::sync::atomic::Ordering::AcqRel);
+    }
+}
+
+#[cfg(not(feature = "parallel"))]
+fn decode_compressed_elements<'a>(
+    _: nom::number::Endianness,
+    _: &ParseOptions,
+    _: &mut ParseContext,
+    _: &'a [u8],
+    _: Option<usize>,
+) -> Vec<DecodedElement<'a>> {
+    Vec::new()
+}
+
+/// Like [`parse_all_with_options`], but reuses the buffers of `context`,
+/// which saves allocations when many files are parsed.
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use matfile::parse::{parse_all_with_context, ParseContext, ParseOptions};
+///
+/// let files = [
+///     &include_bytes!("../tests/struct_cell_v7.mat")[..],
+///     include_bytes!("../tests/logical_v7.mat"),
+/// ];
+/// let mut context = ParseContext::new();
+/// let options = ParseOptions::new();
+/// for data in &files {
+///     let result = parse_all_with_context(data, &options, &mut context)?;
+///     assert!(!result.data_elements.is_empty());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn parse_all_with_context(
+    input: &[u8],
+    options: &ParseOptions,
+    context: &mut ParseContext,
+) -> Result<ParseResult, crate::Error> {
+    let elements = parse_elements(input, options, context, true, |_, _| None)?;
+    Ok(ParseResult {
+        header: elements.header,
+        data_elements: elements.data_elements,
+        trailing_bytes: elements.trailing_bytes,
+        warnings: elements.warnings,
+    })
+}
+
+/// A data element returned by [`parse_elements`], which is either a
+/// [`DataElement`] or one that may borrow from the input.
+trait ParsedElement: From<DataElement> {
+    fn name(&self) -> Option<&str>;
+
+    /// The data element, unless it borrows from the input.
+    fn as_owned_mut(&mut self) -> Option<&mut DataElement>;
+}
+
+impl ParsedElement for DataElement {
+    fn name(&self) -> Option<&str> {
+        DataElement::name(self)
+    }
+
+    fn as_owned_mut(&mut self) -> Option<&mut DataElement> {
+        Some(self)
+    }
+}
+
+/// The variables of a file, as parsed by [`parse_elements`].
+struct ParsedElements<E> {
+    header: Header,
+    data_elements: Vec<E>,
+    trailing_bytes: usize,
+    warnings: Vec<Warning>,
+}
+
+/// Parses the header and the variables of a Level 5 file with `options`.
+///
+/// Each variable is parsed by `parse_variable` first, and by
+/// [`parse_next_data_element`] if that returns `None`. Variables that
+/// [`ParseOptions::filter`] skips and the subsystem data are never passed
+/// to it, and neither are compressed variables decoded ahead of time, which
+/// only happens if `decode_ahead` is set.
+fn parse_elements<'a, E: ParsedElement>(
+    input: &'a [u8],
+    options: &ParseOptions,
+    context: &mut ParseContext,
+    decode_ahead: bool,
+    parse_variable: impl Fn(Context<'_>, &'a [u8]) -> Option<IResult<&'a [u8], E>>,
+) -> Result<ParsedElements<E>, crate::Error> {
+    let (mut i, header) = parse_header(input).map_err(header_error)?;
+    let endianness = if header.is_little_endian {
+        nom::number::Endianness::Little
+    } else {
+        nom::number::Endianness::Big
+    };
+    let subsystem_offset = header
+        .subsystem_offset()
+        .and_then(|offset| usize::try_from(offset).ok());
+    let elements_offset = input.len() - i.len();
+    let decoded = match decode_ahead {
+        true => decode_compressed_elements(
+            endianness,
+            options,
+            context,
             i,
-            DataElementTag {
-                data_type: DataType::from_u32(data_type).ok_or(nom::Err::Failure(
-                    nom::error::Error {
-                        input: i,
-                        // TODO
-                        code: nom::error::ErrorKind::Tag,
-                    },
-                ))?,
-                data_byte_size: byte_size,
-                padding_byte_size: padding_byte_size,
+            subsystem_offset.and_then(|offset| offset.checked_sub(elements_offset)),
+        ),
+        false => Vec::new(),
+    };
+    let mut decoded = decoded.into_iter().peekable();
+    let element_warnings = RefCell::new(Vec::new());
+    let ctx = Context {
+        endianness,
+        options,
+        depth: 0,
+        warnings: &element_warnings,
+        buffers: context,
+    };
+    let mut data_elements: Vec<E> = Vec::new();
+    let mut warnings = Vec::new();
+    let mut subsystem_data = None;
+    let elements_len = i.len();
+    loop {
+        // Elements decoded ahead of time are used as if they were parsed here
+        let offset = elements_len - i.len();
+        while decoded.next_if(|element| element.offset < offset).is_some() {}
+        if let Some(rest) = skip_filler(endianness, i) {
+            i = rest;
+            continue;
+        }
+        let parse_next = |i| {
+            complete(parse_next_data_element(ctx, ElementName::Variable))(i)
+                .map(|(rest, data_element)| (rest, E::from(data_element)))
+        };
+        let result = match decoded.next_if(|element| element.offset == offset) {
+            Some(element) => {
+                element_warnings.replace(element.warnings);
+                element
+                    .result
+                    .map(|(rest, data_element)| (rest, E::from(data_element)))
+            }
+            // The subsystem data isn't a variable and is never filtered
+            None if subsystem_offset == Some(input.len() - i.len()) => parse_next(i),
+            None => match skip_filtered_element(ctx, i) {
+                Some((rest, data_element)) => Ok((rest, E::from(data_element))),
+                None => parse_variable(ctx, i).unwrap_or_else(|| parse_next(i)),
             },
-        ))
+        };
+        let (rest, mut data_element) = match result {
+            Ok(result) => result,
+            Err(nom::Err::Error(_)) if i.is_empty() => break,
+            Err(err) if options.skip_corrupt && !options.strict => {
+                let index = data_elements.len();
+                let (rest, name) = skip_corrupt_element(ctx, i);
+                // Whatever was found before the element failed doesn't
+                // apply anymore
+                element_warnings.take();
+                let error = ParseError::from(err).locate(input, index);
+                let name = name.or_else(|| error.variable_name().map(String::from));
+                warnings.push(Warning {
+                    variable_index: Some(index),
+                    variable_name: name.clone(),
+                    kind: WarningKind::CorruptElement,
+                });
+                data_elements.push(E::from(DataElement::Corrupt(CorruptElement {
+                    name,
+                    offset: (input.len() - i.len()) as u64,
+                    error,
+                })));
+                i = rest;
+                continue;
+            }
+            Err(nom::Err::Error(_)) if options.allow_trailing_bytes && !options.strict => {
+                warnings.push(Warning {
+                    variable_index: None,
+                    variable_name: None,
+                    kind: WarningKind::TrailingBytes(i.len()),
+                });
+                break;
+            }
+            // Anything following the last element would otherwise be
+            // ignored without notice
+            Err(err) => {
+                let err = ParseError::from(err).locate(input, data_elements.len());
+                return Err(crate::Error::ParseError(err));
+            }
+        };
+        let variable_name = data_element
+            .name()
+            .filter(|name| !name.is_empty())
+            .map(String::from);
+        for kind in element_warnings.take() {
+            if options.rejects(&kind) {
+                let err = ParseError::new(i, ParseErrorKind::NotAllowed(kind))
+                    .locate(input, data_elements.len());
+                return Err(crate::Error::ParseError(match &variable_name {
+                    Some(name) => err.in_variable(name),
+                    None => err,
+                }));
+            }
+            warnings.push(Warning {
+                variable_index: Some(data_elements.len()),
+                variable_name: variable_name.clone(),
+                kind,
+            });
+        }
+        if subsystem_offset == Some(input.len() - i.len()) {
+            if let Some(DataElement::NumericMatrix(Numeric {
+                data: NumericData::UInt8 { real, .. },
+                ..
+            })) = data_element.as_owned_mut()
+            {
+                let real = std::mem::take(real);
+                subsystem_data = subsystem::Subsystem::parse(ctx, &real);
+                // Whatever the subsystem holds is an implementation detail
+                element_warnings.take();
+                data_element = E::from(DataElement::SubsystemData(real));
+            }
+        }
+        data_elements.push(data_element);
+        i = rest;
+    }
+
+    if let Some(subsystem_data) = subsystem_data {
+        for data_element in &mut data_elements {
+            if let Some(DataElement::Opaque(opaque)) = data_element.as_owned_mut() {
+                if let Some(strings) = subsystem_data.string_array(opaque) {
+                    *data_element = E::from(DataElement::StringArray(strings));
+                }
+            }
+        }
     }
+
+    Ok(ParsedElements {
+        header,
+        data_elements,
+        trailing_bytes: i.len(),
+        warnings,
+    })
 }
 
-fn parse_array_name_subelement(
-    endianness: nom::number::Endianness,
-) -> impl Fn(&[u8]) -> IResult<&[u8], Option<String>> {
-    move |i: &[u8]| {
-        let (i, data_element_tag) = parse_data_element_tag(endianness)(i)?;
+/// Skips the element at the start of `i` if it is filler, which is what
+/// [`crate::write::update_variable`] leaves behind a variable that got
+/// smaller: a uint8 element holding nothing but zeros. Returns the input
+/// following it.
+fn skip_filler(endianness: nom::number::Endianness, i: &[u8]) -> Option<&[u8]> {
+    let (data, (data_type, size)) = long_tag(endianness, i)?;
+    if data_type != DataType::UInt8 as u32 {
+        return None;
+    }
+    let len = usize::try_from(u64::from(size).next_multiple_of(8)).ok()?;
+    let filler = data.get(..len)?;
+    filler.iter().all(|&b| b == 0).then(|| &data[len..])
+}
+
+/// The array flags, dimensions and name of any but huge N-D arrays fit into
+/// this many bytes.
+const NAME_PREFIX: u64 = 1024;
+
+/// Skips the variable at the start of `i` if [`ParseOptions::filter`]
+/// doesn't match its name. Returns `None` if it has to be parsed, which
+/// includes variables whose header can't be read.
+fn skip_filtered_element<'a>(ctx: Context<'_>, i: &'a [u8]) -> Option<(&'a [u8], DataElement)> {
+    let filter = ctx.options.filter.as_ref()?;
+    let (data, tag) = parse_data_element_tag(ctx.endianness)(i).ok()?;
+    let (rest, data) = take::<_, _, ParseError>(tag.data_byte_size)(data).ok()?;
+    let mut prefix = Vec::new();
+    let array = match tag.data_type {
+        DataType::Matrix => data,
+        DataType::Compressed => {
+            // A stream that fails early is left to the usual error handling
+            Decoder::new(data)
+                .and_then(|decoder| decoder.take(8 + NAME_PREFIX).read_to_end(&mut prefix))
+                .ok()?;
+            prefix.get(8..)?
+        }
+        _ => return None,
+    };
+    let warnings = ctx.warnings.borrow().len();
+    let header = parse_array_header(ctx, ElementName::Variable)(array).map(|(_, header)| header);
+    ctx.warnings.borrow_mut().truncate(warnings);
+    let header = header.ok()?;
+    if filter.matches(&header.name) {
+        return None;
+    }
+    let rest = match tag.data_type {
+        DataType::Compressed => rest,
+        _ => {
+            opt(complete(take::<_, _, ParseError>(tag.padding_byte_size)))(rest)
+                .ok()?
+                .0
+        }
+    };
+    Some((rest, DataElement::Skipped(header)))
+}
+
+/// Skips a variable that failed to parse, using the size given in its tag.
+/// Returns the input following it, which is empty if the element is
+/// truncated or its tag is corrupt, and its name if its header can be read.
+fn skip_corrupt_element<'a>(ctx: Context<'_>, i: &'a [u8]) -> (&'a [u8], Option<String>) {
+    // The name is only stored in plain sight if the element isn't compressed
+    let name = match u32::<_, ParseError>(ctx.endianness)(i) {
+        Ok((_, data_type)) if data_type == DataType::Matrix as u32 => i
+            .get(8..)
+            .and_then(|data| parse_array_header(ctx, ElementName::Variable)(data).ok())
+            .map(|(_, header)| header.name),
+        _ => None,
+    };
+    let rest = parse_data_element_tag(ctx.endianness)(i)
+        .and_then(|(i, tag)| {
+            let (i, _) = take(tag.data_byte_size)(i)?;
+            if tag.data_type == DataType::Compressed {
+                Ok(i)
+            } else {
+                opt(complete(take(tag.padding_byte_size)))(i).map(|(i, _)| i)
+            }
+        })
+        .unwrap_or(&[]);
+    (rest, name)
+}
+
+fn header_error(err: nom::Err<HeaderError>) -> crate::Error {
+    match err {
+        nom::Err::Error(err) | nom::Err::Failure(err) => crate::Error::InvalidHeader(err),
+        nom::Err::Incomplete(_) => {
+            crate::Error::InvalidHeader(HeaderError::Invalid(nom::error::ErrorKind::Eof))
+        }
+    }
+}
+
+/// The layout of a file as read by [`scan_elements`].
+pub(crate) struct ElementScan {
+    pub(crate) header: Header,
+    /// The data elements in the order they are stored in.
+    pub(crate) elements: Vec<ScannedElement>,
+    /// The offset right after the last element, including its padding even
+    /// if the file ends before it.
+    pub(crate) end: u64,
+}
+
+/// Where a data element is stored, as read by [`scan_elements`].
+pub(crate) struct ScannedElement {
+    /// The name of the variable, if names were read and the element has a
+    /// readable one. The subsystem data, for example, has none.
+    pub(crate) name: Option<String>,
+    /// The header of the array, if names were read and it is readable.
+    pub(crate) array: Option<ArrayHeader>,
+    pub(crate) compressed: bool,
+    /// The offset of the tag from the start of the file.
+    pub(crate) offset: u64,
+    /// The offset right after the element, including its padding.
+    pub(crate) end: u64,
+}
+
+/// Reads the header and the tags of the data elements of a Level 5 file,
+/// seeking over their data. With `read_names`, the start of each variable
+/// is read as well, decompressing only as much of compressed variables as
+/// their name needs.
+pub(crate) fn scan_elements<R: Read + std::io::Seek>(
+    reader: &mut R,
+    read_names: bool,
+) -> Result<ElementScan, crate::Error> {
+    use std::io::SeekFrom;
+
+    let len = reader
+        .seek(SeekFrom::End(0))
+        .map_err(crate::Error::IOError)?;
+    reader
+        .seek(SeekFrom::Start(0))
+        .map_err(crate::Error::IOError)?;
+    let mut input = Vec::with_capacity(128);
+    reader
+        .by_ref()
+        .take(128)
+        .read_to_end(&mut input)
+        .map_err(crate::Error::IOError)?;
+    if is_hdf5(&input) {
+        return Err(crate::Error::Hdf5);
+    }
+    let (_, header) = parse_header(&input).map_err(header_error)?;
+    let endianness = if header.is_little_endian {
+        nom::number::Endianness::Little
+    } else {
+        nom::number::Endianness::Big
+    };
+    let options = ParseOptions::default();
+    let warnings = RefCell::new(Vec::new());
+    let buffers = ParseContext::new();
+    let ctx = Context {
+        endianness,
+        options: &options,
+        depth: 0,
+        warnings: &warnings,
+        buffers: &buffers,
+    };
+
+    let mut elements = Vec::new();
+    let mut offset = 128;
+    let mut elements_end = offset;
+    // Anything shorter than a tag is padding some writers add at the end
+    for index in 0.. {
+        if len - offset < 8 {
+            break;
+        }
+        let mut tag = [0; 8];
+        reader.read_exact(&mut tag).map_err(crate::Error::IOError)?;
+        let word = |bytes: &[u8]| {
+            let bytes = <[u8; 4]>::try_from(bytes).unwrap();
+            match endianness {
+                nom::number::Endianness::Big => u32::from_be_bytes(bytes),
+                _ => u32::from_le_bytes(bytes),
+            }
+        };
+        let (data_type, size) = if word(&tag[..4]) >> 16 == 0 {
+            (word(&tag[..4]), u64::from(word(&tag[4..])))
+        } else {
+            // The data of the small format is part of the tag
+            (word(&tag[..4]) & 0xFFFF, 0)
+        };
+        let data_type = DataType::from_u32(data_type).ok_or_else(|| {
+            let kind = ParseErrorKind::UnknownDataType(data_type);
+            crate::Error::ParseError(ParseError::at(kind, offset, index))
+        })?;
+        let data_start = offset + 8;
+        let end = if data_type == DataType::Compressed {
+            data_start + size
+        } else {
+            data_start + size.next_multiple_of(8)
+        };
+        if data_start + size > len {
+            let error = ParseError::at(ParseErrorKind::UnexpectedEof, offset, index);
+            return Err(crate::Error::ParseError(error));
+        }
+        let mut name = None;
+        let mut array_header = None;
+        if read_names {
+            let mut prefix = Vec::new();
+            let array = match data_type {
+                DataType::Matrix => {
+                    reader
+                        .by_ref()
+                        .take(size.min(NAME_PREFIX))
+                        .read_to_end(&mut prefix)
+                        .map_err(crate::Error::IOError)?;
+                    Some(prefix.as_slice())
+                }
+                DataType::Compressed => {
+                    let compressed = reader.by_ref().take(size);
+                    // A stream that fails early just has no readable name
+                    let _ = Decoder::new(compressed)
+                        .and_then(|decoder| decoder.take(8 + NAME_PREFIX).read_to_end(&mut prefix));
+                    prefix.get(8..)
+                }
+                _ => None,
+            };
+            array_header = array
+                .and_then(|array| parse_array_header(ctx, ElementName::Variable)(array).ok())
+                .map(|(_, header)| header);
+            name = array_header
+                .as_ref()
+                .map(|header| header.name.clone())
+                .filter(|name| !name.is_empty());
+            warnings.take();
+        }
+        elements.push(ScannedElement {
+            name,
+            array: array_header,
+            compressed: data_type == DataType::Compressed,
+            offset,
+            end,
+        });
+        elements_end = end;
+        offset = end.min(len);
+        reader
+            .seek(SeekFrom::Start(offset))
+            .map_err(crate::Error::IOError)?;
+    }
+    Ok(ElementScan {
+        header,
+        elements,
+        end: elements_end,
+    })
+}
+
+/// Where the variables of a file are stored, for loading single variables
+/// without parsing the whole file.
+///
+/// Scanning only reads the tags of the data elements and the headers of the
+/// arrays, decompressing just the start of compressed variables.
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use matfile::parse::MatIndex;
+///
+/// let data = include_bytes!("../tests/two_arrays.mat");
+/// let index = MatIndex::scan(data)?;
+/// for entry in index.entries() {
+///     println!("{} is {:?}", entry.name, entry.dimensions);
+/// }
+/// let name = &index.entries()[1].name;
+/// let data_element = index.load(data, name)?;
+/// assert_eq!(data_element.name(), Some(name.as_str()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct MatIndex {
+    header: Header,
+    entries: Vec<IndexEntry>,
+}
+
+/// A variable listed in a [`MatIndex`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexEntry {
+    pub name: String,
+    pub class: ArrayType,
+    pub dimensions: Dimensions,
+    /// Where the data element is stored in the file, from its tag to the
+    /// end of its padding
+    pub byte_range: std::ops::Range<u64>,
+    /// Whether the data element is compressed
+    pub compressed: bool,
+    /// The index of the data element in the file
+    index: usize,
+}
+
+impl MatIndex {
+    /// Reads the header of a Level 5 file and the headers of its variables.
+    /// Elements without a readable array header, such as the subsystem
+    /// data or arrays of unknown classes, aren't listed.
+    pub fn scan(data: &[u8]) -> Result<MatIndex, crate::Error> {
+        let scan = scan_elements(&mut std::io::Cursor::new(data), true)?;
+        let entries = scan
+            .elements
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, element)| {
+                let array = element.array?;
+                Some(IndexEntry {
+                    name: element.name?,
+                    class: array.flags.class,
+                    dimensions: array.dimensions,
+                    byte_range: element.offset..element.end,
+                    compressed: element.compressed,
+                    index,
+                })
+            })
+            .collect();
+        Ok(MatIndex {
+            header: scan.header,
+            entries,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// The variables in the order they are stored in.
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    /// The first variable with the given name.
+    pub fn get(&self, name: &str) -> Option<&IndexEntry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    /// Parses the variable with the given name from `data`, which has to be
+    /// the data the index was scanned from. Fails with
+    /// [`crate::Error::NotFound`] if there is no such variable.
+    ///
+    /// Matlab strings are loaded as [`DataElement::Opaque`], as their
+    /// contents are stored in the subsystem data.
+    pub fn load(&self, data: &[u8], name: &str) -> Result<DataElement, crate::Error> {
+        self.load_with_options(data, name, &ParseOptions::default())
+    }
+
+    /// Like [`MatIndex::load`], but with the given options instead of the
+    /// default ones.
+    pub fn load_with_options(
+        &self,
+        data: &[u8],
+        name: &str,
+        options: &ParseOptions,
+    ) -> Result<DataElement, crate::Error> {
+        let entry = self
+            .get(name)
+            .ok_or_else(|| crate::Error::NotFound(name.to_string()))?;
+        let i = usize::try_from(entry.byte_range.start)
+            .ok()
+            .and_then(|start| data.get(start..))
+            .ok_or_else(|| {
+                let kind = ParseErrorKind::UnexpectedEof;
+                crate::Error::ParseError(ParseError::at(kind, entry.byte_range.start, entry.index))
+            })?;
+        let warnings = RefCell::new(Vec::new());
+        let buffers = ParseContext::new();
+        let ctx = Context {
+            endianness: if self.header.is_little_endian {
+                nom::number::Endianness::Little
+            } else {
+                nom::number::Endianness::Big
+            },
+            options,
+            depth: 0,
+            warnings: &warnings,
+            buffers: &buffers,
+        };
+        let (_, data_element) = complete(parse_next_data_element(ctx, ElementName::Variable))(i)
+            .map_err(|err| {
+                crate::Error::ParseError(ParseError::from(err).locate(data, entry.index))
+            })?;
+        if let Some(kind) = warnings
+            .take()
+            .into_iter()
+            .find(|kind| options.rejects(kind))
+        {
+            let err =
+                ParseError::new(i, ParseErrorKind::NotAllowed(kind)).locate(data, entry.index);
+            return Err(crate::Error::ParseError(err.in_variable(name)));
+        }
+        Ok(data_element)
+    }
+}
+
+/// Whether the input is a v7.3 MAT-file. Those start with the same header
+/// as Level 5 files but with version 0x0200, followed by HDF5 data.
+pub fn is_hdf5(i: &[u8]) -> bool {
+    matches!(
+        i.get(124..128),
+        Some([0x00, 0x02, b'I', b'M']) | Some([0x02, 0x00, b'M', b'I'])
+    )
+}
+
+/// Parses a Level 5 file, falling back to the Level 4 format if the input
+/// isn't one. Errors are reported for the Level 5 format if both fail.
+pub fn parse_auto(i: &[u8]) -> Result<ParseResult, crate::Error> {
+    parse_auto_with_options(i, &ParseOptions::default())
+}
+
+/// Like [`parse_auto`], but with the given options instead of the default
+/// ones.
+pub fn parse_auto_with_options(
+    i: &[u8],
+    options: &ParseOptions,
+) -> Result<ParseResult, crate::Error> {
+    if is_hdf5(i) {
+        #[cfg(feature = "hdf5")]
+        return crate::parse_v73::parse_all_with_options(i, options);
+        #[cfg(not(feature = "hdf5"))]
+        return Err(crate::Error::Hdf5);
+    }
+    parse_all_with_options(i, options).or_else(|err| crate::parse_v4::parse_all(i).map_err(|_| err))
+}
+
+/// Reads the offset of the subsystem data from the header. It is unset if
+/// all bytes are zero or spaces.
+fn parse_subsystem_offset(endianness: nom::number::Endianness, field: &[u8]) -> Option<u64> {
+    if field.iter().all(|&b| b == 0 || b == b' ') {
+        return None;
+    }
+    let (_, offset) = u64::<_, nom::error::Error<_>>(endianness)(field).ok()?;
+    Some(offset)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A context with the default options for calling parsers of single
+    /// subelements directly
+    fn context(endianness: nom::number::Endianness) -> Context<'static> {
+        Context {
+            endianness,
+            options: Box::leak(Box::default()),
+            depth: 0,
+            warnings: Box::leak(Box::default()),
+            buffers: Box::leak(Box::default()),
+        }
+    }
+
+    #[test]
+    fn header_text() {
+        let data = include_bytes!("../tests/double.mat");
+        let (_, header) = parse_header(data).unwrap();
+        assert_eq!(
+            header.text(),
+            "MATLAB 5.0 MAT-file, Platform: MACI64, Created on: Mon Mar 25 21:03:23 2019"
+        );
+        assert_eq!(header.platform(), Some("MACI64"));
+        assert_eq!(header.created(), Some("Mon Mar 25 21:03:23 2019"));
+        assert_eq!(header.byte_order(), ByteOrder::LittleEndian);
+        assert_eq!(header.subsystem_offset(), None);
+
+        let header = Header {
+            text: "MATLAB 5.0 MAT-file written by some other tool".to_string(),
+            is_little_endian: false,
+            raw_text: [0; 116],
+            subsystem_offset: None,
+        };
+        assert_eq!(header.platform(), None);
+        assert_eq!(header.created(), None);
+        assert_eq!(header.byte_order(), ByteOrder::BigEndian);
+    }
+
+    #[test]
+    fn bulk_values() {
+        use nom::number::complete as number;
+        use nom::number::Endianness;
+
+        let bytes: Vec<u8> = (0..64u32).map(|x| (x * 37 + 11) as u8).collect();
+        for &endianness in &[Endianness::Little, Endianness::Big] {
+            macro_rules! check {
+                ( $( $t:ident ),* ) => {
+                    $(
+                        let len = bytes.len() / std::mem::size_of::<$t>() - 1;
+                        let (rest, read) = values::<$t>(endianness, len)(&bytes).unwrap();
+                        let (expected_rest, expected) =
+                            count(number::$t::<_, ()>(endianness), len)(&bytes[..]).unwrap();
+                        assert_eq!(rest, expected_rest);
+                        // Compare the formatted values so that NaNs compare equal
+                        assert_eq!(format!("{:?}", read), format!("{:?}", expected));
+                        assert!(values::<$t>(endianness, len + 2)(&bytes).is_err());
+                    )*
+                };
+            }
+            check!(i16, u16, i32, u32, i64, u64, f32, f64);
+            let (_, read) = values::<u8>(endianness, 3)(&bytes).unwrap();
+            assert_eq!(read, &bytes[..3]);
+            let (_, read) = values::<i8>(endianness, 3)(&bytes).unwrap();
+            assert_eq!(read, vec![11, 48, 85]);
+        }
+    }
+
+    #[test]
+    fn big_endian_numeric_subelements() {
+        use nom::number::Endianness;
+
+        macro_rules! check {
+            ( $( $variant:ident: $t:ty = [ $( $value:expr ),* ] ),* ) => {
+                $(
+                    let values: Vec<$t> = vec![$( $value ),*];
+                    let mut data = Vec::new();
+                    data.extend_from_slice(&(DataType::$variant as u32).to_be_bytes());
+                    data.extend_from_slice(&((values.len() * std::mem::size_of::<$t>()) as u32).to_be_bytes());
+                    for value in &values {
+                        data.extend_from_slice(&value.to_be_bytes());
+                    }
+                    data.resize(data.len() + padding_to_multiple(data.len() as u32, 8) as usize, 0);
+                    let (rest, part) = parse_numeric_subelement(Endianness::Big)(&data).unwrap();
+                    assert!(rest.is_empty());
+                    assert_eq!(
+                        part.into_data(None).unwrap(),
+                        NumericData::$variant { real: values, imag: None }
+                    );
+                )*
+            };
+        }
+        check!(
+            Int8: i8 = [-128, -1, 0, 1, 127],
+            UInt8: u8 = [0, 1, 0x80, 0xFF, 0x12],
+            Int16: i16 = [i16::MIN, -2, 0x1234, i16::MAX],
+            UInt16: u16 = [0, 0x1234, 0xFF00, u16::MAX],
+            Int32: i32 = [i32::MIN, -70000, 0x1234_5678, i32::MAX],
+            UInt32: u32 = [0, 0x1234_5678, 0xFF00_0000, u32::MAX],
+            Int64: i64 = [i64::MIN, -1, 0x1234_5678_9ABC_DEF0, i64::MAX],
+            UInt64: u64 = [0, 0x1234_5678_9ABC_DEF0, 1 << 63, u64::MAX],
+            Single: f32 = [-1.5, 0.0, 3.25e10, f32::MIN_POSITIVE, f32::INFINITY],
+            Double: f64 = [-1.5, 0.0, std::f64::consts::PI, f64::MAX, f64::NEG_INFINITY]
+        );
+    }
+
+    #[test]
+    fn struct_field_name_length() {
+        let parse = |bytes: &[u8]| {
+            parse_struct_field_name_length(nom::number::Endianness::Little)(bytes)
+                .map(|(_, v)| v)
+                .ok()
+        };
+        // int32 32, in the small data element format
+        assert_eq!(parse(&[5, 0, 4, 0, 32, 0, 0, 0]), Some(32));
+        // single 32.0
+        assert!(parse(&[7, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0x42, 0, 0, 0, 0]).is_none());
+        // Two int32 values
+        assert!(parse(&[5, 0, 0, 0, 8, 0, 0, 0, 32, 0, 0, 0, 32, 0, 0, 0]).is_none());
+        // int32 -1
+        assert!(parse(&[5, 0, 4, 0, 0xff, 0xff, 0xff, 0xff]).is_none());
+    }
+
+    #[test]
+    fn char_data_size() {
+        let dimensions = Dimensions::from(vec![1, 1]);
+        let parse = |bytes: &[u8]| {
+            parse_character_array_data(nom::number::Endianness::Little, &dimensions)(bytes)
+                .map(|(_, data)| data)
+                .ok()
+        };
+        // 'a' as uint16, utf16 and utf32
+        assert!(parse(&[4, 0, 2, 0, b'a', 0, 0, 0]).is_some());
+        assert!(parse(&[17, 0, 2, 0, b'a', 0, 0, 0]).is_some());
+        assert!(parse(&[18, 0, 4, 0, b'a', 0, 0, 0]).is_some());
+        // Odd sizes for 16-bit code units
+        assert!(parse(&[4, 0, 3, 0, b'a', 0, 0, 0]).is_none());
+        assert!(parse(&[17, 0, 3, 0, b'a', 0, 0, 0]).is_none());
+        // A size that isn't a multiple of 4 for 32-bit code units
+        assert!(parse(&[18, 0, 0, 0, 6, 0, 0, 0, b'a', 0, 0, 0, 0, 0, 0, 0]).is_none());
+        // More utf16 code units than characters
+        assert!(parse(&[17, 0, 4, 0, b'a', 0, b'b', 0]).is_none());
+    }
+
+    #[test]
+    fn latin1_header_text() {
+        let data = include_bytes!("../tests/latin1_header.mat");
+        let (_, header) = parse_header(data).unwrap();
+        assert_eq!(
+            header.text(),
+            "MATLAB 5.0 MAT-file, Platform: GLNXA64, Created on: Sam Okt 10 12:00:00 2026 \u{fffd}"
+        );
+        assert_eq!(header.platform(), Some("GLNXA64"));
+        assert_eq!(&header.raw_text()[..8], b"MATLAB 5");
+        assert_eq!(header.raw_text()[77], 0xe9);
+        assert_eq!(header.raw_text()[115], b' ');
+    }
+
+    #[test]
+    fn partial_numeric_value() {
+        // A 1x3 int32 array whose data is 10 bytes long
+        let data = include_bytes!("../tests/partial_value.mat");
+        match parse_all(data) {
+            Err(crate::Error::ParseError(err)) => assert_eq!(
+                *err.kind(),
+                ParseErrorKind::SizeMismatch {
+                    subelement: "numeric data"
+                }
+            ),
+            other => panic!("expected a length error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn header_errors() {
+        let data = include_bytes!("../tests/double.mat");
+        let mut v73 = data.to_vec();
+        v73[124..126].copy_from_slice(&[0x00, 0x02]);
+        match parse_header(&v73) {
+            Err(nom::Err::Failure(err)) => {
+                assert_eq!(err, HeaderError::UnsupportedVersion(0x0200));
+                assert!(err.to_string().contains("0x0200 (v7.3/HDF5)"));
+            }
+            other => panic!("expected a version error, got {:?}", other.map(|_| ())),
+        }
+        assert!(parse_all(&v73).is_err());
+
+        let data = include_bytes!("../tests/v4.mat");
+        assert_eq!(
+            parse_header(data).map(|_| ()),
+            Err(nom::Err::Error(HeaderError::NullText))
+        );
+        let data = include_bytes!("../tests/double.mat");
+        assert!(matches!(
+            parse_header(&data[..4]),
+            Err(nom::Err::Error(HeaderError::Invalid(_)))
+        ));
+    }
+
+    #[test]
+    fn parse_error_messages() {
+        let data = include_bytes!("../tests/negative_dims.mat");
+        let err = parse_all(data).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[E_NEGATIVE_DIMENSION] error parsing element 0 at offset 0xa8: negative dimension"
+        );
+        assert!(std::error::Error::source(&err).is_some());
+
+        let err = ParseErrorKind::UnexpectedDataType {
+            subelement: "dimensions",
+            data_type: DataType::Double,
+        };
+        assert_eq!(
+            err.to_string(),
+            "dimensions subelement has unexpected type Double"
+        );
+    }
+
+    #[test]
+    fn parse_error_context() {
+        // The second variable claims three values but holds two
+        let data = include_bytes!("../tests/bad_variable_v7.mat");
+        let Err(crate::Error::ParseError(err)) = parse_all(data) else {
+            panic!("expected a parse error");
+        };
+        assert_eq!(
+            *err.kind(),
+            ParseErrorKind::SizeMismatch {
+                subelement: "real part"
+            }
+        );
+        assert_eq!(err.variable_index(), Some(1));
+        assert_eq!(err.variable_name(), Some("sensor_data"));
+        // The compressed data of the variable follows its tag at 0xad
+        assert_eq!(err.offset(), Some(0xb5));
+        assert_eq!(err.decompressed_offset(), Some(0x58));
+        assert_eq!(
+            err.to_string(),
+            "error parsing variable 'sensor_data' (element 1) at offset 0xb5 \
+             (decompressed offset 0x58): real part subelement has the wrong size"
+        );
+
+        // Level 4 files are located the same way
+        let data = include_bytes!("../tests/v4.mat");
+        let Err(crate::Error::ParseError(err)) =
+            crate::parse_v4::parse_all(&data[..data.len() - 1])
+        else {
+            panic!("expected a parse error");
+        };
+        assert_eq!(*err.kind(), ParseErrorKind::UnexpectedEof);
+        assert!(err.variable_index().is_some());
+        assert!(err.offset().is_some());
+    }
 
-        if data_element_tag.data_type != DataType::Int8 {
-            return Err(nom::Err::Failure(error_position!(
-                i,
-                // TODO
-                nom::error::ErrorKind::Tag
-            )));
-        }
+    #[test]
+    fn warnings() {
+        let data = include_bytes!("../tests/unknown_class.mat");
+        let parse_result = parse_all(data).unwrap();
+        assert_eq!(
+            parse_result.warnings,
+            [Warning {
+                variable_index: Some(1),
+                variable_name: Some("handle".to_string()),
+                kind: WarningKind::UnsupportedClass(16),
+            }]
+        );
+        assert_eq!(
+            parse_result.warnings[0].to_string(),
+            "variable 'handle' (element 1): unknown array class 16"
+        );
 
-        if data_element_tag.data_byte_size == 0 {
-            return Ok((i, None));
-        }
+        let data = include_bytes!("../tests/mismatched_types.mat");
+        let parse_result = parse_all(data).unwrap();
+        let kinds: Vec<_> = parse_result.warnings.iter().map(|w| &w.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                &WarningKind::NumericTypeCoerced {
+                    class: ArrayType::Int16,
+                    data_type: DataType::Int32
+                },
+                &WarningKind::NumericTypeCoerced {
+                    class: ArrayType::UInt8,
+                    data_type: DataType::Double
+                },
+                &WarningKind::NumericTypeCoerced {
+                    class: ArrayType::Single,
+                    data_type: DataType::Double
+                },
+            ]
+        );
 
-        let (i, name) = map_res(take(data_element_tag.data_byte_size), |b| {
-            std::str::from_utf8(b)
-                .map(|s| s.to_owned())
-                .map_err(|_err| {
-                    nom::Err::Failure((i, nom::error::ErrorKind::Tag)) // TODO
-                })
-        })(i)?;
-        // Padding bytes
-        let (i, _) = take(data_element_tag.padding_byte_size)(i)?;
-        Ok((i, Some(name)))
+        let data = include_bytes!("../tests/two_arrays.mat");
+        assert!(parse_all(data).unwrap().warnings.is_empty());
     }
-}
 
-fn maybe_parse_array_name_subelement(
-    endianness: nom::number::Endianness,
-    supplied_name: Option<&str>,
-) -> impl Fn(&[u8]) -> IResult<&[u8], String> + '_ {
-    move |i| {
-        let (i, element_name) = parse_array_name_subelement(endianness)(i)?;
+    #[test]
+    fn strict_mode() {
+        let expect_not_allowed =
+            |data: &[u8], options: &ParseOptions, warning, name| match parse_all_with_options(
+                data, options,
+            ) {
+                Err(crate::Error::ParseError(err)) => {
+                    assert_eq!(*err.kind(), ParseErrorKind::NotAllowed(warning));
+                    assert_eq!(err.variable_name(), name);
+                }
+                other => panic!("expected an error, got {:?}", other.map(|_| ())),
+            };
+        let strict = ParseOptions::new().strict(true);
 
-        match (supplied_name, element_name) {
-            (None, Some(v)) => Ok((i, v)),
-            (Some(v), None) => Ok((i, v.to_string())),
-            _ => {
-                return Err(nom::Err::Failure(error_position!(
-                    i,
-                    // TODO
-                    nom::error::ErrorKind::Tag
-                )));
-            }
+        let data = include_bytes!("../tests/mismatched_types.mat");
+        assert_eq!(parse_all(data).unwrap().data_elements.len(), 3);
+        expect_not_allowed(
+            data,
+            &strict,
+            WarningKind::NumericTypeCoerced {
+                class: ArrayType::Int16,
+                data_type: DataType::Int32,
+            },
+            Some("a"),
+        );
+
+        let data = include_bytes!("../tests/unknown_class.mat");
+        assert_eq!(parse_all(data).unwrap().data_elements.len(), 3);
+        let options = ParseOptions::new().on_unsupported(UnsupportedArrays::Error);
+        for options in [&strict, &options] {
+            expect_not_allowed(
+                data,
+                options,
+                WarningKind::UnsupportedClass(16),
+                Some("handle"),
+            );
         }
-    }
-}
 
-fn parse_dimensions_array_subelement(
-    endianness: nom::number::Endianness,
-) -> impl Fn(&[u8]) -> IResult<&[u8], Dimensions> {
-    move |i: &[u8]| {
-        let (i, data_element_tag) = parse_data_element_tag(endianness)(i)?;
-        if !(data_element_tag.data_type == DataType::Int32
-            && data_element_tag.data_byte_size >= 8
-            && data_element_tag.data_byte_size % 4 == 0)
-        {
-            return Err(nom::Err::Failure(error_position!(
-                i,
-                // TODO
-                nom::error::ErrorKind::Tag
-            )));
+        let mut data = include_bytes!("../tests/two_arrays.mat").to_vec();
+        data.extend_from_slice(&[14, 0, 0, 0, 0xff, 0, 0, 0, 1, 2, 3]);
+        let options = strict.clone().allow_trailing_bytes(true);
+        match parse_all_with_options(&data, &options) {
+            Err(crate::Error::ParseError(err)) => {
+                assert_eq!(*err.kind(), ParseErrorKind::UnexpectedEof)
+            }
+            other => panic!("expected an error, got {:?}", other.map(|_| ())),
         }
-        let (i, dimensions) = count(
-            i32(endianness),
-            (data_element_tag.data_byte_size / 4) as usize,
-        )(i)?;
-        let (i, _) = take(data_element_tag.padding_byte_size)(i)?;
-        Ok((i, dimensions))
     }
-}
 
-fn parse_array_flags_subelement(
-    endianness: nom::number::Endianness,
-) -> impl Fn(&[u8]) -> IResult<&[u8], ArrayFlags> {
-    move |i: &[u8]| {
-        let (i, tag_data_type) = u32(endianness)(i)?;
-        let (i, tag_data_len) = u32(endianness)(i)?;
-        if !(tag_data_type == DataType::UInt32 as u32 && tag_data_len == 8) {
-            return Err(nom::Err::Failure(error_position!(
-                i,
-                // TODO
-                nom::error::ErrorKind::Tag
-            )));
+    #[test]
+    fn invalid_utf8_names() {
+        let data = include_bytes!("../tests/invalid_name.mat");
+        match parse_all(data) {
+            Err(crate::Error::ParseError(err)) => {
+                assert_eq!(
+                    *err.kind(),
+                    ParseErrorKind::InvalidText {
+                        subelement: "array name"
+                    }
+                );
+                assert_eq!(err.variable_index(), Some(1));
+            }
+            other => panic!("expected an error, got {:?}", other.map(|_| ())),
         }
-        let (i, flags_and_class) = u32(endianness)(i)?;
-        let (i, nzmax) = u32(endianness)(i)?;
 
-        Ok((
-            i,
-            ArrayFlags {
-                complex: (flags_and_class & 0x0800) != 0,
-                global: (flags_and_class & 0x0400) != 0,
-                logical: (flags_and_class & 0x0200) != 0,
-                class: ArrayType::from_u8((flags_and_class & 0xFF) as u8).ok_or(
-                    nom::Err::Failure(nom::error::Error {
-                        input: i,
-                        code: nom::error::ErrorKind::Tag,
-                    }), // TODO
-                )?,
-                nzmax: nzmax as usize,
-            },
-        ))
+        let lenient = ParseOptions::new().validate_utf8_names(false);
+        let parse_result = parse_all_with_options(data, &lenient).unwrap();
+        assert_eq!(parse_result.data_elements[1].name(), Some("b\u{FFFD}"));
+        assert_eq!(
+            parse_result.warnings,
+            [Warning {
+                variable_index: Some(1),
+                variable_name: Some("b\u{FFFD}".to_string()),
+                kind: WarningKind::InvalidName {
+                    subelement: "array name"
+                },
+            }]
+        );
+
+        let strict = lenient.strict(true);
+        assert!(parse_all_with_options(data, &strict).is_err());
     }
-}
 
-fn parse_matrix_data_element(
-    endianness: nom::number::Endianness,
-    supplied_name: Option<&str>,
-) -> impl Fn(&[u8]) -> IResult<&[u8], DataElement> + '_ {
-    move |i: &[u8]| {
-        let (i, header) = parse_array_header(endianness, supplied_name)(i)?;
-        match header.flags.class {
-            ArrayType::Char => parse_character_array(endianness, header)(i),
-            ArrayType::Struct => parse_struct(endianness, header)(i)
-                .map(|(i, v)| (i, DataElement::StructureMatrix(v))),
-            ArrayType::Sparse => parse_sparse_matrix_subelements(endianness, header)(i),
-            x if x.numeric_data_type().is_some() => {
-                parse_numeric_matrix_subelements(endianness, header)(i)
-            }
-            _ => {
-                eprintln!("skipping unsupported {:?}", header.flags.class);
-                parse_unsupported_data_element(endianness)(i)
+    #[test]
+    fn skip_corrupt() {
+        let options = ParseOptions::new().skip_corrupt(true);
+
+        // The middle variable claims more values than it holds
+        let data = include_bytes!("../tests/corrupt_middle.mat");
+        assert!(parse_all(data).is_err());
+        let parse_result = parse_all_with_options(data, &options).unwrap();
+        let names: Vec<_> = parse_result
+            .data_elements
+            .iter()
+            .map(DataElement::name)
+            .collect();
+        assert_eq!(names, [Some("a"), Some("sensor_data"), Some("c")]);
+        let DataElement::Corrupt(corrupt) = &parse_result.data_elements[1] else {
+            panic!("expected a corrupt element");
+        };
+        assert_eq!(corrupt.offset, 0xc0);
+        assert_eq!(
+            *corrupt.error.kind(),
+            ParseErrorKind::SizeMismatch {
+                subelement: "real part"
             }
-        }
+        );
+        assert_eq!(corrupt.error.variable_index(), Some(1));
+        assert_eq!(parse_result.warnings[0].kind, WarningKind::CorruptElement);
+
+        // A writer crashed in the middle of the last variable
+        let data = &include_bytes!("../tests/mismatched_types.mat")[..330];
+        assert!(parse_all(data).is_err());
+        let parse_result = parse_all_with_options(data, &options).unwrap();
+        assert_eq!(parse_result.data_elements.len(), 3);
+        assert_eq!(parse_result.data_elements[0].name(), Some("a"));
+        assert_eq!(parse_result.data_elements[1].name(), Some("b"));
+        let DataElement::Corrupt(corrupt) = &parse_result.data_elements[2] else {
+            panic!("expected a corrupt element");
+        };
+        assert_eq!(corrupt.name.as_deref(), Some("c"));
+        assert_eq!(*corrupt.error.kind(), ParseErrorKind::UnexpectedEof);
+        assert_eq!(parse_result.trailing_bytes, 0);
+
+        // Strict mode reports the error instead
+        let strict = options.strict(true);
+        assert!(parse_all_with_options(data, &strict).is_err());
     }
-}
 
-fn numeric_data_types_are_compatible(array_type: DataType, subelement_type: DataType) -> bool {
-    match array_type {
-        DataType::Int8 => match subelement_type {
-            DataType::Int8 => true,
-            _ => false,
-        },
-        DataType::UInt8 => match subelement_type {
-            DataType::UInt8 => true,
-            _ => false,
-        },
-        DataType::Int16 => match subelement_type {
-            DataType::UInt8 | DataType::Int16 => true,
-            _ => false,
-        },
-        DataType::UInt16 => match subelement_type {
-            DataType::UInt8 | DataType::UInt16 => true,
-            _ => false,
-        },
-        DataType::Int32 => match subelement_type {
-            DataType::UInt8 | DataType::Int16 | DataType::UInt16 | DataType::Int32 => true,
-            _ => false,
-        },
-        DataType::UInt32 => match subelement_type {
-            DataType::UInt8 | DataType::Int16 | DataType::UInt16 | DataType::UInt32 => true,
-            _ => false,
-        },
-        DataType::Int64 => match subelement_type {
-            DataType::UInt8
-            | DataType::Int16
-            | DataType::UInt16
-            | DataType::Int32
-            | DataType::Int64 => true,
-            _ => false,
-        },
-        DataType::UInt64 => match subelement_type {
-            DataType::UInt8
-            | DataType::Int16
-            | DataType::UInt16
-            | DataType::Int32
-            | DataType::UInt64 => true,
-            _ => false,
-        },
-        DataType::Single => match subelement_type {
-            DataType::UInt8
-            | DataType::Int16
-            | DataType::UInt16
-            | DataType::Int32
-            | DataType::Single => true,
-            _ => false,
-        },
-        DataType::Double => match subelement_type {
-            DataType::UInt8
-            | DataType::Int16
-            | DataType::UInt16
-            | DataType::Int32
-            | DataType::Double => true,
-            _ => false,
-        },
-        _ => false,
+    #[test]
+    fn trailing_bytes() {
+        let mut data = include_bytes!("../tests/two_arrays.mat").to_vec();
+        let parse_result = parse_all(&data).unwrap();
+        assert_eq!(parse_result.trailing_bytes, 0);
+
+        // A truncated element at the end
+        data.extend_from_slice(&[14, 0, 0, 0, 0xff, 0, 0, 0, 1, 2, 3]);
+        assert!(parse_all(&data).is_err());
+        let options = ParseOptions {
+            allow_trailing_bytes: true,
+            ..ParseOptions::default()
+        };
+        let parse_result = parse_all_with_options(&data, &options).unwrap();
+        assert_eq!(parse_result.data_elements.len(), 2);
+        assert_eq!(parse_result.trailing_bytes, 11);
+        assert_eq!(
+            parse_result.warnings[0].kind,
+            WarningKind::TrailingBytes(11)
+        );
     }
-}
 
-fn parse_numeric_subelement(
-    endianness: nom::number::Endianness,
-) -> impl Fn(&[u8]) -> IResult<&[u8], NumericData> {
-    move |i: &[u8]| {
-        let (i, data_element_tag) = parse_data_element_tag(endianness)(i)?;
-        let (i, numeric_data) = match data_element_tag.data_type {
-            DataType::Int8 => map(
-                count(i8, data_element_tag.data_byte_size as usize),
-                NumericData::Int8,
-            )(i)?,
-            DataType::UInt8 => map(
-                count(u8, data_element_tag.data_byte_size as usize),
-                NumericData::UInt8,
-            )(i)?,
-            DataType::Int16 => map(
-                count(
-                    i16(endianness),
-                    data_element_tag.data_byte_size as usize / 2,
-                ),
-                NumericData::Int16,
-            )(i)?,
-            DataType::UInt16 => map(
-                count(
-                    u16(endianness),
-                    data_element_tag.data_byte_size as usize / 2,
-                ),
-                NumericData::UInt16,
-            )(i)?,
-            DataType::Int32 => map(
-                count(
-                    i32(endianness),
-                    data_element_tag.data_byte_size as usize / 4,
-                ),
-                NumericData::Int32,
-            )(i)?,
-            DataType::UInt32 => map(
-                count(
-                    u32(endianness),
-                    data_element_tag.data_byte_size as usize / 4,
-                ),
-                NumericData::UInt32,
-            )(i)?,
-            DataType::Int64 => map(
-                count(
-                    i64(endianness),
-                    data_element_tag.data_byte_size as usize / 8,
-                ),
-                NumericData::Int64,
-            )(i)?,
-            DataType::UInt64 => map(
-                count(
-                    u64(endianness),
-                    data_element_tag.data_byte_size as usize / 8,
-                ),
-                NumericData::UInt64,
-            )(i)?,
-            DataType::Single => map(
-                count(
-                    f32(endianness),
-                    data_element_tag.data_byte_size as usize / 4,
-                ),
-                NumericData::Single,
-            )(i)?,
-            DataType::Double => map(
-                count(
-                    f64(endianness),
-                    data_element_tag.data_byte_size as usize / 8,
-                ),
-                NumericData::Double,
-            )(i)?,
-            DataType::Compressed
-            | DataType::Matrix
-            | DataType::Utf8
-            | DataType::Utf16
-            | DataType::Utf32 => {
-                return Err(nom::Err::Failure(error_position!(
-                    i,
-                    // TODO
-                    nom::error::ErrorKind::Tag
-                )));
+    #[test]
+    fn compressed_padding() {
+        // Zero bytes after the zlib stream, and after the decompressed element
+        let data = include_bytes!("../tests/compressed_padded.mat");
+        let parse_result = parse_all(data).unwrap();
+        let names: Vec<_> = parse_result.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["a", "b"]);
+
+        // A zlib stream cut short and data following the decompressed element
+        let data = include_bytes!("../tests/compressed_truncated.mat");
+        assert!(matches!(
+            parse_all(data),
+            Err(crate::Error::ParseError(err)) if matches!(err.kind(), ParseErrorKind::Zlib(_))
+        ));
+        let data = include_bytes!("../tests/compressed_trailing.mat");
+        assert!(matches!(
+            parse_all(data),
+            Err(crate::Error::ParseError(err)) if *err.kind() == ParseErrorKind::TrailingData
+        ));
+    }
+
+    #[test]
+    fn char_data_surrogate_pairs() {
+        let data = include_bytes!("../tests/char_emoji.mat");
+        let parse_result = parse_all(data).unwrap();
+        for (name, data_element) in parse_result.iter() {
+            let DataElement::CharacterMatrix(character) = data_element else {
+                panic!("expected a character array");
+            };
+            match name {
+                "m" => assert_eq!(character.real_part.utf16_len(), 6),
+                _ => assert_eq!(character.real_part.to_string_lossy(), "a\u{1f600}b"),
             }
+        }
+        // Three code units or two characters, neither of which is 1x4
+        let bytes = [17, 0, 0, 0, 6, 0, 0, 0, b'a', 0, 0x3d, 0xd8, 0, 0xde, 0, 0];
+        let parse = |dimensions: Vec<usize>| {
+            let dimensions = Dimensions::from(dimensions);
+            let parsed =
+                parse_character_array_data(nom::number::Endianness::Little, &dimensions)(&bytes);
+            parsed.is_ok()
         };
-        // Padding bytes
-        let (i, _) = take(data_element_tag.padding_byte_size)(i)?;
-        Ok((i, numeric_data))
+        assert!(parse(vec![1, 3]));
+        assert!(parse(vec![1, 2]));
+        assert!(!parse(vec![1, 4]));
     }
-}
 
-fn parse_compressed_data_element(
-    endianness: nom::number::Endianness,
-) -> impl Fn(&[u8]) -> IResult<&[u8], DataElement> {
-    move |i: &[u8]| {
-        let mut buf = Vec::new();
-        Decoder::new(i)
-            .map_err(|err| {
-                eprintln!("{:?}", err);
-                nom::Err::Failure(nom::error::Error {
-                    input: i,
-                    code: nom::error::ErrorKind::Tag,
-                }) // TODO
-            })?
-            .read_to_end(&mut buf)
-            .map_err(|err| {
-                eprintln!("{:?}", err);
-                nom::Err::Failure(nom::error::Error {
-                    input: i,
-                    code: nom::error::ErrorKind::Tag,
-                }) // TODO
-            })?;
-        let (_remaining, data_element) = parse_next_data_element(endianness, None)(buf.as_slice())
-            .map_err(|err| replace_err_slice(err, i))?;
-        Ok((&[], data_element))
+    #[test]
+    fn sparse1() {
+        let data = include_bytes!("../tests/sparse1.mat");
+
+        let parsed_data = parse_all(data).unwrap();
+        let parsed_matrix_data = &parsed_data.data_elements[0];
+        assert_eq!(parsed_matrix_data.class(), Some(ArrayType::Sparse));
+        assert_eq!(
+            parsed_matrix_data.dimensions().map(Dimensions::as_slice),
+            Some([8, 8].as_slice())
+        );
+        if let DataElement::SparseMatrix(Sparse {
+            header,
+            row_index,
+            column_index,
+            data,
+            ..
+        }) = parsed_matrix_data
+        {
+            assert_eq!(header.dimensions.as_slice(), &[8, 8]);
+            assert_eq!(*row_index, vec![5, 7, 2, 0, 1, 3, 6]);
+            assert_eq!(*column_index, vec![0, 1, 2, 2, 3, 4, 5, 6, 7]);
+            assert_eq!(
+                *data,
+                NumericData::Double {
+                    real: vec![2.0, 7.0, 4.0, 9.0, 5.0, 8.0, 6.0],
+                    imag: None,
+                }
+            );
+        } else {
+            panic!("Error extracting DataElement::SparseMatrix");
+        }
     }
-}
 
-pub type RowIndex = Vec<usize>;
-pub type ColumnShift = Vec<usize>;
+    #[test]
+    fn sparse2() {
+        let data = include_bytes!("../tests/sparse2.mat");
 
-fn parse_numeric_matrix_subelements(
-    endianness: nom::number::Endianness,
-    header: ArrayHeader,
-) -> impl FnOnce(&[u8]) -> IResult<&[u8], DataElement> {
-    move |i: &[u8]| {
-        let (i, real_part) = parse_numeric_subelement(endianness)(i)?;
-        // Check that size and type of the real part are correct
-        let num_required_elements = header.dimensions.iter().product::<i32>();
-        let array_data_type = header.flags.class.numeric_data_type().unwrap();
-        if !(real_part.len() == num_required_elements as usize
-            && numeric_data_types_are_compatible(array_data_type, real_part.data_type()))
+        let parsed_data = parse_all(data).unwrap();
+        let parsed_matrix_data = &parsed_data.data_elements[0];
+        assert_eq!(parsed_matrix_data.class(), Some(ArrayType::Sparse));
+        assert_eq!(
+            parsed_matrix_data.dimensions().map(Dimensions::as_slice),
+            Some([8, 8].as_slice())
+        );
+        if let DataElement::SparseMatrix(Sparse {
+            header,
+            row_index,
+            column_index,
+            data,
+            ..
+        }) = parsed_matrix_data
         {
-            return Err(nom::Err::Failure(error_position!(
-                i,
-                // TODO
-                nom::error::ErrorKind::Tag
-            )));
+            assert_eq!(header.dimensions.as_slice(), &[8, 8]);
+            assert_eq!(*row_index, vec![5, 7, 2, 0, 1, 5, 3, 6]);
+            assert_eq!(*column_index, vec![0, 1, 2, 2, 3, 4, 6, 7, 8]);
+            assert_eq!(
+                *data,
+                NumericData::Double {
+                    real: vec![2.0, 7.0, 4.0, 9.0, 5.0, 6.0, 8.0, 6.0],
+                    imag: Some(vec![4.0, 0.0, 3.0, 7.0, 0.0, 1.0, 0.0, 0.0]),
+                }
+            );
+        } else {
+            panic!("Error extracting DataElement::SparseMatrix");
         }
-        let (i, imag_part) = cond(header.flags.complex, parse_numeric_subelement(endianness))(i)?;
-        // Check that size and type of imaginary part are correct if present
-        if let Some(imag_part) = &imag_part {
-            if !(imag_part.len() == num_required_elements as usize
-                && numeric_data_types_are_compatible(array_data_type, imag_part.data_type()))
-            {
-                return Err(nom::Err::Failure(error_position!(
-                    i,
-                    // TODO
-                    nom::error::ErrorKind::Tag
-                )));
+    }
+
+    #[test]
+    fn skip_imaginary() {
+        use crate::write::{CompressionLevel, MatFileBuilder, WriteOptions};
+
+        let data = include_bytes!("../tests/sparse2.mat");
+        let options = ParseOptions::new().load_imaginary(false);
+        let loaded = parse_all(data).unwrap();
+        let skipped = parse_all_with_options(data, &options).unwrap();
+        let (DataElement::SparseMatrix(loaded), DataElement::SparseMatrix(skipped)) =
+            (&loaded.data_elements[0], &skipped.data_elements[0])
+        else {
+            panic!("expected sparse matrices");
+        };
+        assert!(!loaded.imag_skipped);
+        assert!(loaded.data.is_complex());
+        assert!(skipped.imag_skipped);
+        assert!(skipped.header.flags.complex);
+        assert_eq!(skipped.row_index, loaded.row_index);
+        assert_eq!(skipped.column_index, loaded.column_index);
+        assert_eq!(
+            skipped.data,
+            NumericData::Double {
+                real: vec![2.0, 7.0, 4.0, 9.0, 5.0, 6.0, 8.0, 6.0],
+                imag: None,
             }
+        );
+        let mat_file = crate::MatFile::parse_with_options(&data[..], &options).unwrap();
+        let sparse = mat_file.get_sparse(&loaded.header.name).unwrap();
+        assert!(sparse.imag_skipped());
+        assert!(!sparse.data().is_complex());
+
+        // Large enough to be converted while it is decompressed
+        let len = 20_000;
+        let complex = NumericData::Double {
+            real: (0..len).map(f64::from).collect(),
+            imag: Some((0..len).map(|x| -f64::from(x)).collect()),
+        };
+        for &compression in &[None, Some(CompressionLevel::Default)] {
+            let data = MatFileBuilder::new()
+                .options(WriteOptions::new().compression(compression))
+                .add_numeric("z", &[1, len as usize], complex.clone())
+                .unwrap()
+                .add_f64("x", &[1, 1], [1.0])
+                .unwrap()
+                .build()
+                .unwrap();
+            let result = parse_all_with_options(&data, &options).unwrap();
+            let DataElement::NumericMatrix(numeric) = &result.data_elements[0] else {
+                panic!("expected a numeric array");
+            };
+            assert!(numeric.imag_skipped);
+            assert_eq!(
+                numeric.data.iter_f64().collect::<Vec<_>>(),
+                (0..len).map(f64::from).collect::<Vec<_>>()
+            );
+            assert!(!numeric.data.is_complex());
+            assert_eq!(
+                result.data_elements[1],
+                parse_all(&data).unwrap().data_elements[1]
+            );
+            let mat_file = crate::MatFile::parse_with_options(&data[..], &options).unwrap();
+            let z = mat_file.get_numeric("z").unwrap();
+            assert!(z.imag_skipped());
+            assert!(!mat_file.get_numeric("x").unwrap().imag_skipped());
         }
-        Ok((
-            i,
-            DataElement::NumericMatrix(Numeric {
-                header,
-                real_part,
-                imag_part,
-            }),
-        ))
     }
-}
 
-fn parse_character_array(
-    endianness: nom::number::Endianness,
-    header: ArrayHeader,
-) -> impl FnOnce(&[u8]) -> IResult<&[u8], DataElement> {
-    move |i: &[u8]| {
-        let (i, real_part) = parse_character_array_data(endianness, &header.dimensions)(i)?;
-        let (i, imag_part) = cond(
-            header.flags.complex,
-            parse_character_array_data(endianness, &header.dimensions),
-        )(i)?;
+    #[test]
+    fn sparse_without_values() {
+        let data = include_bytes!("../tests/sparse_empty.mat");
+        let parsed_data = parse_all(data).unwrap();
+        let Some(DataElement::SparseMatrix(sparse)) = parsed_data.data_elements.first() else {
+            panic!("expected a sparse matrix");
+        };
+        assert_eq!(sparse.header.flags.nzmax, 1);
+        assert!(sparse.row_index.is_empty());
+        assert_eq!(sparse.column_index, vec![0; 6]);
+        assert!(sparse.data.is_empty());
+        assert_eq!(sparse.nnz(), 0);
+    }
 
-        Ok((
-            i,
-            DataElement::CharacterMatrix(Character {
-                header,
-                real_part,
-                imag_part,
-            }),
-        ))
+    #[test]
+    fn sparse_preallocated() {
+        // Created with spalloc(10, 10, 100), storing fewer than nzmax values
+        let data = include_bytes!("../tests/sparse_spalloc.mat");
+        let parsed_data = parse_all(data).unwrap();
+        let Some(DataElement::SparseMatrix(sparse)) = parsed_data.data_elements.first() else {
+            panic!("expected a sparse matrix");
+        };
+        assert_eq!(sparse.header.flags.nzmax, 100);
+        assert_eq!(sparse.row_index, vec![0, 2]);
+        assert_eq!(sparse.nnz(), 2);
+        assert_eq!(sparse.data.len(), 2);
     }
-}
 
-fn parse_character_array_data(
-    endianness: nom::number::Endianness,
-    dimensions: &[i32],
-) -> impl Fn(&[u8]) -> IResult<&[u8], CharacterData> + '_ {
-    move |i| {
-        let (i, data_element_tag) = parse_data_element_tag(endianness)(i)?;
+    #[test]
+    fn sparse_unused_values() {
+        // Stores nzmax = 4 values of which only nnz = 2 are used
+        let data = include_bytes!("../tests/sparse_nzmax.mat");
+        let parsed_data = parse_all(data).unwrap();
+        let Some(DataElement::SparseMatrix(sparse)) = parsed_data.data_elements.first() else {
+            panic!("expected a sparse matrix");
+        };
+        assert_eq!(sparse.header.flags.nzmax, 4);
+        assert_eq!(sparse.nnz(), 2);
+        assert_eq!(sparse.row_index, vec![0, 2]);
+        assert_eq!(
+            sparse.data,
+            NumericData::Double {
+                real: vec![1.0, 2.0],
+                imag: Some(vec![3.0, 4.0]),
+            }
+        );
+    }
 
-        let cells = dimensions.iter().product::<i32>() as usize;
+    #[test]
+    fn take_and_into_iter() {
+        let data = include_bytes!("../tests/variables.mat");
+        let mut parsed_data = parse_all(data).unwrap();
+        let flag = parsed_data.take("flag").unwrap();
+        assert_eq!(flag.name(), Some("flag"));
+        assert!(parsed_data.take("flag").is_none());
 
-        let (i, buf) = take(data_element_tag.data_byte_size)(i)?;
+        let names: Vec<_> = parsed_data.into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["x", "c", "g"]);
+    }
 
-        match data_element_tag.data_type {
-            DataType::UInt16 => {
-                assert!(data_element_tag.data_byte_size % 2 == 0);
+    #[test]
+    fn cell() {
+        let data = include_bytes!("../tests/cell.mat");
+        let parsed_data = parse_all(data).unwrap();
+        let DataElement::CellMatrix(cell) = &parsed_data.data_elements[0] else {
+            panic!("expected a cell array");
+        };
+        assert_eq!(cell.header().name, "c");
+        assert_eq!(cell.len(), 3);
+        let kinds: Vec<_> = cell.iter().map(DataElement::kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                crate::ArrayKind::Numeric,
+                crate::ArrayKind::Character,
+                crate::ArrayKind::Structure,
+            ]
+        );
+        assert_eq!(cell.get(0, 1).and_then(DataElement::name), Some(""));
+        assert!(cell.get(1, 0).is_none());
+        assert!(cell.get(0, 3).is_none());
+        let Some(DataElement::StructureMatrix(structure)) = cell.get(0, 2) else {
+            panic!("expected a structure");
+        };
+        assert_eq!(structure.field_names().collect::<Vec<_>>(), vec!["x"]);
 
-                let (rem, str) = count(u16(endianness), cells)(buf)?;
+        let DataElement::CellMatrix(empty) = &parsed_data.data_elements[1] else {
+            panic!("expected a cell array");
+        };
+        assert_eq!(empty.header().dimensions.as_slice(), &[0, 0]);
+        assert!(empty.is_empty());
+    }
 
-                if !rem.is_empty() {
-                    return Err(nom::Err::Failure(error_position!(
-                        i,
-                        // TODO
-                        nom::error::ErrorKind::Tag
-                    )));
-                }
+    #[test]
+    fn cell_in_struct_field() {
+        let v6 = include_bytes!("../tests/struct_cell_v6.mat");
+        let v7 = include_bytes!("../tests/struct_cell_v7.mat");
+        for data in [v6.as_slice(), v7.as_slice()] {
+            let parsed_data = parse_all(data).unwrap();
+            let DataElement::StructureMatrix(structure) = &parsed_data.data_elements[0] else {
+                panic!("expected a structure");
+            };
+            let Some(DataElement::CellMatrix(names)) = structure.get("names") else {
+                panic!("expected a cell array");
+            };
+            // The cell is named after the field, its cells have no name
+            assert_eq!(names.header().name, "names");
+            assert_eq!(names.len(), 2);
+            assert!(names
+                .iter()
+                .all(|item| item.kind() == crate::ArrayKind::Character && item.name() == Some("")));
+            assert_eq!(structure.get("n").and_then(DataElement::name), Some("n"));
+        }
+    }
 
-                Ok((i, CharacterData::NonUnicode(str)))
-            }
-            DataType::Utf8 => {
-                let Ok(str) = String::from_utf8(buf.to_vec()) else {
-                    return Err(nom::Err::Failure(error_position!(
-                        i,
-                        // TODO
-                        nom::error::ErrorKind::Tag
-                    )));
-                };
+    #[test]
+    fn unnamed_variable() {
+        // Cells are stored without a name, variables may have one
+        let data = include_bytes!("../tests/cell.mat");
+        let endianness = nom::number::Endianness::Little;
+        // The variable `c`, following its tag
+        let variable = &data[136..];
+        assert!(parse_array_header(context(endianness), ElementName::Cell)(variable).is_err());
+        // The first cell of `c`, following its tag
+        let item = &data[184..];
+        let (_, header) = parse_array_header(context(endianness), ElementName::Cell)(item).unwrap();
+        assert_eq!(header.name, "");
+        let (_, header) =
+            parse_array_header(context(endianness), ElementName::Variable)(item).unwrap();
+        assert_eq!(header.name, "");
+    }
 
-                if str.chars().count() != cells {
-                    return Err(nom::Err::Failure(error_position!(
-                        i,
-                        // TODO
-                        nom::error::ErrorKind::Tag
-                    )));
-                }
+    #[test]
+    fn opaque() {
+        // Missing strings can't be decoded, so the object is kept as is
+        let data = include_bytes!("../tests/string_missing.mat");
+        let parse_result = parse_all(data).unwrap();
+        // The string and the subsystem data
+        assert_eq!(parse_result.data_elements.len(), 2);
+        assert_eq!(parse_result.iter().count(), 1);
+        assert!(parse_result.header.subsystem_offset().is_some());
+        assert!(matches!(
+            parse_result.data_elements.get(1),
+            Some(DataElement::SubsystemData(data)) if !data.is_empty()
+        ));
+        let Some(DataElement::Opaque(opaque)) = parse_result.data_elements.first() else {
+            panic!("expected an opaque element");
+        };
+        assert_eq!(opaque.header.name, "s");
+        assert_eq!(opaque.header.flags.class, ArrayType::Opaque);
+        assert_eq!(opaque.header.dimensions.as_slice(), &[1, 1]);
+        assert_eq!(opaque.type_system, "MCOS");
+        assert_eq!(opaque.class_name, "string");
+        // A uint32 array with the object metadata
+        assert_eq!(opaque.data.len(), 80);
+        assert_eq!(opaque.data[..4], [14, 0, 0, 0]);
+    }
 
-                Ok((i, CharacterData::Unicode(str)))
+    #[test]
+    fn string_array() {
+        let strings = |data: &[u8]| {
+            let parse_result = parse_all(data).unwrap();
+            match parse_result.into_iter().next() {
+                Some((name, DataElement::StringArray(strings))) if name == "s" => strings,
+                other => panic!("expected a string array, got {:?}", other),
             }
-            DataType::Utf16 => {
-                assert!(data_element_tag.data_byte_size % 2 == 0);
+        };
+        let scalar = strings(include_bytes!("../tests/string.mat"));
+        assert_eq!(scalar.header.dimensions.as_slice(), &[1, 1]);
+        assert_eq!(scalar.values, ["hello"]);
+        // Compressed, so the subsystem data offset points to a compressed
+        // element
+        let vector = strings(include_bytes!("../tests/string_array.mat"));
+        assert_eq!(vector.header.dimensions.as_slice(), &[1, 3]);
+        assert_eq!(vector.values, ["hello", "wörld", ""]);
+    }
 
-                let mut str = String::with_capacity(data_element_tag.data_byte_size as usize);
-                let u16 = u16::<&[u8], nom::error::Error<&[u8]>>(endianness);
-                let mut rem = buf;
+    #[test]
+    fn struct_array() {
+        let data = include_bytes!("../tests/struct_array.mat");
+        let parsed_data = parse_all(data).unwrap();
+        let DataElement::StructureMatrix(structure) = &parsed_data.data_elements[0] else {
+            panic!("expected a structure");
+        };
+        assert_eq!(structure.numel(), 3);
+        assert_eq!(structure.values.len(), 6);
+        let names: Vec<_> = (0..3)
+            .map(|idx| structure.element(idx).unwrap()[1].name())
+            .collect();
+        assert_eq!(names, vec![Some("name"); 3]);
+        assert!(structure.element(3).is_none());
 
-                let mut iter = char::decode_utf16(std::iter::from_fn(|| {
-                    let (r, ch) = u16(rem).ok()?;
-                    rem = r;
-                    Some(ch)
-                }));
+        let DataElement::StructureMatrix(empty) = &parsed_data.data_elements[1] else {
+            panic!("expected a structure");
+        };
+        assert_eq!(empty.header.dimensions.as_slice(), &[0, 0]);
+        assert_eq!(empty.field_names().collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(empty.numel(), 0);
+        assert!(empty.get("a").is_none());
+    }
 
-                for _ in 0..cells {
-                    let Some(Ok(ch)) = iter.next() else {
-                        return Err(nom::Err::Failure(error_position!(
-                            i,
-                            // TODO
-                            nom::error::ErrorKind::Tag
-                        )));
-                    };
+    #[test]
+    fn struct_full_field_names() {
+        // Field names that fill their slot have no terminating null byte
+        let data = include_bytes!("../tests/struct_full_names.mat");
+        let parsed_data = parse_all(data).unwrap();
+        let DataElement::StructureMatrix(a) = &parsed_data.data_elements[0] else {
+            panic!("expected a structure");
+        };
+        let name_31 = format!("{}b", "a".repeat(30));
+        assert_eq!(
+            a.field_names().collect::<Vec<_>>(),
+            vec![name_31.as_str(), "x"]
+        );
+        assert_eq!(
+            a.get(&name_31).and_then(DataElement::name),
+            Some(name_31.as_str())
+        );
 
-                    str.push(ch);
-                }
+        let DataElement::StructureMatrix(b) = &parsed_data.data_elements[1] else {
+            panic!("expected a structure");
+        };
+        let name_63 = format!("{}d", "c".repeat(62));
+        assert_eq!(b.field_names().collect::<Vec<_>>(), vec![name_63.as_str()]);
+    }
 
-                eof(rem)?;
+    #[test]
+    fn struct_without_fields() {
+        for data in [
+            include_bytes!("../tests/struct_no_fields.mat").as_ref(),
+            include_bytes!("../tests/struct_no_fields_v7.mat").as_ref(),
+        ] {
+            let parsed_data = parse_all(data).unwrap();
+            let dimensions: Vec<_> = parsed_data
+                .data_elements
+                .iter()
+                .map(|data_element| {
+                    let DataElement::StructureMatrix(structure) = data_element else {
+                        panic!("expected a structure");
+                    };
+                    assert_eq!(structure.field_names().count(), 0);
+                    assert!(structure.values.is_empty());
+                    structure.header.dimensions.as_slice().to_vec()
+                })
+                .collect();
+            assert_eq!(dimensions, vec![vec![1, 1], vec![1, 3]]);
+        }
+    }
 
-                str.shrink_to_fit();
-                Ok((i, CharacterData::Unicode(str)))
-            }
-            DataType::Utf32 => {
-                assert!(data_element_tag.data_byte_size % 4 == 0);
+    #[test]
+    fn data_element_kind() {
+        let data = include_bytes!("../tests/variables.mat");
+        let parsed_data = parse_all(data).unwrap();
+        let kinds: Vec<_> = parsed_data
+            .data_elements
+            .iter()
+            .map(DataElement::kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                crate::ArrayKind::Numeric,
+                crate::ArrayKind::Cell,
+                crate::ArrayKind::Numeric,
+                crate::ArrayKind::Numeric,
+            ]
+        );
+        let cell = &parsed_data.data_elements[1];
+        assert_eq!(cell.name(), Some("c"));
+        assert_eq!(cell.flags().map(|flags| flags.class), Some(ArrayType::Cell));
+        assert!(parsed_data.data_elements[3].flags().unwrap().global);
+    }
 
-                let mut str = String::with_capacity(data_element_tag.data_byte_size as usize);
-                let u32 = u32(endianness);
-                let mut rem = buf;
+    #[test]
+    fn unsupported_array_keeps_bytes() {
+        let data = include_bytes!("../tests/object.mat");
+        let parsed_data = parse_all(data).unwrap();
+        let object = &parsed_data.data_elements[0];
+        assert_eq!(object.kind(), crate::ArrayKind::Unsupported);
+        let DataElement::Unsupported(object) = object else {
+            panic!("expected an unsupported element");
+        };
+        assert_eq!(object.data_type, DataType::Matrix);
+        assert_eq!(object.header.as_ref().map(|h| h.name.as_str()), Some("obj"));
+        // The raw bytes start with the array flags subelement
+        let (_, header) = parse_array_header(
+            context(nom::number::Endianness::Little),
+            ElementName::Variable,
+        )(&object.data)
+        .unwrap();
+        assert_eq!(header.flags.class, ArrayType::Object);
+        assert_eq!(header.name, "obj");
+    }
 
-                for _ in 0..cells {
-                    let (r, ch) = u32(rem)?;
+    #[test]
+    fn unknown_class() {
+        let data = include_bytes!("../tests/unknown_class.mat");
+        let parsed_data = parse_all(data).unwrap();
+        assert_eq!(parsed_data.data_elements.len(), 3);
+        let DataElement::Unsupported(unknown) = &parsed_data.data_elements[1] else {
+            panic!("expected an unsupported element");
+        };
+        assert_eq!(unknown.class_id, Some(16));
+        assert!(unknown.header.is_none());
+        assert_eq!(parsed_data.data_elements[1].name(), Some("handle"));
+        assert_eq!(parsed_data.data_elements[2].name(), Some("b"));
 
-                    let Some(ch) = char::from_u32(ch) else {
-                        return Err(nom::Err::Failure(error_position!(
-                            i,
-                            // TODO
-                            nom::error::ErrorKind::Tag
-                        )));
-                    };
+        // Unknown classes don't need to follow the usual array layout
+        let data = include_bytes!("../tests/unknown_class_v7.mat");
+        let parsed_data = parse_all(data).unwrap();
+        let DataElement::Unsupported(unknown) = &parsed_data.data_elements[0] else {
+            panic!("expected an unsupported element");
+        };
+        assert_eq!(unknown.class_id, Some(20));
+        assert_eq!(unknown.name, None);
+        assert_eq!(parsed_data.data_elements[1].name(), Some("b"));
+    }
 
-                    rem = r;
-                    str.push(ch);
-                }
+    #[test]
+    fn dimensions() {
+        let matrix = Dimensions::try_from([2, 3].as_slice()).unwrap();
+        assert_eq!(matrix.rows(), 2);
+        assert_eq!(matrix.columns(), 3);
+        assert_eq!(matrix.numel(), Some(6));
+        assert!(!matrix.is_vector());
+        assert!(!matrix.is_scalar());
 
-                eof(rem)?;
+        let row = Dimensions::try_from([1, 3].as_slice()).unwrap();
+        assert!(row.is_vector());
+        let scalar = Dimensions::try_from([1, 1].as_slice()).unwrap();
+        assert!(scalar.is_vector());
+        assert!(scalar.is_scalar());
+        let cube = Dimensions::try_from([1, 3, 2].as_slice()).unwrap();
+        assert!(!cube.is_vector());
+        assert_eq!(cube.numel(), Some(6));
+        let empty = Dimensions::try_from([0, 0].as_slice()).unwrap();
+        assert_eq!(empty.numel(), Some(0));
 
-                str.shrink_to_fit();
-                Ok((i, CharacterData::Unicode(str)))
-            }
-            _ => Err(nom::Err::Failure(error_position!(
-                i,
-                // TODO
-                nom::error::ErrorKind::Tag
-            ))),
-        }
+        assert!(Dimensions::try_from([2, -1].as_slice()).is_err());
+        assert_eq!(Dimensions::from(vec![usize::MAX, 2]).numel(), None);
     }
-}
 
-fn parse_sparse_matrix_subelements(
-    endianness: nom::number::Endianness,
-    header: ArrayHeader,
-) -> impl FnOnce(&[u8]) -> IResult<&[u8], DataElement> {
-    move |i: &[u8]| {
-        // Figure out the type of array
-        let (i, row_index) = parse_row_index_array_subelement(endianness)(i)?;
-        let (i, column_index) = parse_column_index_array_subelement(endianness)(i)?;
-        let (i, real_part) = parse_numeric_subelement(endianness)(i)?;
-        // Check that size of the real part is correct (can't check for type in sparse matrices)
-        if !(real_part.len() == header.flags.nzmax) {
-            return Err(nom::Err::Failure(error_position!(
-                i,
-                // TODO
-                nom::error::ErrorKind::Tag
-            )));
+    #[test]
+    fn dimension_overflow() {
+        let data = include_bytes!("../tests/overflow_dims.mat");
+        match parse_all(data) {
+            Err(crate::Error::ParseError(err)) => {
+                assert_eq!(*err.kind(), ParseErrorKind::TooManyElements)
+            }
+            other => panic!("expected an overflow error, got {:?}", other.map(|_| ())),
         }
-        let (i, imag_part) = cond(header.flags.complex, parse_numeric_subelement(endianness))(i)?;
-        // Check that size of the imaginary part is correct if present (can't check for type in sparse matrices)
-        if let Some(imag_part) = &imag_part {
-            if !(imag_part.len() == header.flags.nzmax as usize) {
-                return Err(nom::Err::Failure(error_position!(
-                    i,
-                    // TODO
-                    nom::error::ErrorKind::Tag
-                )));
+        let data = include_bytes!("../tests/negative_dims.mat");
+        match parse_all(data) {
+            Err(crate::Error::ParseError(err)) => {
+                assert_eq!(*err.kind(), ParseErrorKind::NegativeDimension)
             }
+            other => panic!("expected an error, got {:?}", other.map(|_| ())),
         }
-        Ok((
-            i,
-            DataElement::SparseMatrix(Sparse {
-                header,
-                row_index: row_index.iter().map(|&i| i as usize).collect(),
-                column_index: column_index.iter().map(|&i| i as usize).collect(),
-                real_part,
-                imag_part,
-            }),
-        ))
     }
-}
 
-fn parse_row_index_array_subelement(
-    endianness: nom::number::Endianness,
-) -> impl Fn(&[u8]) -> IResult<&[u8], RowIndex> {
-    move |i: &[u8]| {
-        let (i, data_element_tag) = parse_data_element_tag(endianness)(i)?;
-        if !(data_element_tag.data_type == DataType::Int32 && data_element_tag.data_byte_size > 0) {
-            return Err(nom::Err::Failure(error_position!(
-                i,
-                // TODO
-                nom::error::ErrorKind::Tag
-            )));
+    #[test]
+    fn huge_declared_sizes() {
+        let endianness = nom::number::Endianness::Little;
+        // A tag claiming the largest possible size, followed by a few bytes
+        // of data
+        let tag = |data_type: DataType| {
+            let mut data = (data_type as u32).to_le_bytes().to_vec();
+            data.extend(u32::MAX.to_le_bytes());
+            data.extend([0; 16]);
+            data
+        };
+        fn is_eof<T>(result: IResult<&[u8], T>) -> bool {
+            matches!(result, Err(nom::Err::Error(err)) if *err.kind() == ParseErrorKind::UnexpectedEof)
         }
-        let (i, row_index) = count(
-            i32(endianness),
-            (data_element_tag.data_byte_size / 4) as usize,
-        )(i)?;
-        let (i, _) = take(data_element_tag.padding_byte_size)(i)?;
-        Ok((i, row_index.iter().map(|&i| i as usize).collect()))
-    }
-}
 
-fn parse_column_index_array_subelement(
-    endianness: nom::number::Endianness,
-) -> impl Fn(&[u8]) -> IResult<&[u8], ColumnShift> {
-    move |i: &[u8]| {
-        let (i, data_element_tag) = parse_data_element_tag(endianness)(i)?;
-        if !(data_element_tag.data_type == DataType::Int32 && data_element_tag.data_byte_size > 0) {
-            return Err(nom::Err::Failure(error_position!(
-                i,
-                // TODO
-                nom::error::ErrorKind::Tag
-            )));
+        assert!(is_eof(parse_data_element_tag(endianness)(&tag(
+            DataType::Matrix
+        ))));
+        assert!(is_eof(parse_numeric_subelement(endianness)(&tag(
+            DataType::Double
+        ))));
+        assert!(is_eof(parse_dimensions_array_subelement(endianness)(&tag(
+            DataType::Int32
+        ))));
+        assert!(is_eof(parse_array_name_subelement(context(endianness))(
+            &tag(DataType::Int8)
+        )));
+        assert!(is_eof(parse_struct_names(context(endianness), 32)(&tag(
+            DataType::Int8
+        ))));
+        let dimensions = Dimensions::from(vec![1, 4]);
+        assert!(is_eof(parse_character_array_data(endianness, &dimensions)(
+            &tag(DataType::UInt16)
+        )));
+        assert!(is_eof(parse_row_index_array_subelement(endianness)(&tag(
+            DataType::Int32
+        ))));
+        assert!(is_eof(parse_column_index_array_subelement(endianness)(
+            &tag(DataType::Int32)
+        )));
+
+        let data = include_bytes!("../tests/double.mat");
+        let mut truncated = data[..128].to_vec();
+        truncated.extend(tag(DataType::Matrix));
+        match parse_all(&truncated) {
+            Err(crate::Error::ParseError(err)) => {
+                assert_eq!(*err.kind(), ParseErrorKind::UnexpectedEof)
+            }
+            other => panic!("expected a size error, got {:?}", other.map(|_| ())),
         }
-        let (i, column_index) = count(
-            i32(endianness),
-            (data_element_tag.data_byte_size / 4) as usize,
-        )(i)?;
-        let (i, _) = take(data_element_tag.padding_byte_size)(i)?;
-        Ok((i, column_index.iter().map(|&i| i as usize).collect()))
-    }
-}
 
-pub fn replace_err_slice<'old, 'new>(
-    err: nom::Err<nom::error::Error<&'old [u8]>>,
-    new_slice: &'new [u8],
-) -> nom::Err<nom::error::Error<&'new [u8]>> {
-    match err {
-        nom::Err::Error(nom::error::Error { code, .. }) => nom::Err::Error(nom::error::Error {
-            code,
-            input: new_slice,
-        }),
-        nom::Err::Failure(nom::error::Error { code, .. }) => nom::Err::Failure(nom::error::Error {
-            code,
-            input: new_slice,
-        }),
-        nom::Err::Incomplete(needed) => nom::Err::Incomplete(needed),
+        // Cells and structure fields are complete elements, so their count
+        // is bounded by the data as well
+        for data in [
+            include_bytes!("../tests/huge_cell.mat").as_slice(),
+            include_bytes!("../tests/huge_struct.mat").as_slice(),
+        ] {
+            match parse_all(data) {
+                Err(crate::Error::ParseError(err)) => {
+                    assert_eq!(*err.kind(), ParseErrorKind::TooManyElements)
+                }
+                other => panic!("expected a size error, got {:?}", other.map(|_| ())),
+            }
+        }
     }
-}
 
-fn parse_array_header(
-    endianness: nom::number::Endianness,
-    supplied_name: Option<&str>,
-) -> impl Fn(&[u8]) -> IResult<&[u8], ArrayHeader> + '_ {
-    move |i| {
-        let (i, flags) = parse_array_flags_subelement(endianness)(i)?;
-        let (i, dimensions) = parse_dimensions_array_subelement(endianness)(i)?;
-        let (i, name) = maybe_parse_array_name_subelement(endianness, supplied_name)(i)?;
+    #[test]
+    fn decompressed_size_limit() {
+        // 2 MiB of zeros, compressed to a few kilobytes
+        let data = include_bytes!("../tests/compressible.mat");
+        let parsed_data = parse_all(data).unwrap();
+        assert_eq!(parsed_data.data_elements[0].byte_size(), 2 << 20);
 
-        Ok((
-            i,
-            ArrayHeader {
-                flags,
-                dimensions,
-                name,
-            },
-        ))
+        let options = ParseOptions {
+            max_decompressed_size: 1 << 20,
+            ..ParseOptions::default()
+        };
+        match parse_all_with_options(data, &options) {
+            Err(crate::Error::ParseError(err)) => {
+                assert_eq!(*err.kind(), ParseErrorKind::DecompressedSizeLimit)
+            }
+            other => panic!("expected a size error, got {:?}", other.map(|_| ())),
+        }
     }
-}
 
-fn parse_struct(
-    endianness: nom::number::Endianness,
-    header: ArrayHeader,
-) -> impl FnOnce(&[u8]) -> IResult<&[u8], Structure> {
-    move |i| {
-        let (i, max_length) = parse_struct_field_name_length(endianness)(i)?;
-        let (i, field_names) = parse_struct_names(endianness, max_length)(i)?;
-        let (i, values) = parse_struct_fields(endianness, &field_names)(i)?;
+    #[test]
+    fn streamed_numeric() {
+        use crate::write::{CompressionLevel, MatFileBuilder, WriteOptions};
 
-        Ok((
-            i,
-            Structure {
-                header,
-                field_names,
-                values,
-            },
-        ))
-    }
-}
+        let len = 200 * 1000;
+        let real: Vec<f64> = (0..len).map(|x| (x as f64 * 0.37).sin()).collect();
+        let imag: Vec<f64> = real.iter().map(|x| x * 2.0).collect();
+        let build = |byte_order, compression| {
+            let options = WriteOptions::new()
+                .byte_order(byte_order)
+                .compression(compression)
+                .compress_integers(true);
+            MatFileBuilder::new()
+                .options(options)
+                .add_numeric(
+                    "a",
+                    &[len / 1000, 1000],
+                    NumericData::Double {
+                        real: real.clone(),
+                        imag: Some(imag.clone()),
+                    },
+                )
+                .unwrap()
+                // Stored as uint8
+                .add_i16(
+                    "b",
+                    &[1, len],
+                    (0..len).map(|x| (x % 200) as i16).collect::<Vec<_>>(),
+                )
+                .unwrap()
+                .add_f32(
+                    "c",
+                    &[len, 1],
+                    real.iter().map(|&x| x as f32).collect::<Vec<_>>(),
+                )
+                .unwrap()
+                .build()
+                .unwrap()
+        };
+        for &byte_order in &[ByteOrder::LittleEndian, ByteOrder::BigEndian] {
+            let expected = parse_all(&build(byte_order, None)).unwrap();
+            let data = build(byte_order, Some(CompressionLevel::Fast));
+            let result = parse_all(&data).unwrap();
+            assert_eq!(result.data_elements, expected.data_elements);
+            assert_eq!(result.warnings, expected.warnings);
 
-fn parse_struct_field_name_length(
-    endianness: nom::number::Endianness,
-) -> impl Fn(&[u8]) -> IResult<&[u8], usize> {
-    move |i| {
-        let (i, numeric) = parse_numeric_subelement(endianness)(i)?;
+            // The end of the data of `a` is missing
+            let mut truncated = data.clone();
+            truncated.truncate(data.len() / 4);
+            assert!(parse_all(&truncated).is_err());
 
-        match numeric {
-            NumericData::Int8(vec) => {
-                assert!(vec.len() == 1);
-                Ok((i, vec[0] as usize))
-            }
-            NumericData::UInt8(vec) => {
-                assert!(vec.len() == 1);
-                Ok((i, vec[0] as usize))
-            }
-            NumericData::Int16(vec) => {
-                assert!(vec.len() == 1);
-                Ok((i, vec[0] as usize))
-            }
-            NumericData::UInt16(vec) => {
-                assert!(vec.len() == 1);
-                Ok((i, vec[0] as usize))
-            }
-            NumericData::Int32(vec) => {
-                assert!(vec.len() == 1);
-                Ok((i, vec[0] as usize))
-            }
-            NumericData::UInt32(vec) => {
-                assert!(vec.len() == 1);
-                Ok((i, vec[0] as usize))
-            }
-            NumericData::Int64(vec) => {
-                assert!(vec.len() == 1);
-                Ok((i, vec[0] as usize))
-            }
-            NumericData::UInt64(vec) => {
-                assert!(vec.len() == 1);
-                Ok((i, vec[0] as usize))
+            let options = ParseOptions {
+                max_decompressed_size: 1 << 20,
+                ..ParseOptions::default()
+            };
+            match parse_all_with_options(&data, &options) {
+                Err(crate::Error::ParseError(err)) => {
+                    assert_eq!(*err.kind(), ParseErrorKind::DecompressedSizeLimit);
+                    assert_eq!(err.variable_name(), Some("a"));
+                }
+                other => panic!("expected a size error, got {:?}", other.map(|_| ())),
             }
-            NumericData::Single(_) | NumericData::Double(_) => todo!(),
         }
     }
-}
 
-fn parse_struct_names(
-    endianness: nom::number::Endianness,
-    max_length: usize,
-) -> impl Fn(&[u8]) -> IResult<&[u8], Vec<String>> {
-    move |i| {
-        let (i, data_element_tag) = parse_data_element_tag(endianness)(i)?;
+    #[test]
+    fn parse_context() {
+        use crate::write::{CompressionLevel, MatFileBuilder, WriteOptions};
 
-        if !(data_element_tag.data_type == DataType::Int8 && data_element_tag.data_byte_size > 0) {
-            return Err(nom::Err::Failure(error_position!(
-                i,
-                // TODO
-                nom::error::ErrorKind::Tag
-            )));
+        let options = WriteOptions::new().compression(Some(CompressionLevel::Default));
+        let data = MatFileBuilder::new()
+            .options(options)
+            .add_f64("a", &[1, 100], vec![1.0; 100])
+            .unwrap()
+            .add_f64("b", &[1, 200], vec![2.0; 200])
+            .unwrap()
+            .add_string("c", "text")
+            .unwrap()
+            .build()
+            .unwrap();
+        let expected = parse_all(&data).unwrap();
+
+        let mut context = ParseContext::new();
+        for _ in 0..2 {
+            let result = parse_all_with_context(&data, &ParseOptions::new(), &mut context).unwrap();
+            assert_eq!(result.data_elements, expected.data_elements);
+            // The buffer is kept for the next variable and file
+            assert!(context.scratch.borrow().capacity() >= 200 * 8);
         }
 
-        let (i, data) = count(u8, data_element_tag.data_byte_size as usize)(i)?;
-        let (i, _) = take(data_element_tag.padding_byte_size)(i)?;
+        let mut context = ParseContext::new().max_retained_size(1024);
+        let result = parse_all_with_context(&data, &ParseOptions::new(), &mut context).unwrap();
+        assert_eq!(result.data_elements, expected.data_elements);
+        // `b` needed more than 1024 bytes, the buffer kept is from `c`
+        assert!(context.scratch.borrow().capacity() <= 1024);
+    }
 
-        let value_count = data.len() / max_length;
-        let mut result = Vec::with_capacity(value_count);
+    #[test]
+    fn mat_index() {
+        use crate::write::{CompressionLevel, MatFileBuilder, WriteOptions};
 
-        for idx in 0..value_count {
-            let buf = &data[max_length * idx..][..max_length];
+        let big_endian = MatFileBuilder::new()
+            .options(
+                WriteOptions::new()
+                    .byte_order(ByteOrder::BigEndian)
+                    .compression(Some(CompressionLevel::Default)),
+            )
+            .add_f64("a", &[2, 3], vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0])
+            .unwrap()
+            .add_string("b", "text")
+            .unwrap()
+            .build()
+            .unwrap();
+        let files = [
+            &include_bytes!("../tests/two_arrays.mat")[..],
+            include_bytes!("../tests/struct_cell_v7.mat"),
+            include_bytes!("../tests/multidimensional.mat"),
+            include_bytes!("../tests/sparse1.mat"),
+            &big_endian,
+        ];
+        for data in &files {
+            let index = MatIndex::scan(data).unwrap();
+            let result = parse_all(data).unwrap();
+            assert_eq!(index.entries().len(), result.data_elements.len());
+            for (entry, data_element) in index.entries().iter().zip(&result.data_elements) {
+                assert_eq!(Some(entry.name.as_str()), data_element.name());
+                assert_eq!(Some(entry.class), data_element.class());
+                assert_eq!(Some(&entry.dimensions), data_element.dimensions());
+                assert_eq!(index.load(data, &entry.name).unwrap(), *data_element);
+            }
+        }
 
-            let Ok(v) = CStr::from_bytes_until_nul(&buf) else {
-                return Err(nom::Err::Failure(error_position!(
-                    i,
-                    // TODO
-                    nom::error::ErrorKind::Tag
-                )));
-            };
+        let data = include_bytes!("../tests/struct_cell_v7.mat");
+        let index = MatIndex::scan(data).unwrap();
+        assert!(index.entries().iter().all(|entry| entry.compressed));
+        assert!(matches!(
+            index.load(data, "missing"),
+            Err(crate::Error::NotFound(name)) if name == "missing"
+        ));
+        // The variables start right after the header
+        assert_eq!(index.entries()[0].byte_range.start, 128);
+        assert!(MatIndex::scan(include_bytes!("../tests/v73.mat")).is_err());
+    }
 
-            let Ok(str) = v.to_str() else {
-                return Err(nom::Err::Failure(error_position!(
-                    i,
-                    // TODO
-                    nom::error::ErrorKind::Tag
-                )));
-            };
+    #[test]
+    fn parse_borrowed() {
+        use crate::write::{CompressionLevel, MatFileBuilder, WriteOptions};
 
-            result.push(str.to_string());
+        let big_endian = MatFileBuilder::new()
+            .options(
+                WriteOptions::new()
+                    .byte_order(ByteOrder::BigEndian)
+                    .compression(Some(CompressionLevel::Default)),
+            )
+            .add_f64("a", &[2, 3], vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0])
+            .unwrap()
+            .add_string("b", "text")
+            .unwrap()
+            .build()
+            .unwrap();
+        let files = [
+            &include_bytes!("../tests/two_arrays.mat")[..],
+            include_bytes!("../tests/single_complex.mat"),
+            include_bytes!("../tests/int32.mat"),
+            include_bytes!("../tests/double_storage.mat"),
+            include_bytes!("../tests/mismatched_types.mat"),
+            include_bytes!("../tests/struct_cell_v6.mat"),
+            include_bytes!("../tests/struct_cell_v7.mat"),
+            include_bytes!("../tests/logical_v6.mat"),
+            include_bytes!("../tests/logical_v7.mat"),
+            include_bytes!("../tests/string_array.mat"),
+            include_bytes!("../tests/opaque.mat"),
+            &big_endian,
+        ];
+        for data in &files {
+            let borrowed = parse_all_borrowed(data).unwrap();
+            let result = parse_all(data).unwrap();
+            assert_eq!(borrowed.header.text, result.header.text);
+            assert_eq!(borrowed.warnings, result.warnings);
+            let data_elements: Vec<_> = borrowed
+                .data_elements
+                .iter()
+                .map(|d| d.to_owned())
+                .collect();
+            assert_eq!(data_elements, result.data_elements);
         }
 
-        Ok((i, result))
-    }
-}
+        // The options apply like they do to the usual parser
+        let options = [
+            ParseOptions::new().strict(true),
+            ParseOptions::new().skip_corrupt(true),
+            ParseOptions::new().allow_trailing_bytes(true),
+            ParseOptions::new().load_imaginary(false),
+            ParseOptions::new().max_decompressed_size(64),
+            ParseOptions::new().filter(|name| name != "a"),
+        ];
+        let more_files = [
+            &include_bytes!("../tests/complex_mixed.mat")[..],
+            include_bytes!("../tests/corrupt_middle.mat"),
+            include_bytes!("../tests/compressed_trailing.mat"),
+            include_bytes!("../tests/unknown_class.mat"),
+        ];
+        for data in files.iter().chain(&more_files) {
+            for options in &options {
+                let borrowed = parse_all_borrowed_with_options(data, options);
+                let result = parse_all_with_options(data, options);
+                let (borrowed, result) = match (borrowed, result) {
+                    (Ok(borrowed), Ok(result)) => (borrowed, result),
+                    (Err(_), Err(_)) => continue,
+                    (borrowed, result) => panic!("{:?} and {:?}", borrowed.err(), result.err()),
+                };
+                assert_eq!(borrowed.warnings, result.warnings);
+                assert_eq!(borrowed.trailing_bytes, result.trailing_bytes);
+                let data_elements: Vec<_> = borrowed
+                    .data_elements
+                    .iter()
+                    .map(|d| d.to_owned())
+                    .collect();
+                assert_eq!(data_elements, result.data_elements);
+            }
+        }
 
-fn parse_struct_field(
-    endianness: nom::number::Endianness,
-    name: &str,
-) -> impl Fn(&[u8]) -> IResult<&[u8], DataElement> + '_ {
-    parse_next_data_element(endianness, Some(name))
-}
+        let data = include_bytes!("../tests/int32.mat");
+        let result = parse_all_borrowed(data).unwrap();
+        match &result.data_elements[0] {
+            DataElementRef::NumericMatrix(numeric) => {
+                assert!(numeric.is_borrowed());
+                assert_eq!(numeric.data_type(), DataType::Int32);
+                assert_eq!(numeric.imag_bytes(), None);
+                let real = numeric.real_bytes();
+                assert_eq!(real[8..12], (-70000i32).to_le_bytes());
+                assert!(data.as_ptr_range().contains(&real.as_ptr()));
+            }
+            data_element => panic!("{:?}", data_element),
+        }
+        // Stored in a smaller type than the class
+        assert!(matches!(
+            result.data_elements[1],
+            DataElementRef::Owned(DataElement::NumericMatrix(_))
+        ));
+        let result = parse_all_borrowed(&big_endian).unwrap();
+        match &result.data_elements[0] {
+            DataElementRef::NumericMatrix(numeric) => {
+                assert!(!numeric.is_borrowed());
+                assert_eq!(numeric.byte_order(), ByteOrder::BigEndian);
+                assert_eq!(numeric.real_bytes()[..8], 1.0f64.to_be_bytes());
+            }
+            data_element => panic!("{:?}", data_element),
+        }
+        assert!(parse_all_borrowed(include_bytes!("../tests/compressed_trailing.mat")).is_err());
+    }
 
-fn parse_struct_fields(
-    endianness: nom::number::Endianness,
-    names: &[String],
-) -> impl Fn(&[u8]) -> IResult<&[u8], Vec<DataElement>> + '_ {
-    move |i| {
-        let mut result = Vec::with_capacity(names.len());
+    #[test]
+    fn variable_filter() {
+        use crate::write::{CompressionLevel, MatFileBuilder, WriteOptions};
+        use std::sync::Mutex;
 
-        let mut i = i;
+        for &compression in &[None, Some(CompressionLevel::Default)] {
+            let data = MatFileBuilder::new()
+                .options(WriteOptions::new().compression(compression))
+                .add_f64("x", &[1, 3], vec![1.0, 2.0, 3.0])
+                .unwrap()
+                .add_struct("meta", |meta| {
+                    meta.add_i32("x", &[1, 1], [7])?.add_string("y", "text")
+                })
+                .unwrap()
+                .add_string("y", "more text")
+                .unwrap()
+                .build()
+                .unwrap();
+            let seen = Arc::new(Mutex::new(Vec::new()));
+            let options = ParseOptions::new().filter({
+                let seen = seen.clone();
+                move |name| {
+                    seen.lock().unwrap().push(name.to_string());
+                    name == "meta"
+                }
+            });
+            let result = parse_all_with_options(&data, &options).unwrap();
+            let expected = parse_all(&data).unwrap();
+            assert_eq!(result.data_elements.len(), 3);
+            assert!(result.warnings.is_empty());
+            // Only the variables are filtered, not the fields of "meta"
+            assert_eq!(*seen.lock().unwrap(), ["x", "meta", "y"]);
+            assert_eq!(result.data_elements[1], expected.data_elements[1]);
+            for index in [0, 2] {
+                match &result.data_elements[index] {
+                    DataElement::Skipped(header) => {
+                        assert_eq!(Some(header), expected.data_elements[index].header());
+                        assert_eq!(
+                            result.data_elements[index].kind(),
+                            expected.data_elements[index].kind()
+                        );
+                    }
+                    data_element => panic!("{:?}", data_element),
+                }
+            }
 
-        for name in names {
-            let (j, val) = parse_struct_field(endianness, name)(i)?;
-            result.push(val);
-            i = j;
+            let mat_file = crate::MatFile::parse_with_options(&data[..], &options).unwrap();
+            assert_eq!(mat_file.arrays().len(), 1);
+            assert!(mat_file.warnings().is_empty());
         }
 
-        Ok((i, result))
+        // The subsystem data is kept even if no variable is parsed
+        let data = include_bytes!("../tests/opaque.mat");
+        let options = ParseOptions::new().filter(|_| false);
+        let result = parse_all_with_options(data, &options).unwrap();
+        assert!(result.data_elements[..2]
+            .iter()
+            .all(|data_element| matches!(data_element, DataElement::Skipped(_))));
+        assert!(matches!(
+            result.data_elements[2],
+            DataElement::SubsystemData(_)
+        ));
     }
-}
 
-fn parse_unsupported_data_element(
-    _endianness: nom::number::Endianness,
-) -> impl Fn(&[u8]) -> IResult<&[u8], DataElement> {
-    |_i: &[u8]| Ok((&[], DataElement::Unsupported))
-}
+    #[test]
+    fn many_compressed_variables() {
+        use crate::write::{CompressionLevel, MatFileBuilder, WriteOptions};
 
-#[derive(Debug)]
-pub struct ParseResult {
-    pub header: Header,
-    pub data_elements: Vec<DataElement>,
-}
+        let build = |compression| {
+            let mut builder =
+                MatFileBuilder::new().options(WriteOptions::new().compression(compression));
+            for n in 0..8 {
+                let data: Vec<f64> = (0..10000).map(|x| (x * n) as f64).collect();
+                builder = builder
+                    .add_f64(&format!("v{}", n), &[100, 100], data)
+                    .unwrap();
+            }
+            builder.build().unwrap()
+        };
+        let expected = parse_all(&build(None)).unwrap();
+        let data = build(Some(CompressionLevel::Fast));
+        assert_eq!(
+            parse_all(&data).unwrap().data_elements,
+            expected.data_elements
+        );
 
-pub fn parse_all(i: &[u8]) -> IResult<&[u8], ParseResult> {
-    let (i, header) = parse_header(i)?;
-    let endianness = if header.is_little_endian {
-        nom::number::Endianness::Little
-    } else {
-        nom::number::Endianness::Big
-    };
-    let (i, data_elements) = many0(complete(parse_next_data_element(endianness, None)))(i)?;
-    Ok((
-        i,
-        ParseResult {
-            header: header,
-            data_elements: data_elements,
-        },
-    ))
-}
+        // Concurrent calls share the threads they decode with
+        std::thread::scope(|scope| {
+            let parses: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| parse_all(&data).unwrap()))
+                .collect();
+            for parse in parses {
+                assert_eq!(parse.join().unwrap().data_elements, expected.data_elements);
+            }
+        });
 
-#[cfg(test)]
-mod test {
-    use super::*;
+        // The first corrupt variable is reported, no matter which one fails
+        // first when they are decoded in parallel
+        let index = MatIndex::scan(&data).unwrap();
+        let mut corrupt = data.clone();
+        for n in &[3, 6] {
+            let range = &index.entries()[*n].byte_range;
+            for byte in &mut corrupt[range.start as usize + 20..range.end as usize - 8] {
+                *byte = !*byte;
+            }
+        }
+        match parse_all(&corrupt) {
+            Err(crate::Error::ParseError(err)) => assert_eq!(err.variable_index(), Some(3)),
+            other => panic!("expected a parse error, got {:?}", other.map(|_| ())),
+        }
+        let options = ParseOptions::new().skip_corrupt(true);
+        let result = parse_all_with_options(&corrupt, &options).unwrap();
+        assert_eq!(result.data_elements.len(), 8);
+        for (n, data_element) in result.data_elements.iter().enumerate() {
+            match data_element {
+                DataElement::Corrupt(_) => assert!(n == 3 || n == 6),
+                data_element => assert_eq!(data_element, &expected.data_elements[n]),
+            }
+        }
+    }
 
     #[test]
-    fn sparse1() {
-        let data = include_bytes!("../tests/sparse1.mat");
-
-        let (_, parsed_data) = parse_all(data).unwrap();
-        let parsed_matrix_data = parsed_data.data_elements[0].clone();
-        if let DataElement::SparseMatrix(Sparse {
-            header,
-            row_index,
-            column_index,
-            real_part,
-            imag_part,
-        }) = parsed_matrix_data
-        {
-            assert_eq!(header.dimensions, vec![8, 8]);
-            assert_eq!(row_index, vec![5, 7, 2, 0, 1, 3, 6]);
-            assert_eq!(column_index, vec![0, 1, 2, 2, 3, 4, 5, 6, 7]);
-            assert_eq!(
-                real_part,
-                NumericData::Double(vec![2.0, 7.0, 4.0, 9.0, 5.0, 8.0, 6.0])
-            );
-            assert_eq!(imag_part, None);
-        } else {
-            panic!("Error extracting DataElement::SparseMatrix");
+    fn nesting_depth() {
+        let data = include_bytes!("../tests/deep_nesting.mat");
+        match parse_all(data) {
+            Err(crate::Error::ParseError(err)) => {
+                assert_eq!(*err.kind(), ParseErrorKind::NestingTooDeep)
+            }
+            other => panic!("expected a depth error, got {:?}", other.map(|_| ())),
         }
+
+        // `c` holds a structure, whose fields are two levels below the
+        // variable
+        let data = include_bytes!("../tests/cell.mat");
+        let options = ParseOptions {
+            max_depth: 2,
+            ..ParseOptions::default()
+        };
+        assert!(parse_all_with_options(data, &options).is_err());
+        let options = ParseOptions {
+            max_depth: 3,
+            ..ParseOptions::default()
+        };
+        assert!(parse_all_with_options(data, &options).is_ok());
     }
 
     #[test]
-    fn sparse2() {
-        let data = include_bytes!("../tests/sparse2.mat");
+    fn two_arrays_summary() {
+        let data = include_bytes!("../tests/two_arrays.mat");
 
-        let (_, parsed_data) = parse_all(data).unwrap();
-        let parsed_matrix_data = parsed_data.data_elements[0].clone();
-        if let DataElement::SparseMatrix(Sparse {
-            header,
-            row_index,
-            column_index,
-            real_part,
-            imag_part,
-        }) = parsed_matrix_data
-        {
-            assert_eq!(header.dimensions, vec![8, 8]);
-            assert_eq!(row_index, vec![5, 7, 2, 0, 1, 5, 3, 6]);
-            assert_eq!(column_index, vec![0, 1, 2, 2, 3, 4, 6, 7, 8]);
-            assert_eq!(
-                real_part,
-                NumericData::Double(vec![2.0, 7.0, 4.0, 9.0, 5.0, 6.0, 8.0, 6.0])
-            );
-            assert_eq!(
-                imag_part,
-                Some(NumericData::Double(vec![
-                    4.0, 0.0, 3.0, 7.0, 0.0, 1.0, 0.0, 0.0
-                ]))
-            );
-        } else {
-            panic!("Error extracting DataElement::SparseMatrix");
-        }
+        let parsed_data = parse_all(data).unwrap();
+        let names: Vec<_> = parsed_data.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["A", "B"]);
+
+        let summary = parsed_data.summary();
+        assert_eq!(summary.len(), 2);
+        assert_eq!(summary[0].name, "A");
+        assert_eq!(summary[0].class, ArrayType::Double);
+        assert_eq!(summary[0].dimensions.as_slice(), &[2, 2]);
+        assert_eq!(summary[0].byte_size, 32);
+        assert_eq!(summary[1].name, "B");
+        assert_eq!(summary[1].class, ArrayType::Double);
+        assert_eq!(summary[1].dimensions.as_slice(), &[2, 3]);
+        assert_eq!(summary[1].byte_size, 48);
     }
 }
@@ -0,0 +1,341 @@
+//! Parse results that borrow the data of numeric arrays from the input.
+//!
+//! Numeric arrays make up most of a typical file, and converting their data
+//! into vectors copies all of it. The arrays parsed here keep the bytes of
+//! their data instead, borrowed from the input unless the array was
+//! compressed. Everything else is parsed as usual.
+
+use super::{
+    byte_order, failure, parse_array_header, parse_data_element_tag, parse_decompressed,
+    parse_elements, parse_raw_array_flags_subelement, values, ArrayHeader, ArrayType, ByteOrder,
+    Context, DataElement, DataType, ElementName, Header, Numeric, NumericData, NumericPart,
+    ParseContext, ParseError, ParseErrorKind, ParseOptions, ParsedElement, Warning,
+};
+use libflate::zlib::Decoder;
+use nom::bytes::complete::take;
+use nom::combinator::{complete, opt};
+use num_traits::FromPrimitive;
+use std::borrow::Cow;
+use std::io::Read;
+use std::ops::Range;
+
+/// The result of [`parse_all_borrowed`].
+#[derive(Debug)]
+pub struct ParseResultRef<'a> {
+    pub header: Header,
+    pub data_elements: Vec<DataElementRef<'a>>,
+    /// The number of bytes following the last data element that couldn't be
+    /// parsed, see [`super::ParseResult::trailing_bytes`].
+    pub trailing_bytes: usize,
+    /// Problems that didn't keep the file from being parsed, in the order
+    /// they were found.
+    pub warnings: Vec<Warning>,
+}
+
+/// A data element of a [`ParseResultRef`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum DataElementRef<'a> {
+    /// A numeric array whose data is stored in the type of its class, as
+    /// Matlab does unless the values fit into a smaller type
+    NumericMatrix(NumericRef<'a>),
+    /// Any other data element, parsed as by [`super::parse_all`]
+    Owned(DataElement),
+}
+
+impl From<DataElement> for DataElementRef<'_> {
+    fn from(data_element: DataElement) -> Self {
+        DataElementRef::Owned(data_element)
+    }
+}
+
+impl ParsedElement for DataElementRef<'_> {
+    fn name(&self) -> Option<&str> {
+        DataElementRef::name(self)
+    }
+
+    fn as_owned_mut(&mut self) -> Option<&mut DataElement> {
+        match self {
+            DataElementRef::NumericMatrix(_) => None,
+            DataElementRef::Owned(data_element) => Some(data_element),
+        }
+    }
+}
+
+impl DataElementRef<'_> {
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            DataElementRef::NumericMatrix(numeric) => Some(&numeric.header.name),
+            DataElementRef::Owned(data_element) => data_element.name(),
+        }
+    }
+
+    /// The data element [`super::parse_all`] would have returned.
+    pub fn to_owned(&self) -> DataElement {
+        match self {
+            DataElementRef::NumericMatrix(numeric) => {
+                DataElement::NumericMatrix(numeric.to_owned())
+            }
+            DataElementRef::Owned(data_element) => data_element.clone(),
+        }
+    }
+}
+
+/// A numeric array holding the bytes of its data.
+///
+/// The data is borrowed from the input, or owned if the array was
+/// compressed. It is stored in the type of the array class, in the byte
+/// order of the file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NumericRef<'a> {
+    pub header: ArrayHeader,
+    data_type: DataType,
+    byte_order: ByteOrder,
+    bytes: Cow<'a, [u8]>,
+    real: Range<usize>,
+    imag: Option<Range<usize>>,
+}
+
+impl NumericRef<'_> {
+    pub fn header(&self) -> &ArrayHeader {
+        &self.header
+    }
+
+    /// The type of the values, which matches the array class.
+    pub fn data_type(&self) -> DataType {
+        self.data_type
+    }
+
+    /// The byte order of the values.
+    pub fn byte_order(&self) -> ByteOrder {
+        self.byte_order
+    }
+
+    /// Whether the data is borrowed from the input rather than
+    /// decompressed.
+    pub fn is_borrowed(&self) -> bool {
+        matches!(self.bytes, Cow::Borrowed(_))
+    }
+
+    /// The bytes of the real part.
+    pub fn real_bytes(&self) -> &[u8] {
+        &self.bytes[self.real.clone()]
+    }
+
+    /// The bytes of the imaginary part, if the array is complex.
+    pub fn imag_bytes(&self) -> Option<&[u8]> {
+        self.imag.clone().map(|imag| &self.bytes[imag])
+    }
+
+    /// Converts the data into the array [`super::parse_all`] would have
+    /// returned.
+    pub fn to_owned(&self) -> Numeric {
+        let part = |bytes: &[u8]| {
+            let endianness = match self.byte_order {
+                ByteOrder::LittleEndian => nom::number::Endianness::Little,
+                ByteOrder::BigEndian => nom::number::Endianness::Big,
+            };
+            // The size was checked when the array was parsed
+            let len = bytes.len() / self.data_type.byte_size().unwrap();
+            let part = match self.data_type {
+                DataType::Int8 => values(endianness, len)(bytes).map(|(_, v)| NumericPart::Int8(v)),
+                DataType::UInt8 => {
+                    values(endianness, len)(bytes).map(|(_, v)| NumericPart::UInt8(v))
+                }
+                DataType::Int16 => {
+                    values(endianness, len)(bytes).map(|(_, v)| NumericPart::Int16(v))
+                }
+                DataType::UInt16 => {
+                    values(endianness, len)(bytes).map(|(_, v)| NumericPart::UInt16(v))
+                }
+                DataType::Int32 => {
+                    values(endianness, len)(bytes).map(|(_, v)| NumericPart::Int32(v))
+                }
+                DataType::UInt32 => {
+                    values(endianness, len)(bytes).map(|(_, v)| NumericPart::UInt32(v))
+                }
+                DataType::Int64 => {
+                    values(endianness, len)(bytes).map(|(_, v)| NumericPart::Int64(v))
+                }
+                DataType::UInt64 => {
+                    values(endianness, len)(bytes).map(|(_, v)| NumericPart::UInt64(v))
+                }
+                DataType::Single => {
+                    values(endianness, len)(bytes).map(|(_, v)| NumericPart::Single(v))
+                }
+                _ => values(endianness, len)(bytes).map(|(_, v)| NumericPart::Double(v)),
+            };
+            part.expect("the size was checked when the array was parsed")
+        };
+        let data: NumericData = part(self.real_bytes())
+            .into_data(self.imag_bytes().map(part))
+            .expect("both parts have the same type");
+        Numeric {
+            header: self.header.clone(),
+            data,
+            imag_skipped: false,
+        }
+    }
+}
+
+/// Like [`super::parse_all`], but keeps the data of numeric arrays as bytes
+/// borrowed from `input`, which avoids copying it.
+///
+/// Compressed arrays own their decompressed data instead. Numeric arrays
+/// whose data is stored in a smaller type than their class, and all other
+/// data elements, are parsed as usual and returned as
+/// [`DataElementRef::Owned`].
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use matfile::parse::{parse_all_borrowed, DataElementRef};
+///
+/// let data = include_bytes!("../../tests/int32.mat");
+/// let result = parse_all_borrowed(data)?;
+/// if let DataElementRef::NumericMatrix(numeric) = &result.data_elements[0] {
+///     assert!(numeric.is_borrowed());
+///     assert_eq!(numeric.real_bytes().len(), 4 * 4);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn parse_all_borrowed(input: &[u8]) -> Result<ParseResultRef<'_>, crate::Error> {
+    parse_all_borrowed_with_options(input, &ParseOptions::default())
+}
+
+/// Like [`parse_all_borrowed`], but with the given options instead of the
+/// default ones.
+///
+/// Compressed variables are always decompressed one after another, even
+/// with the `parallel` feature.
+pub fn parse_all_borrowed_with_options<'a>(
+    input: &'a [u8],
+    options: &ParseOptions,
+) -> Result<ParseResultRef<'a>, crate::Error> {
+    let elements = parse_elements(input, options, &mut ParseContext::new(), false, |ctx, i| {
+        parse_numeric_ref(ctx, i)
+    })?;
+    Ok(ParseResultRef {
+        header: elements.header,
+        data_elements: elements.data_elements,
+        trailing_bytes: elements.trailing_bytes,
+        warnings: elements.warnings,
+    })
+}
+
+/// Parses the next data element if it is a numeric array whose data is
+/// stored in the type of its class. Returns `None` for anything else,
+/// including arrays that fail to parse, which are left to the usual parser.
+#[allow(clippy::type_complexity)]
+fn parse_numeric_ref<'a>(
+    ctx: Context<'_>,
+    i: &'a [u8],
+) -> Option<Result<(&'a [u8], DataElementRef<'a>), nom::Err<ParseError>>> {
+    let ctx = Context {
+        depth: ctx.depth + 1,
+        ..ctx
+    };
+    let (data, tag) = parse_data_element_tag(ctx.endianness)(i).ok()?;
+    let (rest, data) = take::<_, _, ParseError>(tag.data_byte_size)(data).ok()?;
+    let warnings = ctx.warnings.borrow().len();
+    let numeric = match tag.data_type {
+        DataType::Matrix => {
+            let (rest, _) =
+                opt(complete(take::<_, _, ParseError>(tag.padding_byte_size)))(rest).ok()?;
+            numeric_parts(ctx, data).map(|(header, real, imag)| {
+                let numeric = NumericRef {
+                    data_type: header.flags.class.numeric_data_type().unwrap(),
+                    byte_order: byte_order(ctx.endianness),
+                    header,
+                    bytes: Cow::Borrowed(data),
+                    real,
+                    imag,
+                };
+                Ok((rest, DataElementRef::NumericMatrix(numeric)))
+            })
+        }
+        DataType::Compressed => {
+            let mut buf = Vec::new();
+            let limit = ctx.options.max_decompressed_size;
+            Decoder::new(data)
+                .and_then(|decoder| decoder.take(limit.saturating_add(1)).read_to_end(&mut buf))
+                .ok()?;
+            if buf.len() as u64 > limit {
+                return Some(Err(failure(data, ParseErrorKind::DecompressedSizeLimit)));
+            }
+            let element = parse_data_element_tag(ctx.endianness)(&buf)
+                .ok()
+                .filter(|(_, tag)| tag.data_type == DataType::Matrix)
+                .and_then(|(element, tag)| {
+                    let start = buf.len() - element.len();
+                    let end = start.checked_add(tag.data_byte_size as usize)?;
+                    Some((start, end, numeric_parts(ctx, buf.get(start..end)?)?))
+                });
+            match element {
+                Some((start, end, (header, real, imag))) => {
+                    // Checked like in the usual path
+                    let remaining = &buf[end..];
+                    if !(remaining.len() < 8 && remaining.iter().all(|&b| b == 0)) {
+                        return Some(Err(failure(data, ParseErrorKind::TrailingData)));
+                    }
+                    let shift = |range: Range<usize>| range.start + start..range.end + start;
+                    let numeric = NumericRef {
+                        data_type: header.flags.class.numeric_data_type().unwrap(),
+                        byte_order: byte_order(ctx.endianness),
+                        header,
+                        bytes: Cow::Owned(buf),
+                        real: shift(real),
+                        imag: imag.map(shift),
+                    };
+                    Some(Ok((rest, DataElementRef::NumericMatrix(numeric))))
+                }
+                None => {
+                    ctx.warnings.borrow_mut().truncate(warnings);
+                    Some(
+                        parse_decompressed(ctx, &buf, data)
+                            .map(|(_, data_element)| (rest, DataElementRef::Owned(data_element))),
+                    )
+                }
+            }
+        }
+        _ => None,
+    };
+    if numeric.is_none() {
+        ctx.warnings.borrow_mut().truncate(warnings);
+    }
+    numeric
+}
+
+/// Reads the header of a numeric array from the body of a matrix element,
+/// and where its real and imaginary parts are stored in it. Returns `None`
+/// unless both are stored in the type of the array class and match its
+/// size.
+fn numeric_parts<'a>(
+    ctx: Context<'_>,
+    body: &'a [u8],
+) -> Option<(ArrayHeader, Range<usize>, Option<Range<usize>>)> {
+    let (_, (flags_and_class, _)) = parse_raw_array_flags_subelement(ctx.endianness)(body).ok()?;
+    let class = ArrayType::from_u8((flags_and_class & 0xFF) as u8)?;
+    let data_type = class.numeric_data_type()?;
+    let (i, header) = parse_array_header(ctx, ElementName::Variable)(body).ok()?;
+    let numel = header.dimensions.numel()?;
+    let part = |i: &'a [u8]| {
+        let (data, tag) = parse_data_element_tag(ctx.endianness)(i).ok()?;
+        if tag.data_type != data_type || tag.value_count() != Some(numel) {
+            return None;
+        }
+        let (rest, _) =
+            take::<_, _, ParseError>(tag.data_byte_size + tag.padding_byte_size)(data).ok()?;
+        let start = body.len() - data.len();
+        Some((rest, start..start + tag.data_byte_size as usize))
+    };
+    let (i, real) = part(i)?;
+    // Skipping the imaginary part is left to the usual parser
+    if header.flags.complex && !ctx.options.load_imaginary {
+        return None;
+    }
+    let imag = match header.flags.complex {
+        true => Some(part(i)?.1),
+        false => None,
+    };
+    Some((header, real, imag))
+}
@@ -0,0 +1,235 @@
+//! Best-effort decoding of the subsystem data, where Matlab stores the
+//! contents of MCOS objects such as strings.
+//!
+//! The layout isn't documented by MathWorks. The subsystem data is a small
+//! MAT-file of its own, holding a structure with a field "MCOS". That field
+//! is an opaque object of class "FileWrapper__", whose payload is a cell
+//! array. The first cell holds metadata describing the classes, objects and
+//! properties, the following cells hold the property values.
+
+use super::{
+    parse_next_data_element, Context, DataElement, Dimensions, ElementName, Numeric, NumericData,
+    Opaque, StringArray,
+};
+use std::convert::TryFrom;
+
+/// The object metadata and property values read from the subsystem data.
+pub(super) struct Subsystem<'a> {
+    ctx: Context<'a>,
+    metadata: Vec<u8>,
+    cells: Vec<DataElement>,
+}
+
+impl<'a> Subsystem<'a> {
+    /// Reads the subsystem data, returning `None` if it isn't laid out as
+    /// expected.
+    pub(super) fn parse(ctx: Context<'a>, data: &[u8]) -> Option<Self> {
+        // The subsystem data starts with a header of 8 bytes holding the
+        // version and the endian indicator
+        let i = data.get(8..)?;
+        let (_, element) = parse_next_data_element(ctx, ElementName::Variable)(i).ok()?;
+        let DataElement::StructureMatrix(structure) = element else {
+            return None;
+        };
+        let Some(DataElement::Opaque(wrapper)) = structure.get("MCOS") else {
+            return None;
+        };
+        if wrapper.class_name != "FileWrapper__" {
+            return None;
+        }
+        let (_, DataElement::CellMatrix(cell)) =
+            parse_next_data_element(ctx, ElementName::Cell)(&wrapper.data).ok()?
+        else {
+            return None;
+        };
+        let Some(DataElement::NumericMatrix(Numeric {
+            data: NumericData::UInt8 { real: metadata, .. },
+            ..
+        })) = cell.values.first()
+        else {
+            return None;
+        };
+        let subsystem = Subsystem {
+            ctx,
+            metadata: metadata.clone(),
+            cells: cell.values,
+        };
+        // Versions 2 to 4 share the parts of the layout used here
+        if !(2..=4).contains(&subsystem.u32_at(0)?) {
+            return None;
+        }
+        Some(subsystem)
+    }
+
+    /// Decodes a `string` object into its strings. Returns `None` for any
+    /// other object, and for string arrays that aren't scalars or vectors or
+    /// contain missing strings.
+    pub(super) fn string_array(&self, opaque: &Opaque) -> Option<StringArray> {
+        if opaque.type_system != "MCOS" || opaque.class_name != "string" {
+            return None;
+        }
+        let metadata = object_metadata(self.ctx, opaque)?;
+        // The magic number, the number of dimensions, the dimensions, the
+        // object ids and finally the class id
+        let ndims = *metadata.get(1)? as usize;
+        let object_id = *metadata.get(2 + ndims)?;
+        let class_id = *metadata.last()?;
+        if self.class_name(class_id)? != "string" {
+            return None;
+        }
+        let property = self
+            .properties(object_id)?
+            .into_iter()
+            .find(|&(name, _, _)| self.name(name) == Some("any"))?;
+        // Properties of type 1 refer to a cell, counting from the third one
+        let (_, 1, cell) = property else {
+            return None;
+        };
+        let Some(DataElement::NumericMatrix(Numeric {
+            data: NumericData::UInt64 { real, .. },
+            ..
+        })) = self.cells.get(cell as usize + 2)
+        else {
+            return None;
+        };
+        let (dimensions, values) = decode_strings(self.ctx.endianness, real)?;
+        if !(dimensions.is_vector() || dimensions.numel() == Some(0)) {
+            return None;
+        }
+
+        let mut header = opaque.header.clone();
+        header.dimensions = dimensions;
+        Some(StringArray { header, values })
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes = self.metadata.get(offset..offset.checked_add(4)?)?;
+        let bytes = <[u8; 4]>::try_from(bytes).ok()?;
+        Some(match self.ctx.endianness {
+            nom::number::Endianness::Big => u32::from_be_bytes(bytes),
+            _ => u32::from_le_bytes(bytes),
+        })
+    }
+
+    /// The start of one of the regions following the names.
+    fn region(&self, idx: usize) -> Option<usize> {
+        self.u32_at(8 + idx * 4).map(|offset| offset as usize)
+    }
+
+    /// The name with the given 1-based index.
+    fn name(&self, idx: u32) -> Option<&str> {
+        let count = self.u32_at(4)? as usize;
+        let names = self.metadata.get(40..self.region(0)?)?;
+        let name = names
+            .split(|&b| b == 0)
+            .take(count)
+            .nth((idx as usize).checked_sub(1)?)?;
+        std::str::from_utf8(name).ok()
+    }
+
+    fn class_name(&self, class_id: u32) -> Option<&str> {
+        // Each class is described by the index of its package name, the index
+        // of its name and two unknown values. The first entry is empty.
+        let offset = self.region(0)? + 16 * class_id as usize;
+        let package = self.u32_at(offset)?;
+        if package != 0 {
+            return None;
+        }
+        self.name(self.u32_at(offset + 4)?)
+    }
+
+    /// The properties of an object as (name index, type, value).
+    fn properties(&self, object_id: u32) -> Option<Vec<(u32, u32, u32)>> {
+        // Each object is described by its class id, two unknown values, the
+        // ids of its property sets in the second and the fourth region and a
+        // dependency id. The first entry is empty.
+        let offset = self.region(2)? + 24 * object_id as usize;
+        let saveobj_id = self.u32_at(offset + 12)?;
+        let normal_id = self.u32_at(offset + 16)?;
+        let (region, id) = match (saveobj_id, normal_id) {
+            (0, 0) => return None,
+            (0, id) => (3, id),
+            (id, _) => (1, id),
+        };
+
+        // Property sets start with their number of properties followed by
+        // three values per property, padded to 8 bytes. The first set is
+        // empty.
+        let start = self.region(region)?;
+        let mut offset = start + 8;
+        for _ in 1..id {
+            let count = self.u32_at(offset)? as usize;
+            offset += 4 + 12 * count;
+            offset = start + (offset - start).div_ceil(8) * 8;
+        }
+        let count = self.u32_at(offset)? as usize;
+        (0..count)
+            .map(|k| {
+                let property = offset + 4 + 12 * k;
+                Some((
+                    self.u32_at(property)?,
+                    self.u32_at(property + 4)?,
+                    self.u32_at(property + 8)?,
+                ))
+            })
+            .collect()
+    }
+}
+
+/// Reads the uint32 array an MCOS object stores after its class name.
+fn object_metadata(ctx: Context<'_>, opaque: &Opaque) -> Option<Vec<u32>> {
+    let (_, element) = parse_next_data_element(ctx, ElementName::Cell)(&opaque.data).ok()?;
+    match element {
+        DataElement::NumericMatrix(Numeric {
+            data: NumericData::UInt32 { real, .. },
+            ..
+        }) => Some(real),
+        _ => None,
+    }
+}
+
+/// Decodes the "any" property of a string array: a version, the number of
+/// dimensions, the dimensions, the length of each string and then the
+/// characters of all strings as UTF-16, packed into 64-bit integers.
+fn decode_strings(
+    endianness: nom::number::Endianness,
+    data: &[u64],
+) -> Option<(Dimensions, Vec<String>)> {
+    let [1, ndims, rest @ ..] = data else {
+        return None;
+    };
+    let ndims = usize::try_from(*ndims).ok()?;
+    let dimensions = rest
+        .get(..ndims)?
+        .iter()
+        .map(|&d| usize::try_from(d).ok())
+        .collect::<Option<Vec<_>>>()
+        .map(Dimensions::from)?;
+    let rest = &rest[ndims..];
+    let numel = dimensions.numel()?;
+    let lengths = rest.get(..numel)?;
+    let units: Vec<u16> = rest[numel..]
+        .iter()
+        .flat_map(|&v| match endianness {
+            nom::number::Endianness::Big => v.to_be_bytes(),
+            _ => v.to_le_bytes(),
+        })
+        .collect::<Vec<_>>()
+        .chunks_exact(2)
+        .map(|b| match endianness {
+            nom::number::Endianness::Big => u16::from_be_bytes([b[0], b[1]]),
+            _ => u16::from_le_bytes([b[0], b[1]]),
+        })
+        .collect();
+
+    let mut values = Vec::with_capacity(numel);
+    let mut units = units.as_slice();
+    for &length in lengths {
+        // Missing strings have a length of u64::MAX, which fails here
+        let length = usize::try_from(length).ok().filter(|&l| l <= units.len())?;
+        let (string, rest) = units.split_at(length);
+        values.push(String::from_utf16(string).ok()?);
+        units = rest;
+    }
+    Some((dimensions, values))
+}
@@ -0,0 +1,264 @@
+//! Parser for the MAT-file Level 4 format, which Matlab used before version
+//! 5 and which some other software still writes.
+//!
+//! Level 4 files have no file header. Every variable starts with a header of
+//! five 32-bit integers, followed by its name and its data. The variables are
+//! returned using the same types as the Level 5 parser in [`crate::parse`].
+
+use crate::parse::{
+    byte_order, failure, ArrayFlags, ArrayHeader, ArrayType, Character, CharacterData, DataElement,
+    DataType, Dimensions, Header, IResult, Numeric, NumericData, ParseError, ParseErrorKind,
+    ParseResult, UnsupportedArray,
+};
+use nom::bytes::complete::take;
+use nom::combinator::cond;
+use nom::multi::count;
+use nom::number::complete::{f32, f64, i16, i32, u16, u8};
+use std::convert::TryFrom;
+
+/// The type code of a variable, stored as the decimal digits MOPT.
+#[derive(Clone, Copy, Debug)]
+struct TypeCode {
+    /// P: the type the data is stored in
+    precision: i32,
+    /// T: 0 for numeric, 1 for text and 2 for sparse matrices
+    matrix_type: i32,
+}
+
+fn parse_type_code(i: &[u8]) -> IResult<&[u8], (nom::number::Endianness, TypeCode)> {
+    // The thousands digit M gives the byte order: 0 for little endian and 1
+    // for big endian. Other values are formats like VAX or Cray, which aren't
+    // supported.
+    let (_, little) = i32(nom::number::Endianness::Little)(i)?;
+    let endianness = if (0..1000).contains(&little) {
+        nom::number::Endianness::Little
+    } else {
+        nom::number::Endianness::Big
+    };
+    let (i, mopt) = i32(endianness)(i)?;
+    let machine = mopt / 1000;
+    let order = mopt / 100 % 10;
+    let precision = mopt / 10 % 10;
+    let matrix_type = mopt % 10;
+    let expected_machine = match endianness {
+        nom::number::Endianness::Big => 1,
+        _ => 0,
+    };
+    if !(machine == expected_machine && order == 0 && precision <= 5 && matrix_type <= 2) {
+        return Err(failure(i, ParseErrorKind::InvalidTypeCode(mopt)));
+    }
+    Ok((
+        i,
+        (
+            endianness,
+            TypeCode {
+                precision,
+                matrix_type,
+            },
+        ),
+    ))
+}
+
+fn parse_dimension(endianness: nom::number::Endianness) -> impl Fn(&[u8]) -> IResult<&[u8], usize> {
+    move |i| {
+        let (i, value) = i32(endianness)(i)?;
+        let Ok(value) = usize::try_from(value) else {
+            return Err(failure(i, ParseErrorKind::NegativeDimension));
+        };
+        Ok((i, value))
+    }
+}
+
+fn parse_name(length: usize) -> impl Fn(&[u8]) -> IResult<&[u8], String> {
+    move |i| {
+        // The stored length includes the terminating null byte
+        let (i, name) = take(length)(i)?;
+        let name = name.strip_suffix(&[0]).unwrap_or(name);
+        let Ok(name) = std::str::from_utf8(name) else {
+            return Err(failure(
+                i,
+                ParseErrorKind::InvalidText { subelement: "name" },
+            ));
+        };
+        Ok((i, name.to_owned()))
+    }
+}
+
+/// Reads `numel` values stored with the given precision, converted to
+/// double like Matlab does when loading them.
+fn parse_values(
+    endianness: nom::number::Endianness,
+    precision: i32,
+    numel: usize,
+) -> impl Fn(&[u8]) -> IResult<&[u8], Vec<f64>> {
+    move |i| match precision {
+        0 => count(f64(endianness), numel)(i),
+        1 => count(nom::combinator::map(f32(endianness), f64::from), numel)(i),
+        2 => count(nom::combinator::map(i32(endianness), f64::from), numel)(i),
+        3 => count(nom::combinator::map(i16(endianness), f64::from), numel)(i),
+        4 => count(nom::combinator::map(u16(endianness), f64::from), numel)(i),
+        _ => count(nom::combinator::map(u8, f64::from), numel)(i),
+    }
+}
+
+fn parse_variable(i: &[u8]) -> IResult<&[u8], (nom::number::Endianness, DataElement)> {
+    let (i, (endianness, type_code)) = parse_type_code(i)?;
+    let (i, rows) = parse_dimension(endianness)(i)?;
+    let (i, columns) = parse_dimension(endianness)(i)?;
+    let (i, imagf) = i32(endianness)(i)?;
+    let (i, name_length) = parse_dimension(endianness)(i)?;
+    let (i, name) = parse_name(name_length)(i)?;
+    let Some(numel) = rows.checked_mul(columns) else {
+        return Err(failure(i, ParseErrorKind::TooManyElements));
+    };
+    let data_start = i;
+    let (i, real) = parse_values(endianness, type_code.precision, numel)(i)?;
+    let (i, imag) = cond(
+        imagf != 0,
+        parse_values(endianness, type_code.precision, numel),
+    )(i)?;
+    let data = &data_start[..data_start.len() - i.len()];
+
+    let class = match type_code.matrix_type {
+        0 => ArrayType::Double,
+        1 => ArrayType::Char,
+        _ => ArrayType::Sparse,
+    };
+    let header = ArrayHeader {
+        flags: ArrayFlags {
+            complex: imag.is_some(),
+            global: false,
+            logical: false,
+            class,
+            nzmax: 0,
+        },
+        dimensions: Dimensions::from(vec![rows, columns]),
+        name,
+    };
+    let characters = |values: Vec<f64>| {
+        CharacterData::from_utf16(values.into_iter().map(|v| v as u16).collect())
+    };
+    let data_element = match class {
+        ArrayType::Double => DataElement::NumericMatrix(Numeric {
+            header,
+            data: NumericData::Double { real, imag },
+            imag_skipped: false,
+        }),
+        ArrayType::Char => DataElement::CharacterMatrix(Character {
+            header,
+            real_part: characters(real),
+            imag_part: imag.map(characters),
+        }),
+        // Sparse matrices are stored as a list of row indices, column indices
+        // and values, which isn't converted yet
+        _ => DataElement::Unsupported(UnsupportedArray {
+            header: Some(header),
+            data_type: DataType::Double,
+            data: data.to_vec(),
+            class_id: None,
+            name: None,
+            byte_order: byte_order(endianness),
+        }),
+    };
+    Ok((i, (endianness, data_element)))
+}
+
+/// Parses a Level 4 file. The header of the result has an empty text and
+/// the byte order of the first variable.
+pub fn parse_all(input: &[u8]) -> Result<ParseResult, crate::Error> {
+    let mut i = input;
+    let mut is_little_endian = None;
+    let mut data_elements = Vec::new();
+    while !i.is_empty() {
+        let (rest, (endianness, data_element)) = parse_variable(i).map_err(|err| {
+            crate::Error::ParseError(ParseError::from(err).locate(input, data_elements.len()))
+        })?;
+        is_little_endian.get_or_insert(endianness != nom::number::Endianness::Big);
+        data_elements.push(data_element);
+        i = rest;
+    }
+    if data_elements.is_empty() {
+        let err = ParseError::new(input, ParseErrorKind::UnexpectedEof).locate(input, 0);
+        return Err(crate::Error::ParseError(err));
+    }
+    Ok(ParseResult {
+        header: Header {
+            text: String::new(),
+            is_little_endian: is_little_endian.unwrap_or(true),
+            raw_text: [0; 116],
+            subsystem_offset: None,
+        },
+        data_elements,
+        trailing_bytes: 0,
+        warnings: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn little_endian() {
+        let data = include_bytes!("../tests/v4.mat");
+        let parse_result = parse_all(data).unwrap();
+        assert!(parse_result.header.is_little_endian);
+        let names: Vec<_> = parse_result.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["x", "z", "t"]);
+
+        let Some(DataElement::NumericMatrix(x)) = parse_result.data_elements.first() else {
+            panic!("expected a numeric matrix");
+        };
+        assert_eq!(x.header.dimensions.as_slice(), &[2, 3]);
+        assert_eq!(
+            x.data,
+            NumericData::Double {
+                real: vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+                imag: None,
+            }
+        );
+        // Stored as int16, loaded as double
+        let Some(DataElement::NumericMatrix(z)) = parse_result.data_elements.get(1) else {
+            panic!("expected a numeric matrix");
+        };
+        assert!(z.header.flags.complex);
+        assert_eq!(
+            z.data,
+            NumericData::Double {
+                real: vec![1.0, -2.0],
+                imag: Some(vec![3.0, 4.0]),
+            }
+        );
+        let Some(DataElement::CharacterMatrix(t)) = parse_result.data_elements.get(2) else {
+            panic!("expected a character matrix");
+        };
+        assert_eq!(t.header.dimensions.as_slice(), &[1, 5]);
+        let CharacterData::Unicode(chars) = &t.real_part else {
+            panic!("expected text");
+        };
+        assert_eq!(chars, "hello");
+    }
+
+    #[test]
+    fn big_endian() {
+        let data = include_bytes!("../tests/v4_be.mat");
+        let parse_result = parse_all(data).unwrap();
+        assert!(!parse_result.header.is_little_endian);
+        let Some(DataElement::NumericMatrix(x)) = parse_result.data_elements.first() else {
+            panic!("expected a numeric matrix");
+        };
+        assert_eq!(x.header.name, "x");
+        assert_eq!(
+            x.data,
+            NumericData::Double {
+                real: vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+                imag: None,
+            }
+        );
+    }
+
+    #[test]
+    fn not_level_4() {
+        assert!(parse_all(include_bytes!("../tests/double.mat")).is_err());
+    }
+}
@@ -0,0 +1,1660 @@
+//! Parser for v7.3 MAT-files, which Matlab writes with `save -v7.3`. They
+//! are HDF5 files behind a 512-byte user block holding the usual MAT-file
+//! header.
+//!
+//! Only the parts of HDF5 that Matlab uses are read: version 0 and 1
+//! superblocks, version 1 object headers, groups with symbol tables, and
+//! compact, contiguous and chunked datasets, which may be compressed with
+//! deflate and shuffled. Files written by other HDF5 tools with newer format
+//! versions fail with [`ParseErrorKind::InvalidHdf5`].
+//!
+//! The variables are returned using the same types as the Level 5 parser in
+//! [`crate::parse`]. Numeric, logical and character datasets become numeric
+//! and character arrays, datasets of references cell arrays, and groups
+//! structures and sparse matrices. Objects such as strings are left out with
+//! a [`WarningKind::SkippedVariable`].
+
+use crate::parse::{
+    ArrayFlags, ArrayHeader, ArrayType, Cell, Character, CharacterData, DataElement, Header,
+    Numeric, NumericData, ParseError, ParseErrorKind, ParseOptions, ParseResult, Sparse, Structure,
+    Warning, WarningKind,
+};
+use libflate::zlib::Decoder;
+use std::collections::HashSet;
+use std::convert::{TryFrom, TryInto};
+use std::io::Read;
+
+const SIGNATURE: &[u8] = b"\x89HDF\r\n\x1a\n";
+
+type Hdf5Result<T> = Result<T, ParseError>;
+
+/// Parses a v7.3 file with the default options.
+pub fn parse_all(input: &[u8]) -> Result<ParseResult, crate::Error> {
+    parse_all_with_options(input, &ParseOptions::default())
+}
+
+/// Parses a v7.3 file. The variables are returned in the order of their
+/// names, which is how HDF5 stores them, and the header has the text of the
+/// file but not its raw bytes, so that writers don't copy the v7.3 header
+/// into a Level 5 file.
+///
+/// Of the options, [`ParseOptions::max_depth`],
+/// [`ParseOptions::max_decompressed_size`], [`ParseOptions::filter`],
+/// [`ParseOptions::load_imaginary`] and the ones deciding which warnings are
+/// errors are used.
+pub fn parse_all_with_options(
+    input: &[u8],
+    options: &ParseOptions,
+) -> Result<ParseResult, crate::Error> {
+    let locate = |err: ParseError, index| crate::Error::ParseError(err.locate(input, index));
+    let Some(text) = input.get(..116) else {
+        return Err(locate(
+            ParseError::new(input, ParseErrorKind::UnexpectedEof),
+            0,
+        ));
+    };
+    let header = Header {
+        text: String::from_utf8_lossy(text).into_owned(),
+        is_little_endian: input.get(126..128) == Some(b"IM"),
+        raw_text: [0; 116],
+        subsystem_offset: None,
+    };
+    let (file, root) = File::open(input, options).map_err(|err| locate(err, 0))?;
+    let variables = file
+        .object(root)
+        .and_then(|root| file.group_members(&root))
+        .map_err(|err| locate(err, 0))?;
+
+    let mut data_elements = Vec::new();
+    let mut warnings = Vec::new();
+    // The references of cells and structure arrays point into "#refs#", and
+    // "#subsystem#" holds the data of objects
+    for (name, address) in variables.iter().filter(|(name, _)| !name.starts_with('#')) {
+        let index = data_elements.len();
+        let filtered = options
+            .filter
+            .as_ref()
+            .is_some_and(|filter| !filter.matches(name));
+        let element = match filtered {
+            true => file.skipped(*address, name).map(Some),
+            false => file.element(*address, name, 1),
+        };
+        match element.map_err(|err| locate(err.in_variable(name), index))? {
+            Some(element) => data_elements.push(element),
+            None => {
+                let warning = Warning {
+                    variable_index: None,
+                    variable_name: Some(name.clone()),
+                    kind: WarningKind::SkippedVariable,
+                };
+                if options.rejects(&warning.kind) {
+                    return Err(crate::Error::ParseError(ParseError::from_warning(warning)));
+                }
+                warnings.push(warning);
+            }
+        }
+    }
+    Ok(ParseResult {
+        header,
+        data_elements,
+        trailing_bytes: 0,
+        warnings,
+    })
+}
+
+/// The HDF5 part of a file.
+struct File<'a> {
+    input: &'a [u8],
+    /// The offset of the superblock, which addresses are relative to
+    base: usize,
+    offset_size: usize,
+    length_size: usize,
+    options: &'a ParseOptions,
+}
+
+/// A position in the file from which values are read in order. HDF5 stores
+/// its own structures in little-endian byte order.
+struct Cursor<'a, 'f> {
+    file: &'f File<'a>,
+    offset: usize,
+}
+
+/// A message in an object header.
+struct Message {
+    kind: u16,
+    /// The offset of the message data in the file
+    offset: usize,
+}
+
+const DATASPACE: u16 = 0x0001;
+const DATATYPE: u16 = 0x0003;
+const LAYOUT: u16 = 0x0008;
+const FILTER_PIPELINE: u16 = 0x000B;
+const ATTRIBUTE: u16 = 0x000C;
+const CONTINUATION: u16 = 0x0010;
+const SYMBOL_TABLE: u16 = 0x0011;
+
+/// An object with the attributes Matlab uses to describe it.
+#[derive(Default)]
+struct Object {
+    messages: Vec<Message>,
+    /// The `MATLAB_class` attribute, e.g. "double" or "struct"
+    class: Option<String>,
+    /// Whether the dataset holds the dimensions of an empty array instead
+    /// of its data
+    empty: bool,
+    /// The number of rows of a sparse matrix
+    sparse_rows: Option<u64>,
+    global: bool,
+    /// Whether the dataset is an object that needs the subsystem data
+    object_decode: bool,
+    field_names: Option<Vec<String>>,
+}
+
+impl Object {
+    fn message(&self, kind: u16) -> Option<&Message> {
+        self.messages.iter().find(|message| message.kind == kind)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Datatype {
+    Integer {
+        size: usize,
+        signed: bool,
+        big_endian: bool,
+    },
+    Float {
+        size: usize,
+        big_endian: bool,
+    },
+    String {
+        size: usize,
+    },
+    Reference {
+        size: usize,
+    },
+    Compound {
+        size: usize,
+        members: Vec<(String, usize, Datatype)>,
+    },
+    VariableLength {
+        size: usize,
+        base: Box<Datatype>,
+    },
+    Other {
+        size: usize,
+    },
+}
+
+impl Datatype {
+    fn size(&self) -> usize {
+        match *self {
+            Datatype::Integer { size, .. }
+            | Datatype::Float { size, .. }
+            | Datatype::String { size }
+            | Datatype::Reference { size }
+            | Datatype::Compound { size, .. }
+            | Datatype::VariableLength { size, .. }
+            | Datatype::Other { size } => size,
+        }
+    }
+}
+
+/// The data of a dataset, in row-major order of its HDF5 dimensions, which
+/// is column-major order of the Matlab dimensions.
+struct Dataset {
+    datatype: Datatype,
+    /// The Matlab dimensions, which are the HDF5 ones reversed
+    dimensions: Vec<usize>,
+    data: Vec<u8>,
+}
+
+/// A chunk of a chunked dataset.
+struct Chunk {
+    address: usize,
+    size: usize,
+    filter_mask: u32,
+    offsets: Vec<u64>,
+}
+
+impl<'a> File<'a> {
+    /// Reads the superblock, returning the file and the address of the root
+    /// group.
+    fn open(input: &'a [u8], options: &'a ParseOptions) -> Hdf5Result<(Self, usize)> {
+        // The superblock is at 0 or at a power of two from 512 on, after the
+        // user block
+        let base = std::iter::successors(Some(0), |&offset| match offset {
+            0 => Some(512),
+            offset => usize::checked_mul(offset, 2),
+        })
+        .take_while(|&offset| offset < input.len())
+        .find(|&offset| input[offset..].starts_with(SIGNATURE))
+        .ok_or_else(|| invalid(input, 128, "no HDF5 superblock"))?;
+        let mut file = File {
+            input,
+            base,
+            offset_size: 8,
+            length_size: 8,
+            options,
+        };
+        let mut cursor = file.at(base + SIGNATURE.len());
+        let version = cursor.u8()?;
+        if version > 1 {
+            return Err(file.invalid(base, "superblock versions 2 and 3 aren't supported"));
+        }
+        cursor.skip(4)?;
+        let (offset_size, length_size) = (cursor.u8()?.into(), cursor.u8()?.into());
+        if ![2, 4, 8].contains(&offset_size) || ![2, 4, 8].contains(&length_size) {
+            return Err(file.invalid(cursor.offset, "invalid size of offsets or lengths"));
+        }
+        // A reserved byte, the group K values, the consistency flags and, in
+        // version 1, the indexed storage K value
+        let skip = if version == 1 { 13 } else { 9 };
+        let offset = cursor.offset + skip;
+        file.offset_size = offset_size;
+        file.length_size = length_size;
+        // Addresses are relative to the superblock, whatever the base
+        // address says, like the HDF5 library handles it. The root group
+        // follows the free-space, end-of-file and driver information
+        // addresses, and the name offset of its symbol table entry.
+        let mut cursor = file.at(offset + 4 * offset_size + length_size);
+        let root = cursor.required_address()?;
+        Ok((file, root))
+    }
+
+    fn at(&self, offset: usize) -> Cursor<'a, '_> {
+        Cursor { file: self, offset }
+    }
+
+    fn invalid(&self, offset: usize, reason: &'static str) -> ParseError {
+        invalid(self.input, offset, reason)
+    }
+
+    /// Reads the messages of the object header at `address`, and the
+    /// attributes Matlab uses.
+    fn object(&self, address: usize) -> Hdf5Result<Object> {
+        let mut cursor = self.at(address);
+        if cursor.u8()? != 1 {
+            return Err(self.invalid(address, "only version 1 object headers are supported"));
+        }
+        cursor.skip(1)?;
+        let count = usize::from(cursor.u16()?);
+        cursor.skip(4)?;
+        let size = cursor.u32()? as usize;
+        cursor.skip(4)?;
+
+        // Messages that don't fit are continued in blocks elsewhere
+        let mut blocks = vec![(cursor.offset, size)];
+        let mut object = Object::default();
+        let mut n = 0;
+        while let Some(&(start, size)) = blocks.get(n) {
+            n += 1;
+            let mut cursor = self.at(start);
+            while cursor.offset - start + 8 <= size && object.messages.len() < count {
+                let kind = cursor.u16()?;
+                let len = usize::from(cursor.u16()?);
+                let flags = cursor.u8()?;
+                cursor.skip(3)?;
+                let offset = cursor.offset;
+                cursor.skip(len)?;
+                if flags & 0x02 != 0 {
+                    return Err(self.invalid(offset, "shared messages aren't supported"));
+                }
+                if kind == CONTINUATION {
+                    let mut continuation = self.at(offset);
+                    let address = continuation.required_address()?;
+                    blocks.push((address, continuation.length()?));
+                }
+                object.messages.push(Message { kind, offset });
+            }
+        }
+
+        for message in object.messages.iter().filter(|m| m.kind == ATTRIBUTE) {
+            let mut cursor = self.at(message.offset);
+            let (name, datatype, count) = cursor.attribute()?;
+            let offset = cursor.offset;
+            let data = cursor.bytes(count * datatype.size())?;
+            match name {
+                b"MATLAB_class" => object.class = Some(self.text(&datatype, data, offset)?),
+                b"MATLAB_empty" => object.empty = self.uint(&datatype, data, offset)? != 0,
+                b"MATLAB_sparse" => object.sparse_rows = Some(self.uint(&datatype, data, offset)?),
+                b"MATLAB_global" => object.global = self.uint(&datatype, data, offset)? != 0,
+                b"MATLAB_object_decode" => object.object_decode = true,
+                b"MATLAB_fields" => {
+                    object.field_names = Some(self.field_names(&datatype, count, offset)?)
+                }
+                _ => {}
+            }
+        }
+        Ok(object)
+    }
+
+    /// Reads a text attribute.
+    fn text(&self, datatype: &Datatype, data: &[u8], offset: usize) -> Hdf5Result<String> {
+        match datatype {
+            Datatype::String { .. } => {
+                let text = data.split(|&b| b == 0).next().unwrap_or_default();
+                Ok(String::from_utf8_lossy(text).into_owned())
+            }
+            _ => Err(self.invalid(offset, "expected a text attribute")),
+        }
+    }
+
+    /// Reads the first value of an integer attribute.
+    fn uint(&self, datatype: &Datatype, data: &[u8], offset: usize) -> Hdf5Result<u64> {
+        match *datatype {
+            Datatype::Integer {
+                size, big_endian, ..
+            } if size <= 8 => match data.get(..size) {
+                Some(bytes) if big_endian => {
+                    Ok(bytes.iter().fold(0, |n, &b| n << 8 | u64::from(b)))
+                }
+                Some(bytes) => Ok(bytes.iter().rev().fold(0, |n, &b| n << 8 | u64::from(b))),
+                None => Err(self.invalid(offset, "empty attribute")),
+            },
+            _ => Err(self.invalid(offset, "expected an integer attribute")),
+        }
+    }
+
+    /// Reads the `MATLAB_fields` attribute at `offset`, a list of `count`
+    /// variable-length strings stored as sequences of characters in the
+    /// global heap.
+    fn field_names(
+        &self,
+        datatype: &Datatype,
+        count: usize,
+        offset: usize,
+    ) -> Hdf5Result<Vec<String>> {
+        let Datatype::VariableLength { size, base } = datatype else {
+            return Err(self.invalid(offset, "expected a list of field names"));
+        };
+        if base.size() != 1 {
+            return Err(self.invalid(offset, "expected a list of field names"));
+        }
+        (0..count)
+            .map(|n| {
+                let mut cursor = self.at(offset + n * size);
+                let len = cursor.u32()? as usize;
+                let collection = cursor.required_address()?;
+                let index = cursor.u32()?;
+                let object = self.heap_object(collection, index)?;
+                let name = object.get(..len).ok_or_else(|| {
+                    self.invalid(collection, "field name outside of its heap object")
+                })?;
+                Ok(String::from_utf8_lossy(name).into_owned())
+            })
+            .collect()
+    }
+
+    /// Reads an object from the global heap collection at `address`.
+    fn heap_object(&self, address: usize, index: u32) -> Hdf5Result<&'a [u8]> {
+        let mut cursor = self.at(address);
+        cursor.signature(b"GCOL")?;
+        cursor.skip(4)?;
+        let end = address.saturating_add(cursor.length()?);
+        while cursor.offset + 8 + self.length_size <= end {
+            let object_index = cursor.u16()?;
+            cursor.skip(6)?;
+            let size = cursor.length()?;
+            // Index 0 is the free space at the end of the collection
+            if object_index == 0 {
+                break;
+            }
+            let data = cursor.bytes(size)?;
+            if u32::from(object_index) == index {
+                return Ok(data);
+            }
+            cursor.offset = address + (cursor.offset - address).next_multiple_of(8);
+        }
+        Err(self.invalid(address, "missing global heap object"))
+    }
+
+    /// The names and object header addresses of the members of a group,
+    /// ordered by name.
+    fn group_members(&self, group: &Object) -> Hdf5Result<Vec<(String, usize)>> {
+        let Some(symbol_table) = group.message(SYMBOL_TABLE) else {
+            return Err(self.invalid(
+                group.messages.first().map_or(0, |m| m.offset),
+                "only groups with symbol tables are supported",
+            ));
+        };
+        let mut cursor = self.at(symbol_table.offset);
+        let btree = cursor.required_address()?;
+        let heap = cursor.required_address()?;
+
+        // The names are stored in a local heap
+        let mut cursor = self.at(heap);
+        cursor.signature(b"HEAP")?;
+        cursor.skip(4)?;
+        let size = cursor.length()?;
+        cursor.length()?;
+        let data = cursor.required_address()?;
+        let names = self.at(data).bytes(size)?;
+
+        let mut members = Vec::new();
+        let mut visited = HashSet::new();
+        let mut nodes = vec![(btree, None)];
+        while let Some((address, parent_level)) = nodes.pop() {
+            if !visited.insert(address) {
+                return Err(self.invalid(address, "group B-tree node used twice"));
+            }
+            let mut cursor = self.at(address);
+            cursor.signature(b"TREE")?;
+            let (node_type, level) = (cursor.u8()?, cursor.u8()?);
+            if node_type != 0 || parent_level.is_some_and(|parent| level >= parent) {
+                return Err(self.invalid(address, "invalid group B-tree node"));
+            }
+            let entries = cursor.u16()?;
+            cursor.skip(2 * self.offset_size)?;
+            let mut children = Vec::new();
+            for _ in 0..entries {
+                cursor.length()?;
+                children.push(cursor.required_address()?);
+            }
+            if level > 0 {
+                // Popped from the end, so the first child comes first
+                nodes.extend(children.into_iter().rev().map(|child| (child, Some(level))));
+                continue;
+            }
+            for child in children {
+                let mut cursor = self.at(child);
+                cursor.signature(b"SNOD")?;
+                cursor.skip(2)?;
+                for _ in 0..cursor.u16()? {
+                    let name_offset = cursor.length()?;
+                    let header = cursor.required_address()?;
+                    cursor.skip(24)?;
+                    let name = names
+                        .get(name_offset..)
+                        .and_then(|name| name.split(|&b| b == 0).next())
+                        .ok_or_else(|| self.invalid(child, "member name outside of the heap"))?;
+                    members.push((String::from_utf8_lossy(name).into_owned(), header));
+                }
+            }
+        }
+        Ok(members)
+    }
+
+    /// Reads the datatype, dimensions and data of a dataset.
+    fn dataset(&self, object: &Object) -> Hdf5Result<Dataset> {
+        let (Some(dataspace), Some(datatype), Some(layout)) = (
+            object.message(DATASPACE),
+            object.message(DATATYPE),
+            object.message(LAYOUT),
+        ) else {
+            let offset = object.messages.first().map_or(0, |m| m.offset);
+            return Err(self.invalid(offset, "expected a dataset"));
+        };
+        let datatype = self.at(datatype.offset).datatype(0)?;
+        let dims = self.at(dataspace.offset).dataspace()?;
+        let element_size = datatype.size();
+        let numel = dims
+            .iter()
+            .try_fold(1usize, |numel, &dim| numel.checked_mul(dim))
+            .filter(|numel| numel.checked_mul(element_size).is_some())
+            .ok_or_else(|| {
+                ParseError::new(
+                    &self.input[dataspace.offset..],
+                    ParseErrorKind::TooManyElements,
+                )
+            })?;
+        let len = numel * element_size;
+
+        let mut cursor = self.at(layout.offset);
+        let version = cursor.u8()?;
+        let (class, address, chunk) = match version {
+            1 | 2 => {
+                let ndims = cursor.u8()?;
+                let class = cursor.u8()?;
+                cursor.skip(5)?;
+                let address = match class {
+                    0 => None,
+                    _ => cursor.address()?,
+                };
+                let sizes = (0..ndims)
+                    .map(|_| cursor.u32())
+                    .collect::<Hdf5Result<Vec<_>>>()?;
+                if class == 0 {
+                    cursor.skip(4)?;
+                }
+                (class, address, sizes)
+            }
+            3 => match cursor.u8()? {
+                0 => {
+                    cursor.skip(2)?;
+                    (0, None, Vec::new())
+                }
+                1 => (1, cursor.address()?, Vec::new()),
+                2 => {
+                    let ndims = cursor.u8()?;
+                    let address = cursor.address()?;
+                    let sizes = (0..ndims)
+                        .map(|_| cursor.u32())
+                        .collect::<Hdf5Result<Vec<_>>>()?;
+                    (2, address, sizes)
+                }
+                _ => return Err(self.invalid(layout.offset, "unknown data layout")),
+            },
+            _ => {
+                return Err(self.invalid(
+                    layout.offset,
+                    "only data layout versions 1 to 3 are supported",
+                ))
+            }
+        };
+        let data = match (class, address) {
+            // Compact data follows the layout message
+            (0, _) => cursor.bytes(len)?.to_vec(),
+            // Data that was never written is filled with zeros
+            (_, None) => vec![0; len],
+            (1, Some(address)) => self.at(address).bytes(len)?.to_vec(),
+            (2, Some(btree)) => {
+                if len as u64 > self.options.max_decompressed_size {
+                    return Err(ParseError::new(
+                        &self.input[layout.offset..],
+                        ParseErrorKind::DecompressedSizeLimit,
+                    ));
+                }
+                let filters = object
+                    .message(FILTER_PIPELINE)
+                    .map(|message| self.at(message.offset).filters())
+                    .transpose()?
+                    .unwrap_or_default();
+                self.chunked(btree, &dims, &chunk, element_size, &filters, layout.offset)?
+            }
+            _ => return Err(self.invalid(layout.offset, "unknown data layout")),
+        };
+        Ok(Dataset {
+            datatype,
+            dimensions: matlab_dimensions(&dims),
+            data,
+        })
+    }
+
+    /// Reads the chunks of a dataset from the B-tree at `btree` and copies
+    /// them into place.
+    fn chunked(
+        &self,
+        btree: usize,
+        dims: &[usize],
+        chunk: &[u32],
+        element_size: usize,
+        filters: &[u16],
+        offset: usize,
+    ) -> Hdf5Result<Vec<u8>> {
+        // The chunk dimensions end with the size of an element
+        let ndims = dims.len();
+        if chunk.len() != ndims + 1 || ndims == 0 || chunk[..ndims].contains(&0) {
+            return Err(self.invalid(offset, "chunk dimensions don't match the dataspace"));
+        }
+        let chunk: Vec<usize> = chunk[..ndims].iter().map(|&c| c as usize).collect();
+        let chunk_len = chunk
+            .iter()
+            .try_fold(element_size, |len, &c| len.checked_mul(c))
+            .filter(|&len| len as u64 <= self.options.max_decompressed_size)
+            .ok_or_else(|| {
+                ParseError::new(&self.input[offset..], ParseErrorKind::DecompressedSizeLimit)
+            })?;
+        let mut data = vec![0; dims.iter().product::<usize>() * element_size];
+        for Chunk {
+            address,
+            size,
+            filter_mask,
+            offsets,
+        } in self.chunks(btree, ndims)?
+        {
+            let mut raw = self.at(address).bytes(size)?.to_vec();
+            for (n, filter) in filters.iter().enumerate().rev() {
+                if filter_mask & 1 << n != 0 {
+                    continue;
+                }
+                raw = match filter {
+                    1 => inflate(&raw, chunk_len).map_err(|err| {
+                        ParseError::new(
+                            &self.input[address..],
+                            ParseErrorKind::Zlib(err.to_string()),
+                        )
+                    })?,
+                    2 => unshuffle(&raw, element_size),
+                    // The Fletcher-32 checksum isn't verified, but it follows
+                    // the data and is left out when it is copied
+                    3 => raw,
+                    _ => {
+                        return Err(self
+                            .invalid(offset, "only the deflate and shuffle filters are supported"))
+                    }
+                };
+            }
+            if raw.len() < chunk_len {
+                return Err(self.invalid(address, "chunk smaller than its dimensions"));
+            }
+
+            // Copy the rows along the last dimension that are inside the
+            // dataset
+            let offsets: Vec<usize> = offsets
+                .iter()
+                .map(|&o| usize::try_from(o).unwrap_or(usize::MAX))
+                .collect();
+            if offsets.iter().zip(dims).any(|(&o, &dim)| o >= dim) {
+                continue;
+            }
+            let last = ndims - 1;
+            let row_len = chunk[last].min(dims[last] - offsets[last]) * element_size;
+            let rows: usize = chunk[..last].iter().product();
+            let mut index = vec![0; last];
+            for row in 0..rows {
+                let inside = (0..last).all(|d| offsets[d] + index[d] < dims[d]);
+                if inside {
+                    let target = (0..ndims).fold(0, |target, d| {
+                        let coordinate = offsets[d] + if d < last { index[d] } else { 0 };
+                        target * dims[d] + coordinate
+                    });
+                    let source = row * chunk[last] * element_size;
+                    let target = target * element_size;
+                    data[target..target + row_len].copy_from_slice(&raw[source..source + row_len]);
+                }
+                // The index of the next row, in row-major order
+                for d in (0..last).rev() {
+                    index[d] += 1;
+                    if index[d] < chunk[d] {
+                        break;
+                    }
+                    index[d] = 0;
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    /// Collects the chunks from the B-tree of a chunked dataset.
+    fn chunks(&self, btree: usize, ndims: usize) -> Hdf5Result<Vec<Chunk>> {
+        let mut chunks = Vec::new();
+        let mut visited = HashSet::new();
+        let mut nodes = vec![(btree, None)];
+        while let Some((address, parent_level)) = nodes.pop() {
+            if !visited.insert(address) {
+                return Err(self.invalid(address, "chunk B-tree node used twice"));
+            }
+            let mut cursor = self.at(address);
+            cursor.signature(b"TREE")?;
+            let (node_type, level) = (cursor.u8()?, cursor.u8()?);
+            if node_type != 1 || parent_level.is_some_and(|parent| level >= parent) {
+                return Err(self.invalid(address, "invalid chunk B-tree node"));
+            }
+            let entries = cursor.u16()?;
+            cursor.skip(2 * self.offset_size)?;
+            for _ in 0..entries {
+                let size = cursor.u32()? as usize;
+                let filter_mask = cursor.u32()?;
+                let offsets = (0..=ndims)
+                    .map(|_| cursor.uint(8))
+                    .collect::<Hdf5Result<Vec<_>>>()?;
+                let child = cursor.required_address()?;
+                match level {
+                    0 => chunks.push(Chunk {
+                        address: child,
+                        size,
+                        filter_mask,
+                        offsets: offsets[..ndims].to_vec(),
+                    }),
+                    _ => nodes.push((child, Some(level))),
+                }
+            }
+        }
+        Ok(chunks)
+    }
+
+    /// Reads the array at `address`, or `None` if it is an object or of a
+    /// class that isn't supported.
+    fn element(&self, address: usize, name: &str, depth: usize) -> Hdf5Result<Option<DataElement>> {
+        if depth > self.options.max_depth {
+            return Err(ParseError::new(
+                &self.input[address.min(self.input.len())..],
+                ParseErrorKind::NestingTooDeep,
+            ));
+        }
+        let object = self.object(address)?;
+        let header = |class, dimensions: Vec<usize>| ArrayHeader {
+            flags: ArrayFlags {
+                complex: false,
+                global: object.global,
+                logical: object.class.as_deref() == Some("logical"),
+                class,
+                nzmax: 0,
+            },
+            dimensions: dimensions.into(),
+            name: name.to_string(),
+        };
+        let class = object.class.as_deref().and_then(array_type);
+
+        if object.message(SYMBOL_TABLE).is_some() {
+            let members = self.group_members(&object)?;
+            return match (class, object.sparse_rows) {
+                (Some(class), Some(rows)) => {
+                    let header = header(ArrayType::Sparse, Vec::new());
+                    self.sparse(&members, class, rows, header, address)
+                        .map(|sparse| Some(DataElement::SparseMatrix(sparse)))
+                }
+                (Some(ArrayType::Struct), None) => {
+                    let field_names = object
+                        .field_names
+                        .clone()
+                        .unwrap_or_else(|| members.iter().map(|(name, _)| name.clone()).collect());
+                    self.structure(
+                        &members,
+                        field_names,
+                        header(ArrayType::Struct, Vec::new()),
+                        depth,
+                    )
+                    .map(|structure| Some(DataElement::StructureMatrix(structure)))
+                }
+                _ => Ok(None),
+            };
+        }
+
+        let Some(class) = class.filter(|_| !object.object_decode) else {
+            return Ok(None);
+        };
+        let dataset = self.dataset(&object)?;
+        let offset = object.message(DATATYPE).map_or(address, |m| m.offset);
+        if object.empty {
+            // The data holds the dimensions of the empty array
+            let dimensions = self.indices(&dataset, offset)?;
+            let header = header(class, dimensions);
+            return Ok(Some(match class {
+                ArrayType::Cell => DataElement::CellMatrix(Cell {
+                    header,
+                    values: Vec::new(),
+                }),
+                ArrayType::Struct => DataElement::StructureMatrix(Structure {
+                    header,
+                    field_names: object.field_names.unwrap_or_default(),
+                    values: Vec::new(),
+                }),
+                ArrayType::Char => DataElement::CharacterMatrix(Character {
+                    header,
+                    real_part: CharacterData::Unicode(String::new()),
+                    imag_part: None,
+                }),
+                class => DataElement::NumericMatrix(Numeric {
+                    header,
+                    data: self.numeric(class, &dataset.datatype, &[], offset)?,
+                    imag_skipped: false,
+                }),
+            }));
+        }
+
+        let mut header = header(class, dataset.dimensions.clone());
+        Ok(Some(match class {
+            ArrayType::Cell => {
+                let values = self
+                    .references(&dataset, offset)?
+                    .into_iter()
+                    .map(|address| self.nested(address, "", depth))
+                    .collect::<Hdf5Result<_>>()?;
+                DataElement::CellMatrix(Cell { header, values })
+            }
+            ArrayType::Char => {
+                let NumericData::UInt16 { real, .. } =
+                    self.numeric(ArrayType::UInt16, &dataset.datatype, &dataset.data, offset)?
+                else {
+                    unreachable!()
+                };
+                DataElement::CharacterMatrix(Character {
+                    header,
+                    real_part: CharacterData::from_utf16(real),
+                    imag_part: None,
+                })
+            }
+            ArrayType::Struct => return Ok(None),
+            class => {
+                header.flags.complex = is_complex(&dataset.datatype);
+                DataElement::NumericMatrix(Numeric {
+                    imag_skipped: header.flags.complex && !self.options.load_imaginary,
+                    header,
+                    data: self.numeric(class, &dataset.datatype, &dataset.data, offset)?,
+                })
+            }
+        }))
+    }
+
+    /// Reads a cell or field value, which has to be an array.
+    fn nested(&self, address: usize, name: &str, depth: usize) -> Hdf5Result<DataElement> {
+        self.element(address, name, depth + 1)?.ok_or_else(|| {
+            self.invalid(
+                address,
+                "cells and structures holding objects aren't supported",
+            )
+        })
+    }
+
+    /// Reads a structure group. Fields of scalar structures are arrays of
+    /// their own, and those of structure arrays datasets of references to
+    /// the value for each element.
+    fn structure(
+        &self,
+        members: &[(String, usize)],
+        field_names: Vec<String>,
+        mut header: ArrayHeader,
+        depth: usize,
+    ) -> Hdf5Result<Structure> {
+        let fields = field_names
+            .iter()
+            .map(|field| {
+                members
+                    .iter()
+                    .find(|(name, _)| name == field)
+                    .map(|&(_, address)| address)
+                    .ok_or_else(|| self.invalid(self.base, "missing structure field"))
+            })
+            .collect::<Hdf5Result<Vec<_>>>()?;
+        // Datasets of references without a class are the fields of a
+        // structure array
+        let mut arrays = Vec::new();
+        for &address in &fields {
+            let object = self.object(address)?;
+            if object.class.is_none() && object.message(DATATYPE).is_some() {
+                let dataset = self.dataset(&object)?;
+                let references = self.references(&dataset, address)?;
+                arrays.push((dataset.dimensions, references));
+            }
+        }
+        let values = if arrays.is_empty() {
+            header.dimensions = vec![1, 1].into();
+            fields
+                .iter()
+                .zip(&field_names)
+                .map(|(&address, field)| self.nested(address, field, depth))
+                .collect::<Hdf5Result<_>>()?
+        } else {
+            let dimensions = arrays[0].0.clone();
+            let numel = dimensions.iter().product();
+            if arrays.len() != fields.len() || arrays.iter().any(|(dims, _)| *dims != dimensions) {
+                return Err(self.invalid(fields[0], "fields of a structure array differ in size"));
+            }
+            header.dimensions = dimensions.into();
+            let mut values = Vec::with_capacity(numel * fields.len());
+            for n in 0..numel {
+                for ((_, references), field) in arrays.iter().zip(&field_names) {
+                    values.push(self.nested(references[n], field, depth)?);
+                }
+            }
+            values
+        };
+        Ok(Structure {
+            header,
+            field_names,
+            values,
+        })
+    }
+
+    /// Reads a sparse matrix group, which holds the row indices in `ir`,
+    /// the column offsets in `jc` and the values in `data`. The dimensions
+    /// and flags of `header` are filled in.
+    fn sparse(
+        &self,
+        members: &[(String, usize)],
+        class: ArrayType,
+        rows: u64,
+        mut header: ArrayHeader,
+        address: usize,
+    ) -> Hdf5Result<Sparse> {
+        let member = |name: &str| {
+            members
+                .iter()
+                .find(|(member, _)| member == name)
+                .map(|&(_, address)| -> Hdf5Result<_> { self.dataset(&self.object(address)?) })
+                .transpose()
+        };
+        let (Some(ir), Some(jc)) = (member("ir")?, member("jc")?) else {
+            return Err(self.invalid(address, "sparse matrix without indices"));
+        };
+        let row_index = self.indices(&ir, address)?;
+        let column_index = self.indices(&jc, address)?;
+        // All values are zero if there is no data
+        let (data, complex) = match member("data")? {
+            Some(data) => (
+                self.numeric(class, &data.datatype, &data.data, address)?,
+                is_complex(&data.datatype),
+            ),
+            None => (self.numeric(class, &jc.datatype, &[], address)?, false),
+        };
+        let rows = usize::try_from(rows).map_err(|_| self.invalid(address, "too many rows"))?;
+        let columns = column_index
+            .len()
+            .checked_sub(1)
+            .ok_or_else(|| self.invalid(address, "sparse matrix without column offsets"))?;
+        header.dimensions = vec![rows, columns].into();
+        header.flags.complex = complex;
+        header.flags.nzmax = row_index.len();
+        Ok(Sparse {
+            header,
+            row_index,
+            column_index,
+            data,
+            imag_skipped: complex && !self.options.load_imaginary,
+        })
+    }
+
+    /// Reads the addresses in a dataset of object references.
+    fn references(&self, dataset: &Dataset, offset: usize) -> Hdf5Result<Vec<usize>> {
+        let Datatype::Reference { size } = dataset.datatype else {
+            return Err(self.invalid(offset, "expected object references"));
+        };
+        if size != self.offset_size {
+            return Err(self.invalid(offset, "only object references are supported"));
+        }
+        dataset
+            .data
+            .chunks_exact(size)
+            .map(|reference| {
+                let value = reference
+                    .iter()
+                    .rev()
+                    .fold(0u64, |n, &b| n << 8 | u64::from(b));
+                usize::try_from(value)
+                    .ok()
+                    .and_then(|value| value.checked_add(self.base))
+                    .ok_or_else(|| self.invalid(offset, "reference outside of the file"))
+            })
+            .collect()
+    }
+
+    /// Reads a dataset of unsigned integers as indices.
+    fn indices(&self, dataset: &Dataset, offset: usize) -> Hdf5Result<Vec<usize>> {
+        let NumericData::UInt64 { real, .. } =
+            self.numeric(ArrayType::UInt64, &dataset.datatype, &dataset.data, offset)?
+        else {
+            unreachable!()
+        };
+        real.into_iter()
+            .map(|index| {
+                usize::try_from(index).map_err(|_| self.invalid(offset, "index too large"))
+            })
+            .collect()
+    }
+
+    /// Decodes numeric data, which is either of the type of `class` or a
+    /// compound of a `real` and an `imag` member of that type. The imaginary
+    /// part is left out without [`ParseOptions::load_imaginary`]. Empty data
+    /// may have any type, as empty arrays store their dimensions instead.
+    fn numeric(
+        &self,
+        class: ArrayType,
+        datatype: &Datatype,
+        data: &[u8],
+        offset: usize,
+    ) -> Hdf5Result<NumericData> {
+        let stride = datatype.size().max(1);
+        let (real, imag) = match datatype {
+            _ if data.is_empty() => ((0, &Datatype::Other { size: 0 }), None),
+            Datatype::Compound { members, .. } => match members.as_slice() {
+                [(real, real_offset, real_type), (imag, imag_offset, imag_type)]
+                    if real == "real" && imag == "imag" =>
+                {
+                    ((*real_offset, real_type), Some((*imag_offset, imag_type)))
+                }
+                _ => return Err(self.invalid(offset, "compound data isn't complex")),
+            },
+            datatype => ((0, datatype), None),
+        };
+        // The byte order of a part, if it has the type Matlab uses for
+        // `class` and fits into an element
+        let part = |(part_offset, datatype): (usize, &Datatype)| -> Hdf5Result<(usize, bool)> {
+            if data.is_empty() {
+                return Ok((0, false));
+            }
+            let (size, float, signed) = match class {
+                ArrayType::Double => (8, true, true),
+                ArrayType::Single => (4, true, true),
+                ArrayType::Int8 => (1, false, true),
+                ArrayType::UInt8 => (1, false, false),
+                ArrayType::Int16 => (2, false, true),
+                ArrayType::UInt16 => (2, false, false),
+                ArrayType::Int32 => (4, false, true),
+                ArrayType::UInt32 => (4, false, false),
+                ArrayType::Int64 => (8, false, true),
+                _ => (8, false, false),
+            };
+            let big_endian = match *datatype {
+                Datatype::Float {
+                    size: s,
+                    big_endian,
+                } if float && s == size => big_endian,
+                Datatype::Integer {
+                    size: s,
+                    signed: sg,
+                    big_endian,
+                } if !float && s == size && sg == signed => big_endian,
+                _ => {
+                    return Err(
+                        self.invalid(offset, "data type doesn't match the MATLAB_class attribute")
+                    )
+                }
+            };
+            if part_offset + size > stride {
+                return Err(self.invalid(offset, "compound member outside of its element"));
+            }
+            Ok((part_offset, big_endian))
+        };
+        let real = part(real)?;
+        let imag = imag
+            .filter(|_| self.options.load_imaginary)
+            .map(part)
+            .transpose()?;
+        macro_rules! decode {
+            ($ty:ty, $part:expr) => {{
+                let (part_offset, big_endian): (usize, bool) = $part;
+                data.chunks_exact(stride)
+                    .map(|element| {
+                        let size = std::mem::size_of::<$ty>();
+                        let bytes = element[part_offset..part_offset + size].try_into().unwrap();
+                        match big_endian {
+                            true => <$ty>::from_be_bytes(bytes),
+                            false => <$ty>::from_le_bytes(bytes),
+                        }
+                    })
+                    .collect()
+            }};
+        }
+        macro_rules! parts {
+            ($variant:ident, $ty:ty) => {
+                NumericData::$variant {
+                    real: decode!($ty, real),
+                    imag: imag.map(|imag| decode!($ty, imag)),
+                }
+            };
+        }
+        Ok(match class {
+            ArrayType::Double => parts!(Double, f64),
+            ArrayType::Single => parts!(Single, f32),
+            ArrayType::Int8 => parts!(Int8, i8),
+            ArrayType::UInt8 => parts!(UInt8, u8),
+            ArrayType::Int16 => parts!(Int16, i16),
+            ArrayType::UInt16 => parts!(UInt16, u16),
+            ArrayType::Int32 => parts!(Int32, i32),
+            ArrayType::UInt32 => parts!(UInt32, u32),
+            ArrayType::Int64 => parts!(Int64, i64),
+            _ => parts!(UInt64, u64),
+        })
+    }
+
+    /// The header of a variable the filter leaves out.
+    fn skipped(&self, address: usize, name: &str) -> Hdf5Result<DataElement> {
+        let object = self.object(address)?;
+        let class = match (
+            object.class.as_deref().and_then(array_type),
+            object.sparse_rows,
+        ) {
+            (_, Some(_)) => ArrayType::Sparse,
+            (Some(class), None) => class,
+            (None, None) => ArrayType::Object,
+        };
+        let dimensions = match object.message(DATASPACE) {
+            Some(dataspace) if !object.empty => {
+                matlab_dimensions(&self.at(dataspace.offset).dataspace()?)
+            }
+            _ => vec![1, 1],
+        };
+        Ok(DataElement::Skipped(ArrayHeader {
+            flags: ArrayFlags {
+                complex: false,
+                global: object.global,
+                logical: object.class.as_deref() == Some("logical"),
+                class,
+                nzmax: 0,
+            },
+            dimensions: dimensions.into(),
+            name: name.to_string(),
+        }))
+    }
+}
+
+impl<'a> Cursor<'a, '_> {
+    fn bytes(&mut self, len: usize) -> Hdf5Result<&'a [u8]> {
+        let input = self.file.input;
+        let bytes = self
+            .offset
+            .checked_add(len)
+            .and_then(|end| input.get(self.offset..end))
+            .ok_or_else(|| {
+                ParseError::new(
+                    &input[self.offset.min(input.len())..],
+                    ParseErrorKind::UnexpectedEof,
+                )
+            })?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Hdf5Result<()> {
+        self.bytes(len).map(drop)
+    }
+
+    /// Reads a little-endian integer of `len` bytes.
+    fn uint(&mut self, len: usize) -> Hdf5Result<u64> {
+        Ok(self
+            .bytes(len)?
+            .iter()
+            .rev()
+            .fold(0, |n, &b| n << 8 | u64::from(b)))
+    }
+
+    fn u8(&mut self) -> Hdf5Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Hdf5Result<u16> {
+        Ok(self.uint(2)? as u16)
+    }
+
+    fn u32(&mut self) -> Hdf5Result<u32> {
+        Ok(self.uint(4)? as u32)
+    }
+
+    /// Reads a length, which has to fit into memory.
+    fn length(&mut self) -> Hdf5Result<usize> {
+        let offset = self.offset;
+        let length = self.uint(self.file.length_size)?;
+        usize::try_from(length).map_err(|_| self.file.invalid(offset, "length too large"))
+    }
+
+    /// Reads an address, which is `None` if it is undefined.
+    fn address(&mut self) -> Hdf5Result<Option<usize>> {
+        let offset = self.offset;
+        let size = self.file.offset_size;
+        let address = self.uint(size)?;
+        if address == u64::MAX >> (64 - 8 * size) {
+            return Ok(None);
+        }
+        usize::try_from(address)
+            .ok()
+            .and_then(|address| address.checked_add(self.file.base))
+            .map(Some)
+            .ok_or_else(|| self.file.invalid(offset, "address outside of the file"))
+    }
+
+    fn required_address(&mut self) -> Hdf5Result<usize> {
+        let offset = self.offset;
+        self.address()?
+            .ok_or_else(|| self.file.invalid(offset, "undefined address"))
+    }
+
+    fn signature(&mut self, signature: &[u8; 4]) -> Hdf5Result<()> {
+        let offset = self.offset;
+        match self.bytes(4)? == signature {
+            true => Ok(()),
+            false => Err(self.file.invalid(offset, "missing signature")),
+        }
+    }
+
+    /// Reads a null-terminated string.
+    fn c_string(&mut self) -> Hdf5Result<&'a [u8]> {
+        let rest = self.file.input.get(self.offset..).unwrap_or_default();
+        let len = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| self.file.invalid(self.offset, "unterminated name"))?;
+        let name = self.bytes(len)?;
+        self.skip(1)?;
+        Ok(name)
+    }
+
+    /// Reads the dimensions of a dataspace message, which are empty for
+    /// scalars.
+    fn dataspace(&mut self) -> Hdf5Result<Vec<usize>> {
+        let start = self.offset;
+        let version = self.u8()?;
+        let ndims = self.u8()?;
+        self.skip(1)?;
+        match version {
+            1 => self.skip(5)?,
+            // A null dataspace has no elements
+            2 if self.u8()? == 2 => return Ok(vec![0]),
+            2 => {}
+            _ => return Err(self.file.invalid(start, "unknown dataspace version")),
+        }
+        (0..ndims).map(|_| self.length()).collect()
+    }
+
+    /// Reads a datatype message. `depth` counts the enclosing compound and
+    /// variable-length types.
+    fn datatype(&mut self, depth: usize) -> Hdf5Result<Datatype> {
+        let start = self.offset;
+        if depth > self.file.options.max_depth {
+            return Err(ParseError::new(
+                &self.file.input[start..],
+                ParseErrorKind::NestingTooDeep,
+            ));
+        }
+        let class_and_version = self.u8()?;
+        let bits = self.uint(3)? as u32;
+        let size = self.u32()? as usize;
+        let version = class_and_version >> 4;
+        Ok(match class_and_version & 0x0F {
+            0 => {
+                self.skip(4)?;
+                Datatype::Integer {
+                    size,
+                    signed: bits & 0x08 != 0,
+                    big_endian: bits & 0x01 != 0,
+                }
+            }
+            1 => {
+                self.skip(12)?;
+                match bits & 0x41 {
+                    0 => Datatype::Float {
+                        size,
+                        big_endian: false,
+                    },
+                    1 => Datatype::Float {
+                        size,
+                        big_endian: true,
+                    },
+                    // VAX byte order
+                    _ => Datatype::Other { size },
+                }
+            }
+            2 => {
+                self.skip(2)?;
+                Datatype::Other { size }
+            }
+            3 => Datatype::String { size },
+            4 => {
+                self.skip(4)?;
+                Datatype::Other { size }
+            }
+            5 => {
+                self.skip((bits & 0xFF) as usize)?;
+                Datatype::Other { size }
+            }
+            6 => {
+                let mut members = Vec::new();
+                for _ in 0..bits & 0xFFFF {
+                    let name_start = self.offset;
+                    let name = String::from_utf8_lossy(self.c_string()?).into_owned();
+                    let offset = match version {
+                        1 | 2 => {
+                            self.offset =
+                                name_start + (self.offset - name_start).next_multiple_of(8);
+                            self.u32()? as usize
+                        }
+                        // As many bytes as the size of the compound needs
+                        _ => {
+                            let len = (1..4).find(|&n| size >> (8 * n) == 0).unwrap_or(4);
+                            self.uint(len)? as usize
+                        }
+                    };
+                    if version == 1 {
+                        // Array dimensions, which aren't supported
+                        self.skip(28)?;
+                    }
+                    members.push((name, offset, self.datatype(depth + 1)?));
+                }
+                Datatype::Compound { size, members }
+            }
+            7 => Datatype::Reference { size },
+            9 => Datatype::VariableLength {
+                size,
+                base: Box::new(self.datatype(depth + 1)?),
+            },
+            _ => {
+                return Err(self
+                    .file
+                    .invalid(start, "enumerated and array types aren't supported"))
+            }
+        })
+    }
+
+    /// Reads the name, datatype and number of elements of an attribute
+    /// message, leaving the cursor at its data.
+    fn attribute(&mut self) -> Hdf5Result<(&'a [u8], Datatype, usize)> {
+        let start = self.offset;
+        let version = self.u8()?;
+        self.skip(1)?;
+        let name_size = usize::from(self.u16()?);
+        let datatype_size = usize::from(self.u16()?);
+        let dataspace_size = usize::from(self.u16()?);
+        match version {
+            1 | 2 => {}
+            // The character set of the name
+            3 => self.skip(1)?,
+            _ => return Err(self.file.invalid(start, "unknown attribute version")),
+        }
+        // Version 1 pads each part to a multiple of 8 bytes
+        let padded = |size: usize| match version {
+            1 => size.next_multiple_of(8),
+            _ => size,
+        };
+        let name = self.bytes(padded(name_size))?;
+        let name = name[..name_size]
+            .split(|&b| b == 0)
+            .next()
+            .unwrap_or_default();
+        let next = self.offset + padded(datatype_size);
+        let datatype = self.datatype(0)?;
+        self.offset = next;
+        let next = self.offset + padded(dataspace_size);
+        let count = self
+            .dataspace()?
+            .iter()
+            .try_fold(1usize, |n, &dim| n.checked_mul(dim));
+        self.offset = next;
+        let count = count
+            .filter(|count| count.checked_mul(datatype.size()).is_some())
+            .ok_or_else(|| self.file.invalid(start, "attribute too large"))?;
+        Ok((name, datatype, count))
+    }
+
+    /// Reads a filter pipeline message, returning the filter IDs in the
+    /// order they were applied.
+    fn filters(&mut self) -> Hdf5Result<Vec<u16>> {
+        let start = self.offset;
+        let version = self.u8()?;
+        let count = self.u8()?;
+        match version {
+            1 => self.skip(6)?,
+            2 => {}
+            _ => return Err(self.file.invalid(start, "unknown filter pipeline version")),
+        }
+        let mut filters = Vec::new();
+        for _ in 0..count {
+            let id = self.u16()?;
+            let name_len = match version {
+                2 if id < 256 => 0,
+                _ => self.u16()?,
+            };
+            self.skip(2)?;
+            let values = usize::from(self.u16()?);
+            self.skip(usize::from(name_len))?;
+            self.skip(4 * values)?;
+            if version == 1 && values % 2 == 1 {
+                self.skip(4)?;
+            }
+            filters.push(id);
+        }
+        Ok(filters)
+    }
+}
+
+fn invalid(input: &[u8], offset: usize, reason: &'static str) -> ParseError {
+    ParseError::new(
+        &input[offset.min(input.len())..],
+        ParseErrorKind::InvalidHdf5(reason),
+    )
+}
+
+/// The class of an array from its `MATLAB_class` attribute. Logical arrays
+/// are stored as uint8.
+fn array_type(class: &str) -> Option<ArrayType> {
+    Some(match class {
+        "double" => ArrayType::Double,
+        "single" => ArrayType::Single,
+        "int8" => ArrayType::Int8,
+        "uint8" | "logical" => ArrayType::UInt8,
+        "int16" => ArrayType::Int16,
+        "uint16" => ArrayType::UInt16,
+        "int32" => ArrayType::Int32,
+        "uint32" => ArrayType::UInt32,
+        "int64" => ArrayType::Int64,
+        "uint64" => ArrayType::UInt64,
+        "char" => ArrayType::Char,
+        "cell" => ArrayType::Cell,
+        "struct" => ArrayType::Struct,
+        _ => return None,
+    })
+}
+
+fn is_complex(datatype: &Datatype) -> bool {
+    matches!(datatype, Datatype::Compound { .. })
+}
+
+/// The Matlab dimensions of a dataset, which are its HDF5 dimensions in
+/// reverse, with at least two of them.
+fn matlab_dimensions(dims: &[usize]) -> Vec<usize> {
+    let mut dimensions: Vec<usize> = dims.iter().rev().copied().collect();
+    if dimensions.len() < 2 {
+        dimensions.resize(2, 1);
+    }
+    dimensions
+}
+
+/// Decompresses a chunk, which is allowed to be a little larger than
+/// `len` for the checksum of the Fletcher-32 filter.
+fn inflate(data: &[u8], len: usize) -> std::io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    Decoder::new(data)?
+        .take(len as u64 + 4)
+        .read_to_end(&mut out)?;
+    Ok(out)
+}
+
+/// Reverses the shuffle filter, which stores the first bytes of all
+/// elements, then the second bytes and so on.
+fn unshuffle(data: &[u8], element_size: usize) -> Vec<u8> {
+    let count = data.len() / element_size.max(1);
+    let mut out = data.to_vec();
+    for (n, &byte) in data[..count * element_size].iter().enumerate() {
+        out[n % count * element_size + n / count] = byte;
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse() -> ParseResult {
+        parse_all(include_bytes!("../tests/v73.mat")).unwrap()
+    }
+
+    fn get<'a>(result: &'a ParseResult, name: &str) -> &'a DataElement {
+        result
+            .data_elements
+            .iter()
+            .find(|element| element.name() == Some(name))
+            .unwrap_or_else(|| panic!("missing variable {}", name))
+    }
+
+    #[test]
+    fn variables() {
+        let result = parse();
+        assert!(result.header.text.starts_with("MATLAB 7.3 MAT-file"));
+        assert!(result.header.is_little_endian);
+        let names: Vec<_> = result.iter().map(|(name, _)| name).collect();
+        assert_eq!(
+            names,
+            ["a", "b", "c", "cel", "e", "flag", "s", "sa", "sp", "st"]
+        );
+        // The string object can't be read without the subsystem data
+        assert_eq!(result.warnings.len(), 1);
+        assert_eq!(result.warnings[0].kind, WarningKind::SkippedVariable);
+        assert_eq!(result.warnings[0].variable_name.as_deref(), Some("str"));
+        // Written as a Level 5 file with a header of its own
+        let written = crate::write::write_all(&result).unwrap();
+        assert!(written.starts_with(b"MATLAB 5.0 MAT-file"));
+        let read = crate::parse::parse_all(&written).unwrap();
+        assert_eq!(read.data_elements, result.data_elements);
+
+        let strict = ParseOptions::new().strict(true);
+        assert!(parse_all_with_options(include_bytes!("../tests/v73.mat"), &strict).is_err());
+    }
+
+    #[test]
+    fn numeric() {
+        let result = parse();
+        let DataElement::NumericMatrix(a) = get(&result, "a") else {
+            panic!("expected a numeric array");
+        };
+        assert_eq!(a.header.dimensions.as_slice(), &[2, 3]);
+        assert_eq!(
+            a.data,
+            NumericData::Double {
+                real: vec![1.0, 4.0, 2.0, 5.0, 3.0, 6.0],
+                imag: None,
+            }
+        );
+
+        let DataElement::NumericMatrix(b) = get(&result, "b") else {
+            panic!("expected a numeric array");
+        };
+        assert_eq!(b.header.dimensions.as_slice(), &[1, 2]);
+        assert!(b.header.flags.complex);
+        assert_eq!(
+            b.data,
+            NumericData::Single {
+                real: vec![1.0, -3.5],
+                imag: Some(vec![2.0, 0.0]),
+            }
+        );
+
+        // Stored in shuffled and deflated chunks, which overlap the edges
+        let DataElement::NumericMatrix(c) = get(&result, "c") else {
+            panic!("expected a numeric array");
+        };
+        assert_eq!(c.header.dimensions.as_slice(), &[3, 4]);
+        assert_eq!(
+            c.data,
+            NumericData::Int32 {
+                real: (1..=12).collect(),
+                imag: None,
+            }
+        );
+
+        let DataElement::NumericMatrix(flag) = get(&result, "flag") else {
+            panic!("expected a numeric array");
+        };
+        assert!(flag.header.flags.logical);
+        assert_eq!(flag.header.dimensions.as_slice(), &[1, 3]);
+        assert_eq!(
+            flag.data,
+            NumericData::UInt8 {
+                real: vec![1, 0, 1],
+                imag: None,
+            }
+        );
+
+        let DataElement::NumericMatrix(e) = get(&result, "e") else {
+            panic!("expected a numeric array");
+        };
+        assert_eq!(e.header.dimensions.as_slice(), &[0, 3]);
+        assert_eq!(
+            e.data,
+            NumericData::Double {
+                real: Vec::new(),
+                imag: None,
+            }
+        );
+    }
+
+    #[test]
+    fn characters() {
+        let result = parse();
+        let DataElement::CharacterMatrix(s) = get(&result, "s") else {
+            panic!("expected a character array");
+        };
+        assert_eq!(s.header.dimensions.as_slice(), &[1, 5]);
+        assert_eq!(s.real_part, CharacterData::Unicode("héllo".to_string()));
+    }
+
+    #[test]
+    fn structures_and_cells() {
+        let result = parse();
+        let DataElement::StructureMatrix(st) = get(&result, "st") else {
+            panic!("expected a structure");
+        };
+        assert_eq!(st.header.dimensions.as_slice(), &[1, 1]);
+        // In the order of the MATLAB_fields attribute, not by name
+        assert_eq!(st.field_names, ["x", "name"]);
+        assert_eq!(st.values[0].name(), Some("x"));
+        let DataElement::CharacterMatrix(name) = &st.values[1] else {
+            panic!("expected a character array");
+        };
+        assert_eq!(name.header.name, "name");
+        assert_eq!(name.real_part, CharacterData::Unicode("ab".to_string()));
+
+        let DataElement::StructureMatrix(sa) = get(&result, "sa") else {
+            panic!("expected a structure");
+        };
+        assert_eq!(sa.header.dimensions.as_slice(), &[1, 2]);
+        assert_eq!(sa.field_names, ["v"]);
+        assert!(matches!(&sa.values[0], DataElement::NumericMatrix(v) if v.header.name == "v"));
+        assert!(matches!(&sa.values[1], DataElement::CharacterMatrix(v) if v.header.name == "v"));
+
+        let DataElement::CellMatrix(cel) = get(&result, "cel") else {
+            panic!("expected a cell array");
+        };
+        assert_eq!(cel.header.dimensions.as_slice(), &[1, 2]);
+        let DataElement::NumericMatrix(seven) = &cel.values[0] else {
+            panic!("expected a numeric array");
+        };
+        assert_eq!(seven.header.name, "");
+        assert_eq!(
+            seven.data,
+            NumericData::Double {
+                real: vec![7.0],
+                imag: None,
+            }
+        );
+        assert!(matches!(&cel.values[1], DataElement::CharacterMatrix(_)));
+    }
+
+    #[test]
+    fn sparse() {
+        let result = parse();
+        let DataElement::SparseMatrix(sp) = get(&result, "sp") else {
+            panic!("expected a sparse matrix");
+        };
+        assert_eq!(sp.header.dimensions.as_slice(), &[3, 2]);
+        assert_eq!(sp.row_index, [0, 2, 1]);
+        assert_eq!(sp.column_index, [0, 2, 3]);
+        assert_eq!(
+            sp.data,
+            NumericData::Double {
+                real: vec![10.0, 20.0, 30.0],
+                imag: None,
+            }
+        );
+    }
+
+    #[test]
+    fn options() {
+        let data = include_bytes!("../tests/v73.mat");
+        let options = ParseOptions::new()
+            .filter(|name| name == "a")
+            .load_imaginary(false);
+        let result = parse_all_with_options(data, &options).unwrap();
+        assert!(matches!(get(&result, "a"), DataElement::NumericMatrix(_)));
+        assert!(matches!(
+            get(&result, "c"),
+            DataElement::Skipped(header) if header.dimensions.as_slice() == [3, 4]
+        ));
+
+        let result =
+            parse_all_with_options(data, &ParseOptions::new().load_imaginary(false)).unwrap();
+        let DataElement::NumericMatrix(b) = get(&result, "b") else {
+            panic!("expected a numeric array");
+        };
+        assert!(b.imag_skipped);
+        assert!(!b.data.is_complex());
+
+        let options = ParseOptions::new().max_decompressed_size(16);
+        let err = parse_all_with_options(data, &options).unwrap_err();
+        assert!(err.to_string().contains("'c'"), "{}", err);
+    }
+
+    #[test]
+    fn truncated() {
+        let data = include_bytes!("../tests/v73.mat");
+        for len in (0..data.len()).step_by(97) {
+            assert!(parse_all(&data[..len]).is_err());
+        }
+        // Flipping bytes fails or reads something, but doesn't panic
+        for n in (512..data.len()).step_by(5) {
+            let mut corrupt = data.to_vec();
+            corrupt[n] ^= 0xA5;
+            let _ = parse_all(&corrupt);
+        }
+    }
+}
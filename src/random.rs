@@ -0,0 +1,295 @@
+//! Random variables for testing code that reads or writes MAT-files.
+//!
+//! A [`Generator`] produces random but valid data elements: numeric arrays
+//! of every [`NumericData`] variant, including complex, logical and global
+//! ones, sparse matrices, character arrays, and structures and cell arrays
+//! nested up to a maximum depth. Writing the elements with any
+//! [`WriteOptions`](crate::write::WriteOptions) and parsing the file again
+//! gives the same elements. The same seed always gives the same elements.
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use matfile::random::Generator;
+//! use matfile::parse::parse_all;
+//! use matfile::write::write_all;
+//!
+//! let mut generator = Generator::new(42);
+//! for _ in 0..10 {
+//!     let result = generator.parse_result();
+//!     let written = write_all(&result)?;
+//!     assert_eq!(parse_all(&written)?.data_elements, result.data_elements);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::parse::{
+    ArrayFlags, ArrayHeader, ArrayType, Cell, Character, CharacterData, DataElement, Header,
+    Numeric, ParseResult, Sparse, Structure,
+};
+use crate::NumericData;
+
+/// Generates random data elements from a seed, see the
+/// [module documentation](self).
+#[derive(Clone, Debug)]
+pub struct Generator {
+    /// The state of a xorshift generator, which is never zero
+    state: u64,
+    max_depth: usize,
+}
+
+impl Generator {
+    /// Creates a generator whose elements are determined by `seed`.
+    pub fn new(seed: u64) -> Self {
+        Generator {
+            state: if seed == 0 {
+                0x9E37_79B9_7F4A_7C15
+            } else {
+                seed
+            },
+            max_depth: 3,
+        }
+    }
+
+    /// Sets how deeply structures and cell arrays may be nested. A depth of
+    /// 0 only generates numeric, sparse and character arrays. The default is
+    /// 3.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// The next random number.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// A file holding one to four random variables named `v0`, `v1`, and
+    /// so on. The header is left empty, so writers fill in their own.
+    pub fn parse_result(&mut self) -> ParseResult {
+        let data_elements = (0..1 + self.below(4))
+            .map(|i| self.data_element(&format!("v{}", i)))
+            .collect();
+        ParseResult {
+            header: Header {
+                text: String::new(),
+                is_little_endian: true,
+                raw_text: [0; 116],
+                subsystem_offset: None,
+            },
+            data_elements,
+            trailing_bytes: 0,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// A random array named `name`.
+    pub fn data_element(&mut self, name: &str) -> DataElement {
+        self.element(name, self.max_depth)
+    }
+
+    /// A number in `0..n`.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    fn dimensions(&mut self) -> Vec<usize> {
+        let ndims = 2 + self.below(3) / 2;
+        (0..ndims).map(|_| self.below(4)).collect()
+    }
+
+    fn values<T>(&mut self, len: usize, value: impl Fn(u64) -> T) -> Vec<T> {
+        (0..len).map(|_| value(self.next_u64())).collect()
+    }
+
+    fn numeric_data(&mut self, len: usize) -> NumericData {
+        let complex = self.chance(30);
+        macro_rules! parts {
+            ($variant:ident, $value:expr) => {
+                NumericData::$variant {
+                    real: self.values(len, $value),
+                    imag: if complex {
+                        Some(self.values(len, $value))
+                    } else {
+                        None
+                    },
+                }
+            };
+        }
+        match self.below(10) {
+            0 => parts!(Int8, |x| x as i8),
+            1 => parts!(UInt8, |x| x as u8),
+            2 => parts!(Int16, |x| x as i16),
+            3 => parts!(UInt16, |x| x as u16),
+            4 => parts!(Int32, |x| x as i32),
+            5 => parts!(UInt32, |x| x as u32),
+            6 => parts!(Int64, |x| x as i64),
+            7 => parts!(UInt64, |x| x),
+            8 => parts!(Single, |x| (x >> 40) as f32 / 7.0 - 1e5),
+            _ => parts!(Double, |x| (x >> 11) as f64 / 3.0 - 1e15),
+        }
+    }
+
+    /// An array named `name`, with structure and cell arrays nested up to
+    /// `depth` levels deep.
+    fn element(&mut self, name: &str, depth: usize) -> DataElement {
+        let kinds = if depth == 0 { 3 } else { 5 };
+        let mut element = match self.below(kinds) {
+            0 => {
+                let dimensions = self.dimensions();
+                let len = dimensions.iter().product();
+                let logical = self.chance(20);
+                let data = if logical {
+                    NumericData::UInt8 {
+                        real: self.values(len, |x| (x % 2) as u8),
+                        imag: None,
+                    }
+                } else {
+                    self.numeric_data(len)
+                };
+                let mut header = array_header(name, data.class(), dimensions);
+                header.flags.complex = data.is_complex();
+                header.flags.logical = logical;
+                DataElement::NumericMatrix(Numeric {
+                    header,
+                    data,
+                    imag_skipped: false,
+                })
+            }
+            1 => {
+                let (rows, columns) = (1 + self.below(5), self.below(5));
+                let mut row_index = Vec::new();
+                let mut column_index = vec![0];
+                for _ in 0..columns {
+                    row_index.extend((0..rows).filter(|_| self.chance(40)));
+                    column_index.push(row_index.len());
+                }
+                let nnz = row_index.len();
+                let imag = self.chance(30).then(|| self.values(nnz, |x| x as f64));
+                let mut header = array_header(name, ArrayType::Sparse, vec![rows, columns]);
+                header.flags.complex = imag.is_some();
+                header.flags.nzmax = nnz.max(1);
+                DataElement::SparseMatrix(Sparse {
+                    header,
+                    row_index,
+                    column_index,
+                    data: NumericData::Double {
+                        real: self.values(nnz, |x| (x >> 11) as f64),
+                        imag,
+                    },
+                    imag_skipped: false,
+                })
+            }
+            2 => {
+                let alphabet = ['a', 'Z', '0', ' ', 'ä', 'ß', '€', '日', '😀'];
+                let text: String = (0..self.below(8))
+                    .map(|_| alphabet[self.below(alphabet.len())])
+                    .collect();
+                let units = text.encode_utf16().count();
+                let dimensions = if units == 0 {
+                    vec![0, 0]
+                } else {
+                    vec![1, units]
+                };
+                DataElement::CharacterMatrix(Character {
+                    header: array_header(name, ArrayType::Char, dimensions),
+                    real_part: CharacterData::Unicode(text),
+                    imag_part: None,
+                })
+            }
+            3 => {
+                let dimensions = vec![1, 1 + self.below(2)];
+                let field_names: Vec<String> =
+                    (0..self.below(4)).map(|i| format!("f{}", i)).collect();
+                let values = (0..dimensions[1])
+                    .flat_map(|_| field_names.clone())
+                    .map(|field| self.element(&field, depth - 1))
+                    .collect();
+                DataElement::StructureMatrix(Structure {
+                    header: array_header(name, ArrayType::Struct, dimensions),
+                    field_names,
+                    values,
+                })
+            }
+            _ => {
+                let dimensions = vec![1 + self.below(2), 1 + self.below(2)];
+                let values = (0..dimensions[0] * dimensions[1])
+                    .map(|_| self.element("", depth - 1))
+                    .collect();
+                DataElement::CellMatrix(Cell {
+                    header: array_header(name, ArrayType::Cell, dimensions),
+                    values,
+                })
+            }
+        };
+        if self.chance(10) {
+            match &mut element {
+                DataElement::NumericMatrix(Numeric { header, .. })
+                | DataElement::CharacterMatrix(Character { header, .. }) => {
+                    header.flags.global = true
+                }
+                _ => {}
+            }
+        }
+        element
+    }
+}
+
+fn array_header(name: &str, class: ArrayType, dimensions: Vec<usize>) -> ArrayHeader {
+    ArrayHeader {
+        flags: ArrayFlags {
+            complex: false,
+            global: false,
+            logical: false,
+            class,
+            nzmax: 0,
+        },
+        dimensions: dimensions.into(),
+        name: name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How deeply structures and cell arrays are nested in `data_element`.
+    fn depth(data_element: &DataElement) -> usize {
+        let values = match data_element {
+            DataElement::StructureMatrix(structure) => &structure.values,
+            DataElement::CellMatrix(cell) => &cell.values,
+            _ => return 0,
+        };
+        1 + values.iter().map(depth).max().unwrap_or(0)
+    }
+
+    #[test]
+    fn deterministic() {
+        let elements = |seed| {
+            let mut generator = Generator::new(seed);
+            (0..20)
+                .map(|_| generator.data_element("x"))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(elements(7), elements(7));
+        assert_ne!(elements(7), elements(8));
+    }
+
+    #[test]
+    fn nesting() {
+        for max_depth in 0..4 {
+            let mut generator = Generator::new(1).max_depth(max_depth);
+            let depths: Vec<_> = (0..500)
+                .map(|_| depth(&generator.data_element("x")))
+                .collect();
+            assert_eq!(depths.iter().max(), Some(&max_depth));
+        }
+    }
+}
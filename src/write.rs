@@ -0,0 +1,3260 @@
+//! Writer for Level 5 MAT-files.
+//!
+//! The writer is the counterpart of [`crate::parse`]: it turns the data
+//! elements of a [`ParseResult`] back into the bytes of a file. Files are
+//! written in little-endian byte order unless [`WriteOptions::byte_order`]
+//! says otherwise, with the variables compressed if
+//! [`WriteOptions::compression`] is set.
+
+use crate::parse::scan_elements;
+use crate::parse::{
+    numeric_data_types_are_compatible, ArrayFlags, ArrayHeader, ArrayType, ByteOrder, Cell,
+    Character, CharacterData, DataElement, DataType, Header, Numeric, Opaque, ParseResult, Sparse,
+    Structure, UnsupportedArray,
+};
+use crate::NumericData;
+use libflate::lz77::DefaultLz77Encoder;
+use libflate::zlib::{EncodeOptions, Encoder};
+use std::convert::TryFrom;
+use std::io::{SeekFrom, Write};
+
+/// Why a file couldn't be written.
+#[derive(Debug)]
+pub enum WriteError {
+    /// Writing to the output failed.
+    Io(std::io::Error),
+    /// The named variable is of a kind that can't be written yet.
+    Unsupported(String),
+    /// The named variable is too large for the size field of a data element
+    /// tag.
+    TooLarge(String),
+    /// The contents of the named variable are inconsistent, e.g. its data
+    /// doesn't match its dimensions.
+    InvalidArray { name: String, reason: &'static str },
+    /// A field of the named structure has a name longer than the 63 bytes
+    /// Matlab allows.
+    FieldNameTooLong { name: String, field: String },
+    /// The file to append to can't be read as a Level 5 MAT-file.
+    InvalidFile(crate::Error),
+    /// The file to append to already has a variable with the given name.
+    NameCollision(String),
+    /// The file to update has no variable with the given name.
+    NotFound(String),
+    /// The new value of the named variable doesn't fit into the space of the
+    /// old one, so the file would have to grow in the middle.
+    WouldGrow(String),
+    /// A variable or field name isn't a valid Matlab identifier. Field
+    /// names are given as `structure.field`.
+    InvalidName { name: String, error: NameError },
+}
+
+impl std::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WriteError::Io(err) => write!(f, "error writing the file: {}", err),
+            WriteError::Unsupported(name) => {
+                write!(f, "variable '{}' is of a kind that can't be written", name)
+            }
+            WriteError::TooLarge(name) => write!(f, "variable '{}' is too large", name),
+            WriteError::InvalidArray { name, reason } => {
+                write!(f, "invalid array '{}': {}", name, reason)
+            }
+            WriteError::FieldNameTooLong { name, field } => write!(
+                f,
+                "field name '{}' of structure '{}' is longer than {} bytes",
+                field, name, MAX_NAME_LENGTH
+            ),
+            WriteError::InvalidFile(err) => write!(f, "can't append to the file: {}", err),
+            WriteError::NameCollision(name) => {
+                write!(f, "the file already has a variable named '{}'", name)
+            }
+            WriteError::NotFound(name) => write!(f, "the file has no variable named '{}'", name),
+            WriteError::WouldGrow(name) => {
+                write!(
+                    f,
+                    "the new value of '{}' doesn't fit in place of the old one",
+                    name
+                )
+            }
+            WriteError::InvalidName { name, error } => {
+                write!(f, "invalid name '{}': {}", name, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WriteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WriteError::Io(err) => Some(err),
+            WriteError::InvalidFile(err) => Some(err),
+            WriteError::InvalidName { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// The longest variable or field name Matlab supports, in bytes.
+const MAX_NAME_LENGTH: usize = 63;
+
+/// Why a name can't be used for a variable or field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NameError {
+    Empty,
+    /// The name is longer than the 63 bytes Matlab allows.
+    TooLong,
+    /// The name doesn't start with an ASCII letter.
+    InvalidStart(char),
+    /// The name contains a character other than ASCII letters, digits and
+    /// underscores.
+    InvalidCharacter(char),
+}
+
+impl std::fmt::Display for NameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            NameError::Empty => write!(f, "the name is empty"),
+            NameError::TooLong => write!(f, "the name is longer than {} bytes", MAX_NAME_LENGTH),
+            NameError::InvalidStart(c) => write!(f, "the name starts with '{}'", c),
+            NameError::InvalidCharacter(c) => write!(f, "the name contains '{}'", c),
+        }
+    }
+}
+
+impl std::error::Error for NameError {}
+
+/// Checks that Matlab can load a variable or field called `name`, i.e. that
+/// it is an identifier of at most 63 bytes: an ASCII letter followed by
+/// ASCII letters, digits and underscores.
+///
+/// Matlab refuses to load variables with other names, or renames them.
+pub fn validate_name(name: &str) -> Result<(), NameError> {
+    let mut chars = name.chars();
+    match chars.next() {
+        None => return Err(NameError::Empty),
+        Some(c) if !c.is_ascii_alphabetic() => return Err(NameError::InvalidStart(c)),
+        Some(_) => {}
+    }
+    if let Some(c) = chars.find(|&c| !(c.is_ascii_alphanumeric() || c == '_')) {
+        return Err(NameError::InvalidCharacter(c));
+    }
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(NameError::TooLong);
+    }
+    Ok(())
+}
+
+fn check_name(name: &str) -> Result<(), WriteError> {
+    validate_name(name).map_err(|error| WriteError::InvalidName {
+        name: name.to_string(),
+        error,
+    })
+}
+
+/// Options that control how files are written.
+#[derive(Clone, Debug, Default)]
+pub struct WriteOptions {
+    /// Store numeric data in the smallest integer type that holds all values
+    /// without changing them, e.g. a double array of small whole numbers as
+    /// uint8, like Matlab does. Only the types the MAT-file format allows
+    /// for the array class are used, so the data is read back in its class.
+    pub compress_integers: bool,
+
+    /// Compress every variable with zlib, as Matlab does for files saved
+    /// with `-v7`. Variables are written uncompressed if this is `None`.
+    pub compression: Option<CompressionLevel>,
+
+    /// The encoding of character arrays holding valid Unicode. Arrays of
+    /// [`CharacterData::NonUnicode`] are always written as uint16.
+    pub char_encoding: CharEncoding,
+
+    /// The byte order of the file, little-endian by default.
+    pub byte_order: ByteOrder,
+
+    /// When appending, check that the file doesn't already have a variable
+    /// of the same name. This reads the start of every variable in the file.
+    pub check_names: bool,
+
+    /// Write variables and structure fields whose names Matlab can't load,
+    /// as checked by [`validate_name`], for readers that accept them.
+    /// Otherwise such names fail with [`WriteError::InvalidName`] when
+    /// appending, in the builder and for the fields of structures.
+    pub allow_invalid_names: bool,
+
+    /// Write the same bytes for the same variables on every run and
+    /// platform, e.g. for comparing generated files. The header text is
+    /// [`WriteOptions::header_text`] or a fixed text without the version of
+    /// this crate, rather than the text of a header given to the writer,
+    /// which usually holds the time the file was created.
+    ///
+    /// Variables are always written in the order they are given in, and
+    /// compression only depends on [`WriteOptions::compression`], so the
+    /// output doesn't change otherwise.
+    pub deterministic: bool,
+
+    /// The text at the start of the file, truncated to 116 bytes. Replaces
+    /// the text of a header given to the writer.
+    pub header_text: Option<String>,
+}
+
+/// How hard the writer tries to compress variables.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompressionLevel {
+    /// Store the data in the zlib format without compressing it.
+    Store,
+    /// Compress with a small window and fixed Huffman codes.
+    Fast,
+    /// Compress as well as possible.
+    #[default]
+    Default,
+}
+
+/// The encoding character arrays are written in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CharEncoding {
+    /// miUTF8, which is the most compact for mostly ASCII text.
+    Utf8,
+    /// miUTF16, holding one code unit per element of the array like Matlab.
+    #[default]
+    Utf16,
+}
+
+impl WriteOptions {
+    /// The default options, same as [`WriteOptions::default`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets [`WriteOptions::compress_integers`].
+    pub fn compress_integers(mut self, compress_integers: bool) -> Self {
+        self.compress_integers = compress_integers;
+        self
+    }
+
+    /// Sets [`WriteOptions::compression`].
+    pub fn compression(mut self, compression: Option<CompressionLevel>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets [`WriteOptions::char_encoding`].
+    pub fn char_encoding(mut self, char_encoding: CharEncoding) -> Self {
+        self.char_encoding = char_encoding;
+        self
+    }
+
+    /// Sets [`WriteOptions::byte_order`].
+    pub fn byte_order(mut self, byte_order: ByteOrder) -> Self {
+        self.byte_order = byte_order;
+        self
+    }
+
+    /// Sets [`WriteOptions::check_names`].
+    pub fn check_names(mut self, check_names: bool) -> Self {
+        self.check_names = check_names;
+        self
+    }
+
+    /// Sets [`WriteOptions::deterministic`].
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Sets [`WriteOptions::header_text`].
+    pub fn header_text<S: Into<String>>(mut self, header_text: Option<S>) -> Self {
+        self.header_text = header_text.map(Into::into);
+        self
+    }
+
+    /// Sets [`WriteOptions::allow_invalid_names`].
+    pub fn allow_invalid_names(mut self, allow_invalid_names: bool) -> Self {
+        self.allow_invalid_names = allow_invalid_names;
+        self
+    }
+}
+
+impl From<std::io::Error> for WriteError {
+    fn from(err: std::io::Error) -> Self {
+        WriteError::Io(err)
+    }
+}
+
+/// Writes the header and all data elements of `result` into a new buffer.
+///
+/// The header text of `result` is kept if it is a Level 5 header, which
+/// results read from Level 4 files don't have. Opaque objects and elements
+/// that couldn't be parsed are copied as described for
+/// [`MatWriter::write_variable`]. Matlab strings and elements skipped as
+/// corrupt fail with [`WriteError::Unsupported`], as the bytes they were
+/// read from aren't kept.
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let data = include_bytes!("../tests/double.mat");
+/// let result = matfile::parse::parse_all(data)?;
+/// let written = matfile::write::write_all(&result)?;
+/// assert_eq!(matfile::parse::parse_all(&written)?.data_elements.len(), 1);
+/// # Ok(())
+/// # }
+/// ```
+pub fn write_all(result: &ParseResult) -> Result<Vec<u8>, WriteError> {
+    write_all_with_options(result, &WriteOptions::default())
+}
+
+/// Like [`write_all`], but with the given options instead of the default
+/// ones.
+pub fn write_all_with_options(
+    result: &ParseResult,
+    options: &WriteOptions,
+) -> Result<Vec<u8>, WriteError> {
+    let out = std::io::Cursor::new(Vec::new());
+    let mut writer = MatWriter::with_header(out, &result.header, options.clone())?;
+    for data_element in &result.data_elements {
+        writer.write_variable(data_element)?;
+    }
+    Ok(writer.finish()?.into_inner())
+}
+
+/// Like [`write_all`], but writes the file into `writer`.
+pub fn write_to<W: std::io::Write>(writer: &mut W, result: &ParseResult) -> Result<(), WriteError> {
+    write_to_with_options(writer, result, &WriteOptions::default())
+}
+
+/// Like [`write_to`], but with the given options instead of the default ones.
+pub fn write_to_with_options<W: std::io::Write>(
+    writer: &mut W,
+    result: &ParseResult,
+    options: &WriteOptions,
+) -> Result<(), WriteError> {
+    writer.write_all(&write_all_with_options(result, options)?)?;
+    Ok(())
+}
+
+/// Writes a file one variable at a time.
+///
+/// Only the variable being written is kept in memory, so files can be
+/// larger than the memory available. Compressed variables are written as
+/// they are compressed, and the size in their tag is filled in afterwards,
+/// which is why the writer has to be seekable.
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use matfile::write::{MatWriter, WriteOptions};
+///
+/// let data = include_bytes!("../tests/two_arrays.mat");
+/// let result = matfile::parse::parse_all(data)?;
+/// let mut writer = MatWriter::new(std::io::Cursor::new(Vec::new()), WriteOptions::new())?;
+/// for data_element in &result.data_elements {
+///     writer.write_variable(data_element)?;
+/// }
+/// let written = writer.finish()?.into_inner();
+/// assert_eq!(matfile::parse::parse_all(&written)?.data_elements.len(), 2);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct MatWriter<W> {
+    writer: W,
+    options: WriteOptions,
+    /// Where the header starts, which is where offsets in the file are
+    /// counted from
+    start: u64,
+}
+
+impl<W: std::io::Write + std::io::Seek> MatWriter<W> {
+    /// Writes a header naming this crate as the creator, after which the
+    /// variables can be written.
+    pub fn new(writer: W, options: WriteOptions) -> Result<Self, WriteError> {
+        Self::start(writer, None, options)
+    }
+
+    /// Like [`MatWriter::new`], but keeps the text of `header` if it is a
+    /// Level 5 header.
+    pub fn with_header(
+        writer: W,
+        header: &Header,
+        options: WriteOptions,
+    ) -> Result<Self, WriteError> {
+        Self::start(writer, Some(header), options)
+    }
+
+    fn start(
+        mut writer: W,
+        header: Option<&Header>,
+        options: WriteOptions,
+    ) -> Result<Self, WriteError> {
+        let start = writer.stream_position()?;
+        let mut out = Vec::with_capacity(128);
+        write_header(&mut out, header, &options);
+        writer.write_all(&out)?;
+        Ok(MatWriter {
+            writer,
+            options,
+            start,
+        })
+    }
+
+    /// Writes `data_element` as the next variable.
+    ///
+    /// Opaque objects and elements that couldn't be parsed are written as
+    /// they were read, so they stay intact when a file is read and written
+    /// again. Their bytes aren't converted, so writing them in another byte
+    /// order than the one they were read in fails with
+    /// [`WriteError::Unsupported`]. The subsystem data holding
+    /// the contents of opaque objects is written like any other element,
+    /// and its offset is filled in in the header.
+    pub fn write_variable(&mut self, data_element: &DataElement) -> Result<(), WriteError> {
+        if let DataElement::SubsystemData(_) = data_element {
+            let offset = self.writer.stream_position()? - self.start;
+            let end = offset + self.start;
+            self.writer.seek(SeekFrom::Start(self.start + 116))?;
+            self.writer
+                .write_all(&to_bytes([offset], self.options.byte_order))?;
+            self.writer.seek(SeekFrom::Start(end))?;
+        }
+        let name = data_element.name().unwrap_or_default();
+        let mut element = Vec::new();
+        write_data_element(&mut element, data_element, name, &self.options)?;
+        match self.options.compression {
+            Some(level) => write_compressed(
+                &mut self.writer,
+                name,
+                &element,
+                level,
+                self.options.byte_order,
+            ),
+            None => Ok(self.writer.write_all(&element)?),
+        }
+    }
+
+    /// Flushes the file and returns the writer.
+    pub fn finish(mut self) -> Result<W, WriteError> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Appends `data_element` to the MAT-file at `path` as a variable called
+/// `name`, without rewriting the variables already in it.
+///
+/// The variable is written in the byte order of the file, and
+/// [`WriteOptions::byte_order`] is ignored. v7.3 files can't be appended
+/// to and fail with [`WriteError::InvalidFile`] like any other file that
+/// isn't a Level 5 MAT-file.
+pub fn append_variable<P: AsRef<std::path::Path>>(
+    path: P,
+    name: &str,
+    data_element: &DataElement,
+    options: &WriteOptions,
+) -> Result<(), WriteError> {
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)?;
+    append_variable_to(&mut file, name, data_element, options)
+}
+
+/// Like [`append_variable`], but appends to the file in `file`.
+pub fn append_variable_to<F: std::io::Read + std::io::Write + std::io::Seek>(
+    file: &mut F,
+    name: &str,
+    data_element: &DataElement,
+    options: &WriteOptions,
+) -> Result<(), WriteError> {
+    if !options.allow_invalid_names {
+        check_name(name)?;
+    }
+    let scan = match scan_elements(file, options.check_names) {
+        Ok(scan) => scan,
+        Err(crate::Error::IOError(err)) => return Err(WriteError::Io(err)),
+        Err(err) => return Err(WriteError::InvalidFile(err)),
+    };
+    if scan
+        .elements
+        .iter()
+        .any(|element| element.name.as_deref() == Some(name))
+    {
+        return Err(WriteError::NameCollision(name.to_string()));
+    }
+    let options = WriteOptions {
+        byte_order: scan.header.byte_order(),
+        ..options.clone()
+    };
+    // The last element may be missing its padding, which the new one has to
+    // start after to stay aligned to the start of the file
+    let len = file.seek(SeekFrom::End(0))?;
+    let padding = scan.end.saturating_sub(len);
+    file.write_all(&vec![0; padding as usize])?;
+    file.seek(SeekFrom::Start(scan.end))?;
+
+    let mut element = Vec::new();
+    write_data_element(&mut element, data_element, name, &options)?;
+    match options.compression {
+        Some(level) => write_compressed(file, name, &element, level, options.byte_order)?,
+        None => file.write_all(&element)?,
+    }
+    file.flush()?;
+    Ok(())
+}
+
+/// Replaces the variable `name` in the MAT-file at `path` with
+/// `data_element`, overwriting it in place without rewriting the rest of the
+/// file.
+///
+/// The new value has to fit into the space of the old one, unless the old
+/// one is the last variable in the file, and fails with
+/// [`WriteError::WouldGrow`] otherwise. Any space left over is filled with
+/// zeros in an element of type uint8 that isn't a variable, which readers
+/// skip like other elements they don't know; the parser of this crate skips
+/// it without a warning. Compressed variables are followed by up to
+/// 7 zero bytes inside the compressed element if that is needed to keep the
+/// elements after them aligned, so uncompressed variables fit into
+/// compressed ones less often.
+///
+/// Like [`append_variable`], the variable is written in the byte order of
+/// the file.
+pub fn update_variable<P: AsRef<std::path::Path>>(
+    path: P,
+    name: &str,
+    data_element: &DataElement,
+    options: &WriteOptions,
+) -> Result<(), WriteError> {
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)?;
+    update_variable_in(&mut file, name, data_element, options)
+}
+
+/// Like [`update_variable`], but updates the file in `file`.
+pub fn update_variable_in<F: std::io::Read + std::io::Write + std::io::Seek>(
+    file: &mut F,
+    name: &str,
+    data_element: &DataElement,
+    options: &WriteOptions,
+) -> Result<(), WriteError> {
+    if !options.allow_invalid_names {
+        check_name(name)?;
+    }
+    let scan = match scan_elements(file, true) {
+        Ok(scan) => scan,
+        Err(crate::Error::IOError(err)) => return Err(WriteError::Io(err)),
+        Err(err) => return Err(WriteError::InvalidFile(err)),
+    };
+    let index = scan
+        .elements
+        .iter()
+        .position(|element| element.name.as_deref() == Some(name))
+        .ok_or_else(|| WriteError::NotFound(name.to_string()))?;
+    let old = &scan.elements[index];
+    let is_last = index + 1 == scan.elements.len();
+    let byte_order = scan.header.byte_order();
+    let options = WriteOptions {
+        byte_order,
+        ..options.clone()
+    };
+
+    let mut element = Vec::new();
+    write_data_element(&mut element, data_element, name, &options)?;
+    if let Some(level) = options.compression {
+        let mut compressed = std::io::Cursor::new(Vec::new());
+        write_compressed(&mut compressed, name, &element, level, byte_order)?;
+        element = compressed.into_inner();
+    }
+    let available = old.end - old.offset;
+    let len = element.len() as u64;
+    let too_large = || WriteError::WouldGrow(name.to_string());
+    let mut rest = if len <= available {
+        available - len
+    } else if is_last {
+        // The file can grow at the end
+        0
+    } else {
+        return Err(too_large());
+    };
+    if options.compression.is_some() && !rest.is_multiple_of(8) {
+        // Zeros after the zlib stream, counted in the size of the element
+        let zeros = rest % 8;
+        element.resize(element.len() + zeros as usize, 0);
+        let size = u32::try_from(element.len() - 8).map_err(|_| too_large())?;
+        element[4..8].copy_from_slice(&to_bytes([size], byte_order));
+        rest -= zeros;
+    }
+    if !rest.is_multiple_of(8) {
+        return Err(too_large());
+    }
+    if rest > 0 {
+        let size = u32::try_from(rest - 8).map_err(|_| too_large())?;
+        element.extend(to_bytes([DataType::UInt8 as u32, size], byte_order));
+        element.resize(element.len() + size as usize, 0);
+    }
+    file.seek(SeekFrom::Start(old.offset))?;
+    file.write_all(&element)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Collects variables to write into a new file, checking each one as it is
+/// added.
+///
+/// The typed `add_*` methods fill in the class, flags and dimensions of
+/// the arrays, and fail right away if the length of the data doesn't match
+/// the dimensions or if [`validate_name`] rejects the name. Names are only
+/// accepted anyway if [`WriteOptions::allow_invalid_names`] is set in the
+/// options given to [`MatFileBuilder::options`] before the array is added.
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use matfile::write::MatFileBuilder;
+///
+/// let written = MatFileBuilder::new()
+///     .add_f64("pressure", &[2, 3], [1.0, 2.0, 3.0, 4.0, 5.0, 6.0])?
+///     .add_string("units", "kPa")?
+///     .add_struct("meta", |meta| meta.add_i32("run", &[1, 1], [7]))?
+///     .build()?;
+/// let result = matfile::parse::parse_all(&written)?;
+/// assert_eq!(result.data_elements.len(), 3);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MatFileBuilder {
+    data_elements: Vec<DataElement>,
+    options: WriteOptions,
+}
+
+/// The fields of a structure added with [`MatFileBuilder::add_struct`].
+/// Fields are added with the same methods as variables.
+#[derive(Clone, Debug)]
+pub struct StructBuilder {
+    data_elements: Vec<DataElement>,
+    allow_invalid_names: bool,
+}
+
+macro_rules! add_numeric_methods {
+    ($($method:ident => $t:ty, $variant:ident;)*) => {
+        $(
+            #[doc = concat!("Adds a ", stringify!($t), " array with the given dimensions, with the data in column-major order.")]
+            pub fn $method(
+                self,
+                name: &str,
+                dimensions: &[usize],
+                data: impl Into<Vec<$t>>,
+            ) -> Result<Self, WriteError> {
+                let data = NumericData::$variant {
+                    real: data.into(),
+                    imag: None,
+                };
+                self.add_numeric(name, dimensions, data)
+            }
+        )*
+    };
+}
+
+macro_rules! add_methods {
+    ($builder:ty) => {
+        impl $builder {
+            /// Adds a numeric array holding `data`, which may be complex.
+            pub fn add_numeric(
+                self,
+                name: &str,
+                dimensions: &[usize],
+                data: NumericData,
+            ) -> Result<Self, WriteError> {
+                let numeric = new_numeric(name, dimensions, data)?;
+                self.add(DataElement::NumericMatrix(numeric))
+            }
+
+            add_numeric_methods! {
+                add_f64 => f64, Double;
+                add_f32 => f32, Single;
+                add_i8 => i8, Int8;
+                add_u8 => u8, UInt8;
+                add_i16 => i16, Int16;
+                add_u16 => u16, UInt16;
+                add_i32 => i32, Int32;
+                add_u32 => u32, UInt32;
+                add_i64 => i64, Int64;
+                add_u64 => u64, UInt64;
+            }
+
+            /// Adds a numeric array with the shape and data of `array`,
+            /// which is converted to the column-major order of MAT-files.
+            /// Arrays with less than two dimensions are written as row
+            /// vectors, and arrays of complex numbers as complex arrays.
+            #[cfg(feature = "ndarray")]
+            pub fn add_ndarray<A, S, D>(
+                self,
+                name: &str,
+                array: &ndarr::ArrayBase<S, D>,
+            ) -> Result<Self, WriteError>
+            where
+                A: crate::ndarray::NumericElement,
+                S: ndarr::Data<Elem = A>,
+                D: ndarr::Dimension,
+            {
+                let (dimensions, data) = crate::ndarray::column_major(array);
+                self.add_numeric(name, &dimensions, data)
+            }
+
+            /// Adds a 1x1 array holding `value`, whose class follows from its
+            /// type, e.g. double for `f64`, int64 for `i64` and logical for
+            /// `bool`.
+            pub fn add_scalar<T: IntoScalar>(
+                self,
+                name: &str,
+                value: T,
+            ) -> Result<Self, WriteError> {
+                let mut numeric = new_numeric(name, &[1, 1], value.into_numeric_data())?;
+                numeric.header.flags.logical = T::is_logical();
+                self.add(DataElement::NumericMatrix(numeric))
+            }
+
+            /// Adds a logical array with the given dimensions, with the data
+            /// in column-major order.
+            pub fn add_logical(
+                self,
+                name: &str,
+                dimensions: &[usize],
+                data: &[bool],
+            ) -> Result<Self, WriteError> {
+                let data = NumericData::UInt8 {
+                    real: data.iter().map(|&value| u8::from(value)).collect(),
+                    imag: None,
+                };
+                let mut numeric = new_numeric(name, dimensions, data)?;
+                numeric.header.flags.logical = true;
+                self.add(DataElement::NumericMatrix(numeric))
+            }
+
+            /// Adds a character array holding `text` in a single row.
+            pub fn add_string(self, name: &str, text: &str) -> Result<Self, WriteError> {
+                let len = text.encode_utf16().count();
+                let dimensions = if len == 0 { [0, 0] } else { [1, len] };
+                let header = new_array_header(name, ArrayType::Char, &dimensions)?;
+                self.add(DataElement::CharacterMatrix(Character {
+                    header,
+                    real_part: CharacterData::Unicode(text.to_string()),
+                    imag_part: None,
+                }))
+            }
+
+            /// Adds a 1x1 structure whose fields are added by `fields`.
+            pub fn add_struct<F>(self, name: &str, fields: F) -> Result<Self, WriteError>
+            where
+                F: FnOnce(StructBuilder) -> Result<StructBuilder, WriteError>,
+            {
+                let header = new_array_header(name, ArrayType::Struct, &[1, 1])?;
+                let fields = fields(StructBuilder {
+                    data_elements: Vec::new(),
+                    allow_invalid_names: self.allow_invalid_names(),
+                })?;
+                let values = fields.data_elements;
+                let field_names = values
+                    .iter()
+                    .map(|value| value.name().unwrap_or_default().to_string())
+                    .collect();
+                self.add(DataElement::StructureMatrix(Structure {
+                    header,
+                    field_names,
+                    values,
+                }))
+            }
+
+            fn add(mut self, data_element: DataElement) -> Result<Self, WriteError> {
+                let name = data_element.name().unwrap_or_default();
+                if !self.allow_invalid_names() {
+                    check_name(name)?;
+                }
+                if self
+                    .data_elements
+                    .iter()
+                    .any(|existing| existing.name() == Some(name))
+                {
+                    return Err(WriteError::NameCollision(name.to_string()));
+                }
+                self.data_elements.push(data_element);
+                Ok(self)
+            }
+        }
+    };
+}
+
+add_methods!(MatFileBuilder);
+add_methods!(StructBuilder);
+
+impl StructBuilder {
+    fn allow_invalid_names(&self) -> bool {
+        self.allow_invalid_names
+    }
+}
+
+impl MatFileBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn allow_invalid_names(&self) -> bool {
+        self.options.allow_invalid_names
+    }
+
+    /// Sets the options the file is written with.
+    pub fn options(mut self, options: WriteOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Writes the file into a new buffer.
+    pub fn build(&self) -> Result<Vec<u8>, WriteError> {
+        let out = std::io::Cursor::new(Vec::new());
+        Ok(self.write(out)?.into_inner())
+    }
+
+    /// Writes the file to `path`, replacing any file that is there.
+    pub fn write_to<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), WriteError> {
+        let file = std::fs::File::create(path)?;
+        self.write(std::io::BufWriter::new(file))?;
+        Ok(())
+    }
+
+    fn write<W: std::io::Write + std::io::Seek>(&self, writer: W) -> Result<W, WriteError> {
+        let mut writer = MatWriter::new(writer, self.options.clone())?;
+        for data_element in &self.data_elements {
+            writer.write_variable(data_element)?;
+        }
+        writer.finish()
+    }
+}
+
+/// Values that [`MatFileBuilder::add_scalar`] can write as a 1x1 array.
+///
+/// `usize` and `isize` aren't included, as the class of the array would
+/// depend on the platform. Convert them to `u64` or `i64` first.
+pub trait IntoScalar {
+    /// The data of a 1x1 array holding this value.
+    fn into_numeric_data(self) -> NumericData;
+
+    /// Whether the array is logical, which is only the case for `bool`.
+    fn is_logical() -> bool {
+        false
+    }
+}
+
+macro_rules! into_scalar {
+    ( $( $t:ty => $variant:ident ),* ) => {
+        $(
+            impl IntoScalar for $t {
+                fn into_numeric_data(self) -> NumericData {
+                    NumericData::$variant {
+                        real: vec![self],
+                        imag: None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+into_scalar!(
+    f64 => Double,
+    f32 => Single,
+    i8 => Int8,
+    u8 => UInt8,
+    i16 => Int16,
+    u16 => UInt16,
+    i32 => Int32,
+    u32 => UInt32,
+    i64 => Int64,
+    u64 => UInt64
+);
+
+impl IntoScalar for bool {
+    fn into_numeric_data(self) -> NumericData {
+        NumericData::UInt8 {
+            real: vec![u8::from(self)],
+            imag: None,
+        }
+    }
+
+    fn is_logical() -> bool {
+        true
+    }
+}
+
+/// A new numeric array, checking that the data matches the dimensions.
+fn new_numeric(name: &str, dimensions: &[usize], data: NumericData) -> Result<Numeric, WriteError> {
+    let mut header = new_array_header(name, data.class(), dimensions)?;
+    let numel = header.dimensions.numel();
+    if numel != Some(data.len()) || data.imag_len().is_some_and(|len| Some(len) != numel) {
+        return Err(WriteError::InvalidArray {
+            name: name.to_string(),
+            reason: "data length does not match the dimensions",
+        });
+    }
+    header.flags.complex = data.is_complex();
+    Ok(Numeric {
+        header,
+        data,
+        imag_skipped: false,
+    })
+}
+
+/// The header of a new array, checking its dimensions.
+fn new_array_header(
+    name: &str,
+    class: ArrayType,
+    dimensions: &[usize],
+) -> Result<ArrayHeader, WriteError> {
+    if dimensions.len() < 2 {
+        return Err(WriteError::InvalidArray {
+            name: name.to_string(),
+            reason: "arrays need at least two dimensions",
+        });
+    }
+    Ok(ArrayHeader {
+        flags: ArrayFlags {
+            complex: false,
+            global: false,
+            logical: false,
+            class,
+            nzmax: 0,
+        },
+        dimensions: dimensions.to_vec().into(),
+        name: name.to_string(),
+    })
+}
+
+fn write_header(out: &mut Vec<u8>, header: Option<&Header>, options: &WriteOptions) {
+    let byte_order = options.byte_order;
+    // Level 4 files have no header text. Readers take a text starting with
+    // a null byte for a Level 4 file, so this would be written as one.
+    let raw_text = header
+        .filter(|_| !options.deterministic && options.header_text.is_none())
+        .map(Header::raw_text)
+        .filter(|raw_text| !raw_text[..4].contains(&0));
+    if let Some(raw_text) = raw_text {
+        out.extend_from_slice(raw_text);
+    } else {
+        let text = match &options.header_text {
+            Some(text) => text.clone(),
+            None if options.deterministic => "MATLAB 5.0 MAT-file, Created by: matfile".to_string(),
+            None => format!(
+                "MATLAB 5.0 MAT-file, Created by: matfile {}",
+                env!("CARGO_PKG_VERSION")
+            ),
+        };
+        let mut text = text.into_bytes();
+        text.resize(116, b' ');
+        out.extend_from_slice(&text);
+    }
+    // The offset of the subsystem data is filled in once it is written
+    out.extend_from_slice(&[0; 8]);
+    0x0100u16.push_bytes(out, byte_order);
+    // Readers detect the byte order from how these two characters read
+    out.extend_from_slice(match byte_order {
+        ByteOrder::LittleEndian => b"IM",
+        ByteOrder::BigEndian => b"MI",
+    });
+}
+
+/// Primitive values that are written in the byte order of the file.
+pub(crate) trait ToBytes: Copy {
+    fn push_bytes(self, out: &mut Vec<u8>, byte_order: ByteOrder);
+}
+
+macro_rules! to_bytes {
+    ( $( $t:ty ),* ) => {
+        $(
+            impl ToBytes for $t {
+                fn push_bytes(self, out: &mut Vec<u8>, byte_order: ByteOrder) {
+                    match byte_order {
+                        ByteOrder::LittleEndian => out.extend_from_slice(&self.to_le_bytes()),
+                        ByteOrder::BigEndian => out.extend_from_slice(&self.to_be_bytes()),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+to_bytes!(i8, u8, i16, u16, i32, u32, i64, u64, f32, f64);
+
+pub(crate) fn to_bytes<T: ToBytes>(
+    values: impl IntoIterator<Item = T>,
+    byte_order: ByteOrder,
+) -> Vec<u8> {
+    let mut out = Vec::new();
+    for value in values {
+        value.push_bytes(&mut out, byte_order);
+    }
+    out
+}
+
+/// Writes `data_element` as a miMATRIX element storing `name`, which is empty
+/// for the values of structure fields and cells.
+fn write_data_element(
+    out: &mut Vec<u8>,
+    data_element: &DataElement,
+    name: &str,
+    options: &WriteOptions,
+) -> Result<(), WriteError> {
+    match data_element {
+        DataElement::NumericMatrix(numeric) => write_numeric(out, numeric, name, options),
+        DataElement::SparseMatrix(sparse) => write_sparse(out, sparse, name, options),
+        DataElement::CharacterMatrix(character) => write_character(out, character, name, options),
+        DataElement::StructureMatrix(structure) => write_structure(out, structure, name, options),
+        DataElement::CellMatrix(cell) => write_cell(out, cell, name, options),
+        DataElement::Opaque(opaque) => write_opaque(out, opaque, name, options),
+        DataElement::Unsupported(unsupported) => {
+            write_unsupported(out, unsupported, name, options.byte_order)
+        }
+        DataElement::SubsystemData(data) => {
+            let numeric = new_numeric(
+                "",
+                &[data.len(), 1],
+                NumericData::UInt8 {
+                    real: data.clone(),
+                    imag: None,
+                },
+            )?;
+            write_numeric(out, &numeric, "", options)
+        }
+        _ => Err(WriteError::Unsupported(
+            data_element.name().unwrap_or_default().to_string(),
+        )),
+    }
+}
+
+fn write_numeric(
+    out: &mut Vec<u8>,
+    numeric: &Numeric,
+    name: &str,
+    options: &WriteOptions,
+) -> Result<(), WriteError> {
+    let header = &numeric.header;
+    let data = &numeric.data;
+    let invalid = |reason| WriteError::InvalidArray {
+        name: header.name.clone(),
+        reason,
+    };
+    if header.dimensions.numel() != Some(data.len()) {
+        return Err(invalid("number of elements does not match the dimensions"));
+    }
+    if data.imag_len().is_some_and(|len| len != data.len()) {
+        return Err(invalid("imaginary part has a different length"));
+    }
+    // Writing only the real part would silently drop the imaginary part
+    if numeric.imag_skipped {
+        return Err(invalid("imaginary part was not loaded"));
+    }
+
+    let mut body = Vec::new();
+    let flags = ArrayFlagsField {
+        class: data.class() as u8,
+        complex: data.is_complex(),
+        global: header.flags.global,
+        logical: header.flags.logical,
+        nzmax: 0,
+    };
+    write_array_header(&mut body, header, name, flags, options.byte_order)?;
+    write_data_parts(&mut body, data, options);
+    write_matrix(out, &header.name, &body, options.byte_order)
+}
+
+fn write_sparse(
+    out: &mut Vec<u8>,
+    sparse: &Sparse,
+    name: &str,
+    options: &WriteOptions,
+) -> Result<(), WriteError> {
+    let header = &sparse.header;
+    let data = &sparse.data;
+    let invalid = |reason| WriteError::InvalidArray {
+        name: header.name.clone(),
+        reason,
+    };
+    if header.dimensions.ndims() != 2 {
+        return Err(invalid("sparse arrays must have two dimensions"));
+    }
+    if sparse.imag_skipped {
+        return Err(invalid("imaginary part was not loaded"));
+    }
+    let (rows, columns) = (header.dimensions.rows(), header.dimensions.columns());
+    if sparse.column_index.len() != columns + 1 {
+        return Err(invalid(
+            "number of column indices does not match the dimensions",
+        ));
+    }
+    if sparse.column_index[0] != 0 || sparse.column_index.windows(2).any(|w| w[0] > w[1]) {
+        return Err(invalid("column indices are not increasing from zero"));
+    }
+    let nnz = sparse.nnz();
+    if sparse.row_index.len() != nnz {
+        return Err(invalid(
+            "number of row indices does not match the number of values",
+        ));
+    }
+    if sparse.row_index.iter().any(|&row| row >= rows) {
+        return Err(invalid("row index out of bounds"));
+    }
+    if data.len() != nnz || data.imag_len().is_some_and(|len| len != nnz) {
+        return Err(invalid(
+            "number of values does not match the column indices",
+        ));
+    }
+    // Matlab only supports sparse arrays of doubles and logicals
+    if !header.flags.logical && data.data_type() != DataType::Double {
+        return Err(invalid("sparse arrays must hold doubles"));
+    }
+
+    let mut body = Vec::new();
+    let flags = ArrayFlagsField {
+        class: ArrayType::Sparse as u8,
+        complex: data.is_complex(),
+        global: header.flags.global,
+        logical: header.flags.logical,
+        // Matlab allocates room for at least one value
+        nzmax: nnz.max(1),
+    };
+    write_array_header(&mut body, header, name, flags, options.byte_order)?;
+    let too_large = || WriteError::TooLarge(header.name.clone());
+    for indices in [&sparse.row_index, &sparse.column_index] {
+        let mut bytes = Vec::with_capacity(indices.len() * 4);
+        for &index in indices {
+            let index = i32::try_from(index).map_err(|_| too_large())?;
+            index.push_bytes(&mut bytes, options.byte_order);
+        }
+        write_subelement(&mut body, DataType::Int32, &bytes, options.byte_order);
+    }
+    write_data_parts(&mut body, data, options);
+    write_matrix(out, &header.name, &body, options.byte_order)
+}
+
+fn write_character(
+    out: &mut Vec<u8>,
+    character: &Character,
+    name: &str,
+    options: &WriteOptions,
+) -> Result<(), WriteError> {
+    let mut header = character.header.clone();
+    let len = character.real_part.utf16_len();
+    if character
+        .imag_part
+        .as_ref()
+        .is_some_and(|imag| imag.utf16_len() != len)
+    {
+        return Err(WriteError::InvalidArray {
+            name: header.name,
+            reason: "imaginary part has a different length",
+        });
+    }
+    // Strings that don't fit their dimensions get them from the text,
+    // counting UTF-16 code units like Matlab. Char matrices with more rows
+    // and N-D char arrays have to be consistent.
+    let is_string = header.dimensions.ndims() <= 2 && header.dimensions.rows() <= 1;
+    if is_string && header.dimensions.numel() != Some(len) {
+        header.dimensions = if len == 0 { vec![0, 0] } else { vec![1, len] }.into();
+    } else if header.dimensions.numel() != Some(len) {
+        return Err(WriteError::InvalidArray {
+            name: header.name,
+            reason: "number of characters does not match the dimensions",
+        });
+    }
+
+    let mut body = Vec::new();
+    let flags = ArrayFlagsField {
+        class: ArrayType::Char as u8,
+        complex: character.imag_part.is_some(),
+        global: header.flags.global,
+        logical: header.flags.logical,
+        nzmax: 0,
+    };
+    write_array_header(&mut body, &header, name, flags, options.byte_order)?;
+    write_character_data(&mut body, &character.real_part, options);
+    if let Some(imag) = &character.imag_part {
+        write_character_data(&mut body, imag, options);
+    }
+    write_matrix(out, &header.name, &body, options.byte_order)
+}
+
+fn write_character_data(out: &mut Vec<u8>, data: &CharacterData, options: &WriteOptions) {
+    let byte_order = options.byte_order;
+    match (data, options.char_encoding) {
+        (CharacterData::Unicode(str), CharEncoding::Utf8) => {
+            write_subelement(out, DataType::Utf8, str.as_bytes(), byte_order);
+        }
+        (CharacterData::Unicode(str), CharEncoding::Utf16) => {
+            let bytes = to_bytes(str.encode_utf16(), byte_order);
+            write_subelement(out, DataType::Utf16, &bytes, byte_order);
+        }
+        (CharacterData::NonUnicode(units), _) => {
+            let bytes = to_bytes(units.iter().copied(), byte_order);
+            write_subelement(out, DataType::UInt16, &bytes, byte_order);
+        }
+    }
+}
+
+fn write_structure(
+    out: &mut Vec<u8>,
+    structure: &Structure,
+    name: &str,
+    options: &WriteOptions,
+) -> Result<(), WriteError> {
+    let header = &structure.header;
+    let field_names = &structure.field_names;
+    let numel = header.dimensions.numel();
+    let value_count = if field_names.is_empty() {
+        Some(0)
+    } else {
+        numel.and_then(|numel| numel.checked_mul(field_names.len()))
+    };
+    if value_count != Some(structure.values.len()) {
+        return Err(WriteError::InvalidArray {
+            name: header.name.clone(),
+            reason: "number of values does not match the dimensions and fields",
+        });
+    }
+    let longest = field_names.iter().map(String::len).max().unwrap_or(0);
+    if let Some(field) = field_names
+        .iter()
+        .find(|field| field.len() > MAX_NAME_LENGTH)
+    {
+        return Err(WriteError::FieldNameTooLong {
+            name: header.name.clone(),
+            field: field.clone(),
+        });
+    }
+    if !options.allow_invalid_names {
+        for field in field_names {
+            validate_name(field).map_err(|error| WriteError::InvalidName {
+                name: format!("{}.{}", header.name, field),
+                error,
+            })?;
+        }
+    }
+
+    let mut body = Vec::new();
+    let flags = ArrayFlagsField {
+        class: ArrayType::Struct as u8,
+        complex: false,
+        global: header.flags.global,
+        logical: header.flags.logical,
+        nzmax: 0,
+    };
+    write_array_header(&mut body, header, name, flags, options.byte_order)?;
+    // Each name is null-terminated in a slot of 32 bytes, or of the next
+    // multiple of 8 if a name doesn't fit
+    let slot = (longest + 1).next_multiple_of(8).max(32);
+    let slot_bytes = to_bytes([slot as i32], options.byte_order);
+    write_subelement(&mut body, DataType::Int32, &slot_bytes, options.byte_order);
+    let mut names = vec![0; slot * field_names.len()];
+    for (field, chunk) in field_names.iter().zip(names.chunks_exact_mut(slot)) {
+        chunk[..field.len()].copy_from_slice(field.as_bytes());
+    }
+    write_subelement(&mut body, DataType::Int8, &names, options.byte_order);
+    for value in &structure.values {
+        write_data_element(&mut body, value, "", options)?;
+    }
+    write_matrix(out, &header.name, &body, options.byte_order)
+}
+
+fn write_cell(
+    out: &mut Vec<u8>,
+    cell: &Cell,
+    name: &str,
+    options: &WriteOptions,
+) -> Result<(), WriteError> {
+    let header = &cell.header;
+    let Some(numel) = header.dimensions.numel() else {
+        return Err(WriteError::TooLarge(header.name.clone()));
+    };
+    if !cell.values.is_empty() && cell.values.len() != numel {
+        return Err(WriteError::InvalidArray {
+            name: header.name.clone(),
+            reason: "number of cells does not match the dimensions",
+        });
+    }
+
+    let mut body = Vec::new();
+    let flags = ArrayFlagsField {
+        class: ArrayType::Cell as u8,
+        complex: false,
+        global: header.flags.global,
+        logical: header.flags.logical,
+        nzmax: 0,
+    };
+    write_array_header(&mut body, header, name, flags, options.byte_order)?;
+    if cell.values.is_empty() {
+        // Cells without values, like those created by cell(m, n), hold empty
+        // double arrays like in Matlab
+        let empty = DataElement::NumericMatrix(Numeric {
+            header: ArrayHeader {
+                flags: ArrayFlags {
+                    complex: false,
+                    global: false,
+                    logical: false,
+                    class: ArrayType::Double,
+                    nzmax: 0,
+                },
+                dimensions: vec![0, 0].into(),
+                name: header.name.clone(),
+            },
+            data: NumericData::Double {
+                real: Vec::new(),
+                imag: None,
+            },
+            imag_skipped: false,
+        });
+        let mut element = Vec::new();
+        write_data_element(&mut element, &empty, "", options)?;
+        for _ in 0..numel {
+            body.extend_from_slice(&element);
+        }
+    } else {
+        for value in &cell.values {
+            write_data_element(&mut body, value, "", options)?;
+        }
+    }
+    write_matrix(out, &header.name, &body, options.byte_order)
+}
+
+/// Writes the real part of `data` and the imaginary part if there is one.
+fn write_data_parts(out: &mut Vec<u8>, data: &NumericData, options: &WriteOptions) {
+    let byte_order = options.byte_order;
+    numeric_data_parts!(data, real, imag => {
+        let storage_type = if options.compress_integers {
+            smallest_storage_type(data.data_type(), real, imag.as_deref())
+        } else {
+            None
+        };
+        match storage_type {
+            Some(storage_type) => {
+                let bytes = integer_bytes(storage_type, real, byte_order);
+                write_subelement(out, storage_type, &bytes, byte_order);
+                if let Some(imag) = imag {
+                    let bytes = integer_bytes(storage_type, imag, byte_order);
+                    write_subelement(out, storage_type, &bytes, byte_order);
+                }
+            }
+            None => {
+                let bytes = to_bytes(real.iter().copied(), byte_order);
+                write_subelement(out, data.data_type(), &bytes, byte_order);
+                if let Some(imag) = imag {
+                    let bytes = to_bytes(imag.iter().copied(), byte_order);
+                    write_subelement(out, data.data_type(), &bytes, byte_order);
+                }
+            }
+        }
+    });
+}
+
+/// Values that can be stored as integers if they are whole numbers.
+trait IntegerValue: Copy {
+    /// The value as an integer, or `None` if it isn't a whole number or
+    /// doesn't fit into an `i64`.
+    fn as_integer(self) -> Option<i64>;
+}
+
+macro_rules! integer_value {
+    ( $( $t:ty ),* ) => {
+        $(
+            impl IntegerValue for $t {
+                fn as_integer(self) -> Option<i64> {
+                    i64::try_from(self).ok()
+                }
+            }
+        )*
+    };
+}
+
+integer_value!(i8, u8, i16, u16, i32, u32, i64, u64);
+
+macro_rules! float_integer_value {
+    ( $( $t:ty ),* ) => {
+        $(
+            impl IntegerValue for $t {
+                fn as_integer(self) -> Option<i64> {
+                    // NaN, infinities, fractions and -0.0 can't be stored as
+                    // integers. The range is limited to what the storage
+                    // types can hold anyway.
+                    let is_whole = self.trunc() == self && self.abs() <= u32::MAX as $t;
+                    if is_whole && !(self == 0.0 && self.is_sign_negative()) {
+                        Some(self as i64)
+                    } else {
+                        None
+                    }
+                }
+            }
+        )*
+    };
+}
+
+float_integer_value!(f32, f64);
+
+/// The smallest integer type the values of an array of type `data_type` can
+/// be stored in without changing them, or `None` if there is none smaller
+/// than `data_type` itself.
+fn smallest_storage_type<T: IntegerValue>(
+    data_type: DataType,
+    real: &[T],
+    imag: Option<&[T]>,
+) -> Option<DataType> {
+    let mut values = real.iter().chain(imag.unwrap_or_default());
+    let first = values.next()?.as_integer()?;
+    let (min, max) = values.try_fold((first, first), |(min, max), v| {
+        let v = v.as_integer()?;
+        Some((min.min(v), max.max(v)))
+    })?;
+    // Matlab doesn't use int8, so neither does this to write the same bytes
+    let candidates = [
+        (DataType::UInt8, 1, 0, i64::from(u8::MAX)),
+        (DataType::UInt16, 2, 0, i64::from(u16::MAX)),
+        (DataType::Int16, 2, i64::from(i16::MIN), i64::from(i16::MAX)),
+        (DataType::UInt32, 4, 0, i64::from(u32::MAX)),
+        (DataType::Int32, 4, i64::from(i32::MIN), i64::from(i32::MAX)),
+    ];
+    candidates
+        .iter()
+        .copied()
+        .find(|&(storage_type, size, lower, upper)| {
+            size < std::mem::size_of::<T>()
+                && lower <= min
+                && max <= upper
+                && numeric_data_types_are_compatible(data_type, storage_type)
+        })
+        .map(|(storage_type, ..)| storage_type)
+}
+
+/// The values converted into the integer `storage_type`, which all of them
+/// fit into.
+fn integer_bytes<T: IntegerValue>(
+    storage_type: DataType,
+    values: &[T],
+    byte_order: ByteOrder,
+) -> Vec<u8> {
+    let integers = values.iter().map(|v| v.as_integer().unwrap_or_default());
+    match storage_type {
+        DataType::UInt8 => to_bytes(integers.map(|v| v as u8), byte_order),
+        DataType::Int16 => to_bytes(integers.map(|v| v as i16), byte_order),
+        DataType::UInt16 => to_bytes(integers.map(|v| v as u16), byte_order),
+        DataType::Int32 => to_bytes(integers.map(|v| v as i32), byte_order),
+        _ => to_bytes(integers.map(|v| v as u32), byte_order),
+    }
+}
+
+/// The fields of the array flags subelement.
+struct ArrayFlagsField {
+    class: u8,
+    complex: bool,
+    global: bool,
+    logical: bool,
+    nzmax: usize,
+}
+
+/// Writes the array flags, dimensions and name subelements that start every
+/// array. The name of `header` is only used for errors, `name` is stored.
+fn write_array_header(
+    out: &mut Vec<u8>,
+    header: &ArrayHeader,
+    name: &str,
+    flags: ArrayFlagsField,
+    byte_order: ByteOrder,
+) -> Result<(), WriteError> {
+    write_array_flags(out, &header.name, flags, byte_order)?;
+
+    // Matlab expects at least two dimensions
+    let mut dimensions = header.dimensions.0.clone();
+    dimensions.resize(dimensions.len().max(2), 1);
+    let mut bytes = Vec::with_capacity(dimensions.len() * 4);
+    for d in dimensions {
+        let d = i32::try_from(d).map_err(|_| WriteError::TooLarge(header.name.clone()))?;
+        d.push_bytes(&mut bytes, byte_order);
+    }
+    write_subelement(out, DataType::Int32, &bytes, byte_order);
+
+    write_subelement(out, DataType::Int8, name.as_bytes(), byte_order);
+    Ok(())
+}
+
+/// Writes an opaque object as it was read: the class is named in the array
+/// and the object data is copied as is, so it has to be written in the byte
+/// order it was read in.
+fn write_opaque(
+    out: &mut Vec<u8>,
+    opaque: &Opaque,
+    name: &str,
+    options: &WriteOptions,
+) -> Result<(), WriteError> {
+    if opaque.byte_order != options.byte_order {
+        return Err(WriteError::Unsupported(name.to_string()));
+    }
+    let mut body = Vec::new();
+    let flags = ArrayFlagsField {
+        class: ArrayType::Opaque as u8,
+        complex: false,
+        global: opaque.header.flags.global,
+        logical: false,
+        nzmax: 0,
+    };
+    write_array_flags(&mut body, name, flags, options.byte_order)?;
+    // Opaque arrays have no dimensions, but the type system and class name
+    // following the name
+    for text in &[name, &opaque.type_system, &opaque.class_name] {
+        write_subelement(
+            &mut body,
+            DataType::Int8,
+            text.as_bytes(),
+            options.byte_order,
+        );
+    }
+    body.extend_from_slice(&opaque.data);
+    body.resize(body.len().next_multiple_of(8), 0);
+    write_matrix(out, name, &body, options.byte_order)
+}
+
+/// Writes an element that couldn't be parsed with the bytes it was read
+/// with, which includes its name. Only the tag is written in `byte_order`, so
+/// it has to be the one the element was read in.
+fn write_unsupported(
+    out: &mut Vec<u8>,
+    unsupported: &UnsupportedArray,
+    name: &str,
+    byte_order: ByteOrder,
+) -> Result<(), WriteError> {
+    if unsupported.byte_order != byte_order {
+        return Err(WriteError::Unsupported(name.to_string()));
+    }
+    let size = u32::try_from(unsupported.data.len())
+        .map_err(|_| WriteError::TooLarge(name.to_string()))?;
+    out.extend_from_slice(&to_bytes([unsupported.data_type as u32, size], byte_order));
+    out.extend_from_slice(&unsupported.data);
+    out.resize(
+        out.len() + unsupported.data.len().next_multiple_of(8) - unsupported.data.len(),
+        0,
+    );
+    Ok(())
+}
+
+/// Writes the array flags subelement. `name` is only used for errors.
+fn write_array_flags(
+    out: &mut Vec<u8>,
+    name: &str,
+    flags: ArrayFlagsField,
+    byte_order: ByteOrder,
+) -> Result<(), WriteError> {
+    let mut flags_and_class = u32::from(flags.class);
+    if flags.complex {
+        flags_and_class |= 0x0800;
+    }
+    if flags.global {
+        flags_and_class |= 0x0400;
+    }
+    if flags.logical {
+        flags_and_class |= 0x0200;
+    }
+    let nzmax = u32::try_from(flags.nzmax).map_err(|_| WriteError::TooLarge(name.to_string()))?;
+    let bytes = to_bytes([flags_and_class, nzmax], byte_order);
+    write_subelement(out, DataType::UInt32, &bytes, byte_order);
+    Ok(())
+}
+
+/// Writes a miCOMPRESSED element holding `element`. Unlike other elements,
+/// it isn't followed by padding.
+fn write_compressed<W: std::io::Write + std::io::Seek>(
+    writer: &mut W,
+    name: &str,
+    element: &[u8],
+    level: CompressionLevel,
+    byte_order: ByteOrder,
+) -> Result<(), WriteError> {
+    let options = match level {
+        CompressionLevel::Store => EncodeOptions::new().no_compression(),
+        CompressionLevel::Fast => {
+            EncodeOptions::with_lz77(DefaultLz77Encoder::with_window_size(4096))
+                .fixed_huffman_codes()
+        }
+        CompressionLevel::Default => EncodeOptions::new(),
+    };
+    // The size is only known once the data is compressed
+    let start = writer.stream_position()?;
+    writer.write_all(&to_bytes([DataType::Compressed as u32, 0], byte_order))?;
+    let mut encoder = Encoder::with_options(&mut *writer, options)?;
+    encoder.write_all(element)?;
+    encoder.finish().into_result()?;
+    let end = writer.stream_position()?;
+    let size =
+        u32::try_from(end - start - 8).map_err(|_| WriteError::TooLarge(name.to_string()))?;
+    writer.seek(SeekFrom::Start(start + 4))?;
+    writer.write_all(&to_bytes([size], byte_order))?;
+    writer.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+/// Writes a miMATRIX element with the given contents, which are already
+/// aligned to 8 bytes.
+fn write_matrix(
+    out: &mut Vec<u8>,
+    name: &str,
+    body: &[u8],
+    byte_order: ByteOrder,
+) -> Result<(), WriteError> {
+    let size = u32::try_from(body.len()).map_err(|_| WriteError::TooLarge(name.to_string()))?;
+    out.extend_from_slice(&to_bytes([DataType::Matrix as u32, size], byte_order));
+    out.extend_from_slice(body);
+    Ok(())
+}
+
+/// Writes a subelement followed by the padding to the next multiple of 8
+/// bytes. Data of up to 4 bytes is packed into the tag like Matlab does.
+fn write_subelement(out: &mut Vec<u8>, data_type: DataType, bytes: &[u8], byte_order: ByteOrder) {
+    let data_type = data_type as u32;
+    if bytes.len() <= 4 {
+        let starting_bytes = ((bytes.len() as u32) << 16) | data_type;
+        starting_bytes.push_bytes(out, byte_order);
+        out.extend_from_slice(bytes);
+        out.resize(out.len() + 4 - bytes.len(), 0);
+    } else {
+        out.extend_from_slice(&to_bytes([data_type, bytes.len() as u32], byte_order));
+        out.extend_from_slice(bytes);
+        out.resize(out.len() + bytes.len().next_multiple_of(8) - bytes.len(), 0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse::parse_all;
+    use num_traits::FromPrimitive;
+    use std::convert::TryInto;
+
+    fn assert_numeric_eq(written: &DataElement, read: &DataElement) {
+        match (written, read) {
+            (DataElement::NumericMatrix(written), DataElement::NumericMatrix(read)) => {
+                assert_eq!(written.header.name, read.header.name);
+                assert_eq!(written.header.dimensions, read.header.dimensions);
+                assert_eq!(written.header.flags.complex, read.header.flags.complex);
+                assert_eq!(written.header.flags.global, read.header.flags.global);
+                assert_eq!(written.header.flags.logical, read.header.flags.logical);
+                assert_eq!(written.header.flags.class, read.header.flags.class);
+                assert_eq!(written.data, read.data);
+            }
+            _ => panic!("expected numeric arrays"),
+        }
+    }
+
+    fn assert_element_eq(written: &DataElement, read: &DataElement) {
+        match (written, read) {
+            (DataElement::NumericMatrix(_), _) => assert_numeric_eq(written, read),
+            (DataElement::CharacterMatrix(written), DataElement::CharacterMatrix(read)) => {
+                assert_eq!(written.header.name, read.header.name);
+                assert_eq!(written.header.dimensions, read.header.dimensions);
+                assert_character_eq(&written.real_part, &read.real_part);
+            }
+            (DataElement::StructureMatrix(written), DataElement::StructureMatrix(read)) => {
+                assert_eq!(written.header.name, read.header.name);
+                assert_eq!(written.header.dimensions, read.header.dimensions);
+                assert_eq!(written.header.flags.global, read.header.flags.global);
+                assert_eq!(written.field_names, read.field_names);
+                assert_eq!(written.values.len(), read.values.len());
+                for (written, read) in written.values.iter().zip(&read.values) {
+                    assert_element_eq(written, read);
+                }
+            }
+            (DataElement::CellMatrix(written), DataElement::CellMatrix(read)) => {
+                assert_eq!(written.header.name, read.header.name);
+                assert_eq!(written.header.dimensions, read.header.dimensions);
+                assert_eq!(written.values.len(), read.values.len());
+                for (written, read) in written.values.iter().zip(&read.values) {
+                    assert_element_eq(written, read);
+                }
+            }
+            _ => panic!("expected arrays of the same kind"),
+        }
+    }
+
+    fn array_header(name: &str, class: ArrayType, dimensions: Vec<usize>) -> ArrayHeader {
+        ArrayHeader {
+            flags: crate::parse::ArrayFlags {
+                complex: false,
+                global: false,
+                logical: false,
+                class,
+                nzmax: 0,
+            },
+            dimensions: dimensions.into(),
+            name: name.to_string(),
+        }
+    }
+
+    /// A file holding the given variables.
+    fn file(data_elements: Vec<DataElement>) -> ParseResult {
+        let mut result = parse_all(include_bytes!("../tests/double.mat")).unwrap();
+        result.data_elements = data_elements;
+        result
+    }
+
+    /// A file holding the single numeric variable `x`.
+    fn numeric_file(data: NumericData) -> ParseResult {
+        let mut header = array_header("x", data.class(), vec![1, data.len()]);
+        header.flags.complex = data.is_complex();
+        file(vec![DataElement::NumericMatrix(Numeric {
+            header,
+            data,
+            imag_skipped: false,
+        })])
+    }
+
+    /// The type the real part of `x` is stored in.
+    fn storage_type(written: &[u8]) -> DataType {
+        // Header, matrix tag, array flags, dimensions and name
+        let tag = u32::from_le_bytes(written[176..180].try_into().unwrap());
+        DataType::from_u32(tag & 0xFFFF).unwrap()
+    }
+
+    #[test]
+    fn compress_integers() {
+        let options = WriteOptions::new().compress_integers(true);
+        let round_trip = |data: NumericData| {
+            let result = numeric_file(data);
+            let written = write_all_with_options(&result, &options).unwrap();
+            let read = parse_all(&written).unwrap();
+            assert_numeric_eq(&result.data_elements[0], &read.data_elements[0]);
+            storage_type(&written)
+        };
+        let double = |real: Vec<f64>| NumericData::Double { real, imag: None };
+
+        assert_eq!(
+            round_trip(double(vec![0.0, 1.0, 2.0, 255.0])),
+            DataType::UInt8
+        );
+        assert_eq!(round_trip(double(vec![-1.0, 127.0])), DataType::Int16);
+        assert_eq!(round_trip(double(vec![-1.0, 1000.0])), DataType::Int16);
+        assert_eq!(round_trip(double(vec![70000.0])), DataType::UInt32);
+        let complex = NumericData::Double {
+            real: vec![1.0, 2.0],
+            imag: Some(vec![-3.0, 4.0]),
+        };
+        assert_eq!(round_trip(complex), DataType::Int16);
+        let int64 = NumericData::Int64 {
+            real: vec![5, 6, 7],
+            imag: None,
+        };
+        assert_eq!(round_trip(int64), DataType::UInt8);
+        // Double arrays can't be stored as 64 bit integers
+        assert_eq!(round_trip(double(vec![1e12])), DataType::Double);
+        // Values that aren't whole numbers keep their type
+        let nan = numeric_file(double(vec![1.0, f64::NAN]));
+        let written = write_all_with_options(&nan, &options).unwrap();
+        assert_eq!(storage_type(&written), DataType::Double);
+        assert_eq!(round_trip(double(vec![1.0, 0.5])), DataType::Double);
+        assert_eq!(
+            round_trip(double(vec![1.0, f64::INFINITY])),
+            DataType::Double
+        );
+        assert_eq!(round_trip(double(vec![-0.0])), DataType::Double);
+        // Nothing is smaller than a byte
+        let uint8 = NumericData::UInt8 {
+            real: vec![1, 2],
+            imag: None,
+        };
+        assert_eq!(round_trip(uint8), DataType::UInt8);
+
+        // Not done by default
+        let result = numeric_file(double(vec![1.0, 2.0]));
+        assert_eq!(storage_type(&write_all(&result).unwrap()), DataType::Double);
+        let compressed = write_all_with_options(&result, &options).unwrap();
+        assert!(compressed.len() < write_all(&result).unwrap().len());
+    }
+
+    #[test]
+    fn compression() {
+        let fixtures: [&[u8]; 3] = [
+            include_bytes!("../tests/double.mat"),
+            include_bytes!("../tests/single_complex.mat"),
+            include_bytes!("../tests/two_arrays.mat"),
+        ];
+        let levels = [
+            CompressionLevel::Store,
+            CompressionLevel::Fast,
+            CompressionLevel::Default,
+        ];
+        for data in fixtures {
+            let result = parse_all(data).unwrap();
+            let uncompressed = parse_all(&write_all(&result).unwrap()).unwrap();
+            for &level in &levels {
+                let options = WriteOptions::new().compression(Some(level));
+                let written = write_all_with_options(&result, &options).unwrap();
+                // Each variable is a compressed element of its own
+                assert_eq!(
+                    u32::from_le_bytes(written[128..132].try_into().unwrap()),
+                    DataType::Compressed as u32
+                );
+                let compressed = parse_all(&written).unwrap();
+                assert_eq!(
+                    compressed.data_elements.len(),
+                    uncompressed.data_elements.len()
+                );
+                for (a, b) in compressed
+                    .data_elements
+                    .iter()
+                    .zip(&uncompressed.data_elements)
+                {
+                    assert_numeric_eq(a, b);
+                }
+            }
+        }
+
+        // A repetitive array shrinks
+        let result = numeric_file(NumericData::Double {
+            real: vec![1.5; 10000],
+            imag: None,
+        });
+        let uncompressed = write_all(&result).unwrap();
+        let options = WriteOptions::new().compression(Some(CompressionLevel::Default));
+        let compressed = write_all_with_options(&result, &options).unwrap();
+        assert!(compressed.len() * 10 < uncompressed.len());
+        let options = WriteOptions::new().compression(Some(CompressionLevel::Store));
+        let stored = write_all_with_options(&result, &options).unwrap();
+        assert!(stored.len() > uncompressed.len());
+    }
+
+    // The output of these was also loaded with scipy.io.loadmat and Octave's
+    // load, which should be repeated when the framing changes:
+    //   python -c "import scipy.io; print(scipy.io.loadmat('out.mat'))"
+    //   octave --eval "load out.mat; whos"
+    #[test]
+    fn round_trip_numeric() {
+        let fixtures: [&[u8]; 8] = [
+            include_bytes!("../tests/double.mat"),
+            include_bytes!("../tests/int32.mat"),
+            include_bytes!("../tests/int64.mat"),
+            include_bytes!("../tests/uint64.mat"),
+            include_bytes!("../tests/single_complex.mat"),
+            include_bytes!("../tests/multidimensional.mat"),
+            include_bytes!("../tests/globals.mat"),
+            include_bytes!("../tests/two_arrays.mat"),
+        ];
+        for data in fixtures {
+            let result = parse_all(data).unwrap();
+            let written = write_all(&result).unwrap();
+            assert_eq!(written.len() % 8, 0);
+            let read = parse_all(&written).unwrap();
+            assert_eq!(read.header.text(), result.header.text());
+            assert_eq!(read.data_elements.len(), result.data_elements.len());
+            for (written, read) in result.data_elements.iter().zip(&read.data_elements) {
+                assert_numeric_eq(written, read);
+            }
+
+            let mut buf = Vec::new();
+            write_to(&mut buf, &result).unwrap();
+            assert_eq!(buf, written);
+        }
+    }
+
+    #[test]
+    fn round_trip_sparse() {
+        let fixtures: [&[u8]; 6] = [
+            include_bytes!("../tests/sparse1.mat"),
+            include_bytes!("../tests/sparse2.mat"),
+            include_bytes!("../tests/sparse_empty.mat"),
+            include_bytes!("../tests/sparse_logical.mat"),
+            include_bytes!("../tests/sparse_nzmax.mat"),
+            include_bytes!("../tests/sparse_spalloc.mat"),
+        ];
+        for data in fixtures {
+            let result = parse_all(data).unwrap();
+            for options in [
+                WriteOptions::new(),
+                WriteOptions::new().compress_integers(true),
+            ] {
+                let written = write_all_with_options(&result, &options).unwrap();
+                let read = parse_all(&written).unwrap();
+                assert_eq!(read.data_elements.len(), result.data_elements.len());
+                for (written, read) in result.data_elements.iter().zip(&read.data_elements) {
+                    let (DataElement::SparseMatrix(written), DataElement::SparseMatrix(read)) =
+                        (written, read)
+                    else {
+                        panic!("expected sparse matrices");
+                    };
+                    assert_eq!(written.header.name, read.header.name);
+                    assert_eq!(written.header.dimensions, read.header.dimensions);
+                    assert_eq!(written.header.flags.complex, read.header.flags.complex);
+                    assert_eq!(written.header.flags.global, read.header.flags.global);
+                    assert_eq!(written.header.flags.logical, read.header.flags.logical);
+                    assert_eq!(read.header.flags.class, ArrayType::Sparse);
+                    assert_eq!(read.header.flags.nzmax, written.nnz().max(1));
+                    assert_eq!(written.row_index, read.row_index);
+                    assert_eq!(written.column_index, read.column_index);
+                    assert_eq!(written.data, read.data);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_sparse() {
+        let data = include_bytes!("../tests/sparse1.mat");
+        let result = parse_all(data).unwrap();
+        let DataElement::SparseMatrix(sparse) = &result.data_elements[0] else {
+            panic!("expected a sparse matrix");
+        };
+        let invalid = |modify: &dyn Fn(&mut Sparse)| {
+            let mut result = parse_all(data).unwrap();
+            let DataElement::SparseMatrix(sparse) = &mut result.data_elements[0] else {
+                unreachable!();
+            };
+            modify(sparse);
+            matches!(write_all(&result), Err(WriteError::InvalidArray { .. }))
+        };
+        assert!(!invalid(&|_| {}));
+        assert!(invalid(&|sparse| {
+            sparse.column_index.pop();
+        }));
+        assert!(invalid(&|sparse| {
+            sparse.column_index.swap(1, 2);
+        }));
+        let rows = sparse.header.dimensions.rows();
+        assert!(invalid(&|sparse| sparse.row_index[0] = rows));
+        assert!(invalid(&|sparse| {
+            sparse.row_index.pop();
+        }));
+        assert!(invalid(&|sparse| {
+            sparse.data = NumericData::Double {
+                real: vec![1.0],
+                imag: None,
+            }
+        }));
+        assert!(invalid(&|sparse| {
+            sparse.data = NumericData::Single {
+                real: vec![1.0; sparse.nnz()],
+                imag: None,
+            }
+        }));
+    }
+
+    fn assert_character_eq(written: &CharacterData, read: &CharacterData) {
+        match (written, read) {
+            (CharacterData::Unicode(written), CharacterData::Unicode(read)) => {
+                assert_eq!(written, read)
+            }
+            (CharacterData::NonUnicode(written), CharacterData::NonUnicode(read)) => {
+                assert_eq!(written, read)
+            }
+            _ => panic!("expected the same kind of character data"),
+        }
+    }
+
+    /// A file holding the single character array `name`.
+    fn character_file(name: &str, dimensions: Vec<usize>, data: CharacterData) -> ParseResult {
+        file(vec![DataElement::CharacterMatrix(Character {
+            header: array_header(name, ArrayType::Char, dimensions),
+            real_part: data,
+            imag_part: None,
+        })])
+    }
+
+    // When the encoding changes, check that Octave displays the written
+    // strings as expected, as for round_trip_numeric:
+    //   octave --eval "load out.mat; disp(s)"
+    #[test]
+    fn round_trip_character() {
+        let fixtures: [&[u8]; 4] = [
+            include_bytes!("../tests/character.mat"),
+            include_bytes!("../tests/char_emoji.mat"),
+            include_bytes!("../tests/char_matrix.mat"),
+            include_bytes!("../tests/char_surrogate.mat"),
+        ];
+        for data in fixtures {
+            let result = parse_all(data).unwrap();
+            for encoding in [CharEncoding::Utf8, CharEncoding::Utf16] {
+                let options = WriteOptions::new().char_encoding(encoding);
+                let written = write_all_with_options(&result, &options).unwrap();
+                let read = parse_all(&written).unwrap();
+                assert_eq!(read.data_elements.len(), result.data_elements.len());
+                for (written, read) in result.data_elements.iter().zip(&read.data_elements) {
+                    let (DataElement::CharacterMatrix(written), DataElement::CharacterMatrix(read)) =
+                        (written, read)
+                    else {
+                        panic!("expected character arrays");
+                    };
+                    assert_eq!(written.header.name, read.header.name);
+                    // Some writers count characters instead of code units
+                    if written.header.dimensions.rows() == 1 {
+                        let len = written.real_part.utf16_len();
+                        assert_eq!(read.header.dimensions, vec![1, len].into());
+                    } else {
+                        assert_eq!(written.header.dimensions, read.header.dimensions);
+                    }
+                    assert_eq!(read.header.flags.class, ArrayType::Char);
+                    assert_character_eq(&written.real_part, &read.real_part);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn character_encoding() {
+        let text = "a\u{1f600}b";
+        let result = character_file("s", vec![1, 1], CharacterData::Unicode(text.to_string()));
+        let read = |options: &WriteOptions| {
+            let written = write_all_with_options(&result, options).unwrap();
+            let read = parse_all(&written).unwrap();
+            let Some(DataElement::CharacterMatrix(character)) = read.data_elements.first() else {
+                panic!("expected a character array");
+            };
+            assert_character_eq(&character.real_part, &CharacterData::Unicode(text.into()));
+            (storage_type(&written), character.header.dimensions.clone())
+        };
+        // The dimensions count UTF-16 code units in either encoding
+        let options = WriteOptions::new();
+        assert_eq!(read(&options), (DataType::Utf16, vec![1, 4].into()));
+        let options = WriteOptions::new().char_encoding(CharEncoding::Utf8);
+        assert_eq!(read(&options), (DataType::Utf8, vec![1, 4].into()));
+
+        // Unpaired surrogates stay as they are
+        let units = vec![0x61, 0xd83d];
+        let result = character_file("s", vec![1, 2], CharacterData::NonUnicode(units.clone()));
+        for encoding in [CharEncoding::Utf8, CharEncoding::Utf16] {
+            let options = WriteOptions::new().char_encoding(encoding);
+            let written = write_all_with_options(&result, &options).unwrap();
+            assert_eq!(storage_type(&written), DataType::UInt16);
+            let read = parse_all(&written).unwrap();
+            let Some(DataElement::CharacterMatrix(character)) = read.data_elements.first() else {
+                panic!("expected a character array");
+            };
+            assert_character_eq(
+                &character.real_part,
+                &CharacterData::NonUnicode(units.clone()),
+            );
+        }
+
+        // Empty strings keep empty dimensions and are 0x0 otherwise, and
+        // long names need a full tag
+        let name = "a_name_longer_than_four_bytes";
+        for (dimensions, expected) in [(vec![1, 0], vec![1, 0]), (vec![1, 5], vec![0, 0])] {
+            let result = character_file(name, dimensions, CharacterData::Unicode(String::new()));
+            let read = parse_all(&write_all(&result).unwrap()).unwrap();
+            let Some(DataElement::CharacterMatrix(character)) = read.data_elements.first() else {
+                panic!("expected a character array");
+            };
+            assert_eq!(character.header.name, name);
+            assert_eq!(character.header.dimensions, expected.into());
+            assert_eq!(character.real_part.utf16_len(), 0);
+        }
+
+        // Char matrices have to fit their dimensions
+        let result = character_file("m", vec![2, 3], CharacterData::Unicode("abcd".into()));
+        assert!(matches!(
+            write_all(&result),
+            Err(WriteError::InvalidArray { .. })
+        ));
+    }
+
+    #[test]
+    fn round_trip_structure() {
+        let fixtures: [&[u8]; 5] = [
+            include_bytes!("../tests/struct.mat"),
+            include_bytes!("../tests/struct_array.mat"),
+            include_bytes!("../tests/struct_full_names.mat"),
+            include_bytes!("../tests/struct_no_fields.mat"),
+            include_bytes!("../tests/struct_no_fields_v7.mat"),
+        ];
+        for data in fixtures {
+            let result = parse_all(data).unwrap();
+            let read = parse_all(&write_all(&result).unwrap()).unwrap();
+            assert_eq!(read.data_elements.len(), result.data_elements.len());
+            for (written, read) in result.data_elements.iter().zip(&read.data_elements) {
+                assert_element_eq(written, read);
+            }
+        }
+
+        // Two levels of nesting, with a complex field and an empty structure
+        let mut complex = Numeric {
+            header: array_header("z", ArrayType::Double, vec![1, 2]),
+            data: NumericData::Double {
+                real: vec![1.0, 2.0],
+                imag: Some(vec![-1.0, 0.5]),
+            },
+            imag_skipped: false,
+        };
+        complex.header.flags.complex = true;
+        let empty = Structure::new(array_header("empty", ArrayType::Struct, vec![0, 0]));
+        let mut inner = Structure::new(array_header("inner", ArrayType::Struct, vec![1, 1]));
+        inner.insert("z", DataElement::NumericMatrix(complex));
+        inner.insert("empty", DataElement::StructureMatrix(empty));
+        let label = Character {
+            header: array_header("label", ArrayType::Char, vec![1, 5]),
+            real_part: CharacterData::Unicode("hello".into()),
+            imag_part: None,
+        };
+        let mut outer = Structure::new(array_header("s", ArrayType::Struct, vec![1, 1]));
+        outer.insert("inner", DataElement::StructureMatrix(inner));
+        outer.insert("label", DataElement::CharacterMatrix(label));
+        let result = file(vec![DataElement::StructureMatrix(outer)]);
+        for options in [
+            WriteOptions::new(),
+            WriteOptions::new().compression(Some(CompressionLevel::Default)),
+        ] {
+            let read = parse_all(&write_all_with_options(&result, &options).unwrap()).unwrap();
+            assert_element_eq(&result.data_elements[0], &read.data_elements[0]);
+        }
+    }
+
+    #[test]
+    fn structure_field_names() {
+        let value = |name: &str| {
+            DataElement::NumericMatrix(Numeric {
+                header: array_header(name, ArrayType::Double, vec![1, 1]),
+                data: NumericData::Double {
+                    real: vec![1.0],
+                    imag: None,
+                },
+                imag_skipped: false,
+            })
+        };
+        // Names up to 63 bytes fit, with the slot growing beyond 32 bytes
+        let longest = "f".repeat(MAX_NAME_LENGTH);
+        let mut structure = Structure::new(array_header("s", ArrayType::Struct, vec![1, 1]));
+        structure.insert("a", value("a"));
+        structure.insert(&longest, value(&longest));
+        let result = file(vec![DataElement::StructureMatrix(structure.clone())]);
+        let read = parse_all(&write_all(&result).unwrap()).unwrap();
+        assert_element_eq(&result.data_elements[0], &read.data_elements[0]);
+
+        let too_long = "f".repeat(MAX_NAME_LENGTH + 1);
+        structure.insert(&too_long, value(&too_long));
+        let result = file(vec![DataElement::StructureMatrix(structure)]);
+        assert!(matches!(
+            write_all(&result),
+            Err(WriteError::FieldNameTooLong { name, field }) if name == "s" && field == too_long
+        ));
+    }
+
+    #[test]
+    fn empty_and_multidimensional() {
+        let numeric = |name: &str, dimensions: Vec<usize>, data: NumericData, logical: bool| {
+            let mut header = array_header(name, data.class(), dimensions);
+            header.flags.logical = logical;
+            DataElement::NumericMatrix(Numeric {
+                header,
+                data,
+                imag_skipped: false,
+            })
+        };
+        let character = |name: &str, dimensions: Vec<usize>, text: &str| {
+            DataElement::CharacterMatrix(Character {
+                header: array_header(name, ArrayType::Char, dimensions),
+                real_part: CharacterData::Unicode(text.to_string()),
+                imag_part: None,
+            })
+        };
+        let double = |real: Vec<f64>| NumericData::Double { real, imag: None };
+        let uint8 = |real: Vec<u8>| NumericData::UInt8 { real, imag: None };
+        let arrays = vec![
+            numeric("x", vec![0, 0], double(vec![]), false),
+            numeric(
+                "y",
+                vec![0, 3],
+                NumericData::Int8 {
+                    real: vec![],
+                    imag: None,
+                },
+                false,
+            ),
+            numeric("z", vec![2, 0, 4], double(vec![]), false),
+            numeric(
+                "a",
+                vec![4, 3, 2, 5],
+                double((1..=120).map(f64::from).collect()),
+                false,
+            ),
+            numeric(
+                "b",
+                vec![2, 3, 4],
+                double((1..=24).map(f64::from).collect()),
+                false,
+            ),
+            numeric("l", vec![0, 1], uint8(vec![]), true),
+            numeric(
+                "m",
+                vec![2, 2, 3],
+                uint8((0..12).map(|i| i % 2).collect()),
+                true,
+            ),
+            character("e", vec![0, 0], ""),
+            character("f", vec![1, 0], ""),
+            character("g", vec![0, 2, 3], ""),
+            character("c", vec![2, 3, 2], "abcdefghijkl"),
+        ];
+        let result = file(arrays);
+        for options in [
+            WriteOptions::new(),
+            WriteOptions::new()
+                .compression(Some(CompressionLevel::Fast))
+                .byte_order(ByteOrder::BigEndian),
+            WriteOptions::new().compress_integers(true),
+        ] {
+            let written = write_all_with_options(&result, &options).unwrap();
+            let read = parse_all(&written).unwrap();
+            assert_eq!(read.data_elements, result.data_elements);
+        }
+
+        // An empty double as Matlab writes zeros(0,0), in the layout of
+        // empty.mat
+        let written = write_all(&file(result.data_elements[..1].to_vec())).unwrap();
+        let data = include_bytes!("../tests/empty.mat");
+        assert_eq!(written[128..], data[128..128 + 56]);
+
+        // Three dimensions take 12 bytes, padded to 16
+        let written = write_all(&file(result.data_elements[4..5].to_vec())).unwrap();
+        let dimensions = &written[128 + 8 + 16..][..24];
+        let mut expected = to_bytes(
+            [DataType::Int32 as u32, 12, 2, 3, 4],
+            ByteOrder::LittleEndian,
+        );
+        expected.resize(24, 0);
+        assert_eq!(dimensions, expected);
+    }
+
+    #[test]
+    fn round_trip_cell() {
+        let fixtures: [&[u8]; 3] = [
+            include_bytes!("../tests/cell.mat"),
+            include_bytes!("../tests/struct_cell_v6.mat"),
+            include_bytes!("../tests/struct_cell_v7.mat"),
+        ];
+        for data in fixtures {
+            let result = parse_all(data).unwrap();
+            let read = parse_all(&write_all(&result).unwrap()).unwrap();
+            assert_eq!(read.data_elements.len(), result.data_elements.len());
+            for (written, read) in result.data_elements.iter().zip(&read.data_elements) {
+                assert_element_eq(written, read);
+            }
+        }
+
+        // Cells of every kind, including a nested cell array
+        let scalar = |name: &str, value: f64| {
+            DataElement::NumericMatrix(Numeric {
+                header: array_header(name, ArrayType::Double, vec![1, 1]),
+                data: NumericData::Double {
+                    real: vec![value],
+                    imag: None,
+                },
+                imag_skipped: false,
+            })
+        };
+        let text = DataElement::CharacterMatrix(Character {
+            header: array_header("", ArrayType::Char, vec![1, 2]),
+            real_part: CharacterData::Unicode("hi".into()),
+            imag_part: None,
+        });
+        let mut structure = Structure::new(array_header("", ArrayType::Struct, vec![1, 1]));
+        structure.insert("a", scalar("a", 1.0));
+        let nested = Cell {
+            header: array_header("", ArrayType::Cell, vec![2, 1]),
+            values: vec![scalar("", 2.0), scalar("", 3.0)],
+        };
+        let cell = Cell {
+            header: array_header("c", ArrayType::Cell, vec![2, 2]),
+            values: vec![
+                scalar("", 1.0),
+                text,
+                DataElement::StructureMatrix(structure),
+                DataElement::CellMatrix(nested),
+            ],
+        };
+        let result = file(vec![DataElement::CellMatrix(cell)]);
+        let written = write_all(&result).unwrap();
+        let read = parse_all(&written).unwrap();
+        assert_element_eq(&result.data_elements[0], &read.data_elements[0]);
+        if let Some(dir) = std::env::var_os("MATFILE_CHECK_DIR") {
+            std::fs::write(std::path::Path::new(&dir).join("cells.mat"), &written).unwrap();
+        }
+
+        // Cells without values are empty doubles
+        let cell = Cell {
+            header: array_header("e", ArrayType::Cell, vec![2, 3]),
+            values: Vec::new(),
+        };
+        let result = file(vec![DataElement::CellMatrix(cell)]);
+        let read = parse_all(&write_all(&result).unwrap()).unwrap();
+        let Some(DataElement::CellMatrix(cell)) = read.data_elements.first() else {
+            panic!("expected a cell array");
+        };
+        assert_eq!(cell.values.len(), 6);
+        for value in &cell.values {
+            let DataElement::NumericMatrix(numeric) = value else {
+                panic!("expected a numeric array");
+            };
+            assert_eq!(numeric.header.flags.class, ArrayType::Double);
+            assert_eq!(numeric.header.dimensions, vec![0, 0].into());
+        }
+
+        let cell = Cell {
+            header: array_header("c", ArrayType::Cell, vec![2, 3]),
+            values: vec![scalar("", 1.0)],
+        };
+        assert!(matches!(
+            write_all(&file(vec![DataElement::CellMatrix(cell)])),
+            Err(WriteError::InvalidArray { .. })
+        ));
+    }
+
+    #[test]
+    fn logical_and_global() {
+        // Laid out like Matlab writes it, with small integers stored as uint8
+        let data = include_bytes!("../tests/logical_v6.mat");
+        let result = parse_all(data).unwrap();
+        let options = WriteOptions::new().compress_integers(true);
+        assert_eq!(
+            write_all_with_options(&result, &options).unwrap(),
+            data.as_ref()
+        );
+
+        let mask = crate::Numeric::new(
+            "mask",
+            vec![2, 2].into(),
+            NumericData::UInt8 {
+                real: vec![1, 0, 0, 1],
+                imag: None,
+            },
+        )
+        .unwrap()
+        .logical(true)
+        .global(true);
+        let text = crate::Character::try_from(Character {
+            header: array_header("label", ArrayType::Char, vec![1, 2]),
+            real_part: CharacterData::Unicode("ok".into()),
+            imag_part: None,
+        })
+        .unwrap()
+        .global(true);
+        let mut structure = crate::Structure::new("s").global(true);
+        structure.insert("mask", crate::Array::Numeric(mask.clone()));
+        let arrays = vec![
+            crate::Array::Numeric(mask),
+            crate::Array::Character(text),
+            crate::Array::Structure(structure),
+        ];
+        let result = file(arrays.into_iter().map(DataElement::from).collect());
+        let written = write_all(&result).unwrap();
+        let mat_file = crate::MatFile::parse(written.as_slice()).unwrap();
+        let mask = mat_file.get_numeric("mask").unwrap();
+        assert!(mask.is_logical() && mask.is_global());
+        assert_eq!(mask.to_logical(), Some(vec![true, false, false, true]));
+        assert!(mat_file.get_char("label").unwrap().is_global());
+        let structure = mat_file.get_struct("s").unwrap();
+        assert!(structure.is_global());
+        let crate::Array::Numeric(field) = &structure["mask"] else {
+            panic!("expected a numeric array");
+        };
+        assert!(field.is_logical() && field.is_global());
+    }
+
+    #[test]
+    fn big_endian() {
+        let fixtures: [&[u8]; 7] = [
+            include_bytes!("../tests/double.mat"),
+            include_bytes!("../tests/single_complex.mat"),
+            include_bytes!("../tests/int64.mat"),
+            include_bytes!("../tests/sparse2.mat"),
+            include_bytes!("../tests/char_emoji.mat"),
+            include_bytes!("../tests/struct_array.mat"),
+            include_bytes!("../tests/cell.mat"),
+        ];
+        for data in fixtures {
+            let result = parse_all(data).unwrap();
+            for options in [
+                WriteOptions::new(),
+                WriteOptions::new().compress_integers(true),
+                WriteOptions::new().compression(Some(CompressionLevel::Default)),
+            ] {
+                let options = options.byte_order(ByteOrder::BigEndian);
+                let written = write_all_with_options(&result, &options).unwrap();
+                assert_eq!(&written[124..128], &[1, 0, b'M', b'I']);
+                let read = parse_all(&written).unwrap();
+                assert_eq!(read.header.byte_order(), ByteOrder::BigEndian);
+                assert_eq!(read.data_elements.len(), result.data_elements.len());
+                for (written, read) in result.data_elements.iter().zip(&read.data_elements) {
+                    match written {
+                        DataElement::SparseMatrix(written) => {
+                            let DataElement::SparseMatrix(read) = read else {
+                                panic!("expected a sparse matrix");
+                            };
+                            assert_eq!(written.row_index, read.row_index);
+                            assert_eq!(written.column_index, read.column_index);
+                            assert_eq!(written.data, read.data);
+                        }
+                        DataElement::CharacterMatrix(written) => {
+                            let DataElement::CharacterMatrix(read) = read else {
+                                panic!("expected a character array");
+                            };
+                            assert_character_eq(&written.real_part, &read.real_part);
+                        }
+                        _ => assert_element_eq(written, read),
+                    }
+                }
+            }
+        }
+
+        // The tag of small subelements swaps the size and the data type
+        let result = numeric_file(NumericData::Int32 {
+            real: vec![7],
+            imag: None,
+        });
+        let options = WriteOptions::new().byte_order(ByteOrder::BigEndian);
+        let written = write_all_with_options(&result, &options).unwrap();
+        assert_eq!(&written[176..184], &[0, 4, 0, 5, 0, 0, 0, 7]);
+    }
+
+    #[test]
+    fn small_subelements() {
+        let subelement = |bytes: &[u8]| {
+            let mut out = Vec::new();
+            write_subelement(&mut out, DataType::Int8, bytes, ByteOrder::LittleEndian);
+            out
+        };
+        assert_eq!(subelement(b""), [1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(subelement(b"x"), [1, 0, 1, 0, b'x', 0, 0, 0]);
+        assert_eq!(subelement(b"abcd"), [1, 0, 4, 0, b'a', b'b', b'c', b'd']);
+        assert_eq!(
+            subelement(b"abcde"),
+            [1, 0, 0, 0, 5, 0, 0, 0, b'a', b'b', b'c', b'd', b'e', 0, 0, 0]
+        );
+
+        // The name of a variable named x fits into its tag
+        let result = numeric_file(NumericData::Double {
+            real: vec![1.0],
+            imag: None,
+        });
+        let written = write_all(&result).unwrap();
+        assert_eq!(&written[168..176], &[1, 0, 1, 0, b'x', 0, 0, 0]);
+        // Matrix tag, array flags, dimensions, name and data
+        assert_eq!(written.len(), 128 + 8 + 16 + 16 + 8 + 16);
+    }
+
+    #[test]
+    fn matches_matlab() {
+        // Files saved by Matlab, whose variables are compressed elements
+        let fixtures: [&[u8]; 8] = [
+            include_bytes!("../tests/double.mat"),
+            include_bytes!("../tests/double_as_int16.mat"),
+            include_bytes!("../tests/double_as_uint8.mat"),
+            include_bytes!("../tests/long_name.mat"),
+            include_bytes!("../tests/multidimensional.mat"),
+            include_bytes!("../tests/single_complex.mat"),
+            include_bytes!("../tests/character.mat"),
+            include_bytes!("../tests/two_arrays.mat"),
+        ];
+        for data in fixtures {
+            let mut expected = data[..128].to_vec();
+            let mut i = &data[128..];
+            while !i.is_empty() {
+                let size = u32::from_le_bytes(i[4..8].try_into().unwrap()) as usize;
+                let mut decoder = libflate::zlib::Decoder::new(&i[8..8 + size]).unwrap();
+                std::io::copy(&mut decoder, &mut expected).unwrap();
+                i = &i[8 + size..];
+            }
+            // Matlab stores small integers in small types, and names of up
+            // to 4 bytes in the tag
+            let result = parse_all(data).unwrap();
+            let options = WriteOptions::new()
+                .compress_integers(true)
+                .char_encoding(CharEncoding::Utf8);
+            let written = write_all_with_options(&result, &options).unwrap();
+            assert_eq!(written[128..], expected[128..]);
+        }
+    }
+
+    #[test]
+    fn mat_writer() {
+        let arrays: Vec<DataElement> = (0..4)
+            .map(|k| {
+                let real: Vec<f64> = (0..40_000).map(|i| f64::from(i % (1000 + k))).collect();
+                let mut header =
+                    array_header(&format!("x{}", k), ArrayType::Double, vec![200, 200]);
+                header.flags.global = k == 2;
+                DataElement::NumericMatrix(Numeric {
+                    header,
+                    data: NumericData::Double { real, imag: None },
+                    imag_skipped: false,
+                })
+            })
+            .collect();
+        for options in [
+            WriteOptions::new(),
+            WriteOptions::new().compression(Some(CompressionLevel::Fast)),
+            WriteOptions::new()
+                .compression(Some(CompressionLevel::Default))
+                .byte_order(ByteOrder::BigEndian),
+        ] {
+            let cursor = std::io::Cursor::new(Vec::new());
+            let mut writer = MatWriter::new(cursor, options.clone()).unwrap();
+            for array in &arrays {
+                writer.write_variable(array).unwrap();
+            }
+            let written = writer.finish().unwrap().into_inner();
+            assert!(written.starts_with(b"MATLAB 5.0 MAT-file, Created by: matfile"));
+            let read = parse_all(&written).unwrap();
+            assert_eq!(read.data_elements.len(), arrays.len());
+            for (written, read) in arrays.iter().zip(&read.data_elements) {
+                assert_numeric_eq(written, read);
+            }
+            // The same as when the file is written at once
+            let result = file(arrays.clone());
+            let at_once = write_all_with_options(&result, &options).unwrap();
+            assert_eq!(written[128..], at_once[128..]);
+        }
+
+        // The writer stays usable after a variable that can't be written
+        let cursor = std::io::Cursor::new(Vec::new());
+        let mut writer = MatWriter::new(cursor, WriteOptions::new()).unwrap();
+        let opaque = parse_all(include_bytes!("../tests/string.mat")).unwrap();
+        assert!(writer.write_variable(&opaque.data_elements[0]).is_err());
+        writer.write_variable(&arrays[0]).unwrap();
+        let written = writer.finish().unwrap().into_inner();
+        assert_eq!(parse_all(&written).unwrap().data_elements.len(), 1);
+    }
+
+    #[test]
+    fn framing() {
+        let data = include_bytes!("../tests/v4.mat");
+        let mut result = crate::parse_v4::parse_all(data).unwrap();
+        result
+            .data_elements
+            .retain(|data_element| matches!(data_element, DataElement::NumericMatrix(_)));
+        assert!(!result.data_elements.is_empty());
+        let written = write_all(&result).unwrap();
+        assert!(written.starts_with(b"MATLAB 5.0 MAT-file"));
+        assert_eq!(
+            &written[116..128],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 1, b'I', b'M']
+        );
+        let read = parse_all(&written).unwrap();
+        assert_eq!(read.data_elements.len(), result.data_elements.len());
+        for (written, read) in result.data_elements.iter().zip(&read.data_elements) {
+            assert_numeric_eq(written, read);
+        }
+    }
+
+    #[test]
+    fn round_trip_random() {
+        // A new seed every run covers more cases over time. Set MATFILE_SEED
+        // to the seed printed for a failure to run just that case again.
+        let seeds: Vec<u64> = match std::env::var("MATFILE_SEED") {
+            Ok(seed) => vec![seed.parse().expect("MATFILE_SEED is not a number")],
+            Err(_) => {
+                let time = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap();
+                let first = time.as_secs() << 20 ^ u64::from(time.subsec_micros());
+                (first..first + 50).collect()
+            }
+        };
+        eprintln!(
+            "round_trip_random: seeds {}..={}",
+            seeds[0],
+            seeds[seeds.len() - 1]
+        );
+        for seed in seeds {
+            let result = crate::random::Generator::new(seed).parse_result();
+            for compression in [None, Some(CompressionLevel::Fast)] {
+                for byte_order in [ByteOrder::LittleEndian, ByteOrder::BigEndian] {
+                    let options = WriteOptions::new()
+                        .compression(compression)
+                        .byte_order(byte_order);
+                    let context = format!(
+                        "with {:?} and {:?}, rerun with MATFILE_SEED={}",
+                        compression, byte_order, seed
+                    );
+                    let written = write_all_with_options(&result, &options)
+                        .unwrap_or_else(|err| panic!("writing failed {}: {}", context, err));
+                    let read = parse_all(&written)
+                        .unwrap_or_else(|err| panic!("parsing failed {}: {}", context, err));
+                    assert_eq!(
+                        read.data_elements.len(),
+                        result.data_elements.len(),
+                        "different number of variables {}",
+                        context
+                    );
+                    for (read, expected) in read.data_elements.iter().zip(&result.data_elements) {
+                        let name = expected.name().unwrap_or_default();
+                        assert!(read == expected, "{} differs {}", name, context);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn builder() {
+        let written = MatFileBuilder::new()
+            .add_f64("pressure", &[3, 2], [1.0, 2.0, 3.0, 4.0, 5.0, 6.0])
+            .unwrap()
+            .add_i32("counts", &[1, 2], vec![-1, 7])
+            .unwrap()
+            .add_logical("mask", &[2, 1], &[true, false])
+            .unwrap()
+            .add_string("units", "kPa")
+            .unwrap()
+            .add_struct("meta", |meta| {
+                meta.add_u8("run", &[1, 1], [3])?.add_string("operator", "")
+            })
+            .unwrap()
+            .options(WriteOptions::new().compression(Some(CompressionLevel::Fast)))
+            .build()
+            .unwrap();
+        let read = parse_all(&written).unwrap();
+        let names: Vec<_> = read.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["pressure", "counts", "mask", "units", "meta"]);
+        let mat_file = crate::MatFile::parse(written.as_slice()).unwrap();
+        let pressure = mat_file.get_numeric("pressure").unwrap();
+        assert_eq!(pressure.size(), &[3, 2]);
+        assert_eq!(pressure.data().class(), ArrayType::Double);
+        assert!(mat_file.get_numeric("mask").unwrap().is_logical());
+        assert_eq!(mat_file.get_char("units").unwrap().as_str(), Some("kPa"));
+        let meta = mat_file.get_struct("meta").unwrap();
+        assert_eq!(meta.field_names().collect::<Vec<_>>(), ["run", "operator"]);
+
+        // Mistakes are caught when the array is added
+        let builder = MatFileBuilder::new().add_f64("x", &[1, 1], [0.0]).unwrap();
+        assert!(matches!(
+            builder.clone().add_f64("y", &[2, 2], [1.0, 2.0, 3.0]),
+            Err(WriteError::InvalidArray { name, .. }) if name == "y"
+        ));
+        assert!(matches!(
+            builder.clone().add_u16("y", &[3], [1, 2, 3]),
+            Err(WriteError::InvalidArray { .. })
+        ));
+        let complex = NumericData::Double {
+            real: vec![1.0, 2.0],
+            imag: Some(vec![1.0]),
+        };
+        assert!(matches!(
+            builder.clone().add_numeric("y", &[1, 2], complex),
+            Err(WriteError::InvalidArray { .. })
+        ));
+        assert!(matches!(
+            builder.clone().add_string("x", "taken"),
+            Err(WriteError::NameCollision(name)) if name == "x"
+        ));
+        let too_long = "a".repeat(MAX_NAME_LENGTH + 1);
+        for name in ["", "1x", "_x", "has space", "ä", &too_long] {
+            assert!(matches!(
+                builder.clone().add_string(name, "text"),
+                Err(WriteError::InvalidName { name: invalid, .. }) if invalid == name
+            ));
+            assert!(matches!(
+                builder
+                    .clone()
+                    .add_struct("s", |s| s.add_i8(name, &[1, 1], [1])),
+                Err(WriteError::InvalidName { .. })
+            ));
+        }
+        builder
+            .add_string(&"a".repeat(MAX_NAME_LENGTH), "longest")
+            .unwrap();
+
+        let path = std::env::temp_dir().join(format!("matfile-builder-{}.mat", std::process::id()));
+        MatFileBuilder::new()
+            .add_f32("x", &[0, 0], [])
+            .unwrap()
+            .write_to(&path)
+            .unwrap();
+        let read = parse_all(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            read.iter().next().unwrap().1.dimensions(),
+            Some(&vec![0, 0].into())
+        );
+    }
+
+    #[cfg(feature = "ndarray")]
+    #[test]
+    fn builder_ndarray() {
+        use ndarr::{arr1, Array, Array2, Array3, ArrayD, ShapeBuilder};
+        use num_complex::Complex64;
+
+        let matrix = Array2::from_shape_fn((3, 4), |(i, j)| (10 * i + j) as f64 / 3.0);
+        let column_major = Array::from_shape_vec((2, 3).f(), vec![1i16, 2, 3, 4, 5, 6]).unwrap();
+        let cube = Array3::from_shape_fn((2, 3, 4), |(i, j, k)| (100 * i + 10 * j + k) as u32);
+        let complex = Array2::from_shape_fn((2, 2), |(i, j)| Complex64::new(i as f64, -(j as f64)));
+        let written = MatFileBuilder::new()
+            .add_ndarray("matrix", &matrix)
+            .unwrap()
+            .add_ndarray("transposed", &matrix.t())
+            .unwrap()
+            .add_ndarray("column_major", &column_major)
+            .unwrap()
+            .add_ndarray("cube", &cube.clone().into_dyn())
+            .unwrap()
+            .add_ndarray("complex", &complex)
+            .unwrap()
+            .add_ndarray("vector", &arr1(&[1.0f32, 2.0, 3.0]))
+            .unwrap()
+            .build()
+            .unwrap();
+        let mat_file = crate::MatFile::parse(written.as_slice()).unwrap();
+
+        let read = mat_file.get_numeric("matrix").unwrap();
+        assert_eq!(read.size(), &[3, 4]);
+        // Matlab stores the first column first
+        let crate::NumericData::Double { real, .. } = read.data() else {
+            panic!("expected doubles");
+        };
+        assert_eq!(real[..3], [matrix[(0, 0)], matrix[(1, 0)], matrix[(2, 0)]]);
+        let read: Array2<f64> = read.try_into().unwrap();
+        assert_eq!(read, matrix);
+        let read: Array2<f64> = mat_file
+            .get_numeric("transposed")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(read, matrix.t());
+        let read: Array2<i16> = mat_file
+            .get_numeric("column_major")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(read, column_major);
+        let read: ArrayD<u32> = mat_file.get_numeric("cube").unwrap().try_into().unwrap();
+        assert_eq!(read, cube.into_dyn());
+        let read = mat_file.get_numeric("complex").unwrap();
+        assert!(read.is_complex());
+        let read: Array2<Complex64> = read.try_into().unwrap();
+        assert_eq!(read, complex);
+        let read = mat_file.get_numeric("vector").unwrap();
+        assert_eq!(read.size(), &[1, 3]);
+    }
+
+    #[test]
+    fn builder_scalars() {
+        let written = MatFileBuilder::new()
+            .add_scalar("fs", 48_000.0)
+            .unwrap()
+            .add_scalar("gain", 0.5f32)
+            .unwrap()
+            .add_scalar("i8", -1i8)
+            .unwrap()
+            .add_scalar("u8", 255u8)
+            .unwrap()
+            .add_scalar("i16", -300i16)
+            .unwrap()
+            .add_scalar("u16", 60_000u16)
+            .unwrap()
+            .add_scalar("i32", -70_000i32)
+            .unwrap()
+            .add_scalar("u32", u32::MAX)
+            .unwrap()
+            .add_scalar("i64", i64::MIN)
+            .unwrap()
+            .add_scalar("u64", u64::MAX)
+            .unwrap()
+            .add_scalar("enabled", true)
+            .unwrap()
+            .add_scalar("disabled", false)
+            .unwrap()
+            .add_string("device", "X-200 ü")
+            .unwrap()
+            .build()
+            .unwrap();
+        let read = parse_all(&written).unwrap();
+        let classes: Vec<_> = read
+            .iter()
+            .map(|(name, data_element)| {
+                assert_eq!(
+                    data_element.dimensions(),
+                    Some(&vec![1, 1].into()),
+                    "{}",
+                    name
+                );
+                data_element.class().unwrap()
+            })
+            .take(12)
+            .collect();
+        assert_eq!(
+            classes,
+            [
+                ArrayType::Double,
+                ArrayType::Single,
+                ArrayType::Int8,
+                ArrayType::UInt8,
+                ArrayType::Int16,
+                ArrayType::UInt16,
+                ArrayType::Int32,
+                ArrayType::UInt32,
+                ArrayType::Int64,
+                ArrayType::UInt64,
+                ArrayType::UInt8,
+                ArrayType::UInt8,
+            ]
+        );
+
+        let mat_file = crate::MatFile::parse(written.as_slice()).unwrap();
+        assert_eq!(mat_file.get_scalar::<f64>("fs").unwrap(), 48_000.0);
+        assert_eq!(mat_file.get_scalar::<i64>("i64").unwrap(), i64::MIN);
+        assert_eq!(mat_file.get_scalar::<u64>("u64").unwrap(), u64::MAX);
+        let enabled = mat_file.get_numeric("enabled").unwrap();
+        assert!(enabled.is_logical());
+        assert_eq!(
+            enabled.data(),
+            &NumericData::UInt8 {
+                real: vec![1],
+                imag: None
+            }
+        );
+        assert!(mat_file.get_numeric("disabled").unwrap().is_logical());
+        assert!(!mat_file.get_numeric("u8").unwrap().is_logical());
+        let device = mat_file.get_char("device").unwrap();
+        assert_eq!(device.size(), &[1, 7]);
+        assert_eq!(device.as_str(), Some("X-200 ü"));
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(validate_name("x"), Ok(()));
+        assert_eq!(validate_name("Pressure_2"), Ok(()));
+        assert_eq!(validate_name(&"a".repeat(63)), Ok(()));
+        assert_eq!(validate_name(&"a".repeat(64)), Err(NameError::TooLong));
+        assert_eq!(validate_name(""), Err(NameError::Empty));
+        assert_eq!(validate_name("2x"), Err(NameError::InvalidStart('2')));
+        assert_eq!(validate_name("_x"), Err(NameError::InvalidStart('_')));
+        assert_eq!(
+            validate_name("has space"),
+            Err(NameError::InvalidCharacter(' '))
+        );
+        assert_eq!(validate_name("x-y"), Err(NameError::InvalidCharacter('-')));
+        assert_eq!(validate_name("Äpfel"), Err(NameError::InvalidStart('Ä')));
+        assert_eq!(
+            validate_name("straße"),
+            Err(NameError::InvalidCharacter('ß'))
+        );
+
+        // Structure fields are checked when written
+        let value = DataElement::NumericMatrix(Numeric {
+            header: array_header("my field", ArrayType::Double, vec![1, 1]),
+            data: NumericData::Double {
+                real: vec![1.0],
+                imag: None,
+            },
+            imag_skipped: false,
+        });
+        let mut structure = Structure::new(array_header("s", ArrayType::Struct, vec![1, 1]));
+        structure.insert("my field", value.clone());
+        let result = file(vec![DataElement::StructureMatrix(structure)]);
+        assert!(matches!(
+            write_all(&result),
+            Err(WriteError::InvalidName { name, error: NameError::InvalidCharacter(' ') })
+                if name == "s.my field"
+        ));
+        let options = WriteOptions::new().allow_invalid_names(true);
+        let written = write_all_with_options(&result, &options).unwrap();
+        assert_eq!(
+            parse_all(&written).unwrap().data_elements,
+            result.data_elements
+        );
+
+        // And so are variables when appending
+        let data = include_bytes!("../tests/double.mat");
+        let mut cursor = std::io::Cursor::new(data.to_vec());
+        assert!(matches!(
+            append_variable_to(&mut cursor, "1st", &value, &WriteOptions::new()),
+            Err(WriteError::InvalidName {
+                error: NameError::InvalidStart('1'),
+                ..
+            })
+        ));
+        assert_eq!(cursor.get_ref().as_slice(), data.as_slice());
+        append_variable_to(&mut cursor, "1st", &value, &options).unwrap();
+        assert_eq!(parse_all(cursor.get_ref()).unwrap().data_elements.len(), 2);
+
+        // The builder takes the options given before adding
+        let builder = MatFileBuilder::new().options(options);
+        let written = builder
+            .add_string("not valid", "text")
+            .unwrap()
+            .add_struct("s", |s| s.add_f64("é", &[1, 1], [1.0]))
+            .unwrap()
+            .build()
+            .unwrap();
+        let names: Vec<_> = parse_all(&written)
+            .unwrap()
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .collect();
+        assert_eq!(names, ["not valid", "s"]);
+    }
+
+    #[test]
+    fn append() {
+        let new = DataElement::NumericMatrix(Numeric {
+            header: array_header("appended", ArrayType::Double, vec![1, 3]),
+            data: NumericData::Double {
+                real: vec![1.0, 2.5, -3.0],
+                imag: None,
+            },
+            imag_skipped: false,
+        });
+        let fixtures: [&[u8]; 2] = [
+            include_bytes!("../tests/double.mat"),
+            include_bytes!("../tests/two_arrays.mat"),
+        ];
+        for data in fixtures {
+            let before = parse_all(data).unwrap();
+            for options in [
+                WriteOptions::new(),
+                WriteOptions::new().compression(Some(CompressionLevel::Default)),
+            ] {
+                let mut cursor = std::io::Cursor::new(data.to_vec());
+                append_variable_to(&mut cursor, "appended", &new, &options).unwrap();
+                let after = parse_all(cursor.get_ref()).unwrap();
+                assert_eq!(after.data_elements.len(), before.data_elements.len() + 1);
+                for (old, read) in before.data_elements.iter().zip(&after.data_elements) {
+                    assert_element_eq(old, read);
+                }
+                let (name, appended) = after.iter().last().unwrap();
+                assert_eq!(name, "appended");
+                assert_numeric_eq(&new, appended);
+            }
+        }
+
+        // The byte order of the file wins
+        let text = DataElement::CharacterMatrix(Character {
+            header: array_header("s", ArrayType::Char, vec![1, 5]),
+            real_part: CharacterData::Unicode("abcde".to_string()),
+            imag_part: None,
+        });
+        let options = WriteOptions::new()
+            .char_encoding(CharEncoding::Utf8)
+            .byte_order(ByteOrder::BigEndian);
+        let written = write_all_with_options(&file(vec![text]), &options).unwrap();
+        let mut cursor = std::io::Cursor::new(written);
+        append_variable_to(&mut cursor, "x", &new, &WriteOptions::new()).unwrap();
+        let after = parse_all(cursor.get_ref()).unwrap();
+        assert!(!after.header.is_little_endian);
+        let names: Vec<_> = after.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["s", "x"]);
+
+        // Name collisions are only checked when asked for
+        let data = include_bytes!("../tests/two_arrays.mat");
+        let existing = parse_all(data)
+            .unwrap()
+            .iter()
+            .next()
+            .unwrap()
+            .0
+            .to_string();
+        let options = WriteOptions::new().check_names(true);
+        let mut cursor = std::io::Cursor::new(data.to_vec());
+        assert!(matches!(
+            append_variable_to(&mut cursor, &existing, &new, &options),
+            Err(WriteError::NameCollision(name)) if name == existing
+        ));
+        assert_eq!(cursor.get_ref().as_slice(), data.as_slice());
+        append_variable_to(&mut cursor, &existing, &new, &WriteOptions::new()).unwrap();
+        assert_eq!(parse_all(cursor.get_ref()).unwrap().data_elements.len(), 3);
+
+        // v7.3 files and files that aren't MAT-files at all
+        for data in [
+            include_bytes!("../tests/v73.mat").as_slice(),
+            b"not a MAT-file",
+        ] {
+            let mut cursor = std::io::Cursor::new(data.to_vec());
+            assert!(matches!(
+                append_variable_to(&mut cursor, "x", &new, &WriteOptions::new()),
+                Err(WriteError::InvalidFile(_))
+            ));
+            assert_eq!(cursor.get_ref().as_slice(), data);
+        }
+
+        // The same through a path
+        let path = std::env::temp_dir().join(format!("matfile-append-{}.mat", std::process::id()));
+        std::fs::write(&path, include_bytes!("../tests/double.mat")).unwrap();
+        append_variable(&path, "appended", &new, &WriteOptions::new()).unwrap();
+        let after = parse_all(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(after.data_elements.len(), 2);
+    }
+
+    #[test]
+    fn update() {
+        let double = |name: &str, real: Vec<f64>| {
+            DataElement::NumericMatrix(Numeric {
+                header: array_header(name, ArrayType::Double, vec![1, real.len()]),
+                data: NumericData::Double { real, imag: None },
+                imag_skipped: false,
+            })
+        };
+        let arrays = vec![
+            double("a", vec![1.0; 4]),
+            double("b", vec![2.0; 4]),
+            double("c", vec![3.0; 4]),
+        ];
+        let written = write_all(&file(arrays.clone())).unwrap();
+        let names = |data: &[u8]| -> Vec<String> {
+            let read = parse_all(data).unwrap();
+            read.data_elements
+                .iter()
+                .map(|data_element| data_element.name().unwrap_or("?").to_string())
+                .collect()
+        };
+
+        // The same size
+        let new = double("b", vec![-1.0, 0.5, 0.25, 8.0]);
+        let mut cursor = std::io::Cursor::new(written.clone());
+        update_variable_in(&mut cursor, "b", &new, &WriteOptions::new()).unwrap();
+        assert_eq!(cursor.get_ref().len(), written.len());
+        let read = parse_all(cursor.get_ref()).unwrap();
+        assert_eq!(
+            read.data_elements,
+            [arrays[0].clone(), new, arrays[2].clone()]
+        );
+
+        // Smaller, with the rest filled
+        let new = double("b", vec![7.0]);
+        let mut cursor = std::io::Cursor::new(written.clone());
+        update_variable_in(&mut cursor, "b", &new, &WriteOptions::new()).unwrap();
+        assert_eq!(cursor.get_ref().len(), written.len());
+        // The filler isn't reported as a variable, not even by strict parsing
+        assert_eq!(names(cursor.get_ref()), ["a", "b", "c"]);
+        let strict = crate::parse::ParseOptions::new().strict(true);
+        let read = crate::parse::parse_all_with_options(cursor.get_ref(), &strict).unwrap();
+        assert!(read.warnings.is_empty());
+        let mat_file =
+            crate::MatFile::parse_with_options(cursor.get_ref().as_slice(), &strict).unwrap();
+        assert_eq!(mat_file.get_scalar::<f64>("b").unwrap(), 7.0);
+        assert_eq!(mat_file.arrays().len(), 3);
+
+        // Larger fails, unless it is the last variable
+        let new = double("b", vec![1.0; 5]);
+        let mut cursor = std::io::Cursor::new(written.clone());
+        assert!(matches!(
+            update_variable_in(&mut cursor, "b", &new, &WriteOptions::new()),
+            Err(WriteError::WouldGrow(name)) if name == "b"
+        ));
+        assert_eq!(cursor.get_ref(), &written);
+        let new = double("c", vec![1.0; 5]);
+        update_variable_in(&mut cursor, "c", &new, &WriteOptions::new()).unwrap();
+        assert_eq!(cursor.get_ref().len(), written.len() + 8);
+        assert_eq!(parse_all(cursor.get_ref()).unwrap().data_elements[2], new);
+
+        assert!(matches!(
+            update_variable_in(&mut cursor, "d", &new, &WriteOptions::new()),
+            Err(WriteError::NotFound(name)) if name == "d"
+        ));
+
+        // Compressed variables replaced by smaller ones, which need zeros
+        // after their zlib stream to keep the next variable aligned
+        let options = WriteOptions::new().compression(Some(CompressionLevel::Default));
+        let large = double("a", (0..100).map(|i| f64::from(i * i)).collect());
+        let data = write_all_with_options(&file(vec![large, arrays[1].clone()]), &options).unwrap();
+        for len in 0..8 {
+            let new = double("a", (0..len).map(f64::from).collect());
+            let mut cursor = std::io::Cursor::new(data.clone());
+            update_variable_in(&mut cursor, "a", &new, &options).unwrap();
+            assert_eq!(cursor.get_ref().len(), data.len());
+            let read = crate::parse::parse_all_with_options(cursor.get_ref(), &strict).unwrap();
+            assert_eq!(read.data_elements, [new, arrays[1].clone()]);
+        }
+        // Uncompressed ones only fit if that leaves room for the filler
+        let available = 8 + u32::from_le_bytes(data[132..136].try_into().unwrap()) as usize;
+        let new = double("a", vec![]);
+        let mut cursor = std::io::Cursor::new(data.clone());
+        let updated = update_variable_in(&mut cursor, "a", &new, &WriteOptions::new());
+        if (available - 56).is_multiple_of(8) {
+            updated.unwrap();
+            assert_eq!(parse_all(cursor.get_ref()).unwrap().data_elements[0], new);
+        } else {
+            assert!(matches!(updated, Err(WriteError::WouldGrow(_))));
+            assert_eq!(cursor.get_ref(), &data);
+        }
+
+        // The same through a path
+        let path = std::env::temp_dir().join(format!("matfile-update-{}.mat", std::process::id()));
+        std::fs::write(&path, &written).unwrap();
+        update_variable(&path, "a", &double("a", vec![0.0; 4]), &WriteOptions::new()).unwrap();
+        let read = parse_all(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read.data_elements[0], double("a", vec![0.0; 4]));
+    }
+
+    #[test]
+    fn deterministic() {
+        let mat_file =
+            crate::MatFile::parse(&include_bytes!("../tests/struct_cell_v7.mat")[..]).unwrap();
+        let data_elements: Vec<DataElement> =
+            mat_file.arrays().iter().cloned().map(Into::into).collect();
+        let options = WriteOptions::new()
+            .deterministic(true)
+            .compression(Some(CompressionLevel::Default));
+        // The headers of these files were written at different times
+        let write = |header_source: &[u8], options: &WriteOptions| {
+            let mut result = parse_all(header_source).unwrap();
+            result.data_elements = data_elements.clone();
+            write_all_with_options(&result, options).unwrap()
+        };
+        let first = write(include_bytes!("../tests/double.mat"), &options);
+        let second = write(include_bytes!("../tests/two_arrays.mat"), &options);
+        assert_eq!(first, second);
+        assert!(first.starts_with(b"MATLAB 5.0 MAT-file, Created by: matfile  "));
+        assert_ne!(
+            write(include_bytes!("../tests/double.mat"), &WriteOptions::new())[..116],
+            write(
+                include_bytes!("../tests/two_arrays.mat"),
+                &WriteOptions::new()
+            )[..116]
+        );
+
+        // Again after reading the file back
+        let read = parse_all(&first).unwrap();
+        assert_eq!(write_all_with_options(&read, &options).unwrap(), first);
+
+        // With a header text of the caller's choice
+        let options = options.header_text(Some("build 42"));
+        let written = write(include_bytes!("../tests/double.mat"), &options);
+        assert_eq!(written[..116], *format!("{:116}", "build 42").as_bytes());
+        assert_eq!(written[116..124], [0; 8]);
+        assert_eq!(written[128..], first[128..]);
+    }
+
+    #[test]
+    fn unsupported() {
+        let data = include_bytes!("../tests/string.mat");
+        let result = parse_all(data).unwrap();
+        assert!(matches!(
+            write_all(&result),
+            Err(WriteError::Unsupported(name)) if name == "s"
+        ));
+    }
+
+    #[test]
+    fn preserve_unsupported() {
+        let data = include_bytes!("../tests/opaque.mat");
+        let mut result = parse_all(data).unwrap();
+        let DataElement::NumericMatrix(numeric) = &mut result.data_elements[1] else {
+            panic!("expected a numeric array");
+        };
+        numeric.data = NumericData::Double {
+            real: vec![4.0, 5.0, 6.0],
+            imag: None,
+        };
+        for options in &[
+            WriteOptions::new(),
+            WriteOptions::new().compression(Some(CompressionLevel::Default)),
+        ] {
+            let written = write_all_with_options(&result, options).unwrap();
+            let read = parse_all(&written).unwrap();
+            assert_eq!(read.data_elements, result.data_elements);
+            let DataElement::Opaque(opaque) = &read.data_elements[0] else {
+                panic!("expected an opaque object");
+            };
+            assert_eq!(opaque.class_name, "Widget");
+            assert!(matches!(
+                &read.data_elements[1],
+                DataElement::NumericMatrix(Numeric { data: NumericData::Double { real, .. }, .. })
+                    if real == &[4.0, 5.0, 6.0]
+            ));
+        }
+
+        // Arrays of classes the parser doesn't know
+        for data in &[
+            &include_bytes!("../tests/unknown_class.mat")[..],
+            &include_bytes!("../tests/object.mat")[..],
+        ] {
+            let result = parse_all(data).unwrap();
+            let written = write_all(&result).unwrap();
+            assert_eq!(
+                parse_all(&written).unwrap().data_elements,
+                result.data_elements
+            );
+        }
+    }
+
+    #[test]
+    fn unsupported_byte_order() {
+        // Opaque objects and arrays of unknown classes are copied as they were
+        // read, which can't be done in the other byte order
+        let big_endian = WriteOptions::new().byte_order(ByteOrder::BigEndian);
+        for data in &[
+            &include_bytes!("../tests/opaque.mat")[..],
+            &include_bytes!("../tests/unknown_class.mat")[..],
+        ] {
+            let result = parse_all(data).unwrap();
+            assert!(result.data_elements.iter().any(|element| matches!(
+                element,
+                DataElement::Opaque(Opaque {
+                    byte_order: ByteOrder::LittleEndian,
+                    ..
+                }) | DataElement::Unsupported(UnsupportedArray {
+                    byte_order: ByteOrder::LittleEndian,
+                    ..
+                })
+            )));
+            assert!(matches!(
+                write_all_with_options(&result, &big_endian),
+                Err(WriteError::Unsupported(_))
+            ));
+            assert!(write_all(&result).is_ok());
+        }
+    }
+
+    #[test]
+    fn invalid_array() {
+        let data = include_bytes!("../tests/double.mat");
+        let mut result = parse_all(data).unwrap();
+        let DataElement::NumericMatrix(numeric) = &mut result.data_elements[0] else {
+            panic!("expected a numeric array");
+        };
+        numeric.header.dimensions = vec![4, 4].into();
+        assert!(matches!(
+            write_all(&result),
+            Err(WriteError::InvalidArray { .. })
+        ));
+    }
+
+    #[test]
+    fn skipped_imaginary_part() {
+        let options = crate::parse::ParseOptions::new().load_imaginary(false);
+        let is_skipped_error = |result: Result<_, WriteError>| {
+            matches!(
+                result,
+                Err(WriteError::InvalidArray { reason, .. }) if reason == "imaginary part was not loaded"
+            )
+        };
+        let sparse = include_bytes!("../tests/sparse2.mat");
+        let complex = MatFileBuilder::new()
+            .add_numeric(
+                "z",
+                &[1, 2],
+                NumericData::Double {
+                    real: vec![1.0, 2.0],
+                    imag: Some(vec![3.0, 4.0]),
+                },
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+        for data in [&sparse[..], &complex] {
+            let result = crate::parse::parse_all_with_options(data, &options).unwrap();
+            assert!(is_skipped_error(write_all(&result).map(|_| ())));
+
+            let data_element = &result.data_elements[0];
+            let name = data_element.name().unwrap();
+            let mut cursor = std::io::Cursor::new(data.to_vec());
+            assert!(is_skipped_error(update_variable_in(
+                &mut cursor,
+                name,
+                data_element,
+                &WriteOptions::new(),
+            )));
+            assert!(is_skipped_error(append_variable_to(
+                &mut cursor,
+                "copy",
+                data_element,
+                &WriteOptions::new(),
+            )));
+            assert_eq!(cursor.get_ref(), data);
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! Writer for the MAT-file Level 4 format, for software that can't read
+//! anything newer.
+//!
+//! Level 4 files can only hold two-dimensional numeric and text matrices,
+//! which are taken as the same types the Level 5 writer in
+//! [`crate::write`] uses. Anything else fails with
+//! [`WriteError::Unsupported`].
+
+use crate::parse::{ByteOrder, CharacterData, DataElement, Dimensions};
+use crate::write::{to_bytes, ToBytes, WriteError};
+use crate::NumericData;
+use std::convert::TryFrom;
+
+/// Writes the given variables, named by the first element of each pair, as
+/// a Level 4 file.
+///
+/// Numeric matrices are stored in their own type if Level 4 files have it,
+/// i.e. for double, single, int32, int16, uint16 and uint8 data. Other
+/// integer types fail with [`WriteError::Unsupported`], as do sparse
+/// matrices, cells and structures. Text is stored as doubles holding the
+/// UTF-16 code units, like Matlab does.
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let data = include_bytes!("../tests/v4.mat");
+/// let result = matfile::parse_v4::parse_all(data)?;
+/// let variables: Vec<_> = result.iter().collect();
+/// let written = matfile::write_v4::write_all(&variables, Default::default())?;
+/// assert_eq!(matfile::parse_v4::parse_all(&written)?.data_elements.len(), 3);
+/// # Ok(())
+/// # }
+/// ```
+pub fn write_all(
+    variables: &[(&str, &DataElement)],
+    byte_order: ByteOrder,
+) -> Result<Vec<u8>, WriteError> {
+    let mut out = Vec::new();
+    for &(name, data_element) in variables {
+        write_variable(&mut out, name, data_element, byte_order)?;
+    }
+    Ok(out)
+}
+
+fn write_variable(
+    out: &mut Vec<u8>,
+    name: &str,
+    data_element: &DataElement,
+    byte_order: ByteOrder,
+) -> Result<(), WriteError> {
+    let invalid = |reason| WriteError::InvalidArray {
+        name: name.to_string(),
+        reason,
+    };
+    match data_element {
+        DataElement::NumericMatrix(numeric) => {
+            let data = &numeric.data;
+            if numeric.header.dimensions.numel() != Some(data.len()) {
+                return Err(invalid("number of elements does not match the dimensions"));
+            }
+            if data.imag_len().is_some_and(|len| len != data.len()) {
+                return Err(invalid("imaginary part has a different length"));
+            }
+            if numeric.imag_skipped {
+                return Err(invalid("imaginary part was not loaded"));
+            }
+            let precision =
+                precision(data).ok_or_else(|| WriteError::Unsupported(name.to_string()))?;
+            let header = VariableHeader {
+                precision,
+                matrix_type: 0,
+                dimensions: &numeric.header.dimensions,
+                complex: data.is_complex(),
+            };
+            write_header(out, name, header, byte_order)?;
+            numeric_data_parts!(data, real, imag => {
+                out.extend(to_bytes(real.iter().copied(), byte_order));
+                if let Some(imag) = imag {
+                    out.extend(to_bytes(imag.iter().copied(), byte_order));
+                }
+            });
+            Ok(())
+        }
+        DataElement::CharacterMatrix(character) => {
+            let len = character.real_part.utf16_len();
+            if character
+                .imag_part
+                .as_ref()
+                .is_some_and(|imag| imag.utf16_len() != len)
+            {
+                return Err(invalid("imaginary part has a different length"));
+            }
+            // Strings get their dimensions from the text, like in Level 5
+            // files
+            let mut dimensions = character.header.dimensions.clone();
+            let is_string = dimensions.ndims() <= 2 && dimensions.rows() <= 1;
+            if is_string && dimensions.numel() != Some(len) {
+                dimensions = if len == 0 { vec![0, 0] } else { vec![1, len] }.into();
+            } else if dimensions.numel() != Some(len) {
+                return Err(invalid(
+                    "number of characters does not match the dimensions",
+                ));
+            }
+            let header = VariableHeader {
+                precision: 0,
+                matrix_type: 1,
+                dimensions: &dimensions,
+                complex: character.imag_part.is_some(),
+            };
+            write_header(out, name, header, byte_order)?;
+            write_characters(out, &character.real_part, byte_order);
+            if let Some(imag) = &character.imag_part {
+                write_characters(out, imag, byte_order);
+            }
+            Ok(())
+        }
+        _ => Err(WriteError::Unsupported(name.to_string())),
+    }
+}
+
+/// The P digit of the type code for data of this type, if Level 4 files
+/// can store it.
+fn precision(data: &NumericData) -> Option<i32> {
+    match data {
+        NumericData::Double { .. } => Some(0),
+        NumericData::Single { .. } => Some(1),
+        NumericData::Int32 { .. } => Some(2),
+        NumericData::Int16 { .. } => Some(3),
+        NumericData::UInt16 { .. } => Some(4),
+        NumericData::UInt8 { .. } => Some(5),
+        _ => None,
+    }
+}
+
+struct VariableHeader<'a> {
+    precision: i32,
+    /// 0 for numeric and 1 for text matrices
+    matrix_type: i32,
+    dimensions: &'a Dimensions,
+    complex: bool,
+}
+
+/// Writes the five integers starting a variable, followed by its name.
+fn write_header(
+    out: &mut Vec<u8>,
+    name: &str,
+    header: VariableHeader,
+    byte_order: ByteOrder,
+) -> Result<(), WriteError> {
+    if header.dimensions.ndims() != 2 {
+        return Err(WriteError::InvalidArray {
+            name: name.to_string(),
+            reason: "Level 4 files only hold two-dimensional matrices",
+        });
+    }
+    let too_large = |_| WriteError::TooLarge(name.to_string());
+    let rows = i32::try_from(header.dimensions.rows()).map_err(too_large)?;
+    let columns = i32::try_from(header.dimensions.columns()).map_err(too_large)?;
+    // The stored length includes the terminating null byte
+    let name_length = i32::try_from(name.len() + 1).map_err(too_large)?;
+    // The type code MOPT, with M giving the byte order and O always zero
+    let machine = match byte_order {
+        ByteOrder::LittleEndian => 0,
+        ByteOrder::BigEndian => 1,
+    };
+    let type_code = machine * 1000 + header.precision * 10 + header.matrix_type;
+    for value in [
+        type_code,
+        rows,
+        columns,
+        i32::from(header.complex),
+        name_length,
+    ] {
+        value.push_bytes(out, byte_order);
+    }
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    Ok(())
+}
+
+fn write_characters(out: &mut Vec<u8>, data: &CharacterData, byte_order: ByteOrder) {
+    let units: Vec<u16> = match data {
+        CharacterData::Unicode(str) => str.encode_utf16().collect(),
+        CharacterData::NonUnicode(units) => units.clone(),
+    };
+    out.extend(to_bytes(units.into_iter().map(f64::from), byte_order));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse::{ArrayFlags, ArrayHeader, Numeric, ParseResult};
+    use crate::parse_v4::parse_all;
+
+    fn numeric(name: &str, dimensions: Vec<usize>, data: NumericData) -> DataElement {
+        DataElement::NumericMatrix(Numeric {
+            header: ArrayHeader {
+                flags: ArrayFlags {
+                    complex: data.is_complex(),
+                    global: false,
+                    logical: false,
+                    class: data.class(),
+                    nzmax: 0,
+                },
+                dimensions: dimensions.into(),
+                name: name.to_string(),
+            },
+            data,
+            imag_skipped: false,
+        })
+    }
+
+    fn variables(result: &ParseResult) -> Vec<(&str, &DataElement)> {
+        result.iter().collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = include_bytes!("../tests/v4_be.mat");
+        let result = parse_all(data).unwrap();
+        let written = write_all(&variables(&result), ByteOrder::BigEndian).unwrap();
+        assert_eq!(written, data);
+
+        // z is stored as int16 in the fixture, but read as double
+        let data = include_bytes!("../tests/v4.mat");
+        let mut result = parse_all(data).unwrap();
+        result.data_elements[1] = numeric(
+            "z",
+            vec![2, 1],
+            NumericData::Int16 {
+                real: vec![1, -2],
+                imag: Some(vec![3, 4]),
+            },
+        );
+        let written = write_all(&variables(&result), ByteOrder::LittleEndian).unwrap();
+        assert_eq!(written, data);
+    }
+
+    #[test]
+    fn precisions() {
+        let arrays = [
+            numeric(
+                "d",
+                vec![1, 2],
+                NumericData::Double {
+                    real: vec![0.5, -1.0],
+                    imag: None,
+                },
+            ),
+            numeric(
+                "s",
+                vec![2, 1],
+                NumericData::Single {
+                    real: vec![0.25, 2.0],
+                    imag: None,
+                },
+            ),
+            numeric(
+                "i",
+                vec![1, 1],
+                NumericData::Int32 {
+                    real: vec![-7],
+                    imag: None,
+                },
+            ),
+            numeric(
+                "u",
+                vec![1, 1],
+                NumericData::UInt16 {
+                    real: vec![65535],
+                    imag: None,
+                },
+            ),
+            numeric(
+                "b",
+                vec![0, 0],
+                NumericData::UInt8 {
+                    real: vec![],
+                    imag: None,
+                },
+            ),
+        ];
+        let variables: Vec<_> = arrays
+            .iter()
+            .map(|array| (array.name().unwrap(), array))
+            .collect();
+        for byte_order in [ByteOrder::LittleEndian, ByteOrder::BigEndian] {
+            let written = write_all(&variables, byte_order).unwrap();
+            let read = parse_all(&written).unwrap();
+            assert_eq!(read.header.byte_order(), byte_order);
+            let values: Vec<_> = read
+                .data_elements
+                .iter()
+                .map(|data_element| match data_element {
+                    DataElement::NumericMatrix(numeric) => numeric.data.clone(),
+                    _ => panic!("expected a numeric matrix"),
+                })
+                .collect();
+            let double = |real: Vec<f64>| NumericData::Double { real, imag: None };
+            assert_eq!(
+                values,
+                [
+                    double(vec![0.5, -1.0]),
+                    double(vec![0.25, 2.0]),
+                    double(vec![-7.0]),
+                    double(vec![65535.0]),
+                    double(vec![]),
+                ]
+            );
+            // The header of the single matrix, after the 20 + 2 + 16 bytes of d
+            let type_code = &written[38..42];
+            match byte_order {
+                ByteOrder::LittleEndian => assert_eq!(type_code, 10i32.to_le_bytes()),
+                ByteOrder::BigEndian => assert_eq!(type_code, 1010i32.to_be_bytes()),
+            }
+        }
+    }
+
+    #[test]
+    fn unsupported() {
+        let int64 = numeric(
+            "x",
+            vec![1, 1],
+            NumericData::Int64 {
+                real: vec![1],
+                imag: None,
+            },
+        );
+        assert!(matches!(
+            write_all(&[("x", &int64)], ByteOrder::LittleEndian),
+            Err(WriteError::Unsupported(name)) if name == "x"
+        ));
+        let cube = numeric(
+            "c",
+            vec![1, 1, 2],
+            NumericData::Double {
+                real: vec![1.0, 2.0],
+                imag: None,
+            },
+        );
+        assert!(matches!(
+            write_all(&[("c", &cube)], ByteOrder::LittleEndian),
+            Err(WriteError::InvalidArray { name, .. }) if name == "c"
+        ));
+        let data = include_bytes!("../tests/struct.mat");
+        let result = crate::parse::parse_all(data).unwrap();
+        assert!(matches!(
+            write_all(&variables(&result), ByteOrder::LittleEndian),
+            Err(WriteError::Unsupported(_))
+        ));
+        let data = include_bytes!("../tests/sparse1.mat");
+        let result = crate::parse::parse_all(data).unwrap();
+        assert!(matches!(
+            write_all(&variables(&result), ByteOrder::LittleEndian),
+            Err(WriteError::Unsupported(_))
+        ));
+    }
+}
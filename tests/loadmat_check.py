@@ -0,0 +1,30 @@
+#!/usr/bin/env python3
+"""Loads .mat files written by matfile with scipy to check that they are read
+the same way elsewhere.
+
+Write the files with the writer tests and load them:
+
+    mkdir -p /tmp/matfile-check
+    MATFILE_CHECK_DIR=/tmp/matfile-check cargo test write::
+    python3 tests/loadmat_check.py /tmp/matfile-check/*.mat
+"""
+import sys
+
+import scipy.io
+
+
+def main(paths):
+    if not paths:
+        print(__doc__)
+        return 1
+    for path in paths:
+        print(f"== {path}")
+        contents = scipy.io.loadmat(path, simplify_cells=True)
+        for name, value in contents.items():
+            if not name.startswith("__"):
+                print(f"{name} = {value!r}")
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main(sys.argv[1:]))
@@ -0,0 +1,327 @@
+#!/usr/bin/env python3
+"""Writes tests/v73.mat, a v7.3 MAT-file laid out the way Matlab's HDF5 library
+writes them: a 512-byte user block with the MAT-file header, a version 0
+superblock, version 1 object headers, and groups with symbol tables.
+
+There is no HDF5 library in the build environment of the tests, so the file is
+written with the standard library only. It can be checked with h5dump or
+h5py where they are available:
+
+    python3 tests/make_v73.py tests/v73.mat
+    h5dump tests/v73.mat
+
+The variables are those Matlab would write for
+
+    a = [1 2 3; 4 5 6];
+    b = single([1+2i, -3.5]);
+    c = int32(reshape(1:12, 3, 4));          % chunked, shuffled and deflated
+    flag = [true false true];                % compact layout
+    s = 'héllo';
+    e = zeros(0, 3);
+    st = struct('x', 42, 'name', 'ab');
+    cel = {7, 'ab'};
+    sp = sparse([1 3 2], [1 1 2], [10 20 30], 3, 2);
+    sa = struct('v', {1, 'z'});
+    str = "text";                            % an object, which is skipped
+"""
+import struct
+import sys
+import zlib
+
+UNDEFINED = 0xFFFFFFFFFFFFFFFF
+USER_BLOCK = 512
+
+
+def pad8(data):
+    return data + bytes(-len(data) % 8)
+
+
+class File:
+    """The HDF5 part of the file. Addresses are relative to the superblock."""
+
+    def __init__(self):
+        # Room for the superblock, which is written last
+        self.buf = bytearray(96)
+
+    def alloc(self, data):
+        address = len(self.buf)
+        self.buf += pad8(bytes(data))
+        return address
+
+    def reserve(self, size):
+        return self.alloc(bytes(size))
+
+    def put(self, address, data):
+        self.buf[address:address + len(data)] = data
+
+
+# Datatypes
+
+
+def fixed(size, signed):
+    return bytes([0x10, 0x08 if signed else 0, 0, 0]) + struct.pack(
+        "<IHH", size, 0, size * 8)
+
+
+def floating(size):
+    if size == 8:
+        bits, props = [0x20, 63, 0], struct.pack("<HHBBBBI", 0, 64, 52, 11, 0, 52, 1023)
+    else:
+        bits, props = [0x20, 31, 0], struct.pack("<HHBBBBI", 0, 32, 23, 8, 0, 23, 127)
+    return bytes([0x11] + bits) + struct.pack("<I", size) + props
+
+
+def string(size):
+    return bytes([0x13, 0, 0, 0]) + struct.pack("<I", size)
+
+
+def reference():
+    return bytes([0x17, 0, 0, 0]) + struct.pack("<I", 8)
+
+
+def compound(members):
+    size = sum(len_ for _, len_, _ in members)
+    out = bytes([0x16]) + struct.pack("<H", len(members)) + b"\0" + struct.pack("<I", size)
+    offset = 0
+    for name, len_, datatype in members:
+        out += pad8(name.encode() + b"\0")
+        out += struct.pack("<IB3xI4x4I", offset, 0, 0, 0, 0, 0, 0)
+        out += datatype
+        offset += len_
+    return out
+
+
+def vlen_sequence(base):
+    return bytes([0x19, 0, 0, 0]) + struct.pack("<I", 16) + base
+
+
+# Messages
+
+
+def dataspace(dims):
+    return bytes([1, len(dims), 0, 0, 0, 0, 0, 0]) + b"".join(
+        struct.pack("<Q", d) for d in dims)
+
+
+def contiguous(address, size):
+    return bytes([3, 1]) + struct.pack("<QQ", address, size)
+
+
+def compact(data):
+    return bytes([3, 0]) + struct.pack("<H", len(data)) + data
+
+
+def chunked(btree, chunk, element_size):
+    return bytes([3, 2, len(chunk) + 1]) + struct.pack("<Q", btree) + b"".join(
+        struct.pack("<I", c) for c in list(chunk) + [element_size])
+
+
+def filter_pipeline(filters):
+    out = bytes([1, len(filters)]) + bytes(6)
+    for id_, values in filters:
+        out += struct.pack("<HHHH", id_, 0, 0, len(values))
+        out += b"".join(struct.pack("<I", v) for v in values)
+        if len(values) % 2:
+            out += bytes(4)
+    return out
+
+
+def attribute(name, datatype, space, data):
+    name = name.encode() + b"\0"
+    return (struct.pack("<BBHHH", 1, 0, len(name), len(datatype), len(space))
+            + pad8(name) + pad8(datatype) + pad8(space) + data)
+
+
+def class_attribute(name):
+    return attribute("MATLAB_class", string(len(name)), dataspace([]), name.encode())
+
+
+def uint_attribute(name, value, size=1):
+    return attribute(name, fixed(size, False), dataspace([]),
+                     value.to_bytes(size, "little"))
+
+
+def object_header(f, messages, split=None):
+    """Writes an object header, moving the messages from index `split` on
+    into a continuation block."""
+    def encode(messages):
+        return b"".join(
+            struct.pack("<HHB3x", kind, len(pad8(data)), 0) + pad8(data)
+            for kind, data in messages)
+    count = len(messages)
+    if split is not None:
+        rest = encode(messages[split:])
+        rest_address = f.alloc(rest)
+        messages = messages[:split] + [(0x10, struct.pack("<QQ", rest_address, len(rest)))]
+        count += 1
+    body = encode(messages)
+    return f.alloc(struct.pack("<BBHII4x", 1, 0, count, 1, len(body)) + body)
+
+
+def dataset(f, datatype, dims, data, attributes, layout="contiguous", split=None):
+    messages = [(0x01, dataspace(dims)), (0x03, datatype)]
+    if layout == "contiguous":
+        messages.append((0x08, contiguous(f.alloc(data), len(data))))
+    else:
+        messages.append((0x08, compact(data)))
+    messages += [(0x0C, a) for a in attributes]
+    return object_header(f, messages, split)
+
+
+def chunked_dataset(f, datatype, element_size, dims, chunk, values, attributes):
+    """A dataset of `values` in row-major order, shuffled and deflated in
+    chunks of the given shape."""
+    entries = []
+    for row in range(0, dims[0], chunk[0]):
+        for column in range(0, dims[1], chunk[1]):
+            data = b""
+            for r in range(row, row + chunk[0]):
+                for c in range(column, column + chunk[1]):
+                    inside = r < dims[0] and c < dims[1]
+                    value = values[r * dims[1] + c] if inside else 0
+                    data += value.to_bytes(element_size, "little", signed=True)
+            shuffled = bytes(data[i + j] for j in range(element_size)
+                             for i in range(0, len(data), element_size))
+            compressed = zlib.compress(shuffled, 6)
+            entries.append((len(compressed), (row, column, 0), f.alloc(compressed)))
+    node = b"TREE" + struct.pack("<BBHQQ", 1, 0, len(entries), UNDEFINED, UNDEFINED)
+    for size, offsets, address in entries:
+        node += struct.pack("<II3Q", size, 0, *offsets) + struct.pack("<Q", address)
+    node += struct.pack("<II3Q", 0, 0, dims[0], dims[1], 0)
+    btree = f.alloc(node)
+    messages = [
+        (0x01, dataspace(dims)),
+        (0x03, datatype),
+        (0x08, chunked(btree, chunk, element_size)),
+        (0x0B, filter_pipeline([(2, [element_size]), (1, [6])])),
+    ]
+    messages += [(0x0C, a) for a in attributes]
+    return object_header(f, messages)
+
+
+def group(f, members, attributes=()):
+    """A group with a symbol table of `members`, a dict of names to object
+    header addresses. Symbol table nodes hold at most 8 entries, as in files
+    written with the default settings."""
+    names = sorted(members)
+    heap = bytearray(8)  # The empty name at offset 0
+    offsets = {}
+    for name in names:
+        offsets[name] = len(heap)
+        heap += pad8(name.encode() + b"\0")
+    heap_data = f.alloc(heap)
+    heap_address = f.alloc(b"HEAP" + struct.pack("<B3xQQQ", 0, len(heap), UNDEFINED, heap_data))
+    children = []
+    for start in range(0, len(names), 8):
+        node_names = names[start:start + 8]
+        node = b"SNOD" + struct.pack("<BBH", 1, 0, len(node_names))
+        for name in node_names:
+            node += struct.pack("<QQII16x", offsets[name], members[name], 0, 0)
+        node += bytes(40 * (8 - len(node_names)))
+        children.append((offsets[node_names[-1]], f.alloc(node)))
+    node = b"TREE" + struct.pack("<BBHQQ", 0, 0, len(children), UNDEFINED, UNDEFINED)
+    node += struct.pack("<Q", 0)
+    for key, child in children:
+        node += struct.pack("<QQ", child, key)
+    btree = f.alloc(node)
+    messages = [(0x11, struct.pack("<QQ", btree, heap_address))]
+    messages += [(0x0C, a) for a in attributes]
+    return object_header(f, messages), btree, heap_address
+
+
+def global_heap(f, objects):
+    """A global heap collection, returning the address of the collection."""
+    body = b""
+    for index, data in enumerate(objects, 1):
+        body += struct.pack("<HH4xQ", index, 1, len(data)) + pad8(data)
+    size = 16 + len(body) + 16
+    body += struct.pack("<HH4xQ", 0, 0, 16)
+    return f.alloc(b"GCOL" + struct.pack("<B3xQ", 1, size) + body)
+
+
+def fields_attribute(f, names):
+    collection = global_heap(f, [n.encode() for n in names])
+    data = b"".join(struct.pack("<IQI", len(n), collection, i)
+                    for i, n in enumerate(names, 1))
+    return attribute("MATLAB_fields", vlen_sequence(string(1)), dataspace([len(names)]), data)
+
+
+def doubles(values):
+    return struct.pack("<%dd" % len(values), *values)
+
+
+def chars(text):
+    units = text.encode("utf-16-le")
+    return units
+
+
+def main(path):
+    f = File()
+    double, uint64 = floating(8), fixed(8, False)
+
+    refs = {}
+
+    def ref(name, address):
+        refs[name] = address
+        return struct.pack("<Q", address)
+
+    variables = {}
+    # Row-major data with the dimensions reversed is Matlab's column-major
+    # order
+    variables["a"] = dataset(f, double, [3, 2], doubles([1, 4, 2, 5, 3, 6]),
+                             [class_attribute("double")], split=2)
+    complex64 = compound([("real", 4, floating(4)), ("imag", 4, floating(4))])
+    variables["b"] = dataset(f, complex64, [2, 1], struct.pack("<4f", 1, 2, -3.5, 0),
+                             [class_attribute("single")])
+    variables["c"] = chunked_dataset(f, fixed(4, True), 4, [4, 3], [3, 2],
+                                     [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
+                                     [class_attribute("int32")])
+    variables["flag"] = dataset(f, fixed(1, False), [3, 1], bytes([1, 0, 1]),
+                                [class_attribute("logical")], layout="compact")
+    variables["s"] = dataset(f, fixed(2, False), [5, 1], chars("héllo"),
+                             [class_attribute("char"), uint_attribute("MATLAB_int_decode", 2, 4)])
+    variables["e"] = dataset(f, uint64, [2], struct.pack("<2Q", 0, 3),
+                             [class_attribute("double"), uint_attribute("MATLAB_empty", 1)])
+
+    x = dataset(f, double, [1, 1], doubles([42]), [class_attribute("double")])
+    name = dataset(f, fixed(2, False), [2, 1], chars("ab"), [class_attribute("char")])
+    variables["st"] = group(f, {"x": x, "name": name},
+                            [class_attribute("struct"), fields_attribute(f, ["x", "name"])])[0]
+
+    seven = dataset(f, double, [1, 1], doubles([7]), [class_attribute("double")])
+    ab = dataset(f, fixed(2, False), [2, 1], chars("ab"), [class_attribute("char")])
+    variables["cel"] = dataset(f, reference(), [2, 1], ref("a", seven) + ref("b", ab),
+                               [class_attribute("cell")])
+
+    sparse_data = dataset(f, double, [3], doubles([10, 20, 30]), [])
+    ir = dataset(f, uint64, [3], struct.pack("<3Q", 0, 2, 1), [])
+    jc = dataset(f, uint64, [3], struct.pack("<3Q", 0, 2, 3), [])
+    variables["sp"] = group(f, {"data": sparse_data, "ir": ir, "jc": jc},
+                            [class_attribute("double"), uint_attribute("MATLAB_sparse", 3, 8)])[0]
+
+    one = dataset(f, double, [1, 1], doubles([1]), [class_attribute("double")])
+    z = dataset(f, fixed(2, False), [1, 1], chars("z"), [class_attribute("char")])
+    v = dataset(f, reference(), [2, 1], ref("c", one) + ref("d", z), [])
+    variables["sa"] = group(f, {"v": v},
+                            [class_attribute("struct"), fields_attribute(f, ["v"])])[0]
+
+    variables["str"] = dataset(f, fixed(4, False), [6, 1], struct.pack("<6I", 0xDD000000, 2, 1, 1, 1, 1),
+                               [class_attribute("string"), uint_attribute("MATLAB_object_decode", 3, 4)])
+
+    variables["#refs#"] = group(f, refs)[0]
+    root, root_btree, root_heap = group(f, variables)
+
+    superblock = b"\x89HDF\r\n\x1a\n" + bytes([0, 0, 0, 0, 0, 8, 8, 0])
+    superblock += struct.pack("<HHI", 4, 16, 0)
+    superblock += struct.pack("<QQQQ", 0, UNDEFINED, len(f.buf), UNDEFINED)
+    superblock += struct.pack("<QQII", 0, root, 1, 0) + struct.pack("<QQ", root_btree, root_heap)
+    f.put(0, superblock)
+
+    text = b"MATLAB 7.3 MAT-file, Platform: GLNXA64, Created on: Sat Oct 10 12:00:00 2026 HDF5 schema 1.00 ."
+    header = text.ljust(116, b" ") + bytes(8) + struct.pack("<H", 0x0200) + b"IM"
+    with open(path, "wb") as out:
+        out.write(header.ljust(USER_BLOCK, b"\0") + f.buf)
+
+
+if __name__ == "__main__":
+    main(sys.argv[1] if len(sys.argv) > 1 else "tests/v73.mat")